@@ -1,9 +1,6 @@
-const net = require('net');
-const fs = require('fs');
+const EventEmitter = require('events');
 const path = require('path');
-const { spawn } = require('child_process');
-const crypto = require("crypto");
-const { app, dialog } = require('electron');
+const { app } = require('electron');
 
 
 let isPackaged;
@@ -17,138 +14,113 @@ if (
     isPackaged = true;
 }
 
-const serialize = (type, payload = {}) => {
-    const obj = {
-        type,
-        ...payload
-    };
-    const json = JSON.stringify(obj);
-    const buf = Buffer.alloc(256);
-    buf.write(json);
-
-    return buf;
-};
-
-const deserialize = data => JSON.parse(data.toString());
-
-class Socket {
-    constructor(socket) {
-        this._socket = socket;
+// The addon is built from src-rust/node, cargo names it after the platform
+const ADDON_NAME = {
+    darwin: 'libp2p_audio_node.dylib',
+    win32: 'p2p_audio_node.dll',
+}[process.platform] || 'libp2p_audio_node.so';
+
+// A request the audio engine could not carry out, `code` is one of the
+// ErrorCode values in src-rust/src/control.rs
+class ControlError extends Error {
+    constructor(code, message) {
+        super(message);
+        this.name = 'ControlError';
+        this.code = code;
     }
+}
 
-    config = () => {
-        const promise = new Promise((resolve, reject) => {
-            const cb = data => {
-                const msg = deserialize(data);
-                resolve(msg);
-            };
-            
-            this._socket.once('data', cb);
+const loadAddon = () => {
+    const addonPath = isPackaged
+        ? path.join(path.dirname(app.getAppPath()), '..', './Resources', './bin')
+        : path.join(app.getAppPath(), './src-rust', './target/debug');
 
-            this._socket.once('error', err => {
-                reject(err);
-            });
-        });
+    // require() only loads native modules ending in .node
+    const addon = { exports: {} };
+    process.dlopen(addon, path.resolve(path.join(addonPath, ADDON_NAME)));
 
-        const msg = serialize('config');
-        this._socket.write(msg);
+    return addon.exports;
+};
 
-        return promise;
+class AudioClient {
+    constructor(AudioEngine, options) {
+        // Emits messages the audio engine sends on its own, such as 'state',
+        // 'stats', 'peer_connected' or 'xrun', under their type and as 'event'.
+        // Pairing also reports the peer after its first response, which ends
+        // up here too.
+        this.events = new EventEmitter();
+        this._engine = new AudioEngine(msg => {
+            this.events.emit(msg.type, msg);
+            this.events.emit('event', msg);
+        }, options);
     }
 
-    connect = config => {
-        const promise = new Promise((resolve, reject) => {
-            const cb = data => {
-                const msg = deserialize(data);
-                resolve(msg);
-            };
-            
-            this._socket.once('data', cb);
+    // The engine answers a failed request with an error message
+    _check = async promise => {
+        const msg = await promise;
+        if (msg.type === 'error') {
+            throw new ControlError(msg.code, msg.message);
+        }
 
-            this._socket.once('error', err => {
-                reject(err);
-            });
-        });
+        return msg;
+    }
 
-        const msg = serialize('connect', {config});
-        this._socket.write(msg);
+    _request = (type, payload = {}) => this._check(this._engine.request({ type, ...payload }));
 
-        return promise;
-    }
-    
-    stream = async (remote, mode, config) => {
-        const msg = serialize('stream', {
-            remote_addr: remote,
-            mode,
-            config
-        });
-        this._socket.write(msg);
+    config = async () => {
+        const msg = await this._check(this._engine.devices());
+        return msg.configs;
     }
-}
 
-const createSocket = socketPath => {
-    const promise = new Promise((resolve, reject) => {
-        setTimeout(() => {
-            const socket = net.createConnection(socketPath);
+    connect = config => this._check(this._engine.connect({config}));
 
-            socket.once('error', () => {
-                reject();
-            });
+    diagnose = stunServers => this._request('diagnose', {stun_servers: stunServers});
 
-            socket.once('close', () => {
-                reject();
-            });
-
-            socket.on('connect', () => resolve(new Socket(socket)));
-        }, 500);
+    // Meets the peer in a room of the rendezvous server, resolves once the
+    // room is joined
+    rendezvous = (room, mode, config, options = {}) => this._request('rendezvous', {
+        room,
+        mode,
+        config,
+        ...options
     });
 
-    return promise;
-}
+    pairOffer = (mode, config) => this._request('pair_offer', {mode, config});
 
-const init = async () => {
+    pairAnswer = (code, config) => this._request('pair_answer', {code, config});
 
-    const TMP_FOLDER = '/tmp/com.claudio.App';
-    
-    if (!fs.existsSync(TMP_FOLDER)) {
-        fs.mkdirSync(TMP_FOLDER);
-    }
+    pairAccept = code => this._request('pair_accept', {code});
 
-    const socketName = crypto.randomBytes(4).toString('hex') + '.sock';
-    const socketPath = path.join(TMP_FOLDER, socketName);
-    
-    const binaryPath = isPackaged 
-        ? path.join(path.dirname(app.getAppPath()), '..', './Resources', './bin')
-        : path.join(app.getAppPath(), './src-rust', './target/debug');
+    forgetPeer = name => this._request('forget_peer', {name});
 
-    const binaryExecPath  = path.resolve(path.join(binaryPath, './p2p_audio'));
+    advertise = (name, sampleRates) => this._request('advertise', {name, sample_rates: sampleRates});
 
-    const p = spawn(binaryExecPath, [socketPath]);
+    discover = () => this._request('discover');
 
-    p.stdout.on('data', data => console.log(data.toString()));
-    p.stderr.on('data', data => console.log(data.toString()));
+    stream = (remote, mode, config, options = {}) => this._check(this._engine.stream({
+        remote_addr: remote,
+        mode,
+        config,
+        ...options
+    }));
 
-    app.on('before-quit', function () {
-        p.kill();
-    });
+    // Changes the running session, e.g. {input_gain: 0.5, mute: true,
+    // jitter_target: 20}, see StreamParams in src-rust/src/session.rs
+    setParams = params => this._check(this._engine.setParams(params));
 
-    let socket;
-    let tries = 0;
-    while (true) {
-        try {
-            socket = await createSocket(socketPath);
-            break;
-        } catch(e) {
-            if (tries < 5) {
-                tries += 1;
-                continue;
-            }
-
-            throw e;
-        }
-    }
+    // Paths, send rate and xruns of the running session
+    stats = () => this._check(this._engine.stats());
+
+    stop = () => this._check(this._engine.stop());
+}
+
+// `options` are those of the AudioEngine in src-rust/node, such as
+// stun_servers or rendezvous_url
+const init = async (options = {}) => {
+    const { AudioEngine } = loadAddon();
 
-    return socket;
+    return new AudioClient(AudioEngine, options);
 }
 
-module.exports = init;
\ No newline at end of file
+module.exports = init;
+module.exports.ControlError = ControlError;
@@ -9,14 +9,14 @@ contextBridge.exposeInMainWorld('electron', {
         ipcRenderer.send(channel, ...args);
       },
       on(channel, func) {
-        const validChannels = ['return', 'send'];
+        const validChannels = ['return', 'send', 'audio'];
         if (validChannels.includes(channel)) {
           // Deliberately strip event as it includes `sender`
           ipcRenderer.on(channel, (event, ...args) => func(...args));
         }
       },
       once(channel, func) {
-        const validChannels = ['return', 'send'];
+        const validChannels = ['return', 'send', 'audio'];
         if (validChannels.includes(channel)) {
           // Deliberately strip event as it includes `sender`
           ipcRenderer.once(channel, (event, ...args) => func(...args));
@@ -0,0 +1,18 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Generates the C header for the interface in `src/ffi.rs`.
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Could not generate the C header")
+        .write_to_file(crate_dir.join("include/p2p_audio.h"));
+}
@@ -0,0 +1,143 @@
+/* Drives a loopback session through the C interface: a sending and a
+ * returning session on 127.0.0.1 stream the default input device to the
+ * default output device for a few seconds, printing events and statistics.
+ *
+ * With --handshake-only, the sessions are given devices that do not exist,
+ * so both run the handshake and then have to fail on the devices. This
+ * needs no sound card.
+ *
+ * Build the crate first, then run `make check` in this directory, or run
+ * `cargo test`, which does both. */
+
+#include <pthread.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "p2p_audio.h"
+
+#define DURATION 5
+
+static const char *missing_devices =
+    "{\"host\": \"\", \"inputDevice\": \"No such input\", \"outputDevice\": \"No such output\", "
+    "\"sampleRate\": 48000, \"bufferSize\": 64, \"stereo\": false, \"inputChannel\": 0, \"outputChannel\": 0}";
+
+struct start {
+    P2pAudioSession *session;
+    P2pAudioStatus status;
+};
+
+static void *start_session(void *arg)
+{
+    struct start *start = arg;
+
+    start->status = p2p_audio_session_start(start->session);
+    return NULL;
+}
+
+static int check(P2pAudioStatus status, const char *what, P2pAudioSession *session)
+{
+    if (status != P2P_AUDIO_STATUS_OK)
+        fprintf(stderr, "%s failed (%d): %s\n", what, status, p2p_audio_session_last_error(session));
+
+    return status == P2P_AUDIO_STATUS_OK;
+}
+
+static void print_events(const char *label, P2pAudioSession *session)
+{
+    char *event;
+
+    while ((event = p2p_audio_session_poll_event(session)) != NULL) {
+        printf("%s: %s\n", label, event);
+        p2p_audio_string_free(event);
+    }
+}
+
+/* Points each session at the port the other is bound to. */
+static int connect_sessions(P2pAudioSession *sender, P2pAudioSession *receiver)
+{
+    char address[32];
+
+    snprintf(address, sizeof address, "127.0.0.1:%u", p2p_audio_session_local_port(receiver));
+    if (!check(p2p_audio_session_set_remote(sender, address), "Setting the receiver's address", sender))
+        return 0;
+
+    snprintf(address, sizeof address, "127.0.0.1:%u", p2p_audio_session_local_port(sender));
+    return check(p2p_audio_session_set_remote(receiver, address), "Setting the sender's address", receiver);
+}
+
+int main(int argc, char **argv)
+{
+    int handshake_only = argc > 1 && strcmp(argv[1], "--handshake-only") == 0;
+    const char *identity_dir = argc > 1 + handshake_only ? argv[1 + handshake_only] : "/tmp/p2p_audio_loopback";
+    P2pAudioSession *sender = p2p_audio_session_new(P2P_AUDIO_MODE_SEND);
+    P2pAudioSession *receiver = p2p_audio_session_new(P2P_AUDIO_MODE_RETURN);
+    struct start start = { receiver, P2P_AUDIO_STATUS_OK };
+    P2pAudioStatus send_status;
+    pthread_t thread;
+    char *fingerprint;
+    char *stats;
+    int result = 1;
+    int i;
+
+    if (!check(p2p_audio_session_bind(sender, "127.0.0.1:0"), "Binding the sender", sender) ||
+        !check(p2p_audio_session_bind(receiver, "127.0.0.1:0"), "Binding the receiver", receiver) ||
+        !connect_sessions(sender, receiver) ||
+        !check(p2p_audio_session_set_identity_dir(sender, identity_dir), "Setting the identity", sender) ||
+        !check(p2p_audio_session_set_identity_dir(receiver, identity_dir), "Setting the identity", receiver) ||
+        !check(p2p_audio_session_set_params(receiver, "{\"jitter_target\": 20}"), "Setting the jitter target", receiver))
+        goto done;
+
+    if (handshake_only &&
+        (!check(p2p_audio_session_set_audio_config(sender, missing_devices), "Setting the audio config", sender) ||
+         !check(p2p_audio_session_set_audio_config(receiver, missing_devices), "Setting the audio config", receiver)))
+        goto done;
+
+    /* Each start blocks until the handshake with the other side is done */
+    pthread_create(&thread, NULL, start_session, &start);
+    send_status = p2p_audio_session_start(sender);
+    pthread_join(thread, NULL);
+
+    /* The devices are only opened once the handshake has succeeded */
+    if (handshake_only) {
+        if (send_status == P2P_AUDIO_STATUS_AUDIO_DEVICE && start.status == P2P_AUDIO_STATUS_AUDIO_DEVICE) {
+            printf("Handshake done, the devices are missing as expected\n");
+            result = 0;
+        } else {
+            fprintf(stderr, "Expected both sessions to fail on the devices, got %d and %d\n", send_status, start.status);
+        }
+        goto done;
+    }
+    if (!check(send_status, "Starting the sender", sender) || !check(start.status, "Starting the receiver", receiver))
+        goto done;
+
+    fingerprint = p2p_audio_session_fingerprint(sender);
+    printf("Streaming to %s\n", fingerprint);
+    p2p_audio_string_free(fingerprint);
+
+    for (i = 0; i < DURATION; i++) {
+        sleep(1);
+        print_events("send", sender);
+        print_events("return", receiver);
+
+        stats = p2p_audio_session_stats(receiver);
+        printf("stats: %s\n", stats);
+        p2p_audio_string_free(stats);
+
+        if (!p2p_audio_session_is_running(sender) || !p2p_audio_session_is_running(receiver)) {
+            fprintf(stderr, "A session stopped on its own\n");
+            goto done;
+        }
+    }
+
+    if (check(p2p_audio_session_set_params(sender, "{\"input_gain\": 0.5}"), "Changing the gain", sender) &&
+        check(p2p_audio_session_stop(sender), "Stopping the sender", sender) &&
+        check(p2p_audio_session_stop(receiver), "Stopping the receiver", receiver))
+        result = 0;
+
+done:
+    p2p_audio_session_free(sender);
+    p2p_audio_session_free(receiver);
+
+    return result;
+}
@@ -0,0 +1,114 @@
+/* Generated by cbindgen from src/ffi.rs when the crate is built, do not edit. */
+
+#ifndef P2P_AUDIO_H
+#define P2P_AUDIO_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum P2pAudioMode {
+  P2P_AUDIO_MODE_SEND = 0,
+  P2P_AUDIO_MODE_RETURN,
+} P2pAudioMode;
+
+// What a call returned. Anything but `Ok` leaves a message to read with
+// `p2p_audio_session_last_error`.
+typedef enum P2pAudioStatus {
+  P2P_AUDIO_STATUS_OK = 0,
+  // A null pointer, a string that is not UTF-8, or malformed JSON.
+  P2P_AUDIO_STATUS_INVALID_ARGUMENT,
+  // An audio device is missing or does not support the config.
+  P2P_AUDIO_STATUS_AUDIO_DEVICE,
+  P2P_AUDIO_STATUS_BIND_FAILED,
+  P2P_AUDIO_STATUS_INVALID_ADDRESS,
+  // The handshake timed out or the peer used another key.
+  P2P_AUDIO_STATUS_HANDSHAKE_FAILED,
+  // The call needs a running session and there is none.
+  P2P_AUDIO_STATUS_NO_SESSION,
+  // The call needs a session that has not started yet.
+  P2P_AUDIO_STATUS_SESSION_ACTIVE,
+  // A setting is out of range.
+  P2P_AUDIO_STATUS_INVALID_PARAMS,
+  P2P_AUDIO_STATUS_INTERNAL,
+} P2pAudioStatus;
+
+typedef struct P2pAudioSession P2pAudioSession;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a session that sends or returns audio. Free it with
+// `p2p_audio_session_free`.
+struct P2pAudioSession *p2p_audio_session_new(enum P2pAudioMode mode);
+
+// Stops the session if it runs and frees it.
+void p2p_audio_session_free(struct P2pAudioSession *session);
+
+// Binds the session socket to `local_addr`, e.g. `0.0.0.0:5000`. Without
+// this, the session binds any free port when it starts.
+enum P2pAudioStatus p2p_audio_session_bind(struct P2pAudioSession *session, const char *local_addr);
+
+// The port the session socket is bound to, 0 before it is bound.
+uint16_t p2p_audio_session_local_port(const struct P2pAudioSession *session);
+
+// Sets where the peer is, `host:port`.
+enum P2pAudioStatus p2p_audio_session_set_remote(struct P2pAudioSession *session,
+                                                 const char *remote_addr);
+
+// Sets the devices and format, as JSON in the form the daemon's `stream`
+// request takes. Without this, the default devices are used.
+enum P2pAudioStatus p2p_audio_session_set_audio_config(struct P2pAudioSession *session,
+                                                       const char *json);
+
+// Sets a pre-shared key of 64 hex digits, the peer has to use the same one.
+enum P2pAudioStatus p2p_audio_session_set_key(struct P2pAudioSession *session, const char *hex);
+
+// Sets where the identity is kept, created on first use. Defaults to the
+// one the desktop app uses.
+enum P2pAudioStatus p2p_audio_session_set_identity_dir(struct P2pAudioSession *session,
+                                                       const char *dir);
+
+// Changes settings, as JSON in the form of the daemon's `set_params`
+// request. Before the session starts, they apply once audio runs.
+enum P2pAudioStatus p2p_audio_session_set_params(struct P2pAudioSession *session, const char *json);
+
+// Runs the handshake and starts streaming. Blocks until audio runs or the
+// handshake fails, which takes until the peer starts too.
+enum P2pAudioStatus p2p_audio_session_start(struct P2pAudioSession *session);
+
+// Ends the session and waits for it.
+enum P2pAudioStatus p2p_audio_session_stop(struct P2pAudioSession *session);
+
+// Whether the session is streaming. It stops on its own when it fails.
+bool p2p_audio_session_is_running(const struct P2pAudioSession *session);
+
+// The next event as JSON, in the form the daemon sends events, or null
+// when there is none. Does not block.
+char *p2p_audio_session_poll_event(struct P2pAudioSession *session);
+
+// Statistics of the running session as JSON, null when it has not started.
+char *p2p_audio_session_stats(struct P2pAudioSession *session);
+
+// The peer's fingerprint once the session started, else null.
+char *p2p_audio_session_fingerprint(struct P2pAudioSession *session);
+
+// Why the last call on the session failed, null if it did not. Owned by
+// the session and valid until the next call on it.
+const char *p2p_audio_session_last_error(const struct P2pAudioSession *session);
+
+// Audio hosts, devices and supported configs as JSON, in the form the
+// daemon's `config` response carries them. Null if they can not be listed.
+char *p2p_audio_devices(void);
+
+// Frees a string returned by this library.
+void p2p_audio_string_free(char *string);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* P2P_AUDIO_H */
@@ -0,0 +1,203 @@
+//! Node addon for the desktop app. It carries out the requests of the
+//! control protocol in process, so the app needs neither the binary nor the
+//! control socket. Requests and responses are the same JSON objects the
+//! socket carries, minus the framing, the hello and the request ids.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use anyhow::anyhow;
+use napi::{Env, JsDeferred, JsFunction, JsObject};
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use p2p_audio::control::ErrorCode;
+use p2p_audio::daemon::{Credentials, Daemon, RecvMessage, Respond, SendMessage};
+use p2p_audio::events::{Event, Events, State};
+use p2p_audio::identity;
+use p2p_audio::net::BindOptions;
+use p2p_audio::rendezvous;
+use p2p_audio::stun;
+
+type EventSink = ThreadsafeFunction<Value, ErrorStrategy::Fatal>;
+type Deferred = JsDeferred<Value, Box<dyn FnOnce(Env) -> napi::Result<Value> + Send>>;
+
+/// A request and the promise its first response resolves.
+struct Request {
+    message: Value,
+    deferred: Deferred,
+}
+
+/// What the command line takes as global options.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Options {
+    stun_servers: Option<Vec<String>>,
+    /// Where the identity and known peers are kept, defaults to
+    /// ~/.config/claudio.
+    config_dir: Option<PathBuf>,
+    /// Rendezvous server to meet peers through.
+    rendezvous_url: Option<String>,
+    bind: BindOptions,
+}
+
+/// The session state of one app. A worker thread owns the daemon and carries
+/// out the requests one after the other in the order they were made, as they
+/// would be on the control socket. A request that waits for the peer settles
+/// later, `stop` is carried out in the meantime.
+#[napi]
+pub struct AudioEngine {
+    requests: Sender<Request>,
+}
+
+#[napi]
+impl AudioEngine {
+    /// `onEvent` gets the events the daemon pushes to its client, and the
+    /// responses that come after the first, such as the peer pairing reports
+    /// after the connection code.
+    #[napi(constructor)]
+    pub fn new(env: Env, on_event: JsFunction, options: Option<Value>) -> napi::Result<Self> {
+        let options: Options = match options {
+            Some(options) => serde_json::from_value(options).map_err(to_napi)?,
+            None => Options::default()
+        };
+
+        let mut events: EventSink = on_event.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+        // Pending events do not keep Node running
+        events.unref(&env)?;
+
+        let stun_servers = options.stun_servers.unwrap_or_else(|| {
+            stun::client::DEFAULT_SERVERS.iter().map(|server| server.to_string()).collect()
+        });
+        let config_dir = match options.config_dir {
+            Some(dir) => dir,
+            None => identity::default_dir().map_err(to_napi)?
+        };
+        let credentials = Credentials::load(&config_dir).map_err(to_napi)?;
+        let rendezvous_url = options.rendezvous_url.unwrap_or_else(|| rendezvous::client::DEFAULT_URL.to_string());
+
+        let daemon = Daemon::new(stun_servers, rendezvous_url, &options.bind, credentials, forward_events(events.clone())).map_err(to_napi)?;
+
+        let (requests, queue) = mpsc::channel();
+        thread::spawn(move || serve(daemon, queue, events));
+
+        Ok(Self { requests })
+    }
+
+    /// Carries out any request, `message.type` says which. The promise
+    /// resolves with the response, which is an `error` message if the
+    /// request failed.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn request(&self, env: Env, message: Value) -> napi::Result<JsObject> {
+        let (deferred, promise) = env.create_deferred()?;
+        self.requests.send(Request { message, deferred }).map_err(|_| to_napi("The audio engine has stopped"))?;
+
+        Ok(promise)
+    }
+
+    /// The audio hosts, their devices and the configs they support.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn devices(&self, env: Env) -> napi::Result<JsObject> {
+        self.request(env, json!({ "type": "config" }))
+    }
+
+    /// Gathers candidates for the peer, see `connect` in the protocol.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn connect(&self, env: Env, options: Option<Value>) -> napi::Result<JsObject> {
+        self.request(env, with_type("connect", options))
+    }
+
+    /// Starts streaming with the peer.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn stream(&self, env: Env, options: Value) -> napi::Result<JsObject> {
+        self.request(env, with_type("stream", Some(options)))
+    }
+
+    /// Changes settings of the running session.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn set_params(&self, env: Env, params: Value) -> napi::Result<JsObject> {
+        self.request(env, with_type("set_params", Some(params)))
+    }
+
+    /// Statistics of the running session.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn stats(&self, env: Env) -> napi::Result<JsObject> {
+        self.request(env, json!({ "type": "stats" }))
+    }
+
+    /// Ends the running session.
+    #[napi(ts_return_type = "Promise<object>")]
+    pub fn stop(&self, env: Env) -> napi::Result<JsObject> {
+        self.request(env, json!({ "type": "stop" }))
+    }
+}
+
+/// Carries out the requests until the engine is dropped, which also ends
+/// the running session.
+fn serve(mut daemon: Daemon, requests: Receiver<Request>, events: EventSink) {
+    for Request { message, deferred } in requests {
+        let deferred = Mutex::new(Some(deferred));
+        let events = events.clone();
+        let respond: Respond = Arc::new(move |res: SendMessage| {
+            let value = serde_json::to_value(&res)?;
+            match deferred.lock().unwrap().take() {
+                Some(deferred) => deferred.resolve(Box::new(move |_| Ok(value))),
+                None => {
+                    events.call(value, ThreadsafeFunctionCallMode::NonBlocking);
+                }
+            };
+            Ok(())
+        });
+
+        let result = match serde_json::from_value::<RecvMessage>(message) {
+            Ok(res) => daemon.request(res, respond.clone()).map_err(|err| (ErrorCode::of(&err), err)),
+            Err(err) => Err((ErrorCode::InvalidMessage, anyhow!(err).context("Invalid request")))
+        };
+
+        if let Err((code, err)) = result {
+            eprintln!("{:#}", err);
+            let _ = respond(SendMessage::error(&err, code));
+        }
+    }
+}
+
+/// Passes events on to JavaScript. A failed request resets the state, which
+/// may not have changed, so repeats are dropped.
+fn forward_events(sink: EventSink) -> Events {
+    let current_state = Mutex::new(State::Idle);
+
+    Events::callback(move |event| {
+        if let Event::State { state } = event {
+            let mut current_state = current_state.lock().unwrap();
+            if state == *current_state {
+                return;
+            }
+            *current_state = state;
+        }
+
+        match serde_json::to_value(&event) {
+            Ok(value) => {
+                sink.call(value, ThreadsafeFunctionCallMode::NonBlocking);
+            },
+            Err(err) => eprintln!("Could not send event: {}", err)
+        };
+    })
+}
+
+fn with_type(kind: &str, payload: Option<Value>) -> Value {
+    let mut message = match payload {
+        Some(Value::Object(fields)) => fields,
+        _ => serde_json::Map::new()
+    };
+    message.insert("type".to_string(), Value::from(kind));
+
+    Value::Object(message)
+}
+
+fn to_napi(err: impl std::fmt::Display) -> napi::Error {
+    napi::Error::from_reason(format!("{:#}", err))
+}
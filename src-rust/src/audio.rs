@@ -1,12 +1,15 @@
 extern crate cpal;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use cpal::{
     StreamConfig,
     SupportedBufferSize,
     BufferSize,
     Device,
+    Host,
     SampleRate,
     SupportedStreamConfigRange,
     Stream
@@ -16,8 +19,16 @@ use ringbuf::{Producer, Consumer};
 use anyhow::{Result, anyhow};
 use serde::{Serialize, Deserialize};
 
+use crate::events::{Event, Events};
+use crate::ringbuffer;
 use crate::util::Mode;
 
+pub mod params;
+
+use params::{AudioParams, Ramp, Splice};
+
+pub type SupportedConfigs = HashMap<String, HashMap<String, HashMap<String, SupportedConfig>>>;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AudioConfig {
@@ -32,6 +43,7 @@ pub struct AudioConfig {
 }
 
 impl AudioConfig {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         host: String,
         input_device: String,
@@ -43,7 +55,7 @@ impl AudioConfig {
         output_channel: u32,
     ) -> Self {
 
-        let config = Self {
+        Self {
             host,
             stereo,
             input_channel,
@@ -52,9 +64,7 @@ impl AudioConfig {
             buffer_size,
             input_device,
             output_device
-        };
-
-        config
+        }
     }
 
     pub fn get_channel_count(&self) -> u32 {
@@ -64,42 +74,77 @@ impl AudioConfig {
         }
     }
 
+    pub fn set_stereo(&mut self, stereo: bool) {
+        self.stereo = stereo;
+    }
+
     pub fn get_frame_size(&self) -> usize {
         (self.buffer_size * self.get_channel_count()) as usize
     }
 
-}
+    /// The default devices of the default host, failing when there is no
+    /// input or output device.
+    pub fn from_default_devices() -> Result<Self> {
+        AudioConfig::from_host_devices(cpal::default_host())
+    }
 
-impl Default for AudioConfig {
-    fn default() -> Self {
-        let host = cpal::default_host();
-        let input_device = host.default_input_device().unwrap();
-        let output_device = host.default_output_device().unwrap();
+    /// The default devices of the host with the given name, e.g. `JACK`.
+    pub fn from_host_defaults(name: &str) -> Result<Self> {
+        let host_id = cpal::available_hosts().into_iter()
+            .find(|host_id| host_id.name() == name)
+            .ok_or(anyhow!("No audio host named {}", name))?;
+
+        AudioConfig::from_host_devices(cpal::host_from_id(host_id)?)
+    }
 
-        AudioConfig::new(
+    fn from_host_devices(host: Host) -> Result<Self> {
+        let input_device = host.default_input_device().ok_or(anyhow!("No default input device"))?;
+        let output_device = host.default_output_device().ok_or(anyhow!("No default output device"))?;
+
+        Ok(AudioConfig::new(
             host.id().name().to_string(),
-            input_device.name().unwrap(),
-            output_device.name().unwrap(),
+            input_device.name()?,
+            output_device.name()?,
             44100,
             128,
             false,
             0,
             0,
-        )
+        ))
     }
 }
 
 #[derive(Serialize, Debug)]
 pub struct Buffer {
-    min: u32,
-    max: u32,
+    pub min: u32,
+    pub max: u32,
 }
 
 #[derive(Serialize, Debug)]
 pub struct SupportedConfig {
-    sample_rates: Vec<u32>,
-    buffer_size: Buffer,
-    channels: u16
+    pub sample_rates: Vec<u32>,
+    pub buffer_size: Buffer,
+    pub channels: u16
+}
+
+/// Audio callbacks that could not keep up with the network side. Counted
+/// with atomics, since the callbacks must not block.
+#[derive(Debug, Default)]
+pub struct Xruns {
+    /// Input callbacks that found the ring buffer full.
+    overruns: AtomicU64,
+    /// Output callbacks that found the ring buffer empty.
+    underruns: AtomicU64,
+}
+
+impl Xruns {
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
 }
 
 pub struct AudioInterface {
@@ -108,6 +153,9 @@ pub struct AudioInterface {
     output_device: Device,
     input_config: StreamConfig,
     output_config: StreamConfig,
+    xruns: Arc<Xruns>,
+    params: Arc<AudioParams>,
+    events: Events,
 }
 
 impl AudioInterface {
@@ -116,9 +164,9 @@ impl AudioInterface {
 
         let mut devices = host.devices()?;
 
-        let input_device = devices.find(|device| device.name().unwrap() == config.input_device).ok_or(anyhow!("Could not find input device"))?;
+        let input_device = devices.find(|device| device.name().is_ok_and(|name| name == config.input_device)).ok_or(anyhow!("Could not find input device"))?;
 
-        let output_device = devices.find(|device| device.name().unwrap() == config.output_device).ok_or(anyhow!("Could not find output device"))?;
+        let output_device = devices.find(|device| device.name().is_ok_and(|name| name == config.output_device)).ok_or(anyhow!("Could not find output device"))?;
         
         let supported_input_configs = input_device.supported_input_configs()?;
         let _input_config = AudioInterface::get_config(supported_input_configs, config.sample_rate, config.buffer_size, config.input_channel + config.get_channel_count())?;
@@ -129,7 +177,7 @@ impl AudioInterface {
         Ok(())
     }
 
-    pub fn get_supported_configs() -> Result<HashMap<String, HashMap<String, HashMap<String, SupportedConfig>>>> {
+    pub fn get_supported_configs() -> Result<SupportedConfigs> {
         
         let available_hosts = cpal::available_hosts();
 
@@ -165,7 +213,7 @@ impl AudioInterface {
                 }
 
                 let input_config = SupportedConfig {
-                    sample_rates: sample_rates,
+                    sample_rates,
                     channels,
                     buffer_size
                 };
@@ -185,7 +233,7 @@ impl AudioInterface {
                 }
 
                 let output_config = SupportedConfig {
-                    sample_rates: sample_rates,
+                    sample_rates,
                     channels,
                     buffer_size
                 };
@@ -193,7 +241,7 @@ impl AudioInterface {
                 device_map.insert("input".to_string(), input_config);
                 device_map.insert("output".to_string(), output_config);
 
-                host_map.insert(device.name().unwrap(), device_map);
+                host_map.insert(device.name()?, device_map);
             }
 
             val.insert(host_id.name().to_string(), host_map);
@@ -225,11 +273,11 @@ impl AudioInterface {
     pub fn new(config: AudioConfig) -> Result<AudioInterface> {
         let host = cpal::default_host();
 
-        let mut devices = host.devices()?.into_iter();
+        let mut devices = host.devices()?;
         
-        let input_device = devices.find(|device| device.name().unwrap() == config.input_device).ok_or(anyhow!("Could not find input device"))?;
+        let input_device = devices.find(|device| device.name().is_ok_and(|name| name == config.input_device)).ok_or(anyhow!("Could not find input device"))?;
 
-        let output_device = devices.find(|device| device.name().unwrap() == config.output_device).ok_or(anyhow!("Could not find output device"))?;
+        let output_device = devices.find(|device| device.name().is_ok_and(|name| name == config.output_device)).ok_or(anyhow!("Could not find output device"))?;
         
         let supported_input_configs = input_device.supported_input_configs()?;
         let input_config = AudioInterface::get_config(supported_input_configs, config.sample_rate, config.buffer_size, config.input_channel + config.get_channel_count())?;
@@ -237,103 +285,162 @@ impl AudioInterface {
         let supported_output_configs = output_device.supported_output_configs()?;
         let output_config = AudioInterface::get_config(supported_output_configs, config.sample_rate, config.buffer_size, config.output_channel + config.get_channel_count())?;
 
+        let params = AudioParams::new(
+            config.input_channel,
+            config.output_channel,
+            config.get_channel_count(),
+            input_config.channels as u32,
+            output_config.channels as u32,
+            config.sample_rate,
+            config.get_frame_size() * ringbuffer::BUFFERED_FRAMES
+        );
+
         let audio_interface = AudioInterface {
             audio_config: config,
             input_device,
             output_device,
             input_config,
             output_config,
+            xruns: Arc::new(Xruns::default()),
+            params: Arc::new(params),
+            events: Events::default(),
         };
 
         Ok(audio_interface)
     }
 
+    /// Reports device errors to `events`.
+    pub fn set_events(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    /// Settings that can be changed while the streams run.
+    pub fn params(&self) -> Arc<AudioParams> {
+        self.params.clone()
+    }
+
+    pub fn xruns(&self) -> Arc<Xruns> {
+        self.xruns.clone()
+    }
+
     pub fn build_streams(
         &self,
         mode: &Mode,
         mut input_producer: Producer<f32>,
         mut output_consumer: Consumer<f32>
-    ) -> Result<(Stream)> {
+    ) -> Result<Stream> {
         
         match mode {
             Mode::Send => {
-                let start = self.audio_config.input_channel;
-                let end = self.audio_config.input_channel + self.audio_config.get_channel_count();
-                let channels = self.input_config.channels as u32;
+                let params = self.params.clone();
+                let count = self.audio_config.get_channel_count() as usize;
+                let channels = self.input_config.channels as usize;
+                let xruns = self.xruns.clone();
+                let mut active_channel = params.input_channel() as usize;
+                let mut ramp = Ramp::new(0.0, self.audio_config.sample_rate);
 
                 let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
                     let mut output_fell_behind = false;
-                    let mut count = 0; 
-                    
-                    for &sample in data {
-                        if count >= start && count < end {
-                            if input_producer.push(sample).is_err() {
-                                output_fell_behind = true;
-                            }
+                    let channel = params.input_channel() as usize;
+                    let gain = match params.muted() {
+                        true => 0.0,
+                        false => params.input_gain()
+                    };
+
+                    for frame in data.chunks(channels) {
+                        // Another channel is faded in only once the current
+                        // one has faded out
+                        let switching = channel != active_channel;
+                        let frame_gain = ramp.next(if switching { 0.0 } else { gain });
+                        if switching && frame_gain == 0.0 {
+                            active_channel = channel;
                         }
-                        count += 1;
 
-                        if count >= channels {
-                            count = 0;
+                        for &sample in frame.iter().skip(active_channel).take(count) {
+                            if input_producer.push(sample * frame_gain).is_err() {
+                                output_fell_behind = true;
+                            }
                         }
                     }
                     if output_fell_behind {
-                        // eprintln!("output stream fell behind: try increasing latency");
+                        xruns.overruns.fetch_add(1, Ordering::Relaxed);
                     }
                 };
 
-                let input_stream = self.input_device.build_input_stream(&self.input_config, input_data_fn, err_fn)?;
+                let input_stream = self.input_device.build_input_stream(&self.input_config, input_data_fn, self.err_fn())?;
 
                 input_stream.play()?;
 
-                return Ok(input_stream);
+                Ok(input_stream)
             },
             Mode::Return => {
-                let start = self.audio_config.output_channel;
-                let end = self.audio_config.output_channel + self.audio_config.get_channel_count();
-                let channels = self.output_config.channels as u32;
+                let params = self.params.clone();
+                let count = self.audio_config.get_channel_count() as usize;
+                let channels = self.output_config.channels as usize;
+                let xruns = self.xruns.clone();
+                let mut active_channel = params.output_channel() as usize;
+                let mut ramp = Ramp::new(0.0, self.audio_config.sample_rate);
+                let mut splice = Splice::new(count);
 
                 let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
                     let mut input_fell_behind = false;
-                    let mut count = 0;
+                    let channel = params.output_channel() as usize;
+                    let gain = match params.muted() {
+                        true => 0.0,
+                        false => params.output_gain()
+                    };
 
-                    for sample in data {
-                        if count >= start && count < end {
-                            *sample = match output_consumer.pop() {
-                                Some(s) => s,
+                    // Keeps the latency near the jitter target. Up to one
+                    // more callback's worth is left, so that small variations
+                    // do not cause a cut every time.
+                    let needed = data.len() / channels * count;
+                    let excess = output_consumer.len().saturating_sub(needed * 2 + params.jitter_target());
+                    if excess > 0 {
+                        splice.trim(&mut output_consumer, excess + needed);
+                    }
+
+                    for frame in data.chunks_mut(channels) {
+                        let switching = channel != active_channel;
+                        let frame_gain = ramp.next(if switching { 0.0 } else { gain });
+                        if switching && frame_gain == 0.0 {
+                            active_channel = channel;
+                        }
+
+                        for (i, sample) in frame.iter_mut().enumerate() {
+                            if i < active_channel || i >= active_channel + count {
+                                *sample = 0.0;
+                                continue;
+                            }
+
+                            *sample = match splice.pop(&mut output_consumer) {
+                                Some(s) => s * frame_gain,
                                 None => {
                                     input_fell_behind = true;
                                     0.0
                                 }
                             };
-                        } else {
-                            *sample = 0.0;
-                        }
-                        count += 1;
-
-                        if count >= channels {
-                            count = 0;
                         }
                     }
                     if input_fell_behind {
-                        // eprintln!("input stream fell behind: try increasing latency");
+                        xruns.underruns.fetch_add(1, Ordering::Relaxed);
                     }
                 };
 
-                let output_stream = self.output_device.build_output_stream(&self.output_config, output_data_fn, err_fn)?;
+                let output_stream = self.output_device.build_output_stream(&self.output_config, output_data_fn, self.err_fn())?;
 
                 output_stream.play()?;
 
-                return Ok(output_stream);
+                Ok(output_stream)
             }
-        };
-        
-
-        // Ok((input_stream, output_stream))
+        }
     }
-}
 
+    fn err_fn(&self) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        let events = self.events.clone();
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+        move |err| {
+            eprintln!("an error occurred on stream: {}", err);
+            events.emit(Event::DeviceError { message: err.to_string() });
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,352 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use anyhow::{Result, anyhow};
+use ringbuf::Consumer;
+
+/// How long gain changes, muting and channel switches take to fade in, so
+/// they do not click.
+pub const RAMP_TIME: f32 = 0.005;
+/// Longest jitter buffer target, in milliseconds. The output ring buffer is
+/// sized for it.
+pub const MAX_JITTER_TARGET: u32 = 200;
+
+/// Settings the audio callbacks read on every buffer, so that they can be
+/// changed while streaming. Written from the control thread, hence atomics.
+#[derive(Debug)]
+pub struct AudioParams {
+    /// Linear gains, stored as the bits of an `f32`.
+    input_gain: AtomicU32,
+    output_gain: AtomicU32,
+    muted: AtomicBool,
+    input_channel: AtomicU32,
+    output_channel: AtomicU32,
+    /// Samples to keep in the output ring buffer.
+    jitter_target: AtomicUsize,
+    channel_count: u32,
+    input_device_channels: u32,
+    output_device_channels: u32,
+    /// Samples per millisecond, across all channels of the stream.
+    samples_per_ms: f32,
+}
+
+impl AudioParams {
+    pub fn new(
+        input_channel: u32,
+        output_channel: u32,
+        channel_count: u32,
+        input_device_channels: u32,
+        output_device_channels: u32,
+        sample_rate: u32,
+        jitter_target: usize
+    ) -> Self {
+        Self {
+            input_gain: AtomicU32::new(1f32.to_bits()),
+            output_gain: AtomicU32::new(1f32.to_bits()),
+            muted: AtomicBool::new(false),
+            input_channel: AtomicU32::new(input_channel),
+            output_channel: AtomicU32::new(output_channel),
+            jitter_target: AtomicUsize::new(jitter_target),
+            channel_count,
+            input_device_channels,
+            output_device_channels,
+            samples_per_ms: sample_rate as f32 * channel_count as f32 / 1000.0
+        }
+    }
+
+    pub fn input_gain(&self) -> f32 {
+        f32::from_bits(self.input_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_input_gain(&self, gain: f32) -> Result<()> {
+        self.input_gain.store(check_gain(gain)?.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn output_gain(&self) -> f32 {
+        f32::from_bits(self.output_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_output_gain(&self, gain: f32) -> Result<()> {
+        self.output_gain.store(check_gain(gain)?.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Silences what this end captures or plays, depending on its mode.
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn input_channel(&self) -> u32 {
+        self.input_channel.load(Ordering::Relaxed)
+    }
+
+    /// Moves the captured channels to start at `channel` of the input device.
+    pub fn set_input_channel(&self, channel: u32) -> Result<()> {
+        self.input_channel.store(self.check_input_channel(channel)?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Checks that the captured channels fit on the input device when they
+    /// start at `channel`.
+    pub fn check_input_channel(&self, channel: u32) -> Result<u32> {
+        check_channel("input", channel, self.channel_count, self.input_device_channels)
+    }
+
+    pub fn output_channel(&self) -> u32 {
+        self.output_channel.load(Ordering::Relaxed)
+    }
+
+    /// Moves the played channels to start at `channel` of the output device.
+    pub fn set_output_channel(&self, channel: u32) -> Result<()> {
+        self.output_channel.store(self.check_output_channel(channel)?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Checks that the played channels fit on the output device when they
+    /// start at `channel`.
+    pub fn check_output_channel(&self, channel: u32) -> Result<u32> {
+        check_channel("output", channel, self.channel_count, self.output_device_channels)
+    }
+
+    /// Samples the longest jitter target takes, which the output buffer
+    /// needs room for.
+    pub fn max_jitter_samples(&self) -> usize {
+        (MAX_JITTER_TARGET as f32 * self.samples_per_ms) as usize
+    }
+
+    /// Samples the output callback keeps buffered.
+    pub fn jitter_target(&self) -> usize {
+        self.jitter_target.load(Ordering::Relaxed)
+    }
+
+    /// Sets how much audio the receiver keeps buffered against jitter, in
+    /// milliseconds. More survives a burstier network at the cost of latency.
+    pub fn set_jitter_target(&self, milliseconds: u32) -> Result<()> {
+        let samples = (check_jitter_target(milliseconds)? as f32 * self.samples_per_ms) as usize;
+        // Whole frames, so that channels stay in place
+        let samples = samples - samples % self.channel_count as usize;
+        self.jitter_target.store(samples, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+pub fn check_gain(gain: f32) -> Result<f32> {
+    match gain.is_finite() && gain >= 0.0 {
+        true => Ok(gain),
+        false => Err(anyhow!("Invalid gain {}", gain))
+    }
+}
+
+pub fn check_jitter_target(milliseconds: u32) -> Result<u32> {
+    match milliseconds <= MAX_JITTER_TARGET {
+        true => Ok(milliseconds),
+        false => Err(anyhow!("The jitter buffer holds at most {} ms", MAX_JITTER_TARGET))
+    }
+}
+
+fn check_channel(device: &str, channel: u32, channel_count: u32, device_channels: u32) -> Result<u32> {
+    match channel.checked_add(channel_count) {
+        Some(end) if end <= device_channels => Ok(channel),
+        _ => Err(anyhow!("The {} device has {} channels, {} from channel {} do not fit", device, device_channels, channel_count, channel))
+    }
+}
+
+/// Moves a gain towards its target by a fixed step per frame, so changes
+/// take `RAMP_TIME` from silence to full scale.
+#[derive(Debug)]
+pub struct Ramp {
+    value: f32,
+    step: f32,
+}
+
+impl Ramp {
+    pub fn new(value: f32, sample_rate: u32) -> Self {
+        Self {
+            value,
+            step: 1.0 / (RAMP_TIME * sample_rate as f32).max(1.0)
+        }
+    }
+
+    pub fn next(&mut self, target: f32) -> f32 {
+        self.value = match self.value < target {
+            true => (self.value + self.step).min(target),
+            false => (self.value - self.step).max(target)
+        };
+
+        self.value
+    }
+}
+
+/// Frames over which the audio on either side of a cut is crossfaded.
+const SPLICE_FRAMES: usize = 64;
+
+/// Drops audio from the output ring buffer when it holds more than the
+/// jitter target, crossfading across the cut instead of jumping.
+pub struct Splice {
+    buffer: Vec<f32>,
+    after: Vec<f32>,
+    position: usize,
+    len: usize,
+    channel_count: usize,
+}
+
+impl Splice {
+    pub fn new(channel_count: usize) -> Self {
+        // Allocated up front, the audio callback must not allocate
+        Self {
+            buffer: vec![0.0; SPLICE_FRAMES * channel_count],
+            after: vec![0.0; SPLICE_FRAMES * channel_count],
+            position: 0,
+            len: 0,
+            channel_count
+        }
+    }
+
+    /// Skips `excess` samples, keeping the crossfade to play before the
+    /// rest of the buffer.
+    pub fn trim(&mut self, consumer: &mut Consumer<f32>, excess: usize) {
+        if self.position < self.len {
+            return;
+        }
+
+        let excess = excess - excess % self.channel_count;
+        let fade = excess.min(self.buffer.len()).min(consumer.len().saturating_sub(excess));
+        let fade = fade - fade % self.channel_count;
+        if fade == 0 {
+            return;
+        }
+
+        // The audio at the read position fades out while the audio after
+        // the skipped part fades in
+        consumer.pop_slice(&mut self.buffer[..fade]);
+        consumer.discard(excess - fade);
+        consumer.pop_slice(&mut self.after[..fade]);
+
+        let frames = fade / self.channel_count;
+        for (frame, (before, after)) in self.buffer[..fade].chunks_mut(self.channel_count).zip(self.after[..fade].chunks(self.channel_count)).enumerate() {
+            let t = (frame + 1) as f32 / (frames + 1) as f32;
+            for (sample, after) in before.iter_mut().zip(after) {
+                *sample = *sample * (1.0 - t) + after * t;
+            }
+        }
+
+        self.position = 0;
+        self.len = fade;
+    }
+
+    /// The next sample to play, from the crossfade while one is pending.
+    pub fn pop(&mut self, consumer: &mut Consumer<f32>) -> Option<f32> {
+        if self.position < self.len {
+            self.position += 1;
+            return Some(self.buffer[self.position - 1]);
+        }
+
+        consumer.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ringbuf::RingBuffer;
+
+    fn params() -> AudioParams {
+        // Stereo from a four channel input and a two channel output
+        AudioParams::new(0, 0, 2, 4, 2, 48000, 0)
+    }
+
+    #[test]
+    fn rejects_invalid_gains() {
+        let params = params();
+
+        params.set_input_gain(0.5).unwrap();
+        params.set_output_gain(0.0).unwrap();
+        assert_eq!((params.input_gain(), params.output_gain()), (0.5, 0.0));
+
+        assert!(params.set_input_gain(-0.1).is_err());
+        assert!(params.set_output_gain(f32::NAN).is_err());
+        assert!(params.set_output_gain(f32::INFINITY).is_err());
+        assert_eq!((params.input_gain(), params.output_gain()), (0.5, 0.0));
+    }
+
+    #[test]
+    fn keeps_channels_on_the_device() {
+        let params = params();
+
+        params.set_input_channel(2).unwrap();
+        assert_eq!(params.input_channel(), 2);
+        assert!(params.set_input_channel(3).is_err());
+        assert!(params.set_output_channel(1).is_err());
+        assert!(params.set_input_channel(u32::MAX).is_err());
+        assert!(params.set_output_channel(u32::MAX - 1).is_err());
+        assert_eq!(params.input_channel(), 2);
+        assert_eq!(params.output_channel(), 0);
+    }
+
+    #[test]
+    fn sets_the_jitter_target_in_whole_frames() {
+        let params = AudioParams::new(0, 0, 2, 2, 2, 44100, 0);
+
+        params.set_jitter_target(10).unwrap();
+        assert_eq!(params.jitter_target(), 882);
+        params.set_jitter_target(1).unwrap();
+        // 88.2 samples, rounded down to 44 frames
+        assert_eq!(params.jitter_target(), 88);
+
+        params.set_jitter_target(MAX_JITTER_TARGET).unwrap();
+        assert_eq!(params.jitter_target(), params.max_jitter_samples());
+        assert!(params.set_jitter_target(MAX_JITTER_TARGET + 1).is_err());
+    }
+
+    #[test]
+    fn ramps_over_the_ramp_time() {
+        let sample_rate = 48000;
+        let frames = (RAMP_TIME * sample_rate as f32) as usize;
+        let mut ramp = Ramp::new(0.0, sample_rate);
+
+        let values: Vec<f32> = (0..frames).map(|_| ramp.next(1.0)).collect();
+
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((values[frames - 1] - 1.0).abs() < 1e-3);
+        assert_eq!(ramp.next(1.0), 1.0);
+        assert!(ramp.next(0.0) < 1.0);
+    }
+
+    #[test]
+    fn splices_out_the_excess_with_a_crossfade() {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(1024).split();
+        let mut splice = Splice::new(1);
+        for i in 0..512 {
+            producer.push(i as f32).unwrap();
+        }
+
+        splice.trim(&mut consumer, 200);
+
+        let played: Vec<f32> = std::iter::from_fn(|| splice.pop(&mut consumer)).collect();
+        assert_eq!(played.len(), 312);
+        // Fades from the read position to the audio after the cut
+        assert!(played[0] < 64.0 && played[0] > 0.0);
+        assert!(played[SPLICE_FRAMES - 1] > 200.0);
+        assert_eq!(played[SPLICE_FRAMES], 200.0 + SPLICE_FRAMES as f32);
+        assert_eq!(played[311], 511.0);
+    }
+
+    #[test]
+    fn splices_nothing_from_a_short_buffer() {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(64).split();
+        let mut splice = Splice::new(2);
+        for i in 0..8 {
+            producer.push(i as f32).unwrap();
+        }
+
+        splice.trim(&mut consumer, 8);
+
+        assert_eq!(splice.pop(&mut consumer), Some(0.0));
+        assert_eq!(consumer.len(), 7);
+    }
+}
@@ -0,0 +1,50 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{App, Arg};
+
+use p2p_audio::relay::server::{RelayServer, RelayServerConfig};
+
+fn main() {
+    let matches = App::new("relay")
+        .about("Forwards audio between peers that cannot connect directly")
+        .arg(Arg::with_name("bind")
+            .value_name("ADDRESS")
+            .long("bind")
+            .takes_value(true)
+            .default_value("0.0.0.0:3479"))
+        .arg(Arg::with_name("bandwidth")
+            .value_name("KBITS")
+            .long("bandwidth")
+            .help("Bandwidth limit per session in kbit/s")
+            .takes_value(true)
+            .default_value("10000"))
+        .arg(Arg::with_name("idle_timeout")
+            .value_name("SECONDS")
+            .long("idle-timeout")
+            .takes_value(true)
+            .default_value("30"))
+        .get_matches();
+
+    let bind = matches.value_of("bind").unwrap();
+    let bandwidth = matches.value_of("bandwidth").unwrap();
+    let idle_timeout = matches.value_of("idle_timeout").unwrap();
+
+    match run(bind, bandwidth, idle_timeout) {
+        Ok(_) => (),
+        Err(err) => eprintln!("{}", err)
+    }
+}
+
+fn run(bind: &str, bandwidth: &str, idle_timeout: &str) -> Result<()> {
+    let config = RelayServerConfig {
+        bandwidth: bandwidth.parse::<u32>()? * 1000 / 8,
+        idle_timeout: Duration::from_secs(idle_timeout.parse()?)
+    };
+
+    let mut server = RelayServer::new(UdpSocket::bind(bind)?, config);
+    println!("Relay listening on {}", server.local_addr()?);
+
+    server.run()
+}
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use clap::{App, Arg};
+
+use p2p_audio::rendezvous::server::RendezvousServer;
+
+fn main() {
+    let matches = App::new("rendezvous")
+        .about("Lets peers find each other and exchange session offers")
+        .arg(Arg::with_name("bind")
+            .value_name("ADDRESS")
+            .long("bind")
+            .takes_value(true)
+            .default_value("0.0.0.0:4000"))
+        .get_matches();
+
+    match run(matches.value_of("bind").unwrap()) {
+        Ok(_) => (),
+        Err(err) => eprintln!("{}", err)
+    }
+}
+
+fn run(bind: &str) -> Result<()> {
+    let server = RendezvousServer::bind(bind)?;
+    println!("Rendezvous server listening on ws://{}", server.local_addr()?);
+
+    server.run()
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::io::{self, prelude::*};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+/// Version of the control protocol, exchanged in the `hello` both sides
+/// start with. Bumped whenever a message changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Largest message either side accepts.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+const LENGTH_SIZE: usize = 4;
+
+/// Reads one message, a big-endian 32-bit length followed by that many
+/// bytes of JSON. Returns `None` when the other side closed the connection
+/// between messages.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; LENGTH_SIZE];
+
+    match reader.read_exact(&mut length) {
+        Ok(_) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Message of {} bytes exceeds the maximum of {}", length, MAX_MESSAGE_SIZE));
+    }
+
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message)?;
+
+    Ok(Some(message))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> Result<()> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Message of {} bytes exceeds the maximum of {}", message.len(), MAX_MESSAGE_SIZE));
+    }
+
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+
+    writer.write_all(&frame)?;
+
+    Ok(())
+}
+
+pub fn write_json<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    write_message(writer, &serde_json::to_vec(message)?)
+}
+
+/// What went wrong with a request. Clients match on these, so they stay the
+/// same across versions, new ones are only added.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not JSON, or not a request the daemon knows.
+    InvalidMessage,
+    /// The client sent a request before its hello.
+    HelloRequired,
+    /// An audio device is missing or does not support the config.
+    AudioDevice,
+    /// No STUN server answered.
+    StunFailed,
+    /// The session socket could not be bound.
+    BindFailed,
+    /// An address could not be parsed or resolved.
+    InvalidAddress,
+    /// A connection code is malformed or does not answer ours.
+    InvalidCode,
+    /// `pair_accept` came without a `pair_offer` before it.
+    NoPendingOffer,
+    /// None of the peer's candidates answered the connectivity checks.
+    ConnectivityFailed,
+    /// The handshake timed out or the peer used another key.
+    HandshakeFailed,
+    /// The peer's fingerprint does not match the pinned or remembered one.
+    UntrustedPeer,
+    /// Local network discovery could not be started.
+    DiscoveryFailed,
+    /// The rendezvous server could not be reached or refused the request, or
+    /// no peer came in time.
+    RendezvousFailed,
+    /// The request needs a running session and there is none.
+    NoSession,
+    /// A session is already running.
+    SessionActive,
+    /// A setting is out of range, e.g. a channel the device does not have.
+    InvalidParams,
+    /// A `stop` ended the request before its session started.
+    Cancelled,
+    /// Anything else, the message says what.
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code an error was tagged with, or `Internal` if it has none.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<ControlError>().map_or(ErrorCode::Internal, |err| err.code)
+    }
+}
+
+/// An error tagged with the code reported to the client.
+#[derive(Debug)]
+pub struct ControlError {
+    pub code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Keeps the code an error was tagged with, anything else is `Internal`.
+impl From<anyhow::Error> for ControlError {
+    fn from(err: anyhow::Error) -> Self {
+        ControlError { code: ErrorCode::of(&err), message: format!("{:#}", err) }
+    }
+}
+
+/// Tags the error of a result with a code.
+pub trait WithCode<T> {
+    /// An error that already has a code keeps it, the innermost one is the
+    /// most specific.
+    fn code(self, code: ErrorCode) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithCode<T> for std::result::Result<T, E> {
+    fn code(self, code: ErrorCode) -> Result<T> {
+        self.map_err(|err| {
+            let err = err.into();
+            if err.is::<ControlError>() {
+                return err;
+            }
+
+            anyhow::Error::new(ControlError { code, message: format!("{:#}", err) })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips_framed_messages() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, b"{}").unwrap();
+        write_message(&mut buffer, b"").unwrap();
+        write_json(&mut buffer, &serde_json::json!({ "type": "hello", "version": PROTOCOL_VERSION })).unwrap();
+        assert_eq!(&buffer[..6], &[0, 0, 0, 2, b'{', b'}']);
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap().unwrap(), b"{}");
+        assert_eq!(read_message(&mut reader).unwrap().unwrap(), b"");
+
+        let hello: serde_json::Value = serde_json::from_slice(&read_message(&mut reader).unwrap().unwrap()).unwrap();
+        assert_eq!(hello["version"], PROTOCOL_VERSION);
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn fails_on_a_cut_off_message() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, b"{\"type\": \"stop\"}").unwrap();
+
+        assert!(read_message(&mut Cursor::new(&buffer[..buffer.len() - 1])).is_err());
+        assert!(read_message(&mut Cursor::new(&buffer[..2])).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_messages_beyond_the_maximum() {
+        let length = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        assert!(read_message(&mut Cursor::new(length)).is_err());
+
+        let message = vec![b' '; MAX_MESSAGE_SIZE + 1];
+        let mut buffer = Vec::new();
+        assert!(write_message(&mut buffer, &message).is_err());
+        assert!(buffer.is_empty());
+
+        write_message(&mut buffer, &message[1..]).unwrap();
+        assert_eq!(read_message(&mut Cursor::new(buffer)).unwrap().unwrap().len(), MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn serializes_codes_in_snake_case() {
+        assert_eq!(serde_json::to_value(ErrorCode::HelloRequired).unwrap(), "hello_required");
+        assert_eq!(serde_json::to_value(ErrorCode::NoSession).unwrap(), "no_session");
+    }
+
+    #[test]
+    fn keeps_the_innermost_code() {
+        let result: Result<()> = Err(anyhow!("Device gone"));
+
+        let err = result.code(ErrorCode::AudioDevice).code(ErrorCode::Internal).unwrap_err();
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::AudioDevice);
+        assert_eq!(err.to_string(), "Device gone");
+    }
+
+    #[test]
+    fn codes_survive_context() {
+        let err = Err::<(), _>(io::Error::new(io::ErrorKind::AddrInUse, "Address in use"))
+            .code(ErrorCode::BindFailed)
+            .map_err(|err| err.context("Could not start"))
+            .unwrap_err();
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BindFailed);
+
+        let control_error = ControlError::from(err);
+        assert_eq!(control_error.code, ErrorCode::BindFailed);
+        assert_eq!(control_error.to_string(), "Could not start: Address in use");
+    }
+
+    #[test]
+    fn untagged_errors_are_internal() {
+        let err = anyhow!("Something broke");
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Internal);
+        assert_eq!(ControlError::from(err).code, ErrorCode::Internal);
+    }
+}
@@ -0,0 +1,1023 @@
+//! The requests of the control protocol and the state kept across them.
+//! The binary serves them over a Unix socket, the Node addon in process.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+
+use crate::audio::{AudioConfig, AudioInterface, SupportedConfigs};
+use crate::control::{self, ErrorCode, WithCode};
+use crate::discovery::{DiscoveredPeer, Discovery};
+use crate::events::{Event, Events, State};
+use crate::ice::{self, Candidate, CandidateType, Role};
+use crate::identity::{Identity, KnownPeers, Trust};
+use crate::net::{self, BindOptions};
+use crate::noise;
+use crate::pairing::{self, ConnectionCode};
+use crate::portmap::{self, Gateway, PortMapping};
+use crate::relay::client::{self as relay_client, RelayConfig};
+use crate::rendezvous::client::RendezvousClient;
+use crate::rendezvous::message::{PeerInfo, SessionDescription};
+use crate::session::{Session, SessionBuilder, Stats, StreamParams};
+use crate::stun;
+use crate::stun::client::Mapping;
+use crate::stun::nat::NatReport;
+use crate::udp::crypto::SessionKey;
+use crate::udp::keepalive::{self, Keepalive};
+use crate::util::{self, Mode};
+
+/// How long the first `Discover` waits for peers to answer.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sends a response to the client that made the request. Shared, as setups
+/// that wait for the peer answer from their own thread.
+pub type Respond = Arc<dyn Fn(SendMessage) -> Result<()> + Send + Sync>;
+
+/// A request, as clients send it.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum RecvMessage {
+    #[serde(rename = "hello")]
+    Hello {
+        version: u32
+    },
+    #[serde(rename = "config")]
+    Config,
+    #[serde(rename = "connect")]
+    Connect {
+        config: Option<AudioConfig>,
+        /// Milliseconds between keepalive packets, from 1000 to 60000.
+        keepalive_interval: Option<u64>,
+        stun_servers: Option<Vec<String>>,
+        /// Milliseconds to wait for the STUN servers.
+        stun_timeout: Option<u64>,
+        /// Whether to ask the gateway for a port mapping, on by default.
+        port_mapping: Option<bool>,
+        /// Where to ask for it, the default route's gateway if not given.
+        gateway: Option<Gateway>,
+        /// Rebinds the session socket, e.g. to a port range the firewall
+        /// lets through.
+        bind: Option<BindOptions>
+    },
+    #[serde(rename = "diagnose")]
+    Diagnose {
+        stun_servers: Option<Vec<String>>,
+        /// Milliseconds to wait for each probe.
+        stun_timeout: Option<u64>
+    },
+    #[serde(rename = "stream")]
+    Stream {
+        mode: Mode,
+        remote_addr: String,
+        config: AudioConfig,
+        #[serde(flatten)]
+        options: StreamOptions
+    },
+    /// Creates a connection code for the peer to answer. Without a config,
+    /// the one from the last `Connect` is used.
+    #[serde(rename = "pair_offer")]
+    PairOffer {
+        mode: Mode,
+        config: Option<AudioConfig>
+    },
+    /// Answers the peer's connection code and starts streaming.
+    #[serde(rename = "pair_answer")]
+    PairAnswer {
+        code: String,
+        config: Option<AudioConfig>
+    },
+    /// Takes the answer to our offer and starts streaming.
+    #[serde(rename = "pair_accept")]
+    PairAccept {
+        code: String
+    },
+    /// Meets the peer in a room of the rendezvous server and starts
+    /// streaming. Whoever comes second offers the session, and its sample
+    /// rate and buffer size are used on both ends.
+    #[serde(rename = "rendezvous")]
+    Rendezvous {
+        room: String,
+        mode: Mode,
+        /// Shown to the other peers in the room.
+        name: Option<String>,
+        /// Defaults to the server the daemon was started with.
+        url: Option<String>,
+        config: Option<AudioConfig>,
+        /// Milliseconds to wait for the peer, two minutes by default.
+        timeout: Option<u64>
+    },
+    /// Advertises the session socket on the local network. Sample rates
+    /// default to the one of the last `Connect`.
+    #[serde(rename = "advertise")]
+    Advertise {
+        name: String,
+        sample_rates: Option<Vec<u32>>
+    },
+    /// Lists the peers advertising on the local network.
+    #[serde(rename = "discover")]
+    Discover {
+        /// Milliseconds to wait for answers when browsing has just started.
+        timeout: Option<u64>
+    },
+    /// Forgets the fingerprint remembered for a peer, so that a new one is
+    /// accepted, e.g. after the peer reinstalled.
+    #[serde(rename = "forget_peer")]
+    ForgetPeer {
+        name: String
+    },
+    /// Changes settings of the running session, leaving out what stays.
+    #[serde(rename = "set_params")]
+    SetParams {
+        #[serde(flatten)]
+        params: StreamParams
+    },
+    /// Ends the running session.
+    #[serde(rename = "stop")]
+    Stop,
+    /// Reads the statistics of the running session.
+    #[serde(rename = "stats")]
+    Stats,
+}
+
+/// How to reach and authenticate the peer of a `Stream` request.
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamOptions {
+    pub session_id: Option<u32>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub dtx: bool,
+    /// Milliseconds between keepalive packets, from 1000 to 60000. Defaults
+    /// to the interval given in `Connect`.
+    pub keepalive_interval: Option<u64>,
+    /// The peer's candidates. When given, connectivity checks pick the
+    /// address to stream to, otherwise `remote_addr` is used as is.
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    /// Relay to fall back to when no direct path works.
+    pub relay: Option<RelayConfig>,
+    /// Milliseconds to keep running connectivity checks.
+    pub check_timeout: Option<u64>,
+    /// Pre-shared key mixed into the handshake, the peer has to use the
+    /// same one.
+    pub key: Option<SessionKey>,
+    /// Only streams to a peer with this fingerprint.
+    pub fingerprint: Option<String>,
+    /// Name to remember the peer's fingerprint by. Later sessions under the
+    /// same name fail if the fingerprint changes.
+    pub peer_name: Option<String>,
+}
+
+/// A response, or a report sent while a request is carried out.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub enum SendMessage {
+    #[serde(rename = "hello")]
+    Hello { version: u32 },
+    #[serde(rename = "config")]
+    Config { configs: SupportedConfigs },
+    #[serde(rename = "connect")]
+    Connect { address: String, local_address: String, is_valid: bool, consistent: bool, candidates: Vec<Candidate>, fingerprint: String },
+    #[serde(rename = "diagnose")]
+    Diagnose(NatReport),
+    #[serde(rename = "pair")]
+    Pair { code: String },
+    /// Sent once the room is joined, the peer comes later.
+    #[serde(rename = "rendezvous")]
+    Rendezvous { room: String, id: String, peers: Vec<PeerInfo> },
+    #[serde(rename = "discover")]
+    Discover { peers: Vec<DiscoveredPeer> },
+    /// Sent once the handshake is done, before audio starts.
+    #[serde(rename = "peer")]
+    Peer { fingerprint: String, trust: Trust },
+    #[serde(rename = "advertise")]
+    Advertise { name: String, port: u16 },
+    #[serde(rename = "forget_peer")]
+    ForgetPeer { name: String },
+    /// The settings that were changed.
+    #[serde(rename = "set_params")]
+    SetParams(StreamParams),
+    #[serde(rename = "stop")]
+    Stop,
+    #[serde(rename = "stats")]
+    Stats(Stats),
+    /// Answers a request that failed, the daemon carries on with the next.
+    #[serde(rename = "error")]
+    Error { code: ErrorCode, message: String },
+}
+
+impl SendMessage {
+    pub fn error(err: &anyhow::Error, code: ErrorCode) -> Self {
+        SendMessage::Error { code, message: format!("{:#}", err) }
+    }
+}
+
+/// Who this installation is and the peers it has met.
+pub struct Credentials {
+    pub identity: Identity,
+    pub known_peers: KnownPeers,
+}
+
+impl Credentials {
+    pub fn load(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            identity: Identity::load_or_create(dir)?,
+            known_peers: KnownPeers::load(dir)?
+        })
+    }
+
+    /// Runs the handshake with the peer and checks its fingerprint.
+    pub fn authenticate(&mut self, conn: &UdpSocket, remote_addr: SocketAddr, mode: Mode, options: &StreamOptions) -> Result<(noise::Session, Trust)> {
+        let session = self.handshake(conn, remote_addr, mode, options)?;
+        let trust = self.verify(&session, options)?;
+
+        Ok((session, trust))
+    }
+
+    /// The handshake alone, which only reads the identity, so that several
+    /// can run at once.
+    pub fn handshake(&self, conn: &UdpSocket, remote_addr: SocketAddr, mode: Mode, options: &StreamOptions) -> Result<noise::Session> {
+        // The sender picks the session id unless one was agreed on during
+        // signalling; the receiver learns it from the handshake.
+        let session_id = match mode {
+            Mode::Send => Some(options.session_id.map_or_else(util::random_id, Ok)?),
+            Mode::Return => options.session_id
+        };
+
+        noise::handshake(conn, remote_addr, mode, session_id, &self.identity, options.key.as_ref(), noise::DEFAULT_TIMEOUT)
+            .code(ErrorCode::HandshakeFailed)
+    }
+
+    pub fn verify(&mut self, session: &noise::Session, options: &StreamOptions) -> Result<Trust> {
+        self.known_peers.verify(&session.fingerprint(), options.fingerprint.as_deref(), options.peer_name.as_deref())
+            .code(ErrorCode::UntrustedPeer)
+    }
+}
+
+/// A session being set up on its own thread, so that requests are still
+/// answered while it waits for the peer. Clearing `running` gives it up.
+struct Setup {
+    running: Arc<AtomicBool>,
+    handle: JoinHandle<Option<Session>>,
+}
+
+impl Setup {
+    fn join(self) -> Option<Session> {
+        self.handle.join().unwrap_or_else(|_| {
+            eprintln!("The session setup stopped unexpectedly");
+            None
+        })
+    }
+}
+
+/// Session state kept across requests and clients.
+pub struct Daemon {
+    stun_servers: Vec<String>,
+    rendezvous_url: String,
+    credentials: Arc<Mutex<Credentials>>,
+    events: Events,
+    conn: Arc<UdpSocket>,
+    keepalive_interval: Duration,
+    keepalive: Option<Keepalive>,
+    audio_config: Option<AudioConfig>,
+    pending_offer: Option<(ConnectionCode, AudioConfig)>,
+    discovery: Option<Discovery>,
+    port_mapping: Option<PortMapping>,
+    setup: Option<Setup>,
+    stream: Option<Session>,
+}
+
+impl Daemon {
+    pub fn new(stun_servers: Vec<String>, rendezvous_url: String, bind_options: &BindOptions, credentials: Credentials, events: Events) -> Result<Self> {
+        Ok(Self {
+            stun_servers,
+            rendezvous_url,
+            credentials: Arc::new(Mutex::new(credentials)),
+            events,
+            conn: Arc::new(net::bind(bind_options)?),
+            keepalive_interval: keepalive::DEFAULT_INTERVAL,
+            keepalive: None,
+            audio_config: None,
+            pending_offer: None,
+            discovery: None,
+            port_mapping: None,
+            setup: None,
+            stream: None
+        })
+    }
+
+    /// Carries out a request. `respond` gets the response, and may be called
+    /// again: pairing reports the peer after the connection code. A failed
+    /// request leaves the daemon ready for the next, its error is for the
+    /// caller to report.
+    ///
+    /// Requests that wait for the peer return once the wait has started and
+    /// respond from another thread, errors included, so that `stop` can
+    /// give them up in the meantime.
+    pub fn request(&mut self, res: RecvMessage, respond: Respond) -> Result<()> {
+        self.collect_setup();
+
+        let sets_up_session = matches!(res,
+            RecvMessage::Connect { .. } | RecvMessage::Stream { .. } |
+            RecvMessage::PairOffer { .. } | RecvMessage::PairAnswer { .. } | RecvMessage::PairAccept { .. } |
+            RecvMessage::Rendezvous { .. }
+        );
+
+        let result = match sets_up_session {
+            true => self.ensure_idle().and_then(|_| self.handle(res, &respond)),
+            false => self.handle(res, &respond)
+        };
+
+        if result.is_err() && sets_up_session && !self.is_streaming() {
+            self.events.emit(Event::State { state: State::Idle });
+        }
+
+        result
+    }
+
+    fn handle(&mut self, res: RecvMessage, respond: &Respond) -> Result<()> {
+        match res {
+            RecvMessage::Hello { .. } => {
+                respond(SendMessage::Hello { version: control::PROTOCOL_VERSION })?;
+            },
+            RecvMessage::Config => {
+                let supported_configs = AudioInterface::get_supported_configs().code(ErrorCode::AudioDevice)?;
+                respond(SendMessage::Config { configs: supported_configs })?;
+            },
+            RecvMessage::Connect { config, keepalive_interval: interval, stun_servers: servers, stun_timeout, port_mapping: map_port, gateway, bind } => {
+                if let Some(interval) = interval {
+                    self.keepalive_interval = keepalive::check_interval(Duration::from_millis(interval)).code(ErrorCode::InvalidParams)?;
+                }
+                let config = match config {
+                    Some(config) => config,
+                    None => AudioConfig::from_default_devices().code(ErrorCode::AudioDevice)?
+                };
+                self.audio_config = Some(config.clone());
+
+                let is_valid = AudioInterface::validate_config(&config).is_ok();
+
+                let servers = servers.as_ref().unwrap_or(&self.stun_servers);
+                let timeout = stun_timeout.map_or(stun::client::DEFAULT_TIMEOUT, Duration::from_millis);
+                let gateway = match map_port.unwrap_or(true) {
+                    true => gateway.or_else(|| Gateway::default_route().ok()),
+                    false => None
+                };
+
+                // Removes the mapping of an earlier attempt
+                self.port_mapping = None;
+                if let Some(bind) = bind {
+                    // Stops the keepalive thread holding the old socket
+                    self.keepalive = None;
+                    self.conn = Arc::new(net::bind(&bind).code(ErrorCode::BindFailed)?);
+                }
+                self.events.emit(Event::State { state: State::Gathering });
+                let (mapping, candidates, mapped) = gather_candidates(&self.conn, servers, timeout, gateway)?;
+                self.port_mapping = mapped;
+                let addr = mapping.address;
+
+                // Hold the mapping open until the peer's address arrives
+                self.keepalive = Some(Keepalive::stun(self.conn.clone(), self.keepalive_interval, mapping.server));
+                self.events.emit(Event::State { state: State::Waiting });
+
+                let res = SendMessage::Connect {
+                    address: addr.to_string(),
+                    local_address: self.conn.local_addr()?.to_string(),
+                    is_valid,
+                    consistent: mapping.consistent,
+                    candidates,
+                    fingerprint: self.credentials.lock().unwrap().identity.fingerprint()
+                };
+
+                respond(res)?;
+            },
+            RecvMessage::Diagnose { stun_servers: servers, stun_timeout } => {
+                let servers = servers.as_ref().unwrap_or(&self.stun_servers);
+                let timeout = stun_timeout.map_or(stun::nat::DEFAULT_TIMEOUT, Duration::from_millis);
+                let report = stun::nat::diagnose(servers, timeout).code(ErrorCode::StunFailed)?;
+
+                respond(SendMessage::Diagnose(report))?;
+            },
+            RecvMessage::Stream { mode, remote_addr, config, options } => {
+                let remote_addr = resolve(&remote_addr)?;
+                let interval = match options.keepalive_interval {
+                    Some(interval) => keepalive::check_interval(Duration::from_millis(interval)).code(ErrorCode::InvalidParams)?,
+                    None => self.keepalive_interval
+                };
+                self.keepalive = None;
+
+                let (conn, credentials, events) = (self.conn.clone(), self.credentials.clone(), self.events.clone());
+                self.spawn_setup(respond, move |running, report| {
+                    open_stream(conn, remote_addr, mode, &options, interval, config, &credentials, &events, running, report)
+                });
+            },
+            RecvMessage::PairOffer { mode, config } => {
+                let config = self.audio_config(config)?;
+                self.port_mapping = None;
+                self.events.emit(Event::State { state: State::Gathering });
+                let (mapping, candidates, mapped) = gather_candidates(&self.conn, &self.stun_servers, stun::client::DEFAULT_TIMEOUT, Gateway::default_route().ok())?;
+                self.port_mapping = mapped;
+                let offer = ConnectionCode::offer(mode, &config, candidates)?;
+
+                // Hold the mapping open until the answer is pasted
+                self.keepalive = Some(Keepalive::stun(self.conn.clone(), self.keepalive_interval, mapping.server));
+                self.events.emit(Event::State { state: State::Waiting });
+
+                respond(SendMessage::Pair { code: offer.encode()? })?;
+
+                self.pending_offer = Some((offer, config));
+            },
+            RecvMessage::PairAnswer { code, config } => {
+                let offer = ConnectionCode::decode(&code).code(ErrorCode::InvalidCode)?;
+                let config = self.audio_config(config)?;
+                self.port_mapping = None;
+                self.events.emit(Event::State { state: State::Gathering });
+                let (_, candidates, mapped) = gather_candidates(&self.conn, &self.stun_servers, stun::client::DEFAULT_TIMEOUT, Gateway::default_route().ok())?;
+                self.port_mapping = mapped;
+                let answer = offer.answer(candidates).code(ErrorCode::InvalidCode)?;
+                let config = offer.configure(config);
+
+                respond(SendMessage::Pair { code: answer.encode()? })?;
+
+                self.keepalive = None;
+                let (conn, credentials, events, interval) = (self.conn.clone(), self.credentials.clone(), self.events.clone(), self.keepalive_interval);
+                self.spawn_setup(respond, move |running, report| {
+                    run_paired(conn, &answer, &offer, config, interval, Some(pairing::PAIRING_TIMEOUT), &credentials, &events, running, report)
+                });
+            },
+            RecvMessage::PairAccept { code } => {
+                let answer = ConnectionCode::decode(&code).code(ErrorCode::InvalidCode)?;
+                let (offer, config) = self.pending_offer.take()
+                    .ok_or(anyhow!("No connection code to answer, create an offer first"))
+                    .code(ErrorCode::NoPendingOffer)?;
+
+                self.keepalive = None;
+                let (conn, credentials, events, interval) = (self.conn.clone(), self.credentials.clone(), self.events.clone(), self.keepalive_interval);
+                self.spawn_setup(respond, move |running, report| {
+                    run_paired(conn, &offer, &answer, config, interval, None, &credentials, &events, running, report)
+                });
+            },
+            RecvMessage::Rendezvous { room, mode, name, url, config, timeout } => {
+                let config = self.audio_config(config)?;
+                let timeout = timeout.map_or(pairing::PAIRING_TIMEOUT, Duration::from_millis);
+                let url = url.as_ref().unwrap_or(&self.rendezvous_url);
+
+                let mut client = RendezvousClient::connect(url).code(ErrorCode::RendezvousFailed)?;
+                let peers = client.join(&room, name.as_deref()).code(ErrorCode::RendezvousFailed)?;
+                let id = client.id().unwrap_or_default().to_string();
+                respond(SendMessage::Rendezvous { room, id, peers: peers.clone() })?;
+
+                self.port_mapping = None;
+                self.events.emit(Event::State { state: State::Gathering });
+                let (mapping, candidates, mapped) = gather_candidates(&self.conn, &self.stun_servers, stun::client::DEFAULT_TIMEOUT, Gateway::default_route().ok())?;
+                self.port_mapping = mapped;
+
+                // Hold the mapping open until the peer comes
+                self.keepalive = Some(Keepalive::stun(self.conn.clone(), self.keepalive_interval, mapping.server));
+                self.events.emit(Event::State { state: State::Waiting });
+
+                let local = SessionDescription { address: mapping.address.to_string(), candidates, mode, config: Some(config) };
+                let keepalive = self.keepalive.take();
+                let (conn, credentials, events, interval) = (self.conn.clone(), self.credentials.clone(), self.events.clone(), self.keepalive_interval);
+                self.spawn_setup(respond, move |running, report| {
+                    client.stop_when_cleared(running.clone());
+                    let (remote, session_id, config) = meet(&mut client, &peers, local, timeout)?;
+                    if let Err(err) = client.leave() {
+                        eprintln!("Could not leave the room: {}", err);
+                    }
+
+                    drop(keepalive);
+                    let remote_addr = resolve(&remote.address)?;
+                    let options = StreamOptions {
+                        session_id: Some(session_id),
+                        candidates: remote.candidates,
+                        ..Default::default()
+                    };
+                    open_stream(conn, remote_addr, mode, &options, interval, config, &credentials, &events, running, report)
+                });
+            },
+            RecvMessage::Advertise { name, sample_rates } => {
+                let sample_rates = match sample_rates {
+                    Some(sample_rates) => sample_rates,
+                    None => vec![self.audio_config(None)?.sample_rate]
+                };
+                let port = self.conn.local_addr()?.port();
+
+                self.discovery()?.advertise(&name, port, &sample_rates).code(ErrorCode::DiscoveryFailed)?;
+
+                respond(SendMessage::Advertise { name, port })?;
+            },
+            RecvMessage::ForgetPeer { name } => {
+                self.credentials.lock().unwrap().known_peers.forget(&name)?;
+
+                respond(SendMessage::ForgetPeer { name })?;
+            },
+            RecvMessage::SetParams { params } => {
+                let stream = self.stream.as_ref()
+                    .filter(|stream| stream.is_running())
+                    .ok_or(anyhow!("No session is running"))
+                    .code(ErrorCode::NoSession)?;
+                stream.update(&params)?;
+
+                respond(SendMessage::SetParams(params))?;
+            },
+            RecvMessage::Stop => {
+                let gave_up_setup = self.cancel_setup();
+
+                match self.stream.take() {
+                    Some(stream) => stream.stop()?,
+                    None if gave_up_setup => (),
+                    None => return Err(anyhow!("No session is running")).code(ErrorCode::NoSession)
+                };
+
+                respond(SendMessage::Stop)?;
+            },
+            RecvMessage::Stats => {
+                let stream = self.stream.as_ref()
+                    .filter(|stream| stream.is_running())
+                    .ok_or(anyhow!("No session is running"))
+                    .code(ErrorCode::NoSession)?;
+
+                respond(SendMessage::Stats(stream.stats()))?;
+            },
+            RecvMessage::Discover { timeout } => {
+                if self.discovery.is_none() {
+                    self.discovery()?;
+                    thread::sleep(timeout.map_or(DISCOVERY_TIMEOUT, Duration::from_millis));
+                }
+
+                let peers = self.discovery()?.peers();
+
+                respond(SendMessage::Discover { peers })?;
+            }
+        };
+
+        Ok(())
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.stream.as_ref().is_some_and(Session::is_running)
+    }
+
+    /// Fails while a session is running or being set up, as setting up
+    /// another one would use the same socket. Collects a session that ended
+    /// on its own.
+    fn ensure_idle(&mut self) -> Result<()> {
+        if self.setup.is_some() {
+            return Err(anyhow!("A session is being set up, stop it first")).code(ErrorCode::SessionActive);
+        }
+        if self.is_streaming() {
+            return Err(anyhow!("A session is running, stop it first")).code(ErrorCode::SessionActive);
+        }
+
+        if let Some(stream) = self.stream.take() {
+            if let Err(err) = stream.wait() {
+                eprintln!("The last session ended with an error: {:#}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the rest of a setup on its own thread. A failure is reported to
+    /// the client from there, as the request has returned by then.
+    fn spawn_setup<F>(&mut self, respond: &Respond, setup: F)
+    where F: FnOnce(&Arc<AtomicBool>, &mut dyn FnMut(SendMessage) -> Result<()>) -> Result<Session> + Send + 'static {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let events = self.events.clone();
+        let respond = respond.clone();
+
+        let handle = thread::spawn(move || {
+            let err = match setup(&thread_running, &mut |res| respond(res)) {
+                Ok(session) => return Some(session),
+                // Whatever failed after a stop failed because of it
+                Err(err) => ensure_running(&thread_running).err().unwrap_or(err)
+            };
+
+            eprintln!("{:#}", err);
+            events.emit(Event::State { state: State::Idle });
+            if let Err(err) = respond(SendMessage::error(&err, ErrorCode::of(&err))) {
+                eprintln!("Could not report the failed setup: {}", err);
+            }
+            None
+        });
+
+        self.setup = Some(Setup { running, handle });
+    }
+
+    /// Takes the session of a setup that has finished in the meantime.
+    fn collect_setup(&mut self) {
+        if self.setup.as_ref().is_some_and(|setup| setup.handle.is_finished()) {
+            self.stream = self.setup.take().and_then(Setup::join);
+        }
+    }
+
+    /// Gives up a setup in progress and waits for its thread, which notices
+    /// within moments. Returns whether there was one. A setup that finished
+    /// just before leaves its session to stop.
+    fn cancel_setup(&mut self) -> bool {
+        match self.setup.take() {
+            Some(setup) => {
+                setup.running.store(false, Ordering::Relaxed);
+                self.stream = setup.join();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The given config, else the one of the last `Connect`, else the
+    /// default devices.
+    fn audio_config(&self, config: Option<AudioConfig>) -> Result<AudioConfig> {
+        match config.or_else(|| self.audio_config.clone()) {
+            Some(config) => Ok(config),
+            None => AudioConfig::from_default_devices().code(ErrorCode::AudioDevice)
+        }
+    }
+
+    /// Starts browsing the local network on first use.
+    fn discovery(&mut self) -> Result<&mut Discovery> {
+        Ok(match self.discovery {
+            Some(ref mut discovery) => discovery,
+            None => self.discovery.insert(Discovery::new().code(ErrorCode::DiscoveryFailed)?)
+        })
+    }
+}
+
+/// Queries our external address and gathers the candidates to hand to the
+/// peer. With a gateway, this also asks it for a port mapping, which lasts
+/// as long as the returned handle.
+pub fn gather_candidates(
+    conn: &UdpSocket,
+    servers: &[String],
+    timeout: Duration,
+    gateway: Option<Gateway>
+) -> Result<(Mapping, Vec<Candidate>, Option<PortMapping>)> {
+    let port = conn.local_addr()?.port();
+
+    // Runs while STUN is queried, so a missing gateway costs no extra time
+    let port_mapping = gateway.map(|gateway| thread::spawn(move || PortMapping::start(gateway, port, portmap::DEFAULT_TIMEOUT)));
+
+    let mapping = stun::client::query_external_address(conn, servers, timeout).code(ErrorCode::StunFailed)?;
+
+    let port_mapping = match port_mapping.map(|handle| handle.join()) {
+        Some(Ok(Ok(port_mapping))) => Some(port_mapping),
+        Some(Ok(Err(err))) => {
+            eprintln!("{}", err);
+            None
+        },
+        _ => None
+    };
+
+    let mapped_address = port_mapping.as_ref().map(|port_mapping| port_mapping.external_address());
+    let candidates = ice::gather(conn, Some(&mapping), mapped_address, None)?;
+
+    Ok((mapping, candidates, port_mapping))
+}
+
+/// Offers a session to the first peer already in the room, or waits for
+/// one to offer. Returns the peer's description, the session id and the
+/// audio config to stream with, whose sample rate and buffer size are the
+/// offering side's.
+fn meet(
+    client: &mut RendezvousClient,
+    peers: &[PeerInfo],
+    mut local: SessionDescription,
+    timeout: Duration
+) -> Result<(SessionDescription, u32, AudioConfig)> {
+    let mut config = local.config.clone().ok_or(anyhow!("No audio config to offer"))?;
+
+    let (remote, session_id) = match peers.first() {
+        Some(peer) => {
+            let session_id = util::random_id()?;
+            client.offer(&peer.id, session_id, local.clone()).code(ErrorCode::RendezvousFailed)?;
+            let remote = client.wait_for_answer(session_id, Some(timeout)).code(ErrorCode::RendezvousFailed)?;
+
+            (remote, session_id)
+        },
+        None => {
+            let (from, session_id, remote) = client.wait_for_offer(Some(timeout)).code(ErrorCode::RendezvousFailed)?;
+            if let Some(offered) = &remote.config {
+                config.sample_rate = offered.sample_rate;
+                config.buffer_size = offered.buffer_size;
+            }
+            local.config = Some(config.clone());
+            client.answer(&from, session_id, local.clone()).code(ErrorCode::RendezvousFailed)?;
+
+            (remote, session_id)
+        }
+    };
+
+    if remote.mode == local.mode {
+        return Err(anyhow!("Both peers stream in {:?} mode", local.mode)).code(ErrorCode::RendezvousFailed);
+    }
+
+    Ok((remote, session_id, config))
+}
+
+/// Starts streaming to a peer we exchanged connection codes with.
+#[allow(clippy::too_many_arguments)]
+pub fn run_paired(
+    conn: Arc<UdpSocket>,
+    local: &ConnectionCode,
+    remote: &ConnectionCode,
+    audio_config: AudioConfig,
+    keepalive_interval: Duration,
+    check_timeout: Option<Duration>,
+    credentials: &Mutex<Credentials>,
+    events: &Events,
+    running: &AtomicBool,
+    report: &mut dyn FnMut(SendMessage) -> Result<()>
+) -> Result<Session> {
+    if local.session_id != remote.session_id || local.answer == remote.answer {
+        return Err(anyhow!("The connection code does not answer ours")).code(ErrorCode::InvalidCode);
+    }
+
+    let remote_addr = remote.candidates.first()
+        .ok_or(anyhow!("The connection code has no addresses"))
+        .code(ErrorCode::InvalidCode)?
+        .address;
+    let options = StreamOptions {
+        session_id: Some(local.session_id),
+        candidates: remote.candidates.clone(),
+        check_timeout: check_timeout.map(|timeout| timeout.as_millis() as u64),
+        key: Some(local.session_key.clone()),
+        ..Default::default()
+    };
+
+    open_stream(conn, remote_addr, local.mode, &options, keepalive_interval, audio_config, credentials, events, running, report)
+}
+
+/// Checks connectivity, authenticates the peer and starts streaming. This
+/// is what requests, pairing and the command line all go through.
+/// `report` learns who the peer is before audio starts. Clearing `running`
+/// gives up the setup.
+#[allow(clippy::too_many_arguments)]
+pub fn open_stream(
+    conn: Arc<UdpSocket>,
+    remote_addr: SocketAddr,
+    mode: Mode,
+    options: &StreamOptions,
+    keepalive_interval: Duration,
+    audio_config: AudioConfig,
+    credentials: &Mutex<Credentials>,
+    events: &Events,
+    running: &AtomicBool,
+    report: &mut dyn FnMut(SendMessage) -> Result<()>
+) -> Result<Session> {
+    let remote_addr = select_remote_addr(&conn, remote_addr, mode, options, events, running)?;
+    events.emit(Event::State { state: State::Handshaking });
+    // Locked for the handshake alone, the checks before can take minutes
+    let (session, trust) = credentials.lock().unwrap().authenticate(&conn, remote_addr, mode, options)?;
+    ensure_running(running)?;
+    report(SendMessage::Peer { fingerprint: session.fingerprint(), trust })?;
+
+    start_stream(mode, conn, remote_addr, options, keepalive_interval, audio_config, session, events)
+}
+
+/// Looks up a `host:port` address, the first one if it has several.
+pub fn resolve(address: &str) -> Result<SocketAddr> {
+    address.to_socket_addrs()
+        .code(ErrorCode::InvalidAddress)?
+        .next()
+        .ok_or(anyhow!("Invalid address {}", address))
+        .code(ErrorCode::InvalidAddress)
+}
+
+/// Picks the address to stream to. With candidates, this runs connectivity
+/// checks, in which the sending peer decides. The relay is only bound once
+/// they have failed, which both peers notice after the same check timeout.
+/// Traffic through the relay looks like traffic from the peer at the relay
+/// address, so the rest of the session works unchanged.
+fn select_remote_addr(conn: &UdpSocket, remote_addr: SocketAddr, mode: Mode, options: &StreamOptions, events: &Events, running: &AtomicBool) -> Result<SocketAddr> {
+    if options.candidates.is_empty() && options.relay.is_none() {
+        return Ok(remote_addr);
+    }
+    events.emit(Event::State { state: State::Checking });
+
+    let mut candidates = options.candidates.clone();
+    if !candidates.iter().any(|candidate| candidate.address == remote_addr) {
+        candidates.push(Candidate::new(CandidateType::ServerReflexive, remote_addr, 0));
+    }
+
+    let timeout = options.check_timeout.map_or(ice::DEFAULT_TIMEOUT, Duration::from_millis);
+    let checked = ice::check(conn, &candidates, Role::of(mode), timeout, running);
+    // Stopping is no reason to try the relay
+    ensure_running(running)?;
+
+    let remote_addr = match (checked, &options.relay) {
+        (Ok(remote_addr), _) => remote_addr,
+        (Err(err), Some(relay)) => {
+            eprintln!("{}, trying the relay", err);
+            let relay_addr = resolve(&relay.address)?;
+            relay_client::bind(conn, relay_addr, &relay.token, relay_client::DEFAULT_TIMEOUT).code(ErrorCode::ConnectivityFailed)?;
+            relay_addr
+        },
+        (Err(err), None) => return Err(err).code(ErrorCode::ConnectivityFailed)
+    };
+    println!("Selected {}", remote_addr);
+
+    Ok(remote_addr)
+}
+
+/// Fails once the setup has been given up, so that it goes no further.
+fn ensure_running(running: &AtomicBool) -> Result<()> {
+    match running.load(Ordering::Relaxed) {
+        true => Ok(()),
+        false => Err(anyhow!("Stopped before the session started")).code(ErrorCode::Cancelled)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_stream(
+    mode: Mode,
+    conn: Arc<UdpSocket>,
+    remote_addr: SocketAddr,
+    options: &StreamOptions,
+    keepalive_interval: Duration,
+    audio_config: AudioConfig,
+    session: noise::Session,
+    events: &Events
+) -> Result<Session> {
+    let mut builder = SessionBuilder::new(mode, remote_addr)
+        .socket(conn)
+        .audio_config(audio_config)
+        .handshake(session)
+        .dtx(options.dtx)
+        .keepalive_interval(keepalive_interval)
+        .events(events.clone());
+
+    for path in &options.paths {
+        builder = builder.path(path);
+    }
+
+    Ok(builder.start()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Instant;
+    use serde_json::{Value, json};
+
+    use crate::rendezvous::server::RendezvousServer;
+    use crate::stun::server;
+
+    /// A daemon on loopback with its own identity, and the events it emits.
+    fn daemon(name: &str, stun_servers: Vec<String>) -> (Daemon, Receiver<Event>) {
+        let dir = std::env::temp_dir().join(format!("p2p_audio_daemon_{}_{}", name, std::process::id()));
+        let credentials = Credentials::load(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let bind_options = BindOptions { address: Some("127.0.0.1".parse().unwrap()), ..BindOptions::default() };
+        let (events, receiver) = Events::channel();
+
+        let daemon = Daemon::new(stun_servers, "ws://127.0.0.1:1".to_string(), &bind_options, credentials, events).unwrap();
+
+        (daemon, receiver)
+    }
+
+    fn config() -> Value {
+        json!({
+            "host": "",
+            "inputDevice": "No such input",
+            "outputDevice": "No such output",
+            "sampleRate": 48000,
+            "bufferSize": 64,
+            "stereo": false,
+            "inputChannel": 0,
+            "outputChannel": 0
+        })
+    }
+
+    /// Starts a request given as JSON, returning what it responds, also
+    /// later from the thread of a setup.
+    fn start(daemon: &mut Daemon, message: Value) -> std::result::Result<Receiver<SendMessage>, ErrorCode> {
+        let (sender, responses) = mpsc::channel();
+
+        let result = daemon.request(serde_json::from_value(message).unwrap(), Arc::new(move |res| {
+            sender.send(res)?;
+            Ok(())
+        }));
+
+        match result {
+            Ok(()) => Ok(responses),
+            Err(err) => Err(ErrorCode::of(&err))
+        }
+    }
+
+    /// Carries out a request given as JSON, returning its responses or the
+    /// code it failed with.
+    fn request(daemon: &mut Daemon, message: Value) -> std::result::Result<Vec<SendMessage>, ErrorCode> {
+        start(daemon, message).map(|responses| responses.try_iter().collect())
+    }
+
+    #[test]
+    fn needs_a_session_for_session_requests() {
+        let (mut daemon, _) = daemon("no_session", Vec::new());
+
+        assert_eq!(request(&mut daemon, json!({ "type": "stats" })).err(), Some(ErrorCode::NoSession));
+        assert_eq!(request(&mut daemon, json!({ "type": "stop" })).err(), Some(ErrorCode::NoSession));
+        assert_eq!(request(&mut daemon, json!({ "type": "set_params", "mute": true })).err(), Some(ErrorCode::NoSession));
+    }
+
+    #[test]
+    fn rejects_bad_keepalive_intervals() {
+        let (mut daemon, events) = daemon("keepalive", Vec::new());
+
+        let connect = request(&mut daemon, json!({ "type": "connect", "keepalive_interval": 0 }));
+        let stream = request(&mut daemon, json!({
+            "type": "stream",
+            "mode": "Send",
+            "remote_addr": "127.0.0.1:9",
+            "config": config(),
+            "keepalive_interval": 61000
+        }));
+
+        assert_eq!(connect.err(), Some(ErrorCode::InvalidParams));
+        assert_eq!(stream.err(), Some(ErrorCode::InvalidParams));
+        // A failed setup goes back to idle
+        assert!(matches!(events.try_recv().unwrap(), Event::State { state: State::Idle }));
+    }
+
+    #[test]
+    fn tags_failed_setups() {
+        let (mut daemon, _) = daemon("setup", Vec::new());
+
+        let stream = request(&mut daemon, json!({ "type": "stream", "mode": "Send", "remote_addr": "nowhere", "config": config() }));
+        assert_eq!(stream.err(), Some(ErrorCode::InvalidAddress));
+
+        let answer = request(&mut daemon, json!({ "type": "pair_answer", "code": "not a code", "config": config() }));
+        assert_eq!(answer.err(), Some(ErrorCode::InvalidCode));
+
+        let config: AudioConfig = serde_json::from_value(config()).unwrap();
+        let offer = ConnectionCode::offer(Mode::Send, &config, Vec::new()).unwrap().encode().unwrap();
+        let accept = request(&mut daemon, json!({ "type": "pair_accept", "code": offer }));
+        assert_eq!(accept.err(), Some(ErrorCode::NoPendingOffer));
+
+        let rendezvous = request(&mut daemon, json!({ "type": "rendezvous", "room": "studio", "mode": "Send", "config": config }));
+        assert_eq!(rendezvous.err(), Some(ErrorCode::RendezvousFailed));
+    }
+
+    #[test]
+    fn connects_through_a_local_stun_server() {
+        let stun_conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stun_server = stun_conn.local_addr().unwrap().to_string();
+        thread::spawn(move || server::serve(&stun_conn));
+        let (mut daemon, events) = daemon("connect", vec![stun_server]);
+
+        let responses = request(&mut daemon, json!({ "type": "connect", "config": config(), "port_mapping": false })).unwrap();
+
+        match &responses[..] {
+            [SendMessage::Connect { address, local_address, is_valid, fingerprint, .. }] => {
+                assert_eq!(address, local_address);
+                assert!(!is_valid);
+                assert_eq!(fingerprint, &daemon.credentials.lock().unwrap().identity.fingerprint());
+            },
+            responses => panic!("Unexpected {:?}", responses)
+        };
+        let states: Vec<_> = events.try_iter().collect();
+        assert!(matches!(states[..], [Event::State { state: State::Gathering }, Event::State { state: State::Waiting }]));
+    }
+
+    #[test]
+    fn stops_a_rendezvous_waiting_for_the_peer() {
+        let stun_conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stun_server = stun_conn.local_addr().unwrap().to_string();
+        thread::spawn(move || server::serve(&stun_conn));
+        let rendezvous_server = RendezvousServer::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", rendezvous_server.local_addr().unwrap());
+        thread::spawn(move || rendezvous_server.run());
+        let (mut daemon, _) = daemon("rendezvous", vec![stun_server]);
+
+        let responses = start(&mut daemon, json!({ "type": "rendezvous", "room": "studio", "mode": "Send", "url": url, "config": config() })).unwrap();
+        assert!(matches!(responses.try_recv().unwrap(), SendMessage::Rendezvous { .. }));
+
+        // Answered while the setup waits for the peer
+        assert_eq!(request(&mut daemon, json!({ "type": "stats" })).err(), Some(ErrorCode::NoSession));
+        assert_eq!(request(&mut daemon, json!({ "type": "stream", "mode": "Send", "remote_addr": "127.0.0.1:9", "config": config() })).err(), Some(ErrorCode::SessionActive));
+
+        let stopping = Instant::now();
+        assert!(matches!(request(&mut daemon, json!({ "type": "stop" })).unwrap()[..], [SendMessage::Stop]));
+        assert!(stopping.elapsed() < Duration::from_secs(1));
+        assert!(matches!(responses.try_recv().unwrap(), SendMessage::Error { code: ErrorCode::Cancelled, .. }));
+
+        assert_eq!(request(&mut daemon, json!({ "type": "stop" })).err(), Some(ErrorCode::NoSession));
+    }
+
+    #[test]
+    fn reports_when_no_stun_server_answers() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut daemon, _) = daemon("diagnose", vec![silent.local_addr().unwrap().to_string()]);
+
+        let diagnose = request(&mut daemon, json!({ "type": "diagnose", "stun_timeout": 100 }));
+
+        assert_eq!(diagnose.err(), Some(ErrorCode::StunFailed));
+    }
+}
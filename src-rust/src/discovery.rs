@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Result, anyhow};
+use local_ip_address::list_afinet_netifas;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+
+use crate::net;
+use crate::util;
+
+pub const SERVICE_TYPE: &str = "_claudio._udp.local.";
+/// Longest name that fits a TXT entry, which holds 255 bytes including
+/// the `name=` key.
+const MAX_NAME_LENGTH: usize = 250;
+
+/// A peer advertising on the local network.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredPeer {
+    /// Service instance name, unique on the network.
+    pub id: String,
+    pub name: String,
+    /// Where the peer's session socket can be reached, one per interface
+    /// address.
+    pub addresses: Vec<SocketAddr>,
+    pub sample_rates: Vec<u32>,
+}
+
+impl DiscoveredPeer {
+    fn from_service(info: &ServiceInfo) -> Self {
+        let sample_rates = info.get_property_val_str("rates")
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|rate| rate.parse().ok())
+            .collect();
+
+        let mut addresses: Vec<_> = info.get_addresses().iter()
+            .filter(|ip| !net::is_link_local(ip))
+            .map(|ip| SocketAddr::new(*ip, info.get_port()))
+            .collect();
+        // IPv6 first, matching the preference of the connectivity checks
+        addresses.sort_by_key(|addr| (addr.is_ipv4(), *addr));
+
+        Self {
+            id: info.get_fullname().to_string(),
+            name: info.get_property_val_str("name").unwrap_or(info.get_fullname()).to_string(),
+            addresses,
+            sample_rates
+        }
+    }
+}
+
+/// Advertises this process as a `_claudio._udp` service and keeps track of
+/// the other instances on the local network, so a session can be set up
+/// without STUN or a signalling server.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    instance: String,
+    advertised: Option<String>,
+    peers: Arc<Mutex<HashMap<String, DiscoveredPeer>>>,
+}
+
+impl Discovery {
+    /// Starts browsing right away, peers show up as they answer.
+    pub fn new() -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let receiver = daemon.browse(SERVICE_TYPE)?;
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+
+        let browsed_peers = peers.clone();
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                let mut peers = browsed_peers.lock().unwrap();
+
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        let peer = DiscoveredPeer::from_service(&info);
+                        peers.insert(peer.id.clone(), peer);
+                    },
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        peers.remove(&fullname);
+                    },
+                    ServiceEvent::SearchStopped(_) => break,
+                    _ => ()
+                };
+            }
+        });
+
+        Ok(Self {
+            daemon,
+            instance: format!("claudio-{:08x}", util::random_id()?),
+            advertised: None,
+            peers
+        })
+    }
+
+    /// Advertises the session socket listening on `port`. Calling this again
+    /// replaces the previous advertisement.
+    pub fn advertise(&mut self, name: &str, port: u16, sample_rates: &[u32]) -> Result<()> {
+        if let Some(fullname) = self.advertised.take() {
+            self.daemon.unregister(&fullname)?;
+        }
+
+        let ips: Vec<IpAddr> = list_afinet_netifas()
+            .map_err(|err| anyhow!("{:?}", err))?
+            .into_iter()
+            .map(|(_, ip)| ip)
+            .filter(|ip| !ip.is_loopback() && !net::is_link_local(ip))
+            .collect();
+
+        let rates = sample_rates.iter().map(|rate| rate.to_string()).collect::<Vec<_>>().join(",");
+        let properties = [("name", truncate_name(name)), ("rates", rates.as_str())];
+
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.instance,
+            &format!("{}.local.", self.instance),
+            &ips[..],
+            port,
+            &properties[..]
+        )?.enable_addr_auto();
+
+        self.advertised = Some(info.get_fullname().to_string());
+        self.daemon.register(info)?;
+
+        Ok(())
+    }
+
+    /// The peers found so far, without ourselves.
+    pub fn peers(&self) -> Vec<DiscoveredPeer> {
+        let peers = self.peers.lock().unwrap();
+
+        let mut peers: Vec<_> = peers.values()
+            .filter(|peer| self.advertised.as_ref() != Some(&peer.id))
+            .cloned()
+            .collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        peers
+    }
+}
+
+/// Cuts `name` down to what fits a TXT entry, without splitting a
+/// character.
+fn truncate_name(name: &str) -> &str {
+    let mut end = name.len().min(MAX_NAME_LENGTH);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &name[..end]
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        if let Some(fullname) = self.advertised.take() {
+            let _ = self.daemon.unregister(&fullname);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_peers_from_their_service() {
+        let ips: Vec<IpAddr> = ["192.0.2.7", "fe80::1", "fd00::7"].iter().map(|ip| ip.parse().unwrap()).collect();
+        let properties = [("name", "Studio B"), ("rates", "44100,48000,bogus")];
+        let info = ServiceInfo::new(SERVICE_TYPE, "claudio-test", "claudio-test.local.", &ips[..], 5000, &properties[..]).unwrap();
+
+        let peer = DiscoveredPeer::from_service(&info);
+
+        assert_eq!(peer.id, format!("claudio-test.{}", SERVICE_TYPE));
+        assert_eq!(peer.name, "Studio B");
+        assert_eq!(peer.sample_rates, vec![44100, 48000]);
+        assert_eq!(peer.addresses, vec![
+            "[fd00::7]:5000".parse::<SocketAddr>().unwrap(),
+            "192.0.2.7:5000".parse().unwrap()
+        ]);
+    }
+
+    #[test]
+    fn falls_back_to_the_instance_name() {
+        let ips: Vec<IpAddr> = vec!["192.0.2.7".parse().unwrap()];
+        let info = ServiceInfo::new(SERVICE_TYPE, "claudio-test", "claudio-test.local.", &ips[..], 5000, None).unwrap();
+
+        let peer = DiscoveredPeer::from_service(&info);
+
+        assert_eq!(peer.name, peer.id);
+        assert!(peer.sample_rates.is_empty());
+    }
+
+    #[test]
+    fn truncates_long_names_on_a_character_boundary() {
+        assert_eq!(truncate_name("Studio"), "Studio");
+
+        let name = "\u{e9}".repeat(200);
+        let truncated = truncate_name(&name);
+
+        assert_eq!(truncated.len(), MAX_NAME_LENGTH);
+        assert!(name.starts_with(truncated));
+
+        let name = format!("a{}", name);
+        assert_eq!(truncate_name(&name).len(), MAX_NAME_LENGTH - 1);
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+/// How often comfort noise descriptors are sent while the input is silent.
+pub const DESCRIPTOR_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Level in dBFS below which the input is considered silent.
+const SILENCE_THRESHOLD: f32 = -60.0;
+/// How long the input has to stay below the threshold before transmission
+/// stops, so that decaying notes and short pauses are still sent.
+const HANGOVER: Duration = Duration::from_millis(300);
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+
+    let sum: f32 = samples.iter().map(|sample| sample * sample).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+fn to_dbfs(level: f32) -> f32 {
+    20.0 * level.max(1e-10).log10()
+}
+
+/// Energy based activity detector for the send path.
+#[derive(Clone, Debug)]
+pub struct SilenceDetector {
+    hangover_frames: u32,
+    silent_frames: u32,
+    noise_level: f32,
+}
+
+impl SilenceDetector {
+    pub fn new(sample_rate: u32, buffer_size: u32) -> Self {
+        let frame_duration = buffer_size as f32 / sample_rate as f32;
+        let hangover_frames = (HANGOVER.as_secs_f32() / frame_duration).ceil() as u32;
+
+        Self {
+            hangover_frames,
+            silent_frames: 0,
+            noise_level: 0.0
+        }
+    }
+
+    /// Returns whether the frame should be transmitted.
+    pub fn process(&mut self, samples: &[f32]) -> bool {
+        let level = rms(samples);
+
+        if to_dbfs(level) > SILENCE_THRESHOLD {
+            self.silent_frames = 0;
+            return true;
+        }
+
+        self.noise_level += (level - self.noise_level) / 8.0;
+        self.silent_frames = self.silent_frames.saturating_add(1);
+
+        self.silent_frames <= self.hangover_frames
+    }
+
+    /// RMS level of the background noise during silence.
+    pub fn noise_level(&self) -> f32 {
+        self.noise_level
+    }
+}
+
+/// Generates noise at the level described by the sender while it is not
+/// transmitting audio.
+#[derive(Clone, Debug)]
+pub struct ComfortNoise {
+    level: f32,
+    state: u32,
+}
+
+impl ComfortNoise {
+    pub fn new() -> Self {
+        Self {
+            level: 0.0,
+            state: 0x9e37_79b9
+        }
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level;
+    }
+
+    pub fn fill(&mut self, samples: &mut [f32]) {
+        // Uniform noise in [-a, a] has an RMS of a / sqrt(3).
+        let amplitude = self.level * 3f32.sqrt();
+
+        for sample in samples {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+
+            let uniform = self.state as f32 / u32::MAX as f32 * 2.0 - 1.0;
+            *sample = uniform * amplitude;
+        }
+    }
+}
+
+impl Default for ComfortNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fades in the first frame after a period of comfort noise, so playback
+/// restarts without a click.
+pub fn fade_in(samples: &mut [f32]) {
+    let len = samples.len() as f32;
+
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample *= i as f32 / len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_after_the_hangover() {
+        // 10 ms frames, about 30 of them make up the hangover
+        let mut detector = SilenceDetector::new(48000, 480);
+        let silence = vec![1e-4f32; 480];
+        let tone = vec![0.5f32; 480];
+
+        assert!(detector.process(&tone));
+        assert!((30..=31).contains(&detector.hangover_frames));
+        for _ in 0..detector.hangover_frames {
+            assert!(detector.process(&silence));
+        }
+        assert!(!detector.process(&silence));
+        assert!(detector.noise_level() > 0.0 && detector.noise_level() <= 1e-4);
+
+        assert!(detector.process(&tone));
+    }
+
+    #[test]
+    fn comfort_noise_has_the_described_level() {
+        let mut noise = ComfortNoise::new();
+        noise.set_level(0.01);
+
+        let mut samples = vec![0f32; 48000];
+        noise.fill(&mut samples);
+
+        assert!((rms(&samples) - 0.01).abs() < 0.001, "{}", rms(&samples));
+        assert!(samples.iter().all(|sample| sample.abs() <= 0.01 * 3f32.sqrt()));
+    }
+
+    #[test]
+    fn fades_in_from_zero() {
+        let mut samples = vec![1f32; 4];
+        fade_in(&mut samples);
+
+        assert_eq!(samples, vec![0.0, 0.25, 0.5, 0.75]);
+    }
+}
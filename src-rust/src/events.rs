@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::audio::Xruns;
+use crate::udp::client::UdpClient;
+use crate::udp::congestion::RateStats;
+use crate::udp::multipath::PathStats;
+
+/// How often statistics are reported while streaming.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the peer has to be silent before it counts as lost. Receiver
+/// reports arrive every 250 ms and comfort noise descriptors keep coming
+/// during silence, so a few seconds means the path is down.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Stage of the session, reported whenever it changes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Idle,
+    /// Querying STUN servers and the gateway for our addresses.
+    Gathering,
+    /// Addresses are known, waiting for the peer's.
+    Waiting,
+    /// Running connectivity checks against the peer's candidates.
+    Checking,
+    Handshaking,
+    Streaming,
+}
+
+/// Something that happened on its own rather than in answer to a request.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    State { state: State },
+    /// Traffic from the peer arrived, for the first time or after it was lost.
+    PeerConnected { address: SocketAddr },
+    /// Nothing arrived from the peer for `PEER_TIMEOUT`.
+    PeerLost { address: SocketAddr },
+    Stats {
+        paths: Vec<PathStats>,
+        rate: RateStats,
+    },
+    /// The audio device failed, e.g. because it was unplugged.
+    DeviceError { message: String },
+    /// The audio callback found the ring buffer full or empty since the
+    /// last event. Counts are totals for the session.
+    Xrun { overruns: u64, underruns: u64 },
+}
+
+/// Where events are sent, a channel or a callback. The default one drops
+/// them, for callers that do not listen.
+#[derive(Clone, Default)]
+pub struct Events {
+    callback: Option<Arc<dyn Fn(Event) + Send + Sync>>,
+}
+
+impl Events {
+    pub fn channel() -> (Self, Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel();
+
+        // Nobody listening any more is not an error
+        (Self::callback(move |event| { let _ = sender.send(event); }), receiver)
+    }
+
+    /// Calls `callback` with every event, on the thread it happened on.
+    /// That may be an audio callback's error handler, so it should return
+    /// quickly.
+    pub fn callback<F: Fn(Event) + Send + Sync + 'static>(callback: F) -> Self {
+        Self { callback: Some(Arc::new(callback)) }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(callback) = &self.callback {
+            callback(event);
+        }
+    }
+}
+
+/// Watches a running session, reporting statistics, xruns and whether the
+/// peer can be heard. Watching stops when the handle is dropped.
+pub struct Monitor {
+    running: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub fn start(client: UdpClient, xruns: Arc<Xruns>, events: Events, interval: Duration) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        thread::spawn(move || {
+            let mut connected = false;
+            let mut reported_xruns = (0, 0);
+
+            loop {
+                thread::sleep(interval);
+
+                if !thread_running.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let heard = client.idle_time().is_some_and(|idle_time| idle_time < PEER_TIMEOUT);
+                match (heard, connected) {
+                    (true, false) => events.emit(Event::PeerConnected { address: client.remote_addr() }),
+                    (false, true) => events.emit(Event::PeerLost { address: client.remote_addr() }),
+                    _ => ()
+                };
+                connected = heard;
+
+                events.emit(Event::Stats {
+                    paths: client.path_stats(),
+                    rate: client.rate_stats()
+                });
+
+                // Counted in the audio callback, which must not block, and
+                // reported from here
+                let current = (xruns.overruns(), xruns.underruns());
+                if current != reported_xruns {
+                    events.emit(Event::Xrun { overruns: current.0, underruns: current.1 });
+                    reported_xruns = current;
+                }
+            }
+        });
+
+        Self { running }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::sync::Mutex;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    use crate::audio::AudioConfig;
+
+    #[test]
+    fn serializes_with_a_snake_case_type() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+
+        let state = serde_json::to_value(Event::State { state: State::Streaming }).unwrap();
+        let connected = serde_json::to_value(Event::PeerConnected { address }).unwrap();
+
+        assert_eq!(state, serde_json::json!({ "type": "state", "state": "streaming" }));
+        assert_eq!(connected, serde_json::json!({ "type": "peer_connected", "address": "192.0.2.1:5000" }));
+    }
+
+    #[test]
+    fn delivers_to_a_channel_or_callback() {
+        let (events, receiver) = Events::channel();
+        events.emit(Event::State { state: State::Gathering });
+        events.emit(Event::Xrun { overruns: 1, underruns: 2 });
+        drop(events);
+
+        let received: Vec<_> = receiver.iter().collect();
+        assert!(matches!(received[..], [Event::State { state: State::Gathering }, Event::Xrun { overruns: 1, underruns: 2 }]));
+
+        let count = Arc::new(Mutex::new(0));
+        let callback_count = count.clone();
+        let events = Events::callback(move |_| *callback_count.lock().unwrap() += 1);
+        events.clone().emit(Event::DeviceError { message: "Unplugged".to_string() });
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        // Nobody listens, which is fine
+        Events::default().emit(Event::State { state: State::Idle });
+    }
+
+    #[test]
+    fn monitor_reports_the_peer_until_dropped() {
+        let config = AudioConfig::new("".into(), "".into(), "".into(), 48000, 64, false, 0, 0);
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let receiver_conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        receiver_conn.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        let mut sender = UdpClient::new(conn.clone(), receiver_conn.local_addr().unwrap(), Some(7), 0, config.clone()).unwrap();
+        let mut receiver = UdpClient::new(receiver_conn, conn.local_addr().unwrap(), Some(7), 0, config.clone()).unwrap();
+        sender.send(&vec![0.5f32; config.get_frame_size()]).unwrap();
+        receiver.recv();
+
+        let (events, queue) = Events::channel();
+        let monitor = Monitor::start(receiver, Arc::new(Xruns::default()), events, Duration::from_millis(50));
+
+        match queue.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Event::PeerConnected { address } => assert_eq!(address, conn.local_addr().unwrap()),
+            event => panic!("Unexpected {:?}", event)
+        };
+        assert!(matches!(queue.recv_timeout(Duration::from_secs(1)).unwrap(), Event::Stats { .. }));
+
+        drop(monitor);
+        loop {
+            match queue.recv_timeout(Duration::from_secs(1)) {
+                Ok(Event::Stats { .. }) => continue,
+                Ok(event) => panic!("Unexpected {:?}", event),
+                Err(err) => {
+                    assert_eq!(err, RecvTimeoutError::Disconnected);
+                    break;
+                }
+            };
+        }
+    }
+}
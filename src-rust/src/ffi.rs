@@ -0,0 +1,475 @@
+//! C interface to sessions, for embedding in applications not written in
+//! Rust. `include/p2p_audio.h` is generated from this module by cbindgen
+//! when the crate is built.
+//!
+//! Every pointer passed in has to be null or valid: a session from
+//! `p2p_audio_session_new` that was not freed yet, or a nul-terminated
+//! string. A session is not thread-safe, calls on one session have to come
+//! from one thread at a time. Sessions are independent, so two can start on
+//! two threads at once, as a loopback needs. No call unwinds into C, one
+//! that panics returns `Status::Internal`, or null, false or 0.
+#![allow(clippy::missing_safety_doc)]
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+
+use anyhow::anyhow;
+
+use crate::audio::{AudioConfig, AudioInterface};
+use crate::control::{ControlError, ErrorCode, WithCode};
+use crate::events::{Event, Events};
+use crate::identity::{self, Identity};
+use crate::session::{self, SessionBuilder, StreamParams};
+use crate::udp::crypto::SessionKey;
+use crate::util;
+
+/// What a call returned. Anything but `Ok` leaves a message to read with
+/// `p2p_audio_session_last_error`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok = 0,
+    /// A null pointer, a string that is not UTF-8, or malformed JSON.
+    InvalidArgument,
+    /// An audio device is missing or does not support the config.
+    AudioDevice,
+    BindFailed,
+    InvalidAddress,
+    /// The handshake timed out or the peer used another key.
+    HandshakeFailed,
+    /// The call needs a running session and there is none.
+    NoSession,
+    /// The call needs a session that has not started yet.
+    SessionActive,
+    /// A setting is out of range.
+    InvalidParams,
+    Internal,
+}
+
+impl From<ErrorCode> for Status {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::InvalidMessage => Status::InvalidArgument,
+            ErrorCode::AudioDevice => Status::AudioDevice,
+            ErrorCode::BindFailed => Status::BindFailed,
+            ErrorCode::InvalidAddress => Status::InvalidAddress,
+            ErrorCode::HandshakeFailed => Status::HandshakeFailed,
+            ErrorCode::NoSession => Status::NoSession,
+            ErrorCode::SessionActive => Status::SessionActive,
+            ErrorCode::InvalidParams => Status::InvalidParams,
+            _ => Status::Internal
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Send = 0,
+    Return,
+}
+
+impl From<Mode> for util::Mode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Send => util::Mode::Send,
+            Mode::Return => util::Mode::Return
+        }
+    }
+}
+
+/// What is collected before the session starts.
+#[derive(Default)]
+struct Setup {
+    conn: Option<Arc<UdpSocket>>,
+    remote_addr: Option<SocketAddr>,
+    audio_config: Option<AudioConfig>,
+    key: Option<SessionKey>,
+    identity_dir: Option<PathBuf>,
+    params: StreamParams,
+}
+
+/// A session and what the C side polls from it.
+pub struct Session {
+    mode: Mode,
+    setup: Setup,
+    session: Option<session::Session>,
+    events: Events,
+    receiver: Receiver<Event>,
+    last_error: Option<CString>,
+}
+
+impl Session {
+    /// Keeps the message of a failed call and returns its status.
+    fn finish(&mut self, result: session::Result<()>) -> Status {
+        match result {
+            Ok(()) => {
+                self.last_error = None;
+                Status::Ok
+            },
+            Err(err) => {
+                // Messages never contain a nul, but they must not take the
+                // whole call down if one does
+                self.last_error = CString::new(err.to_string().replace('\0', " ")).ok();
+                Status::from(err.code)
+            }
+        }
+    }
+
+    fn ensure_idle(&self) -> anyhow::Result<()> {
+        match &self.session {
+            Some(_) => Err(anyhow!("The session has already started")).code(ErrorCode::SessionActive),
+            None => Ok(())
+        }
+    }
+
+    fn start(&mut self) -> anyhow::Result<()> {
+        self.ensure_idle()?;
+
+        let remote_addr = self.setup.remote_addr
+            .ok_or(anyhow!("No remote address, set one before starting"))
+            .code(ErrorCode::InvalidAddress)?;
+        let identity_dir = match self.setup.identity_dir.clone() {
+            Some(dir) => dir,
+            None => identity::default_dir()?
+        };
+
+        let mut builder = SessionBuilder::new(self.mode.into(), remote_addr)
+            .identity(Identity::load_or_create(&identity_dir)?)
+            .params(self.setup.params.clone())
+            .events(self.events.clone());
+
+        if let Some(conn) = self.setup.conn.clone() {
+            builder = builder.socket(conn);
+        }
+        if let Some(audio_config) = self.setup.audio_config.clone() {
+            builder = builder.audio_config(audio_config);
+        }
+        if let Some(key) = self.setup.key.clone() {
+            builder = builder.key(key);
+        }
+
+        self.session = Some(builder.start()?);
+
+        Ok(())
+    }
+}
+
+/// Runs `f` on the session behind `session`, failing on a null pointer.
+/// A panic fails the call with `Status::Internal`.
+unsafe fn with_session<F>(session: *mut Session, f: F) -> Status
+where F: FnOnce(&mut Session) -> anyhow::Result<()> {
+    let session = match session.as_mut() {
+        Some(session) => session,
+        None => return Status::InvalidArgument
+    };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut *session)))
+        .unwrap_or_else(|_| Err(anyhow!("The call panicked")).code(ErrorCode::Internal))
+        .map_err(ControlError::from);
+    session.finish(result)
+}
+
+/// Runs the body of a call that does not go through `with_session`,
+/// returning `default` on a panic, which must not unwind into C.
+fn catch_panic<T>(default: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+/// Borrows a C string, which has to be UTF-8.
+unsafe fn to_str<'a>(string: *const c_char) -> anyhow::Result<&'a str> {
+    if string.is_null() {
+        return Err(anyhow!("Unexpected null string")).code(ErrorCode::InvalidMessage);
+    }
+
+    CStr::from_ptr(string).to_str().code(ErrorCode::InvalidMessage)
+}
+
+/// Hands a string to the C side, which frees it with `p2p_audio_string_free`.
+fn to_c_string(string: String) -> *mut c_char {
+    CString::new(string).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Creates a session that sends or returns audio. Free it with
+/// `p2p_audio_session_free`.
+#[no_mangle]
+pub extern "C" fn p2p_audio_session_new(mode: Mode) -> *mut Session {
+    catch_panic(ptr::null_mut(), || {
+        let (events, receiver) = Events::channel();
+
+        Box::into_raw(Box::new(Session {
+            mode,
+            setup: Setup::default(),
+            session: None,
+            events,
+            receiver,
+            last_error: None
+        }))
+    })
+}
+
+/// Stops the session if it runs and frees it.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_free(session: *mut Session) {
+    catch_panic((), || {
+        if !session.is_null() {
+            drop(Box::from_raw(session));
+        }
+    })
+}
+
+/// Binds the session socket to `local_addr`, e.g. `0.0.0.0:5000`. Without
+/// this, the session binds any free port when it starts.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_bind(session: *mut Session, local_addr: *const c_char) -> Status {
+    with_session(session, |session| {
+        session.ensure_idle()?;
+        let address: SocketAddr = to_str(local_addr)?.parse().code(ErrorCode::InvalidAddress)?;
+        let conn = UdpSocket::bind(address).code(ErrorCode::BindFailed)?;
+
+        session.setup.conn = Some(Arc::new(conn));
+        Ok(())
+    })
+}
+
+/// The port the session socket is bound to, 0 before it is bound.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_local_port(session: *const Session) -> u16 {
+    catch_panic(0, || {
+        session.as_ref()
+            .and_then(|session| session.setup.conn.as_ref())
+            .and_then(|conn| conn.local_addr().ok())
+            .map_or(0, |addr| addr.port())
+    })
+}
+
+/// Sets where the peer is, `host:port`.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_set_remote(session: *mut Session, remote_addr: *const c_char) -> Status {
+    with_session(session, |session| {
+        session.ensure_idle()?;
+        session.setup.remote_addr = Some(resolve(to_str(remote_addr)?)?);
+        Ok(())
+    })
+}
+
+fn resolve(address: &str) -> anyhow::Result<SocketAddr> {
+    address.to_socket_addrs()
+        .code(ErrorCode::InvalidAddress)?
+        .next()
+        .ok_or(anyhow!("Invalid address {}", address))
+        .code(ErrorCode::InvalidAddress)
+}
+
+/// Sets the devices and format, as JSON in the form the daemon's `stream`
+/// request takes. Without this, the default devices are used.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_set_audio_config(session: *mut Session, json: *const c_char) -> Status {
+    with_session(session, |session| {
+        session.ensure_idle()?;
+        session.setup.audio_config = Some(serde_json::from_str(to_str(json)?).code(ErrorCode::InvalidMessage)?);
+        Ok(())
+    })
+}
+
+/// Sets a pre-shared key of 64 hex digits, the peer has to use the same one.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_set_key(session: *mut Session, hex: *const c_char) -> Status {
+    with_session(session, |session| {
+        session.ensure_idle()?;
+        session.setup.key = Some(SessionKey::try_from(to_str(hex)?.to_string()).code(ErrorCode::InvalidMessage)?);
+        Ok(())
+    })
+}
+
+/// Sets where the identity is kept, created on first use. Defaults to the
+/// one the desktop app uses.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_set_identity_dir(session: *mut Session, dir: *const c_char) -> Status {
+    with_session(session, |session| {
+        session.ensure_idle()?;
+        session.setup.identity_dir = Some(PathBuf::from(to_str(dir)?));
+        Ok(())
+    })
+}
+
+/// Changes settings, as JSON in the form of the daemon's `set_params`
+/// request. Before the session starts, they apply once audio runs.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_set_params(session: *mut Session, json: *const c_char) -> Status {
+    with_session(session, |session| {
+        let params: StreamParams = serde_json::from_str(to_str(json)?).code(ErrorCode::InvalidMessage)?;
+
+        match &session.session {
+            Some(running) => Ok(running.update(&params)?),
+            None => {
+                session.setup.params = params;
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Runs the handshake and starts streaming. Blocks until audio runs or the
+/// handshake fails, which takes until the peer starts too.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_start(session: *mut Session) -> Status {
+    with_session(session, Session::start)
+}
+
+/// Ends the session and waits for it.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_stop(session: *mut Session) -> Status {
+    with_session(session, |session| {
+        let running = session.session.take()
+            .ok_or(anyhow!("The session has not started"))
+            .code(ErrorCode::NoSession)?;
+
+        Ok(running.stop()?)
+    })
+}
+
+/// Whether the session is streaming. It stops on its own when it fails.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_is_running(session: *const Session) -> bool {
+    catch_panic(false, || {
+        session.as_ref()
+            .and_then(|session| session.session.as_ref())
+            .is_some_and(session::Session::is_running)
+    })
+}
+
+/// The next event as JSON, in the form the daemon sends events, or null
+/// when there is none. Does not block.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_poll_event(session: *mut Session) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        session.as_ref()
+            .and_then(|session| session.receiver.try_recv().ok())
+            .and_then(|event| serde_json::to_string(&event).ok())
+            .map_or(ptr::null_mut(), to_c_string)
+    })
+}
+
+/// Statistics of the running session as JSON, null when it has not started.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_stats(session: *mut Session) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        session.as_ref()
+            .and_then(|session| session.session.as_ref())
+            .and_then(|running| serde_json::to_string(&running.stats()).ok())
+            .map_or(ptr::null_mut(), to_c_string)
+    })
+}
+
+/// The peer's fingerprint once the session started, else null.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_fingerprint(session: *mut Session) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        session.as_ref()
+            .and_then(|session| session.session.as_ref())
+            .map_or(ptr::null_mut(), |running| to_c_string(running.fingerprint().to_string()))
+    })
+}
+
+/// Why the last call on the session failed, null if it did not. Owned by
+/// the session and valid until the next call on it.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_session_last_error(session: *const Session) -> *const c_char {
+    catch_panic(ptr::null(), || {
+        session.as_ref()
+            .and_then(|session| session.last_error.as_ref())
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Audio hosts, devices and supported configs as JSON, in the form the
+/// daemon's `config` response carries them. Null if they can not be listed.
+#[no_mangle]
+pub extern "C" fn p2p_audio_devices() -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        AudioInterface::get_supported_configs().ok()
+            .and_then(|configs| serde_json::to_string(&configs).ok())
+            .map_or(ptr::null_mut(), to_c_string)
+    })
+}
+
+/// Frees a string returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_audio_string_free(string: *mut c_char) {
+    catch_panic((), || {
+        if !string.is_null() {
+            drop(CString::from_raw(string));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c_string(string: &str) -> CString {
+        CString::new(string).unwrap()
+    }
+
+    unsafe fn last_error(session: *const Session) -> String {
+        CStr::from_ptr(p2p_audio_session_last_error(session)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn a_panic_fails_the_call() {
+        unsafe {
+            let session = p2p_audio_session_new(Mode::Send);
+
+            assert_eq!(with_session(session, |_| panic!("in a call")), Status::Internal);
+            assert!(last_error(session).contains("panicked"));
+            assert_eq!(catch_panic(ptr::null_mut::<c_char>(), || panic!("in a call")), ptr::null_mut());
+
+            p2p_audio_session_free(session);
+        }
+    }
+
+    #[test]
+    fn rejects_null_and_invalid_arguments() {
+        unsafe {
+            assert_eq!(p2p_audio_session_start(ptr::null_mut()), Status::InvalidArgument);
+            assert_eq!(p2p_audio_session_local_port(ptr::null()), 0);
+            assert!(p2p_audio_session_poll_event(ptr::null_mut()).is_null());
+
+            let session = p2p_audio_session_new(Mode::Return);
+            assert_eq!(p2p_audio_session_set_remote(session, ptr::null()), Status::InvalidArgument);
+            assert_eq!(p2p_audio_session_bind(session, c_string("not an address").as_ptr()), Status::InvalidAddress);
+            assert_eq!(p2p_audio_session_set_key(session, c_string("abc").as_ptr()), Status::InvalidArgument);
+            assert_eq!(p2p_audio_session_set_params(session, c_string("{").as_ptr()), Status::InvalidArgument);
+            assert!(!last_error(session).is_empty());
+
+            p2p_audio_session_free(session);
+        }
+    }
+
+    #[test]
+    fn keeps_the_setup_until_the_session_starts() {
+        unsafe {
+            let session = p2p_audio_session_new(Mode::Send);
+
+            assert_eq!(p2p_audio_session_bind(session, c_string("127.0.0.1:0").as_ptr()), Status::Ok);
+            assert_ne!(p2p_audio_session_local_port(session), 0);
+            assert_eq!(p2p_audio_session_set_remote(session, c_string("127.0.0.1:9").as_ptr()), Status::Ok);
+            assert_eq!(p2p_audio_session_set_params(session, c_string("{\"input_gain\": 0.5}").as_ptr()), Status::Ok);
+            assert!(p2p_audio_session_last_error(session).is_null());
+
+            assert!(!p2p_audio_session_is_running(session));
+            assert!(p2p_audio_session_stats(session).is_null());
+            assert_eq!(p2p_audio_session_stop(session), Status::NoSession);
+
+            p2p_audio_session_free(session);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use local_ip_address::list_afinet_netifas;
+use serde::{Serialize, Deserialize};
+
+use crate::net;
+use crate::stun::client::Mapping;
+use crate::udp::packet::{ConnectivityCheck, MessageType, Packet};
+use crate::util::{self, Mode};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between two rounds of checks.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for better pairs once one has succeeded.
+const NOMINATION_DELAY: Duration = Duration::from_millis(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CandidateType {
+    #[serde(rename = "host")]
+    Host,
+    /// Learned from a check arriving from an address we did not know about.
+    #[serde(rename = "prflx")]
+    PeerReflexive,
+    #[serde(rename = "srflx")]
+    ServerReflexive,
+    #[serde(rename = "relay")]
+    Relayed,
+}
+
+impl CandidateType {
+    fn preference(&self) -> u32 {
+        match self {
+            CandidateType::Host => 126,
+            CandidateType::PeerReflexive => 110,
+            CandidateType::ServerReflexive => 100,
+            CandidateType::Relayed => 0,
+        }
+    }
+}
+
+/// An address the peer might be reachable at.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    #[serde(rename = "type")]
+    pub candidate_type: CandidateType,
+    pub address: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// `local_preference` orders candidates of the same type, higher is better.
+    pub fn new(candidate_type: CandidateType, address: SocketAddr, local_preference: u16) -> Self {
+        // Same layout as the ICE priority (RFC 8445) with a single component
+        let priority = (candidate_type.preference() << 24) + ((local_preference as u32) << 8) + 255;
+
+        Self {
+            candidate_type,
+            address,
+            priority
+        }
+    }
+}
+
+/// Local preference added to IPv6 candidates. Checks to both families run
+/// at the same time, so IPv6 wins when it answers within the nomination
+/// delay of IPv4, much like happy eyeballs (RFC 8305).
+const IPV6_PREFERENCE: u16 = 0x8000;
+
+fn family_preference(ip: &IpAddr) -> u16 {
+    match ip {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(_) => IPV6_PREFERENCE
+    }
+}
+
+/// Gathers the candidates of `conn`: its port on every local interface,
+/// a port mapped on the gateway, the addresses found through STUN and a
+/// relay address if there is one. A dual-stack socket gets candidates of
+/// both families, one bound to a single address only gets that address.
+pub fn gather(conn: &UdpSocket, mapping: Option<&Mapping>, port_mapping: Option<SocketAddr>, relay: Option<SocketAddr>) -> Result<Vec<Candidate>> {
+    let local_addr = conn.local_addr()?;
+    let dual_stack = local_addr.is_ipv6() && local_addr.ip().is_unspecified();
+    let mut candidates = Vec::new();
+
+    let interfaces = list_afinet_netifas().map_err(|err| anyhow!("{:?}", err))?;
+    let ips = interfaces.into_iter()
+        .map(|(_, ip)| ip)
+        .filter(|ip| (ip.is_ipv4() == local_addr.is_ipv4() || dual_stack) && !ip.is_unspecified() && !net::is_link_local(ip))
+        .filter(|ip| local_addr.ip().is_unspecified() || *ip == local_addr.ip());
+
+    for ip in ips {
+        let local_preference = match ip.is_loopback() {
+            true => 0,
+            false => family_preference(&ip) + (IPV6_PREFERENCE - 1 - candidates.len() as u16)
+        };
+        let address = SocketAddr::new(ip, local_addr.port());
+
+        if !candidates.iter().any(|candidate: &Candidate| candidate.address == address) {
+            candidates.push(Candidate::new(CandidateType::Host, address, local_preference));
+        }
+    }
+
+    // A mapped port is reachable without hole punching, so it goes before
+    // the STUN address, which is often the same anyway.
+    if let Some(address) = port_mapping {
+        let local_preference = family_preference(&address.ip()) + (IPV6_PREFERENCE - 1);
+        candidates.push(Candidate::new(CandidateType::ServerReflexive, address, local_preference));
+    }
+
+    if let Some(mapping) = mapping {
+        for address in std::iter::once(mapping.address).chain(mapping.address_v6) {
+            if !candidates.iter().any(|candidate| candidate.address == address) {
+                let local_preference = family_preference(&address.ip()) + (IPV6_PREFERENCE - 2);
+                candidates.push(Candidate::new(CandidateType::ServerReflexive, address, local_preference));
+            }
+        }
+    }
+
+    if let Some(relay) = relay {
+        candidates.push(Candidate::new(CandidateType::Relayed, relay, u16::MAX));
+    }
+
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.priority));
+
+    Ok(candidates)
+}
+
+/// Which peer picks the address both stream to (RFC 8445, section 6.1.1).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Picks the best pair that answered and nominates it.
+    Controlling,
+    /// Checks too, but uses whatever the controlling peer nominates.
+    Controlled,
+}
+
+impl Role {
+    /// The sending peer controls. Peers always stream in opposite modes, so
+    /// they agree without exchanging anything.
+    pub fn of(mode: Mode) -> Self {
+        match mode {
+            Mode::Send => Role::Controlling,
+            Mode::Return => Role::Controlled
+        }
+    }
+}
+
+/// Runs connectivity checks against the peer's candidates and returns the
+/// address to stream to. The controlling peer takes the best address that
+/// answered and nominates it, the controlled peer returns the address the
+/// nomination came from.
+///
+/// Both peers check at the same time, so each side's outgoing checks open
+/// the NAT mappings the other side's checks arrive through. Checks from the
+/// peer are answered while this runs, and by `UdpClient` afterwards, so the
+/// slower side can still complete once the faster one is streaming. Clearing
+/// `running` ends the checks early.
+pub fn check(conn: &UdpSocket, remote_candidates: &[Candidate], role: Role, timeout: Duration, running: &AtomicBool) -> Result<SocketAddr> {
+    let previous_timeout = conn.read_timeout()?;
+    conn.set_read_timeout(Some(Duration::from_millis(10)))?;
+
+    let mut candidates = remote_candidates.to_vec();
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.priority));
+
+    // Each round retransmits the same transaction, so there is at most one
+    // per candidate that has not answered yet.
+    let mut transactions: HashMap<SocketAddr, u32> = HashMap::new();
+    let mut succeeded: Vec<Candidate> = Vec::new();
+    let mut first_success: Option<Instant> = None;
+    let mut nomination: Option<(SocketAddr, u32)> = None;
+    let mut last_round: Option<Instant> = None;
+    let start = Instant::now();
+    let mut buffer = [0u8; 2048];
+
+    let result = loop {
+        if let (Role::Controlling, None) = (role, nomination) {
+            if let Some(best) = succeeded.iter().max_by_key(|candidate| candidate.priority) {
+                let best_possible = candidates.iter()
+                    .filter(|candidate| !succeeded.contains(candidate))
+                    .all(|candidate| candidate.priority <= best.priority);
+
+                if best_possible || first_success.is_some_and(|first| first.elapsed() >= NOMINATION_DELAY) {
+                    let transaction_id = match util::random_id() {
+                        Ok(transaction_id) => transaction_id,
+                        Err(err) => break Err(err)
+                    };
+
+                    println!("Nominating {}", best.address);
+                    nomination = Some((best.address, transaction_id));
+                    last_round = None;
+                }
+            }
+        }
+
+        if !running.load(Ordering::Relaxed) {
+            break Err(anyhow!("Connectivity checks stopped"));
+        }
+
+        if start.elapsed() >= timeout {
+            break Err(match (role, nomination) {
+                (Role::Controlled, _) if !succeeded.is_empty() => anyhow!("Connectivity checks failed, the peer nominated no candidate"),
+                (_, Some((address, _))) => anyhow!("Connectivity checks failed, the peer did not confirm {}", address),
+                _ => anyhow!("Connectivity checks failed, no candidate of the peer answered")
+            });
+        }
+
+        if last_round.is_none_or(|last_round| last_round.elapsed() >= CHECK_INTERVAL) {
+            let checks: Result<Vec<(SocketAddr, ConnectivityCheck)>> = match nomination {
+                Some((address, transaction_id)) => Ok(vec![(address, ConnectivityCheck { response: false, transaction_id, nominate: true })]),
+                None => candidates.iter()
+                    .filter(|candidate| !succeeded.contains(candidate))
+                    .map(|candidate| {
+                        let transaction_id = match transactions.entry(candidate.address) {
+                            Entry::Occupied(entry) => *entry.get(),
+                            Entry::Vacant(entry) => *entry.insert(util::random_id()?)
+                        };
+                        Ok((candidate.address, ConnectivityCheck { response: false, transaction_id, nominate: false }))
+                    })
+                    .collect()
+            };
+            let checks = match checks {
+                Ok(checks) => checks,
+                Err(err) => break Err(err)
+            };
+
+            for (address, check) in checks {
+                // Unreachable candidates are expected, e.g. a LAN address
+                // of a peer on another network.
+                let _ = net::send_to(conn, &check.to_buffer(), address);
+            }
+            last_round = Some(Instant::now());
+        }
+
+        let (len, from) = match net::recv_from(conn, &mut buffer) {
+            Ok(received) => received,
+            Err(_) => continue
+        };
+
+        let check = match ConnectivityCheck::from_buffer(&buffer[..len]) {
+            Ok(check) => check,
+            Err(_) => continue
+        };
+
+        if !check.response {
+            respond(conn, &check, from);
+
+            if !candidates.iter().any(|candidate| candidate.address == from) {
+                println!("Learned peer reflexive candidate {}", from);
+                candidates.push(Candidate::new(CandidateType::PeerReflexive, from, u16::MAX));
+            }
+
+            if check.nominate && role == Role::Controlled {
+                println!("Peer nominated {}", from);
+                break Ok(from);
+            }
+            continue;
+        }
+
+        if nomination == Some((from, check.transaction_id)) {
+            break Ok(from);
+        }
+
+        // A response only counts if it comes from where the check was sent
+        if transactions.get(&from) != Some(&check.transaction_id) {
+            continue;
+        }
+        transactions.remove(&from);
+
+        if let Some(candidate) = candidates.iter().find(|candidate| candidate.address == from) {
+            if !succeeded.contains(candidate) {
+                println!("Candidate {} ({:?}) answered", candidate.address, candidate.candidate_type);
+                succeeded.push(candidate.clone());
+                first_success.get_or_insert_with(Instant::now);
+            }
+        }
+    };
+
+    conn.set_read_timeout(previous_timeout)?;
+
+    result
+}
+
+/// Answers a connectivity check from the peer.
+pub fn respond(conn: &UdpSocket, check: &ConnectivityCheck, from: SocketAddr) {
+    let response = ConnectivityCheck { response: true, ..check.clone() };
+
+    if let Err(err) = net::send_to(conn, &response.to_buffer(), from) {
+        eprintln!("Error answering connectivity check from {}: {}", from, err);
+    }
+}
+
+/// Whether a datagram is a connectivity check request.
+pub fn is_check(buffer: &[u8]) -> bool {
+    matches!(Packet::peek(buffer), Some((MessageType::Check, _)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn host(conn: &UdpSocket) -> Candidate {
+        Candidate::new(CandidateType::Host, conn.local_addr().unwrap(), 0)
+    }
+
+    #[test]
+    fn gathers_both_families_on_a_dual_stack_socket() {
+        let conn = net::bind_dual_stack(0).unwrap();
+        let port = conn.local_addr().unwrap().port();
+
+        let candidates = gather(&conn, None, None, None).unwrap();
+
+        let expected: Vec<IpAddr> = list_afinet_netifas().unwrap().into_iter()
+            .map(|(_, ip)| ip)
+            .filter(|ip| !net::is_link_local(ip))
+            .collect();
+        for ip in &expected {
+            assert!(candidates.iter().any(|candidate| candidate.address == SocketAddr::new(*ip, port)), "{} missing", ip);
+        }
+        assert!(candidates.iter().all(|candidate| !net::is_link_local(&candidate.address.ip())));
+
+        // IPv6 goes first, as the connectivity checks prefer it
+        let first_v4 = candidates.iter().position(|candidate| candidate.address.is_ipv4() && !candidate.address.ip().is_loopback());
+        let first_v6 = candidates.iter().position(|candidate| candidate.address.is_ipv6() && !candidate.address.ip().is_loopback());
+        if let (Some(v4), Some(v6)) = (first_v4, first_v6) {
+            assert!(v6 < v4);
+        }
+    }
+
+    #[test]
+    fn gathers_only_the_bound_address() {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let candidates = gather(&conn, None, None, None).unwrap();
+
+        assert_eq!(candidates, vec![Candidate::new(CandidateType::Host, conn.local_addr().unwrap(), 0)]);
+    }
+
+    /// Checks from both sides at once, returning what each picked.
+    fn check_both(controlling: &UdpSocket, to_controlled: Vec<Candidate>, controlled: &UdpSocket, to_controlling: Vec<Candidate>) -> (Result<SocketAddr>, Result<SocketAddr>) {
+        let controlled = controlled.try_clone().unwrap();
+        let other_side = thread::spawn(move || check(&controlled, &to_controlling, Role::Controlled, TIMEOUT, &AtomicBool::new(true)));
+        let picked = check(controlling, &to_controlled, Role::Controlling, TIMEOUT, &AtomicBool::new(true));
+
+        (picked, other_side.join().unwrap())
+    }
+
+    #[test]
+    fn both_sides_use_the_nominated_pair() {
+        let (a, b) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        // Preferred, but nothing answers there
+        let unreachable = Candidate::new(CandidateType::Host, "127.0.0.1:9".parse().unwrap(), u16::MAX);
+
+        let (picked, nominated) = check_both(&a, vec![unreachable.clone(), host(&b)], &b, vec![unreachable, host(&a)]);
+
+        assert_eq!(picked.unwrap(), b.local_addr().unwrap());
+        assert_eq!(nominated.unwrap(), a.local_addr().unwrap());
+    }
+
+    #[test]
+    fn the_controlled_side_follows_a_peer_reflexive_nomination() {
+        let (a, b) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        let wrong = Candidate::new(CandidateType::ServerReflexive, "127.0.0.1:9".parse().unwrap(), 0);
+
+        let (picked, nominated) = check_both(&a, vec![host(&b)], &b, vec![wrong]);
+
+        assert_eq!(picked.unwrap(), b.local_addr().unwrap());
+        assert_eq!(nominated.unwrap(), a.local_addr().unwrap());
+    }
+
+    #[test]
+    fn fails_when_no_candidate_answers() {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let result = check(&conn, &[host(&silent)], Role::Controlling, Duration::from_millis(300), &AtomicBool::new(true));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn stops_when_no_longer_running() {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let start = Instant::now();
+
+        let result = check(&conn, &[host(&silent)], Role::Controlling, TIMEOUT, &AtomicBool::new(false));
+
+        assert!(result.unwrap_err().to_string().contains("stopped"));
+        assert!(start.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn prefers_ipv6_within_the_same_type() {
+        let v4 = Candidate::new(CandidateType::Host, "192.0.2.1:1".parse().unwrap(), family_preference(&"192.0.2.1".parse().unwrap()) + 1);
+        let v6 = Candidate::new(CandidateType::Host, "[2001:db8::1]:1".parse().unwrap(), family_preference(&"2001:db8::1".parse().unwrap()));
+        let relayed = Candidate::new(CandidateType::Relayed, "192.0.2.2:1".parse().unwrap(), u16::MAX);
+
+        assert!(v6.priority > v4.priority);
+        assert!(v4.priority > relayed.priority);
+    }
+}
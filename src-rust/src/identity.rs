@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::noise;
+use crate::util;
+
+const IDENTITY_FILE: &str = "identity.json";
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+/// Where the identity and known peers are kept, `$XDG_CONFIG_HOME/claudio`
+/// or `~/.config/claudio`.
+pub fn default_dir() -> Result<PathBuf> {
+    if let Some(config) = env::var_os("XDG_CONFIG_HOME") {
+        return Ok(PathBuf::from(config).join("claudio"));
+    }
+
+    let home = env::var_os("HOME").ok_or(anyhow!("No home directory to keep the identity in"))?;
+
+    Ok(PathBuf::from(home).join(".config").join("claudio"))
+}
+
+/// Short form of a public key for people to compare, the first 16 bytes of
+/// its SHA-256 hash in groups of four hex digits.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+
+    hash[..16].chunks(2)
+        .map(util::to_hex)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The long-term static keypair of this installation, which peers recognize
+/// it by.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(with = "hex")]
+    private_key: Vec<u8>,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self> {
+        let keypair = noise::generate_keypair()?;
+
+        Ok(Self {
+            private_key: keypair.private,
+            public_key: keypair.public
+        })
+    }
+
+    /// Loads the identity kept in `dir`, creating one on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+
+        if path.exists() {
+            return Ok(serde_json::from_str(&fs::read_to_string(&path)?)?);
+        }
+
+        let identity = Self::generate()?;
+        fs::create_dir_all(dir)?;
+        write_private(&path, &serde_json::to_string_pretty(&identity)?)?;
+        println!("Created identity {} in {}", identity.fingerprint(), path.display());
+
+        Ok(identity)
+    }
+
+    pub fn private_key(&self) -> &[u8] {
+        &self.private_key
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+}
+
+/// Whether a peer's fingerprint was seen before.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trust {
+    /// Matched the fingerprint pinned by the caller.
+    Pinned,
+    /// Matched the fingerprint remembered for the peer's name.
+    Known,
+    /// First time this name was seen, its fingerprint is remembered now.
+    New,
+    /// Neither pinned nor named, so nothing to compare against.
+    Unverified,
+}
+
+/// Fingerprints of the peers met so far, by the name the user knows them
+/// as. The first fingerprint seen for a name is trusted from then on, as
+/// with SSH host keys.
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: HashMap<String, String>,
+}
+
+impl KnownPeers {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(KNOWN_PEERS_FILE);
+
+        let peers = match path.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&path)?)?,
+            false => HashMap::new()
+        };
+
+        Ok(Self { path, peers })
+    }
+
+    /// Checks a peer's fingerprint against the one pinned by the caller, or
+    /// else against the one remembered for `name`.
+    pub fn verify(&mut self, fingerprint: &str, pinned: Option<&str>, name: Option<&str>) -> Result<Trust> {
+        if let Some(pinned) = pinned {
+            return match same_fingerprint(pinned, fingerprint) {
+                true => Ok(Trust::Pinned),
+                false => Err(anyhow!("The peer's fingerprint {} does not match the pinned {}", fingerprint, pinned))
+            };
+        }
+
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(Trust::Unverified)
+        };
+
+        match self.peers.get(name) {
+            Some(known) if same_fingerprint(known, fingerprint) => Ok(Trust::Known),
+            Some(known) => Err(anyhow!(
+                "The fingerprint of {} changed from {} to {}, forget the peer if this is expected",
+                name, known, fingerprint
+            )),
+            None => {
+                self.peers.insert(name.to_string(), fingerprint.to_string());
+                self.save()?;
+                Ok(Trust::New)
+            }
+        }
+    }
+
+    pub fn forget(&mut self, name: &str) -> Result<()> {
+        self.peers.remove(name);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(&self.path, serde_json::to_string_pretty(&self.peers)?)?;
+
+        Ok(())
+    }
+}
+
+/// Compares fingerprints regardless of case and spacing, so they can be
+/// pasted as people write them down.
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |fingerprint: &str| -> String {
+        fingerprint.chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    };
+
+    normalize(a) == normalize(b)
+}
+
+/// Writes a file only the current user can read.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+        file.write_all(contents.as_bytes())?;
+    }
+
+    #[cfg(not(unix))]
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::util;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&util::to_hex(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        util::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("p2p_audio_identity_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        dir
+    }
+
+    #[test]
+    fn creates_an_identity_once_and_loads_it_after() {
+        let dir = temp_dir("create");
+
+        let created = Identity::load_or_create(&dir).unwrap();
+        let loaded = Identity::load_or_create(&dir).unwrap();
+
+        assert_eq!(loaded.public_key, created.public_key);
+        assert_eq!(loaded.private_key(), created.private_key());
+        assert_eq!(created.fingerprint().split(' ').count(), 8);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(dir.join(IDENTITY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trusts_the_first_fingerprint_seen_for_a_name() {
+        let dir = temp_dir("tofu");
+        let mut peers = KnownPeers::load(&dir).unwrap();
+
+        assert_eq!(peers.verify("aaaa bbbb", None, Some("Studio")).unwrap(), Trust::New);
+        assert_eq!(peers.verify("aaaa bbbb", None, Some("Studio")).unwrap(), Trust::Known);
+
+        // Remembered across loads
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.verify("AAAA:BBBB", None, Some("Studio")).unwrap(), Trust::Known);
+        assert_eq!(peers.verify("cccc dddd", None, None).unwrap(), Trust::Unverified);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_changed_or_unpinned_fingerprint() {
+        let dir = temp_dir("mismatch");
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        peers.verify("aaaa bbbb", None, Some("Studio")).unwrap();
+
+        assert!(peers.verify("cccc dddd", None, Some("Studio")).is_err());
+
+        // A pinned fingerprint takes precedence over the remembered one
+        assert_eq!(peers.verify("cccc dddd", Some("CCCCDDDD"), Some("Studio")).unwrap(), Trust::Pinned);
+        assert!(peers.verify("aaaa bbbb", Some("cccc dddd"), Some("Studio")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forgets_a_peer() {
+        let dir = temp_dir("forget");
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        peers.verify("aaaa bbbb", None, Some("Studio")).unwrap();
+
+        peers.forget("Studio").unwrap();
+
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.verify("cccc dddd", None, Some("Studio")).unwrap(), Trust::New);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
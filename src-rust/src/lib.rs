@@ -1,4 +1,20 @@
 pub mod udp;
 pub mod util;
 pub mod audio;
-pub mod ringbuffer;
\ No newline at end of file
+pub mod ringbuffer;
+pub mod dtx;
+pub mod stun;
+pub mod ice;
+pub mod relay;
+pub mod rendezvous;
+pub mod pairing;
+pub mod discovery;
+pub mod net;
+pub mod portmap;
+pub mod noise;
+pub mod identity;
+pub mod control;
+pub mod events;
+pub mod session;
+pub mod daemon;
+pub mod ffi;
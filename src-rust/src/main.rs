@@ -1,158 +1,1041 @@
-use std::sync::Arc;
-use std::net::UdpSocket;
-use std::os::unix::net::UnixListener;
-use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Receiver;
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::convert::TryFrom;
+use std::io;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread;
-use std::env;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use serde;
-use serde::{Serialize, Deserialize};
-use serde_json;
-use stunclient::StunClient;
-use local_ip_address::local_ip;
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use clap::{App, AppSettings, Arg, SubCommand};
 
-use p2p_audio::udp::client::{UdpClient};
+use p2p_audio::udp::crypto::SessionKey;
+use p2p_audio::udp::keepalive::{self, Keepalive};
 use p2p_audio::audio::{AudioInterface, AudioConfig};
 use p2p_audio::util::Mode;
-use p2p_audio::ringbuffer;
+use p2p_audio::stun;
+use p2p_audio::rendezvous;
+use p2p_audio::pairing::{self, ConnectionCode};
+use p2p_audio::net::{self, BindOptions, PortRange};
+use p2p_audio::portmap::{Gateway, Protocol};
+use p2p_audio::portmap::gateway::StandInGateway;
+use p2p_audio::identity;
+use p2p_audio::noise;
+use p2p_audio::control::{self, ErrorCode};
+use p2p_audio::events::{Event, Events, State};
+use p2p_audio::session::StreamParams;
+use p2p_audio::daemon::{
+    Credentials, Daemon, RecvMessage, SendMessage, StreamOptions,
+    gather_candidates, open_stream, resolve, run_paired, start_stream
+};
 
 fn main() {
-    match run() {
-        Ok(_) => (),
-        Err(err) => println!("{}", err),
-    }
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type")]
-enum RecvMessage {
-    #[serde(rename = "config")]
-    Config,
-    #[serde(rename = "connect")]
-    Connect {
-        config: Option<AudioConfig>
-    },
-    #[serde(rename = "stream")]
-    Stream {
-        mode: Mode,
-        remote_addr: String,
-        config: AudioConfig
-    },
-}
-
-
-#[derive(Serialize, Debug)]
-#[serde(tag = "type")]
-enum SendMessage {
-    #[serde(rename = "connect")]
-    Connect { address: String, is_valid: bool },
-}
-
-fn run() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let socket = match args.get(1) {
-        Some(path) => path,
-        None => panic!("No socket path")
+    let matches = app().get_matches();
+
+    let stun_servers: Vec<String> = match matches.values_of("stun") {
+        Some(servers) => servers.map(String::from).collect(),
+        None => stun::client::DEFAULT_SERVERS.iter().map(|server| server.to_string()).collect()
+    };
+
+    let bind_options = match bind_options(&matches) {
+        Ok(bind_options) => bind_options,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let credentials = matches.value_of("config_dir")
+        .map(|dir| Ok(PathBuf::from(dir)))
+        .unwrap_or_else(identity::default_dir)
+        .and_then(|dir| Credentials::load(&dir));
+
+    let result = match matches.subcommand() {
+        ("daemon", Some(matches)) => credentials.and_then(|credentials| {
+            run(matches.value_of("socket").unwrap(), stun_servers, matches.value_of("rendezvous").unwrap(), bind_options, credentials)
+        }),
+        ("devices", Some(matches)) => run_devices(matches.is_present("json")),
+        ("send", Some(matches)) => credentials.and_then(|credentials| run_direct(matches, Mode::Send, &bind_options, credentials)),
+        ("return", Some(matches)) => credentials.and_then(|credentials| run_direct(matches, Mode::Return, &bind_options, credentials)),
+        ("duplex", Some(matches)) => credentials.and_then(|mut credentials| run_duplex(matches, &bind_options, &mut credentials)),
+        ("loopback-test", Some(matches)) => credentials.and_then(|credentials| run_loopback_test(matches, &credentials)),
+        ("stun", Some(matches)) => run_stun(&stun_servers, &bind_options, !matches.is_present("no_port_mapping")),
+        ("stun-server", Some(matches)) => run_stun_server(matches.value_of("bind").unwrap(), matches.value_of("alternate")),
+        ("nat-check", Some(matches)) => run_nat_check(matches, &stun_servers),
+        ("gateway", Some(matches)) => run_gateway(matches),
+        ("pair", Some(matches)) => {
+            let mode = match matches.value_of("mode") {
+                Some("return") => Mode::Return,
+                _ => Mode::Send
+            };
+            credentials.and_then(|credentials| run_pair(matches.value_of("code"), mode, &stun_servers, &bind_options, credentials))
+        },
+        ("fingerprint", Some(_)) => credentials.map(|credentials| println!("{}", credentials.identity.fingerprint())),
+        _ => unreachable!("clap requires a subcommand")
+    };
+
+    if let Err(err) = result {
+        eprintln!("{:#}", err);
+        process::exit(1);
+    }
+}
+
+/// The command line, its global options and subcommands.
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("p2p_audio")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("stun")
+            .value_name("HOST:PORT")
+            .long("stun")
+            .help("STUN server to query, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("bind")
+            .value_name("IP")
+            .long("bind")
+            .help("Local address to bind the session socket to")
+            .takes_value(true))
+        .arg(Arg::with_name("port")
+            .value_name("PORT[-PORT]")
+            .long("port")
+            .help("Local port, or range of ports to try")
+            .takes_value(true))
+        .arg(Arg::with_name("interface")
+            .value_name("NAME")
+            .long("interface")
+            .help("Network interface to bind the session socket to")
+            .takes_value(true)
+            .conflicts_with("bind"))
+        .arg(Arg::with_name("config_dir")
+            .value_name("DIR")
+            .long("config-dir")
+            .help("Where the identity and known peers are kept, defaults to ~/.config/claudio")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Serves the control protocol on a Unix socket, for the desktop app")
+            .arg(Arg::with_name("socket")
+                .value_name("SOCKET")
+                .help("Path of the control socket")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("rendezvous")
+                .value_name("URL")
+                .long("rendezvous")
+                .help("Rendezvous server to meet peers through")
+                .takes_value(true)
+                .default_value(rendezvous::client::DEFAULT_URL)))
+        .subcommand(SubCommand::with_name("devices")
+            .about("Lists audio hosts, their devices and the configurations they support")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Prints the list as JSON, as the daemon reports it")))
+        .subcommand(SubCommand::with_name("send")
+            .about("Sends the input device to a peer whose address is known")
+            .args(&audio_args())
+            .args(&peer_args())
+            .args(&session_args()))
+        .subcommand(SubCommand::with_name("return")
+            .about("Plays what a peer whose address is known sends")
+            .args(&audio_args())
+            .args(&peer_args())
+            .args(&session_args()))
+        .subcommand(SubCommand::with_name("duplex")
+            .about("Sends and returns at once. Sends from the bound port to the peer's next port, \
+                and returns on the next port what the peer sends from its bound port")
+            .args(&audio_args())
+            .args(&peer_args())
+            .args(&session_args()))
+        .subcommand(SubCommand::with_name("loopback-test")
+            .about("Streams the input device to the output device through the network stack on this machine")
+            .args(&audio_args())
+            .args(&session_args())
+            .arg(Arg::with_name("duration")
+                .value_name("SECONDS")
+                .long("duration")
+                .takes_value(true)
+                .default_value("10")))
+        .subcommand(SubCommand::with_name("stun")
+            .about("Prints the addresses peers could reach this machine at")
+            .arg(Arg::with_name("no_port_mapping")
+                .long("no-port-mapping")
+                .help("Does not ask the gateway for a port mapping")))
+        .subcommand(SubCommand::with_name("fingerprint")
+            .about("Prints the fingerprint peers know this installation by"))
+        .subcommand(SubCommand::with_name("stun-server")
+            .about("Runs a minimal STUN server")
+            .arg(Arg::with_name("bind")
+                .value_name("ADDRESS")
+                .long("bind")
+                .takes_value(true)
+                .default_value("0.0.0.0:3478"))
+            .arg(Arg::with_name("alternate")
+                .value_name("ADDRESS")
+                .long("alternate")
+                .help("Second address with another IP and port, enables NAT behavior tests")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("nat-check")
+            .about("Detects the NAT type and whether direct connections are likely to work")
+            .arg(Arg::with_name("timeout")
+                .value_name("MS")
+                .long("timeout")
+                .help("Milliseconds to wait for each probe")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("gateway")
+            .about("Runs a stand-in gateway answering PCP, NAT-PMP and UPnP, for trying port mapping locally")
+            .arg(Arg::with_name("bind")
+                .value_name("IP")
+                .long("bind")
+                .takes_value(true)
+                .default_value("127.0.0.1"))
+            .arg(Arg::with_name("pmp_port")
+                .value_name("PORT")
+                .long("pmp-port")
+                .takes_value(true)
+                .default_value("5351"))
+            .arg(Arg::with_name("ssdp_port")
+                .value_name("PORT")
+                .long("ssdp-port")
+                .takes_value(true)
+                .default_value("1900"))
+            .arg(Arg::with_name("external")
+                .value_name("IP")
+                .long("external")
+                .help("External address reported for mappings")
+                .takes_value(true)
+                .default_value("203.0.113.1"))
+            .arg(Arg::with_name("protocols")
+                .value_name("PROTOCOL")
+                .long("protocol")
+                .help("Protocol to answer, can be repeated, defaults to all")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["pcp", "natpmp", "upnp"])))
+        .subcommand(SubCommand::with_name("pair")
+            .about("Connects to a peer by exchanging connection codes, without any server")
+            .arg(Arg::with_name("code")
+                .value_name("CODE")
+                .help("The peer's offer, leave out to create one")
+                .index(1))
+            .arg(Arg::with_name("mode")
+                .value_name("MODE")
+                .long("mode")
+                .help("Whether this side sends or returns audio, when creating an offer")
+                .possible_values(&["send", "return"])
+                .default_value("send")))
+}
+
+/// Devices and format, for the subcommands that open audio devices.
+fn audio_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("host")
+            .value_name("NAME")
+            .long("host")
+            .help("Audio host, e.g. ALSA or JACK, defaults to the system's")
+            .takes_value(true),
+        Arg::with_name("input")
+            .value_name("DEVICE")
+            .short("i")
+            .long("input")
+            .help("Input device, defaults to the host's")
+            .takes_value(true),
+        Arg::with_name("output")
+            .value_name("DEVICE")
+            .short("o")
+            .long("output")
+            .help("Output device, defaults to the host's")
+            .takes_value(true),
+        Arg::with_name("sample_rate")
+            .value_name("HZ")
+            .short("s")
+            .long("sample-rate")
+            .takes_value(true)
+            .default_value("44100"),
+        Arg::with_name("buffer_size")
+            .value_name("FRAMES")
+            .short("b")
+            .long("buffer-size")
+            .takes_value(true)
+            .default_value("128"),
+        Arg::with_name("stereo")
+            .long("stereo")
+            .help("Streams two channels instead of one"),
+        Arg::with_name("input_channel")
+            .value_name("CHANNEL")
+            .long("input-channel")
+            .help("First input device channel to capture, from 0")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("output_channel")
+            .value_name("CHANNEL")
+            .long("output-channel")
+            .help("First output device channel to play to, from 0")
+            .takes_value(true)
+            .default_value("0"),
+    ]
+}
+
+/// Who to stream with and how to trust them.
+fn peer_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("remote")
+            .value_name("HOST:PORT")
+            .long("remote")
+            .help("Address of the peer")
+            .takes_value(true)
+            .required(true),
+        Arg::with_name("key")
+            .value_name("HEX")
+            .long("key")
+            .help("Pre-shared key mixed into the handshake, the peer has to use the same one")
+            .takes_value(true),
+        Arg::with_name("fingerprint")
+            .value_name("FINGERPRINT")
+            .long("fingerprint")
+            .help("Only streams with a peer with this fingerprint")
+            .takes_value(true),
+        Arg::with_name("peer_name")
+            .value_name("NAME")
+            .long("peer-name")
+            .help("Name to remember the peer's fingerprint by")
+            .takes_value(true),
+        Arg::with_name("paths")
+            .value_name("ADDRESS|INTERFACE")
+            .long("path")
+            .help("Extra network path to send copies over, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// Settings of a running session.
+fn session_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dtx")
+            .long("dtx")
+            .help("Stops sending audio during silence"),
+        Arg::with_name("jitter_target")
+            .value_name("MS")
+            .long("jitter-target")
+            .help("Milliseconds of audio the receiver keeps buffered")
+            .takes_value(true),
+        Arg::with_name("redundancy")
+            .value_name("COPIES")
+            .long("redundancy")
+            .help("Copies of each packet sent, from 1 to 3")
+            .takes_value(true),
+        Arg::with_name("stats")
+            .long("stats")
+            .help("Prints statistics every second"),
+    ]
+}
+
+/// A message with the id of the request it answers, which clients pick
+/// freely to match responses to requests.
+#[derive(Serialize)]
+struct Response<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<u64>,
+    #[serde(flatten)]
+    message: &'a SendMessage,
+}
+
+/// Writes to the connected client. Responses and events are written from
+/// different threads, the lock keeps their frames from interleaving.
+#[derive(Clone, Default)]
+struct Outbox {
+    stream: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl Outbox {
+    fn open(&self, stream: &UnixStream) -> Result<()> {
+        *self.stream.lock().unwrap() = Some(stream.try_clone()?);
+        Ok(())
+    }
+
+    fn close(&self) {
+        *self.stream.lock().unwrap() = None;
+    }
+
+    fn respond(&self, request_id: Option<u64>, message: &SendMessage) -> Result<()> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => control::write_json(stream, &Response { request_id, message }),
+            None => Err(anyhow!("No client to respond to"))
+        }
+    }
+
+    /// Events while no client is connected are dropped.
+    fn emit(&self, event: &Event) {
+        if let Some(stream) = self.stream.lock().unwrap().as_mut() {
+            if let Err(err) = control::write_json(stream, event) {
+                eprintln!("Could not send event: {}", err);
+            }
+        }
+    }
+}
+
+/// How often the command line checks whether its sessions are still running.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn bind_options(matches: &clap::ArgMatches) -> Result<BindOptions> {
+    Ok(BindOptions {
+        address: matches.value_of("bind").map(str::parse).transpose()?,
+        port: matches.value_of("port").map(str::parse).transpose()?,
+        interface: matches.value_of("interface").map(String::from)
+    })
+}
+
+fn run_stun_server(bind: &str, alternate: Option<&str>) -> Result<()> {
+    if let Some(alternate) = alternate {
+        println!("STUN server listening on {} and {}", bind, alternate);
+        return stun::server::serve_with_alternate(bind.parse()?, alternate.parse()?);
+    }
+
+    let conn = UdpSocket::bind(bind)?;
+    println!("STUN server listening on {}", conn.local_addr()?);
+
+    stun::server::serve(&conn)
+}
+
+fn run_gateway(matches: &clap::ArgMatches) -> Result<()> {
+    let protocols: Vec<Protocol> = match matches.values_of("protocols") {
+        Some(protocols) => protocols.map(|protocol| match protocol {
+            "pcp" => Protocol::Pcp,
+            "natpmp" => Protocol::NatPmp,
+            _ => Protocol::Upnp
+        }).collect(),
+        None => vec![Protocol::Pcp, Protocol::NatPmp, Protocol::Upnp]
+    };
+
+    let gateway = StandInGateway::bind(
+        matches.value_of("bind").unwrap().parse()?,
+        matches.value_of("pmp_port").unwrap().parse()?,
+        matches.value_of("ssdp_port").unwrap().parse()?,
+        matches.value_of("external").unwrap().parse()?,
+        &protocols
+    )?;
+
+    println!("Stand-in gateway answering {:?}, pass this as the gateway to connect:", protocols);
+    println!("{}", serde_json::to_string(&gateway.gateway()?)?);
+
+    gateway.run()
+}
+
+fn run_nat_check(matches: &clap::ArgMatches, stun_servers: &[String]) -> Result<()> {
+    let timeout = matches.value_of("timeout").map(str::parse).transpose()?.map_or(stun::nat::DEFAULT_TIMEOUT, Duration::from_millis);
+    let report = stun::nat::diagnose(stun_servers, timeout)?;
+
+    println!("{}", report.summary);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+/// Pairs through the terminal: prints our code and reads the peer's from
+/// standard input when we made the offer.
+fn run_pair(code: Option<&str>, mode: Mode, stun_servers: &[String], bind_options: &BindOptions, credentials: Credentials) -> Result<()> {
+    let credentials = Mutex::new(credentials);
+    let conn = Arc::new(net::bind(bind_options)?);
+    let (mapping, candidates, _port_mapping) = gather_candidates(&conn, stun_servers, stun::client::DEFAULT_TIMEOUT, Gateway::default_route().ok())?;
+
+    match code {
+        Some(code) => {
+            let offer = ConnectionCode::decode(code)?;
+            let answer = offer.answer(candidates)?;
+            let config = offer.configure(AudioConfig::from_default_devices()?);
+
+            println!("Paste this code on the other side:\n\n{}\n", answer.encode()?);
+
+            let stream = run_paired(conn, &answer, &offer, config, keepalive::DEFAULT_INTERVAL, Some(pairing::PAIRING_TIMEOUT), &credentials, &Events::default(), &AtomicBool::new(true), &mut print_peer)?;
+            Ok(stream.wait()?)
+        },
+        None => {
+            let config = AudioConfig::from_default_devices()?;
+            let offer = ConnectionCode::offer(mode, &config, candidates)?;
+            let _keepalive = Keepalive::stun(conn.clone(), keepalive::DEFAULT_INTERVAL, mapping.server);
+
+            println!("Send this code to the peer:\n\n{}\n", offer.encode()?);
+            println!("Paste their answer:");
+
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            let answer = ConnectionCode::decode(&answer)?;
+
+            let stream = run_paired(conn, &offer, &answer, config, keepalive::DEFAULT_INTERVAL, None, &credentials, &Events::default(), &AtomicBool::new(true), &mut print_peer)?;
+            Ok(stream.wait()?)
+        }
+    }
+}
+
+fn print_peer(res: SendMessage) -> Result<()> {
+    if let SendMessage::Peer { fingerprint, trust } = res {
+        println!("Connected to {} ({:?})", fingerprint, trust);
+    }
+    Ok(())
+}
+
+fn run_devices(json: bool) -> Result<()> {
+    let configs = AudioInterface::get_supported_configs()?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&configs)?);
+        return Ok(());
+    }
+
+    let mut hosts: Vec<_> = configs.iter().collect();
+    hosts.sort_by_key(|(host, _)| host.as_str());
+
+    for (host, devices) in hosts {
+        println!("{}", host);
+        if devices.is_empty() {
+            println!("  No devices");
+        }
+
+        let mut devices: Vec<_> = devices.iter().collect();
+        devices.sort_by_key(|(device, _)| device.as_str());
+
+        for (device, directions) in devices {
+            println!("  {}", device);
+
+            for direction in ["input", "output"] {
+                // Devices that only play or only capture report no channels
+                // for the other direction
+                let config = match directions.get(direction) {
+                    Some(config) if config.channels > 0 => config,
+                    _ => continue
+                };
+                let sample_rates: Vec<String> = config.sample_rates.iter().map(u32::to_string).collect();
+
+                println!(
+                    "    {}: {} channels, {} Hz, buffer {}-{} frames",
+                    direction,
+                    config.channels,
+                    sample_rates.join(", "),
+                    config.buffer_size.min,
+                    config.buffer_size.max
+                );
+            }
+        }
+    }
+
+    if let Ok(config) = AudioConfig::from_default_devices() {
+        println!("\nDefault input: {}\nDefault output: {}", config.input_device, config.output_device);
+    }
+
+    Ok(())
+}
+
+/// Streams with a peer whose address is known, without any signalling.
+fn run_direct(matches: &clap::ArgMatches, mode: Mode, bind_options: &BindOptions, credentials: Credentials) -> Result<()> {
+    let audio_config = audio_config_from(matches)?;
+    let options = stream_options_from(matches)?;
+    let params = stream_params_from(matches)?;
+    let remote_addr = resolve(matches.value_of("remote").unwrap())?;
+
+    let conn = Arc::new(net::bind(bind_options)?);
+    println!("Listening on {}, {} {}", conn.local_addr()?, match mode {
+        Mode::Send => "sending to",
+        Mode::Return => "returning from"
+    }, remote_addr);
+
+    let (events, receiver) = Events::channel();
+    print_events(receiver, "", matches.is_present("stats"));
+
+    let stream = open_stream(conn, remote_addr, mode, &options, keepalive::DEFAULT_INTERVAL, audio_config, &Mutex::new(credentials), &events, &AtomicBool::new(true), &mut print_peer)?;
+    stream.update(&params)?;
+
+    Ok(stream.wait()?)
+}
+
+/// Sends and returns at once, over two sockets on consecutive ports on
+/// either end: each side sends from its bound port to the other's next one.
+fn run_duplex(matches: &clap::ArgMatches, bind_options: &BindOptions, credentials: &mut Credentials) -> Result<()> {
+    let audio_config = audio_config_from(matches)?;
+    let options = stream_options_from(matches)?;
+    let params = stream_params_from(matches)?;
+    let remote_addr = resolve(matches.value_of("remote").unwrap())?;
+    let send_remote = SocketAddr::new(remote_addr.ip(), next_port(remote_addr.port())?);
+
+    let send_conn = Arc::new(net::bind(bind_options)?);
+    let return_port = next_port(send_conn.local_addr()?.port())?;
+    let return_conn = Arc::new(net::bind(&BindOptions {
+        port: Some(PortRange { start: return_port, end: return_port }),
+        ..bind_options.clone()
+    })?);
+    println!("Sending from {} to {}, returning on {} from {}", send_conn.local_addr()?, send_remote, return_conn.local_addr()?, remote_addr);
+
+    let (send_session, return_session) = handshake_both(credentials, &send_conn, send_remote, &return_conn, remote_addr, &options)?;
+    if send_session.fingerprint() != return_session.fingerprint() {
+        return Err(anyhow!("The two directions reached different peers"));
+    }
+    print_peer(SendMessage::Peer {
+        fingerprint: send_session.fingerprint(),
+        trust: credentials.verify(&send_session, &options)?
+    })?;
+
+    let stats = matches.is_present("stats");
+    let (send_events, receiver) = Events::channel();
+    print_events(receiver, "send: ", stats);
+    let (return_events, receiver) = Events::channel();
+    print_events(receiver, "return: ", stats);
+
+    let send_stream = start_stream(Mode::Send, send_conn, send_remote, &options, keepalive::DEFAULT_INTERVAL, audio_config.clone(), send_session, &send_events)?;
+    let return_stream = start_stream(Mode::Return, return_conn, remote_addr, &options, keepalive::DEFAULT_INTERVAL, audio_config, return_session, &return_events)?;
+    send_stream.update(&params)?;
+    return_stream.update(&params)?;
+
+    // One direction ending ends the other
+    while send_stream.is_running() && return_stream.is_running() {
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+
+    let send_result = send_stream.stop();
+    Ok(return_stream.stop().and(send_result)?)
+}
+
+/// Sends the input device to the output device through two sessions over
+/// the loopback interface, for trying devices and settings without a peer.
+fn run_loopback_test(matches: &clap::ArgMatches, credentials: &Credentials) -> Result<()> {
+    let audio_config = audio_config_from(matches)?;
+    let options = stream_options_from(matches)?;
+    let params = stream_params_from(matches)?;
+    let duration = Duration::from_secs(matches.value_of("duration").unwrap().parse()?);
+
+    let send_conn = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?);
+    let return_conn = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?);
+    let send_addr = send_conn.local_addr()?;
+    let return_addr = return_conn.local_addr()?;
+
+    let (send_session, return_session) = handshake_both(credentials, &send_conn, return_addr, &return_conn, send_addr, &options)?;
+
+    let (send_events, receiver) = Events::channel();
+    let send_summary = print_events(receiver, "send: ", true);
+    let (return_events, receiver) = Events::channel();
+    let return_summary = print_events(receiver, "return: ", true);
+
+    let send_stream = start_stream(Mode::Send, send_conn, return_addr, &options, keepalive::DEFAULT_INTERVAL, audio_config.clone(), send_session, &send_events)?;
+    let return_stream = start_stream(Mode::Return, return_conn, send_addr, &options, keepalive::DEFAULT_INTERVAL, audio_config, return_session, &return_events)?;
+    send_stream.update(&params)?;
+    return_stream.update(&params)?;
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline && send_stream.is_running() && return_stream.is_running() {
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+
+    let send_result = send_stream.stop();
+    return_stream.stop().and(send_result)?;
+
+    // The summaries are complete once nothing can emit any more
+    drop((send_events, return_events));
+    let send_summary = send_summary.join().map_err(|_| anyhow!("Could not print events"))?;
+    let return_summary = return_summary.join().map_err(|_| anyhow!("Could not print events"))?;
+
+    if !return_summary.heard {
+        return Err(anyhow!("Nothing arrived over the loopback session"));
+    }
+    println!(
+        "Loopback test done: {} overruns while capturing, {} underruns while playing",
+        send_summary.overruns,
+        return_summary.underruns
+    );
+
+    Ok(())
+}
+
+/// Runs the sending and the returning handshake at once, as each only
+/// finishes when the other side answers.
+fn handshake_both(
+    credentials: &Credentials,
+    send_conn: &UdpSocket,
+    send_remote: SocketAddr,
+    return_conn: &UdpSocket,
+    return_remote: SocketAddr,
+    options: &StreamOptions
+) -> Result<(noise::Session, noise::Session)> {
+    thread::scope(|scope| {
+        let send = scope.spawn(|| credentials.handshake(send_conn, send_remote, Mode::Send, options));
+        let return_session = credentials.handshake(return_conn, return_remote, Mode::Return, options)?;
+        let send_session = send.join().map_err(|_| anyhow!("The handshake stopped unexpectedly"))??;
+
+        Ok((send_session, return_session))
+    })
+}
+
+fn next_port(port: u16) -> Result<u16> {
+    port.checked_add(1).ok_or(anyhow!("Port {} has no next port", port))
+}
+
+/// Prints the addresses a peer could reach this machine at.
+fn run_stun(stun_servers: &[String], bind_options: &BindOptions, port_mapping: bool) -> Result<()> {
+    let conn = net::bind(bind_options)?;
+    let gateway = match port_mapping {
+        true => Gateway::default_route().ok(),
+        false => None
     };
+    let (mapping, candidates, _port_mapping) = gather_candidates(&conn, stun_servers, stun::client::DEFAULT_TIMEOUT, gateway)?;
+
+    println!("Local address: {}", conn.local_addr()?);
+    println!("External address: {} (from {})", mapping.address, mapping.server);
+    if let Some(address) = mapping.address_v6 {
+        println!("External IPv6 address: {}", address);
+    }
+    println!("{}", match mapping.consistent {
+        true => "The mapping is the same for every server",
+        false => "The mapping differs per server, direct connections may fail"
+    });
+
+    println!("Candidates:");
+    for candidate in candidates {
+        println!("  {:?} {} (priority {})", candidate.candidate_type, candidate.address, candidate.priority);
+    }
+
+    Ok(())
+}
+
+/// The default devices of the chosen host, changed by the options given.
+fn audio_config_from(matches: &clap::ArgMatches) -> Result<AudioConfig> {
+    let mut config = match matches.value_of("host") {
+        Some(host) => AudioConfig::from_host_defaults(host)?,
+        None => AudioConfig::from_default_devices()?
+    };
+
+    if let Some(device) = matches.value_of("input") {
+        config.input_device = device.to_string();
+    }
+    if let Some(device) = matches.value_of("output") {
+        config.output_device = device.to_string();
+    }
+    config.sample_rate = matches.value_of("sample_rate").unwrap().parse()?;
+    config.buffer_size = matches.value_of("buffer_size").unwrap().parse()?;
+    config.input_channel = matches.value_of("input_channel").unwrap().parse()?;
+    config.output_channel = matches.value_of("output_channel").unwrap().parse()?;
+    config.set_stereo(matches.is_present("stereo"));
+
+    Ok(config)
+}
+
+fn stream_options_from(matches: &clap::ArgMatches) -> Result<StreamOptions> {
+    Ok(StreamOptions {
+        paths: matches.values_of("paths").map_or(Vec::new(), |paths| paths.map(String::from).collect()),
+        dtx: matches.is_present("dtx"),
+        key: matches.value_of("key").map(|key| SessionKey::try_from(key.to_string())).transpose().map_err(|err| anyhow!("Invalid key: {}", err))?,
+        fingerprint: matches.value_of("fingerprint").map(String::from),
+        peer_name: matches.value_of("peer_name").map(String::from),
+        ..Default::default()
+    })
+}
+
+fn stream_params_from(matches: &clap::ArgMatches) -> Result<StreamParams> {
+    Ok(StreamParams {
+        jitter_target: matches.value_of("jitter_target").map(str::parse).transpose()?,
+        redundancy: matches.value_of("redundancy").map(str::parse).transpose()?,
+        ..Default::default()
+    })
+}
+
+/// What the command line learns from a session's events once it ends.
+#[derive(Default)]
+struct Summary {
+    /// Whether the peer was heard at any point.
+    heard: bool,
+    overruns: u64,
+    underruns: u64,
+}
+
+/// Prints a session's events as they arrive, statistics only when asked
+/// to. The thread ends when every sender of the channel is gone.
+fn print_events(receiver: Receiver<Event>, label: &'static str, stats: bool) -> thread::JoinHandle<Summary> {
+    thread::spawn(move || {
+        let mut summary = Summary::default();
 
+        for event in receiver {
+            match event {
+                Event::State { state } => println!("{}{:?}", label, state),
+                Event::PeerConnected { address } => {
+                    summary.heard = true;
+                    println!("{}Hearing the peer at {}", label, address);
+                },
+                Event::PeerLost { address } => println!("{}Lost the peer at {}", label, address),
+                Event::Stats { paths, rate } => {
+                    if !stats {
+                        continue;
+                    }
+
+                    println!(
+                        "{}Loss {:.1}%, delay {} ms, redundancy {}, {} kbit/s",
+                        label,
+                        rate.loss * 100.0,
+                        rate.delay,
+                        rate.redundancy,
+                        rate.target_bitrate / 1000
+                    );
+                    for path in paths {
+                        println!("{}  {}: {} received, loss {:.1}%, latency {:.1} ms", label, path.addr, path.received, path.loss * 100.0, path.latency);
+                    }
+                },
+                Event::DeviceError { message } => eprintln!("{}Audio device error: {}", label, message),
+                Event::Xrun { overruns, underruns } => {
+                    summary.overruns = overruns;
+                    summary.underruns = underruns;
+                    println!("{}{} overruns, {} underruns", label, overruns, underruns);
+                }
+            };
+        }
+
+        summary
+    })
+}
+
+fn run(socket: &str, stun_servers: Vec<String>, rendezvous_url: &str, bind_options: BindOptions, credentials: Credentials) -> Result<()> {
     let path = Path::new(&socket);
-    if path.exists() == true {
-        fs::remove_file(path).expect("Could not delete socket");
+    if path.exists() {
+        fs::remove_file(path).map_err(|err| anyhow!("Could not delete socket {}: {}", path.display(), err))?;
     }
 
     let listener = UnixListener::bind(path)?;
-    let conn = UdpSocket::bind("0:0")?;
+    let outbox = Outbox::default();
+    let (events, receiver) = Events::channel();
 
-    let conn = Arc::new(conn);
+    let event_outbox = outbox.clone();
+    thread::spawn(move || {
+        let mut current_state = State::Idle;
+
+        for event in receiver {
+            // A failed request resets the state, which may not have changed
+            if let Event::State { state } = event {
+                if state == current_state {
+                    continue;
+                }
+                current_state = state;
+            }
+
+            event_outbox.emit(&event);
+        }
+    });
+
+    let mut daemon = Daemon::new(stun_servers, rendezvous_url.to_string(), &bind_options, credentials, events)?;
 
     // Iterate over clients, blocks if no client available
     for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        loop {
-            let mut buf = [0u8; 256];
-            let read = stream.read(&mut buf).unwrap();
-            if read < 256 {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Could not accept a client: {}", err);
+                continue;
+            }
+        };
+
+        if let Err(err) = serve(&mut daemon, &outbox, &mut stream) {
+            eprintln!("Closing the connection: {}", err);
+        }
+        outbox.close();
+    }
+
+    Ok(())
+}
+
+/// Answers the requests of one client until it disconnects. A failed
+/// request is answered with an error and the next one is read; only a
+/// broken connection ends this.
+fn serve(daemon: &mut Daemon, outbox: &Outbox, stream: &mut UnixStream) -> Result<()> {
+    let mut greeted = false;
+    outbox.open(stream)?;
+
+    while let Some(msg) = control::read_message(stream)? {
+        // Parsed in two steps, so that the request id can be echoed
+        // even when the rest of the request is invalid
+        let value: serde_json::Value = match serde_json::from_slice(&msg) {
+            Ok(value) => value,
+            Err(err) => {
+                outbox.respond(None, &SendMessage::error(&anyhow!(err).context("Invalid JSON"), ErrorCode::InvalidMessage))?;
                 continue;
             }
-            let msg = std::str::from_utf8(&buf).unwrap();
-            let msg = msg.trim_matches(char::from(0));
+        };
+        let request_id = value.get("request_id").and_then(serde_json::Value::as_u64);
 
-            let res: RecvMessage = serde_json::from_str(msg)?;
+        let res: RecvMessage = match serde_json::from_value(value) {
+            Ok(res) => res,
+            Err(err) => {
+                outbox.respond(request_id, &SendMessage::error(&anyhow!(err).context("Invalid request"), ErrorCode::InvalidMessage))?;
+                continue;
+            }
+        };
 
-            match res {
-                RecvMessage::Config => {
-                    let supported_configs = AudioInterface::get_supported_configs().unwrap();
-                    let res = serde_json::to_string(&supported_configs).unwrap();
-                    stream.write_all(res.as_bytes());
-                },
-                RecvMessage::Connect { config } => {
-                    let config = match config {
-                        Some(config) => config,
-                        None => AudioConfig::default()
-                    };
-
-                    let is_valid = match AudioInterface::validate_config(&config) {
-                        Ok(_) => true,
-                        Err(_) => false
-                    };
-
-                    let sc = StunClient::with_google_stun_server();
-                    let addr = sc.query_external_address(&conn)?;
-                    // let addr = conn.local_addr().unwrap();
-                    // let ip = local_ip().unwrap();
-                    // let addr = format!("{}:{}", ip, addr.port());
-                    let res = SendMessage::Connect { address: addr.to_string(), is_valid };
-                    let res = serde_json::to_string(&res).unwrap();
-
-                    stream.write_all(res.as_bytes());
-                },
-                RecvMessage::Stream { mode, remote_addr, config } => {
-                    let conn = conn.clone();
-                    conn.connect(&remote_addr)?;
-                    run_stream(mode, conn, config);
+        // Clients open with a hello, so that a client built against
+        // another version of the protocol fails right away
+        match (res, greeted) {
+            (RecvMessage::Hello { version }, _) => {
+                outbox.respond(request_id, &SendMessage::Hello { version: control::PROTOCOL_VERSION })?;
+
+                if version != control::PROTOCOL_VERSION {
+                    return Err(anyhow!("Client speaks protocol version {}, expected {}", version, control::PROTOCOL_VERSION));
+                }
+                greeted = true;
+            },
+            (_, false) => {
+                let err = anyhow!("Expected a hello from the client");
+                outbox.respond(request_id, &SendMessage::error(&err, ErrorCode::HelloRequired))?;
+                return Err(err);
+            },
+            (res, true) => {
+                let respond_outbox = outbox.clone();
+                if let Err(err) = daemon.request(res, Arc::new(move |res| respond_outbox.respond(request_id, &res))) {
+                    eprintln!("{:#}", err);
+                    outbox.respond(request_id, &SendMessage::error(&err, ErrorCode::of(&err)))?;
                 }
             }
-        }
+        };
     }
 
     Ok(())
 }
 
-fn run_stream(mode: Mode, conn: Arc<UdpSocket>, audio_config: AudioConfig) -> Result<()> {
-    let audio_interface = AudioInterface::new(audio_config.clone())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
 
-    let packet_buffer_size = (audio_config.buffer_size * audio_config.get_channel_count() as u32) as usize;
-    let (input_producer, input_consumer, output_producer, output_consumer) = ringbuffer::create(packet_buffer_size);
+    /// A daemon on loopback with its own identity.
+    fn daemon(name: &str) -> Daemon {
+        let dir = std::env::temp_dir().join(format!("p2p_audio_{}_{}", name, process::id()));
+        let credentials = Credentials::load(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let bind_options = BindOptions { address: Some("127.0.0.1".parse().unwrap()), ..BindOptions::default() };
 
-    let client = UdpClient::new(
-        conn.clone(),
-        2000,
-        audio_config.clone()
-    )?;
+        Daemon::new(Vec::new(), rendezvous::client::DEFAULT_URL.to_string(), &bind_options, credentials, Events::channel().0).unwrap()
+    }
 
-    let _streams = audio_interface.build_streams(&mode, input_producer, output_consumer)?;
+    /// Serves one client on a thread, returning its end of the connection.
+    fn connect(name: &str) -> (UnixStream, thread::JoinHandle<Result<()>>) {
+        let mut daemon = daemon(name);
+        let (client, mut stream) = UnixStream::pair().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
 
-    match mode {
-        Mode::Send => {
-            let mut client1 = client.clone();
-            let send_handle = thread::spawn(move || {
-                client1.send_loop(input_consumer);
-            });
+        let handle = thread::spawn(move || serve(&mut daemon, &Outbox::default(), &mut stream));
 
-            send_handle.join().unwrap();
-        },
-        Mode::Return => {
-            let mut client2 = client.clone();
-            let recv_handle = thread::spawn(move || {
-                client2.recv_loop(output_producer);
-            });
-        
-            recv_handle.join().unwrap();
-        }
+        (client, handle)
     }
 
-    Ok(())
-}
\ No newline at end of file
+    fn request(client: &mut UnixStream, message: &Value) -> Value {
+        control::write_json(client, message).unwrap();
+
+        serde_json::from_slice(&control::read_message(client).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn answers_requests_after_the_hello() {
+        let (mut client, handle) = connect("hello");
+
+        let hello = request(&mut client, &json!({ "type": "hello", "version": control::PROTOCOL_VERSION, "request_id": 1 }));
+        assert_eq!(hello, json!({ "type": "hello", "version": control::PROTOCOL_VERSION, "request_id": 1 }));
+
+        let stats = request(&mut client, &json!({ "type": "stats", "request_id": 2 }));
+        assert_eq!(stats["type"], "error");
+        assert_eq!(stats["code"], "no_session");
+        assert_eq!(stats["request_id"], 2);
+
+        let unknown = request(&mut client, &json!({ "type": "dance", "request_id": 3 }));
+        assert_eq!(unknown["code"], "invalid_message");
+        assert_eq!(unknown["request_id"], 3);
+
+        control::write_message(&mut client, b"{").unwrap();
+        let invalid: Value = serde_json::from_slice(&control::read_message(&mut client).unwrap().unwrap()).unwrap();
+        assert_eq!(invalid["code"], "invalid_message");
+        assert!(invalid.get("request_id").is_none());
+
+        drop(client);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn requires_a_hello_first() {
+        let (mut client, handle) = connect("no_hello");
+
+        let response = request(&mut client, &json!({ "type": "stats" }));
+
+        assert_eq!(response["code"], "hello_required");
+        assert!(handle.join().unwrap().is_err());
+        assert!(control::read_message(&mut client).unwrap().is_none());
+    }
+
+    #[test]
+    fn hangs_up_on_another_version() {
+        let (mut client, handle) = connect("version");
+
+        let hello = request(&mut client, &json!({ "type": "hello", "version": control::PROTOCOL_VERSION + 1 }));
+
+        assert_eq!(hello["version"], control::PROTOCOL_VERSION);
+        assert!(handle.join().unwrap().unwrap_err().to_string().contains("protocol version"));
+    }
+
+    fn parse(args: &[&str]) -> clap::Result<clap::ArgMatches<'static>> {
+        app().get_matches_from_safe(std::iter::once("p2p_audio").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_global_bind_options() {
+        let matches = parse(&["--bind", "127.0.0.1", "--port", "5000-5010", "--stun", "a:3478", "--stun", "b:3478", "fingerprint"]).unwrap();
+
+        let options = bind_options(&matches).unwrap();
+        assert_eq!(options.address, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(options.port, Some(PortRange { start: 5000, end: 5010 }));
+        assert_eq!(matches.values_of("stun").unwrap().collect::<Vec<_>>(), ["a:3478", "b:3478"]);
+
+        let matches = parse(&["--port", "5010-5000", "fingerprint"]).unwrap();
+        assert!(bind_options(&matches).is_err());
+        assert!(parse(&["--bind", "127.0.0.1", "--interface", "lo", "fingerprint"]).is_err());
+    }
+
+    #[test]
+    fn requires_a_subcommand_and_its_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["send"]).is_err());
+        assert!(parse(&["daemon"]).is_err());
+        assert!(parse(&["pair", "--mode", "both"]).is_err());
+        assert!(parse(&["gateway", "--protocol", "smtp"]).is_err());
+
+        let matches = parse(&["daemon", "/tmp/claudio.sock"]).unwrap();
+        let (name, daemon) = matches.subcommand();
+        assert_eq!(name, "daemon");
+        assert_eq!(daemon.unwrap().value_of("rendezvous"), Some(rendezvous::client::DEFAULT_URL));
+    }
+
+    #[test]
+    fn reads_stream_options_and_params() {
+        let key = "ab".repeat(32);
+        let matches = parse(&[
+            "send", "--remote", "192.0.2.1:5000", "--key", &key, "--path", "192.0.2.9", "--path", "eth1",
+            "--dtx", "--jitter-target", "40", "--redundancy", "2"
+        ]).unwrap();
+        let send = matches.subcommand_matches("send").unwrap();
+
+        let options = stream_options_from(send).unwrap();
+        assert_eq!(options.paths, ["192.0.2.9", "eth1"]);
+        assert!(options.dtx);
+        assert!(options.key.unwrap().0.iter().all(|byte| *byte == 0xab));
+
+        let params = stream_params_from(send).unwrap();
+        assert_eq!(params.jitter_target, Some(40));
+        assert_eq!(params.redundancy, Some(2));
+
+        let matches = parse(&["return", "--remote", "192.0.2.1:5000", "--key", "xyz", "--redundancy", "many"]).unwrap();
+        let returning = matches.subcommand_matches("return").unwrap();
+        assert!(stream_options_from(returning).unwrap_err().to_string().starts_with("Invalid key"));
+        assert!(stream_params_from(returning).is_err());
+    }
+
+    #[test]
+    fn duplex_needs_a_next_port() {
+        assert_eq!(next_port(5000).unwrap(), 5001);
+        assert!(next_port(u16::MAX).is_err());
+    }
+}
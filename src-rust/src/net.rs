@@ -0,0 +1,266 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use anyhow::{Result, anyhow};
+use local_ip_address::list_afinet_netifas;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Ports to try binding, both ends included.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "PortSpec")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// A single port, or a range written as `start-end`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: PortSpec) -> Result<Self> {
+        match spec {
+            PortSpec::Port(port) => Ok(Self { start: port, end: port }),
+            PortSpec::Range(range) => range.parse()
+        }
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(range: &str) -> Result<Self> {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+            None => {
+                let port = range.trim().parse()?;
+                (port, port)
+            }
+        };
+
+        if start > end {
+            return Err(anyhow!("Invalid port range {}", range));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+/// Where the session socket is bound. Without an address or interface, the
+/// socket is dual-stack on all interfaces; without ports, the system picks
+/// one.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BindOptions {
+    pub address: Option<IpAddr>,
+    pub port: Option<PortRange>,
+    /// Name of the interface whose address to bind, e.g. `en0`.
+    pub interface: Option<String>,
+}
+
+/// Binds the session socket, trying the ports of the range in order.
+pub fn bind(options: &BindOptions) -> Result<UdpSocket> {
+    let ip = match (&options.address, &options.interface) {
+        (Some(address), _) => Some(*address),
+        (None, Some(interface)) => Some(interface_ip(interface)?),
+        (None, None) => None
+    };
+
+    let range = options.port.unwrap_or(PortRange { start: 0, end: 0 });
+    let mut last_error = None;
+
+    for port in range.start..=range.end {
+        let result = match ip {
+            Some(ip) => UdpSocket::bind((ip, port)).map_err(anyhow::Error::from),
+            None => bind_dual_stack(port)
+        };
+
+        match result {
+            Ok(conn) => return Ok(conn),
+            Err(err) => last_error = Some(err)
+        };
+    }
+
+    Err(anyhow!(
+        "No free port in {}-{}: {}",
+        range.start,
+        range.end,
+        last_error.map_or(String::new(), |err| err.to_string())
+    ))
+}
+
+/// The address of a network interface, IPv4 if it has one.
+pub fn interface_ip(name: &str) -> Result<IpAddr> {
+    let interfaces = list_afinet_netifas().map_err(|err| anyhow!("{:?}", err))?;
+
+    interfaces.into_iter()
+        .filter(|(interface, _)| interface == name)
+        .map(|(_, ip)| ip)
+        .min_by_key(|ip| ip.is_ipv6())
+        .ok_or(anyhow!("Could not find interface {}", name))
+}
+
+/// Binds a socket that reaches both IPv4 and IPv6 peers, falling back to
+/// IPv4 only on hosts without IPv6.
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    let dual_stack = || -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        // Off by default on Linux and macOS, but not on every system
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+
+        Ok(socket.into())
+    };
+
+    match dual_stack() {
+        Ok(conn) => Ok(conn),
+        Err(err) => {
+            eprintln!("No IPv6 ({}), binding IPv4 only", err);
+            Ok(UdpSocket::bind(("0.0.0.0", port))?)
+        }
+    }
+}
+
+/// Sends to an address of either family. A dual-stack socket needs IPv4
+/// destinations in their IPv4-mapped form on some systems.
+pub fn send_to(conn: &UdpSocket, buffer: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    match (conn.local_addr()?, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            conn.send_to(buffer, SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()))
+        },
+        _ => conn.send_to(buffer, addr)
+    }
+}
+
+/// Receives a datagram, reporting IPv4 senders on a dual-stack socket with
+/// their plain IPv4 address, so they compare equal to the addresses peers
+/// exchange.
+pub fn recv_from(conn: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    let (len, from) = conn.recv_from(buffer)?;
+
+    Ok((len, canonical(from)))
+}
+
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Link-local addresses only work with an interface index, which peers on
+/// other hosts cannot know.
+pub fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reaches_both_families_from_a_dual_stack_socket() {
+        let conn = bind_dual_stack(0).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let port = conn.local_addr().unwrap().port();
+        let mut buffer = [0u8; 16];
+
+        for ip in ["127.0.0.1", "::1"].iter() {
+            let peer = UdpSocket::bind((*ip, 0)).unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+            send_to(&conn, b"ping", peer.local_addr().unwrap()).unwrap();
+            let (len, from) = peer.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..len], b"ping");
+            assert_eq!(from.port(), port);
+
+            peer.send_to(b"pong", from).unwrap();
+            let (len, from) = recv_from(&conn, &mut buffer).unwrap();
+            assert_eq!(&buffer[..len], b"pong");
+            assert_eq!(from, peer.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn reports_mapped_addresses_as_ipv4() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:5000".parse().unwrap();
+        let v6: SocketAddr = "[fd00::1]:5000".parse().unwrap();
+
+        assert_eq!(canonical(mapped), "192.0.2.1:5000".parse().unwrap());
+        assert_eq!(canonical(v6), v6);
+    }
+
+    #[test]
+    fn tells_link_local_addresses() {
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_link_local(&"febf::1".parse().unwrap()));
+        assert!(is_link_local(&"169.254.1.1".parse().unwrap()));
+        assert!(!is_link_local(&"fec0::1".parse().unwrap()));
+        assert!(!is_link_local(&"fd00::1".parse().unwrap()));
+        assert!(!is_link_local(&"192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_ports_and_ranges() {
+        assert_eq!("5000".parse::<PortRange>().unwrap(), PortRange { start: 5000, end: 5000 });
+        assert_eq!(" 5000 - 5010 ".parse::<PortRange>().unwrap(), PortRange { start: 5000, end: 5010 });
+        assert!("5010-5000".parse::<PortRange>().is_err());
+        assert!("5000-".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+
+        let options: BindOptions = serde_json::from_str(r#"{"address": "127.0.0.1", "port": 5000}"#).unwrap();
+        assert_eq!(options.port, Some(PortRange { start: 5000, end: 5000 }));
+        let options: BindOptions = serde_json::from_str(r#"{"port": "5000-5010"}"#).unwrap();
+        assert_eq!(options.port, Some(PortRange { start: 5000, end: 5010 }));
+        assert!(serde_json::from_str::<BindOptions>(r#"{"port": "5010-5000"}"#).is_err());
+    }
+
+    #[test]
+    fn binds_the_next_free_port_of_the_range() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let options = BindOptions {
+            address: Some("127.0.0.1".parse().unwrap()),
+            port: Some(PortRange { start: port, end: port.saturating_add(20) }),
+            interface: None
+        };
+
+        let conn = bind(&options).unwrap();
+
+        let address = conn.local_addr().unwrap();
+        assert_eq!(address.ip(), options.address.unwrap());
+        assert!(address.port() > port && address.port() <= port.saturating_add(20));
+    }
+
+    #[test]
+    fn fails_when_the_range_is_taken() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let options = BindOptions {
+            address: Some("127.0.0.1".parse().unwrap()),
+            port: Some(PortRange { start: port, end: port }),
+            interface: None
+        };
+
+        let err = bind(&options).unwrap_err();
+
+        assert!(err.to_string().starts_with(&format!("No free port in {}-{}", port, port)));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn binds_the_address_of_an_interface() {
+        let options = BindOptions { interface: Some("lo".to_string()), ..BindOptions::default() };
+
+        assert_eq!(bind(&options).unwrap().local_addr().unwrap().ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert!(interface_ip("no-such-interface").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use snow::{Builder, HandshakeState, Keypair};
+
+use crate::identity::{self, Identity};
+use crate::ice;
+use crate::net;
+use crate::udp::crypto::SessionKey;
+use crate::udp::packet::{ConnectivityCheck, HandshakeMessage, MessageType, Packet};
+use crate::util::Mode;
+
+/// Both sides learn each other's static key during the handshake, so
+/// neither has to know it beforehand.
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// The same with a pre-shared key mixed in, for peers that exchanged one
+/// while pairing.
+const PATTERN_PSK: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+pub fn generate_keypair() -> Result<Keypair> {
+    Ok(Builder::new(PATTERN.parse()?).generate_keypair()?)
+}
+
+/// What the handshake established.
+pub struct Session {
+    pub session_id: u32,
+    /// Encrypts the stream in both directions.
+    pub key: SessionKey,
+    pub remote_public_key: Vec<u8>,
+    /// The initiator's last message, to repeat when the responder asks
+    /// again because it was lost.
+    pub final_message: Option<Vec<u8>>,
+}
+
+impl Session {
+    pub fn fingerprint(&self) -> String {
+        identity::fingerprint(&self.remote_public_key)
+    }
+}
+
+/// Runs a Noise XX handshake with the peer at `remote` before streaming.
+///
+/// The sending side initiates and picks the session id, the returning side
+/// learns it from the first message unless it was agreed on beforehand.
+/// Messages are repeated until the other side answers, and connectivity
+/// checks from a peer that is still checking are answered meanwhile.
+/// Messages that fail to decrypt, such as spoofed or corrupted ones, are
+/// dropped, and only fail the handshake if nothing better arrives in time.
+pub fn handshake(
+    conn: &UdpSocket,
+    remote: SocketAddr,
+    mode: Mode,
+    session_id: Option<u32>,
+    identity: &Identity,
+    psk: Option<&SessionKey>,
+    timeout: Duration
+) -> Result<Session> {
+    let previous_timeout = conn.read_timeout()?;
+    conn.set_read_timeout(Some(RETRANSMIT_INTERVAL))?;
+
+    let result = match mode {
+        Mode::Send => {
+            let session_id = session_id.ok_or(anyhow!("The sending side needs a session id"))?;
+            initiate(conn, remote, session_id, identity, psk, timeout)
+        },
+        Mode::Return => respond(conn, remote, session_id, identity, psk, timeout)
+    };
+
+    conn.set_read_timeout(previous_timeout)?;
+
+    result
+}
+
+fn initiate(conn: &UdpSocket, remote: SocketAddr, session_id: u32, identity: &Identity, psk: Option<&SessionKey>, timeout: Duration) -> Result<Session> {
+    let prologue = session_id.to_be_bytes();
+    let mut state = build(&prologue, identity, psk)?.build_initiator()?;
+    let first = write(&mut state, session_id, 1)?;
+
+    let start = Instant::now();
+    let mut rejected = None;
+    net::send_to(conn, &first, remote)?;
+
+    while start.elapsed() < timeout {
+        let message = match recv(conn, remote, session_id)? {
+            Some(message) => message,
+            None => {
+                net::send_to(conn, &first, remote)?;
+                continue;
+            }
+        };
+
+        if message.step != 2 {
+            continue;
+        }
+
+        if let Err(err) = read(&mut state, &message) {
+            rejected = Some(drop_message(remote, err));
+            continue;
+        }
+        let last = write(&mut state, session_id, 3)?;
+        net::send_to(conn, &last, remote)?;
+
+        return finish(state, session_id, Some(last));
+    }
+
+    Err(rejected.unwrap_or_else(|| anyhow!("No handshake response from {}", remote)))
+}
+
+fn respond(conn: &UdpSocket, remote: SocketAddr, session_id: Option<u32>, identity: &Identity, psk: Option<&SessionKey>, timeout: Duration) -> Result<Session> {
+    let mut session_id = session_id;
+    let mut state: Option<HandshakeState> = None;
+    let mut response: Option<Vec<u8>> = None;
+    let mut rejected = None;
+
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        let message = match recv(conn, remote, session_id.unwrap_or(0))? {
+            Some(message) => message,
+            None => {
+                if let Some(response) = &response {
+                    net::send_to(conn, response, remote)?;
+                }
+                continue;
+            }
+        };
+
+        if session_id.is_some_and(|id| message.session_id != id) {
+            continue;
+        }
+
+        match (message.step, &mut state) {
+            (1, None) => {
+                let prologue = message.session_id.to_be_bytes();
+                let mut new_state = build(&prologue, identity, psk)?.build_responder()?;
+                if let Err(err) = read(&mut new_state, &message) {
+                    rejected = Some(drop_message(remote, err));
+                    continue;
+                }
+
+                let second = write(&mut new_state, message.session_id, 2)?;
+                net::send_to(conn, &second, remote)?;
+
+                session_id = Some(message.session_id);
+                state = Some(new_state);
+                response = Some(second);
+            },
+            // The initiator repeats its first message until ours arrives
+            (1, Some(_)) => {
+                if let Some(response) = &response {
+                    net::send_to(conn, response, remote)?;
+                }
+            },
+            (3, Some(current)) => {
+                if let Err(err) = read(current, &message) {
+                    rejected = Some(drop_message(remote, err));
+                    continue;
+                }
+
+                return match state {
+                    Some(state) => finish(state, message.session_id, None),
+                    None => Err(anyhow!("Handshake out of order"))
+                };
+            },
+            _ => ()
+        };
+    }
+
+    Err(rejected.unwrap_or_else(|| anyhow!("No handshake from {}", remote)))
+}
+
+/// Waits for the next handshake message from `remote`, answering
+/// connectivity checks meanwhile. Returns `None` when it is time to repeat
+/// the last message. A `session_id` of 0 accepts any session.
+fn recv(conn: &UdpSocket, remote: SocketAddr, session_id: u32) -> Result<Option<HandshakeMessage>> {
+    let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+
+    loop {
+        let (len, addr) = match net::recv_from(conn, &mut buffer) {
+            Ok(received) => received,
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+        let buffer = &buffer[..len];
+
+        match Packet::peek(buffer) {
+            Some((MessageType::Check, _)) => {
+                if let Ok(check) = ConnectivityCheck::from_buffer(buffer) {
+                    ice::respond(conn, &check, addr);
+                }
+            },
+            Some((MessageType::Handshake, id)) if addr == remote && (session_id == 0 || id == session_id) => {
+                match HandshakeMessage::from_buffer(buffer) {
+                    Ok(message) => return Ok(Some(message)),
+                    Err(err) => eprintln!("Dropped handshake message from {}: {}", addr, err)
+                };
+            },
+            _ => ()
+        };
+    }
+}
+
+/// The session id is sent in the clear, so it goes into the prologue, which
+/// both sides have to agree on.
+fn build<'a>(prologue: &'a [u8; 4], identity: &'a Identity, psk: Option<&'a SessionKey>) -> Result<Builder<'a>> {
+    let builder = match psk {
+        Some(psk) => Builder::new(PATTERN_PSK.parse()?).psk(3, &psk.0),
+        None => Builder::new(PATTERN.parse()?)
+    };
+
+    Ok(builder.local_private_key(identity.private_key()).prologue(prologue))
+}
+
+fn write(state: &mut HandshakeState, session_id: u32, step: u8) -> Result<Vec<u8>> {
+    let mut payload = [0u8; MAX_MESSAGE_SIZE];
+    let len = state.write_message(&[], &mut payload)?;
+
+    Ok(HandshakeMessage { session_id, step, payload: payload[..len].to_vec() }.to_buffer())
+}
+
+fn read(state: &mut HandshakeState, message: &HandshakeMessage) -> Result<()> {
+    let mut payload = [0u8; MAX_MESSAGE_SIZE];
+    state.read_message(&message.payload, &mut payload)
+        .map_err(|err| anyhow!("Handshake failed, the peer may have used another key: {}", err))?;
+
+    Ok(())
+}
+
+/// A failed read leaves the handshake state as it was, so the right
+/// message can still follow.
+fn drop_message(remote: SocketAddr, err: anyhow::Error) -> anyhow::Error {
+    eprintln!("Dropped handshake message from {}: {}", remote, err);
+    err
+}
+
+fn finish(mut state: HandshakeState, session_id: u32, final_message: Option<Vec<u8>>) -> Result<Session> {
+    let remote_public_key = state.get_remote_static().ok_or(anyhow!("The peer sent no static key"))?.to_vec();
+    // SessionCipher keeps the directions apart itself, one key is enough
+    let (key, _) = state.dangerously_get_raw_split();
+
+    Ok(Session {
+        session_id,
+        key: SessionKey(key),
+        remote_public_key,
+        final_message
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const SESSION_ID: u32 = 42;
+
+    fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").unwrap()
+    }
+
+    /// Runs the initiator on its own thread against `responder`, returning
+    /// both results.
+    fn run(responder: UdpSocket, psks: (Option<SessionKey>, Option<SessionKey>), before: impl FnOnce(&UdpSocket, SocketAddr)) -> (Result<Session>, Result<Session>) {
+        let initiator = bind();
+        let (initiator_addr, responder_addr) = (initiator.local_addr().unwrap(), responder.local_addr().unwrap());
+        let timeout = Duration::from_secs(1);
+
+        let (initiator_psk, responder_psk) = psks;
+        let sending = thread::spawn(move || {
+            let identity = Identity::generate().unwrap();
+            handshake(&initiator, responder_addr, Mode::Send, Some(SESSION_ID), &identity, initiator_psk.as_ref(), timeout)
+        });
+
+        before(&responder, initiator_addr);
+        let identity = Identity::generate().unwrap();
+        let returning = handshake(&responder, initiator_addr, Mode::Return, None, &identity, responder_psk.as_ref(), timeout);
+
+        (sending.join().unwrap(), returning)
+    }
+
+    #[test]
+    fn both_sides_agree_on_the_key() {
+        let psk = SessionKey::random().unwrap();
+        let (sending, returning) = run(bind(), (Some(psk.clone()), Some(psk)), |_, _| ());
+        let (sending, returning) = (sending.unwrap(), returning.unwrap());
+
+        assert_eq!(sending.key.0, returning.key.0);
+        assert_eq!(returning.session_id, SESSION_ID);
+        assert_ne!(sending.fingerprint(), returning.fingerprint());
+        assert!(sending.final_message.is_some());
+    }
+
+    #[test]
+    fn drops_messages_that_do_not_decrypt() {
+        let (sending, returning) = run(bind(), (None, None), |responder, initiator| {
+            // Arrives at the initiator before the real second message
+            let forged = HandshakeMessage { session_id: SESSION_ID, step: 2, payload: vec![7u8; 96] };
+            responder.send_to(&forged.to_buffer(), initiator).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+
+        assert_eq!(sending.unwrap().key.0, returning.unwrap().key.0);
+    }
+
+    #[test]
+    fn fails_with_different_keys() {
+        let psks = (Some(SessionKey::random().unwrap()), Some(SessionKey::random().unwrap()));
+        let (sending, returning) = run(bind(), psks, |_, _| ());
+
+        // The key is only mixed into the last message, which the initiator
+        // sends without waiting for an answer
+        assert!(sending.is_ok());
+        let err = returning.err().unwrap();
+        assert!(err.to_string().contains("another key"), "{}", err);
+    }
+}
@@ -0,0 +1,369 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::audio::AudioConfig;
+use crate::ice::{Candidate, CandidateType};
+use crate::udp::crypto::{self, SessionKey};
+use crate::util::{self, Mode};
+
+/// How long the answering side keeps checking, since the offering side only
+/// starts once someone has pasted the answer there.
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+const VERSION: u8 = 2;
+const FLAG_RETURN: u8 = 0x01;
+const FLAG_ANSWER: u8 = 0x02;
+const FAMILY_V6: u8 = 0x80;
+/// Crockford's base32, which avoids letters that are easily confused.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Everything a peer needs to connect, packed into a string short enough to
+/// paste into a chat message. The offering side creates one, the other side
+/// pastes it and answers with its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionCode {
+    /// How the author of the code streams.
+    pub mode: Mode,
+    pub answer: bool,
+    pub session_id: u32,
+    /// Mixed into the handshake, so that only the holder of the code can
+    /// answer it. Chosen by the offering side.
+    pub session_key: SessionKey,
+    pub sample_rate: u32,
+    pub buffer_size: u32,
+    pub channel_count: u32,
+    pub candidates: Vec<Candidate>,
+}
+
+impl ConnectionCode {
+    pub fn offer(mode: Mode, config: &AudioConfig, candidates: Vec<Candidate>) -> Result<Self> {
+        Ok(Self {
+            mode,
+            answer: false,
+            session_id: util::random_id()?,
+            session_key: SessionKey::random()?,
+            sample_rate: config.sample_rate,
+            buffer_size: config.buffer_size,
+            channel_count: config.get_channel_count(),
+            candidates
+        })
+    }
+
+    /// Answers an offer for the same session, streaming the other way.
+    pub fn answer(&self, candidates: Vec<Candidate>) -> Result<Self> {
+        if self.answer {
+            return Err(anyhow!("This is an answer code, paste it on the side that created the offer"));
+        }
+
+        let mode = match self.mode {
+            Mode::Send => Mode::Return,
+            Mode::Return => Mode::Send
+        };
+
+        Ok(Self {
+            mode,
+            answer: true,
+            candidates,
+            ..self.clone()
+        })
+    }
+
+    /// Takes the sample rate and buffer size from the code, keeping the
+    /// local devices.
+    pub fn configure(&self, mut config: AudioConfig) -> AudioConfig {
+        config.sample_rate = self.sample_rate;
+        config.buffer_size = self.buffer_size;
+        config
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![VERSION];
+
+        let mut flags = 0;
+        if self.mode == Mode::Return {
+            flags |= FLAG_RETURN;
+        }
+        if self.answer {
+            flags |= FLAG_ANSWER;
+        }
+        buffer.push(flags);
+
+        buffer.extend_from_slice(&self.session_id.to_be_bytes());
+        buffer.extend_from_slice(&self.session_key.0);
+        buffer.extend_from_slice(&self.sample_rate.to_be_bytes());
+        let buffer_size: u16 = self.buffer_size.try_into().map_err(|_| anyhow!("Buffer size too large"))?;
+        buffer.extend_from_slice(&buffer_size.to_be_bytes());
+        buffer.push(self.channel_count as u8);
+
+        let mut candidates: Vec<_> = self.candidates.iter().collect();
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.priority));
+        candidates.truncate(u8::MAX as usize);
+        buffer.push(candidates.len() as u8);
+
+        for candidate in candidates {
+            let candidate_type = match candidate.candidate_type {
+                CandidateType::Host => 0,
+                CandidateType::PeerReflexive => 1,
+                CandidateType::ServerReflexive => 2,
+                CandidateType::Relayed => 3,
+            };
+
+            match candidate.address.ip() {
+                IpAddr::V4(ip) => {
+                    buffer.push(candidate_type);
+                    buffer.extend_from_slice(&ip.octets());
+                },
+                IpAddr::V6(ip) => {
+                    buffer.push(candidate_type | FAMILY_V6);
+                    buffer.extend_from_slice(&ip.octets());
+                }
+            };
+            buffer.extend_from_slice(&candidate.address.port().to_be_bytes());
+        }
+
+        buffer.extend_from_slice(&crc32(&buffer).to_be_bytes());
+
+        Ok(to_base32(&buffer))
+    }
+
+    /// Decodes a pasted code. Whitespace and dashes are ignored, so codes
+    /// that were wrapped or split into groups still work.
+    pub fn decode(code: &str) -> Result<Self> {
+        let buffer = from_base32(code)?;
+
+        if buffer.len() < 4 {
+            return Err(anyhow!("Connection code too short"));
+        }
+
+        let (buffer, checksum) = buffer.split_at(buffer.len() - 4);
+        if crc32(buffer).to_be_bytes() != checksum {
+            return Err(anyhow!("Invalid connection code, it may not have been copied completely"));
+        }
+
+        let mut reader = Reader { buffer, offset: 0 };
+
+        let version = reader.read(1)?[0];
+        if version != VERSION {
+            return Err(anyhow!("Unsupported connection code version {}", version));
+        }
+
+        let flags = reader.read(1)?[0];
+        let mode = match flags & FLAG_RETURN {
+            0 => Mode::Send,
+            _ => Mode::Return
+        };
+
+        let session_id = u32::from_be_bytes(reader.read(4)?.try_into()?);
+        let session_key = SessionKey(reader.read(crypto::KEY_SIZE)?.try_into()?);
+        let sample_rate = u32::from_be_bytes(reader.read(4)?.try_into()?);
+        let buffer_size = u16::from_be_bytes(reader.read(2)?.try_into()?) as u32;
+        let channel_count = reader.read(1)?[0] as u32;
+
+        let count = reader.read(1)?[0] as usize;
+        let mut candidates = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let kind = reader.read(1)?[0];
+
+            let candidate_type = match kind & !FAMILY_V6 {
+                0 => CandidateType::Host,
+                1 => CandidateType::PeerReflexive,
+                2 => CandidateType::ServerReflexive,
+                3 => CandidateType::Relayed,
+                other => return Err(anyhow!("Unknown candidate type {}", other))
+            };
+
+            let ip = match kind & FAMILY_V6 {
+                0 => {
+                    let octets: [u8; 4] = reader.read(4)?.try_into()?;
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                },
+                _ => {
+                    let octets: [u8; 16] = reader.read(16)?.try_into()?;
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+            };
+            let port = u16::from_be_bytes(reader.read(2)?.try_into()?);
+
+            // Candidates are encoded best first, which is all the priority
+            // needs to carry across.
+            candidates.push(Candidate::new(candidate_type, SocketAddr::new(ip, port), u16::MAX - i as u16));
+        }
+
+        Ok(Self {
+            mode,
+            answer: flags & FLAG_ANSWER != 0,
+            session_id,
+            session_key,
+            sample_rate,
+            buffer_size,
+            channel_count,
+            candidates
+        })
+    }
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.buffer.get(self.offset..self.offset + len).ok_or(anyhow!("Connection code too short"))?;
+        self.offset += len;
+
+        Ok(bytes)
+    }
+}
+
+fn to_base32(buffer: &[u8]) -> String {
+    let mut code = String::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for byte in buffer {
+        bits = (bits << 8) | *byte as u32;
+        bit_count += 8;
+
+        while bit_count >= 5 {
+            bit_count -= 5;
+            code.push(ALPHABET[((bits >> bit_count) & 0x1f) as usize] as char);
+        }
+    }
+
+    if bit_count > 0 {
+        code.push(ALPHABET[((bits << (5 - bit_count)) & 0x1f) as usize] as char);
+    }
+
+    code
+}
+
+fn from_base32(code: &str) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for c in code.chars().filter(|c| !c.is_whitespace() && *c != '-') {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c
+        };
+
+        let value = ALPHABET.iter()
+            .position(|symbol| *symbol as char == c)
+            .ok_or(anyhow!("Invalid character {:?} in connection code", c))?;
+
+        bits = (bits << 5) | value as u32;
+        bit_count += 5;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            buffer.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// CRC-32 as used by Ethernet and zip.
+fn crc32(buffer: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in buffer {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer() -> ConnectionCode {
+        ConnectionCode {
+            mode: Mode::Send,
+            answer: false,
+            session_id: 0x1234_5678,
+            session_key: SessionKey([7; crypto::KEY_SIZE]),
+            sample_rate: 48000,
+            buffer_size: 256,
+            channel_count: 2,
+            candidates: vec![
+                Candidate::new(CandidateType::Host, "[fd00::1]:5000".parse().unwrap(), u16::MAX),
+                Candidate::new(CandidateType::Host, "192.168.1.2:5000".parse().unwrap(), u16::MAX - 1),
+                Candidate::new(CandidateType::ServerReflexive, "203.0.113.9:40000".parse().unwrap(), u16::MAX - 2),
+            ]
+        }
+    }
+
+    #[test]
+    fn round_trips_an_offer_and_its_answer() {
+        let offer = offer();
+        assert_eq!(ConnectionCode::decode(&offer.encode().unwrap()).unwrap(), offer);
+
+        let answer = offer.answer(vec![Candidate::new(CandidateType::Relayed, "198.51.100.1:3478".parse().unwrap(), u16::MAX)]).unwrap();
+        let decoded = ConnectionCode::decode(&answer.encode().unwrap()).unwrap();
+
+        assert_eq!(decoded, answer);
+        assert_eq!(decoded.mode, Mode::Return);
+        assert!(decoded.answer);
+        assert_eq!(decoded.session_key, offer.session_key);
+        assert!(decoded.answer(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn encodes_candidates_best_first() {
+        let mut code = offer();
+        code.candidates.reverse();
+
+        let decoded = ConnectionCode::decode(&code.encode().unwrap()).unwrap();
+
+        assert_eq!(decoded.candidates, offer().candidates);
+    }
+
+    #[test]
+    fn ignores_case_separators_and_lookalikes() {
+        let code = offer().encode().unwrap();
+        let grouped = code.to_lowercase()
+            .as_bytes()
+            .chunks(6)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join("-\n");
+
+        assert_eq!(ConnectionCode::decode(&grouped).unwrap(), offer());
+        assert_eq!(from_base32("oIl").unwrap(), from_base32("011").unwrap());
+        assert!(from_base32("U").is_err());
+    }
+
+    #[test]
+    fn rejects_damaged_codes() {
+        let code = offer().encode().unwrap();
+
+        let mut changed = code.clone().into_bytes();
+        changed[10] = if changed[10] == b'0' { b'1' } else { b'0' };
+        let err = ConnectionCode::decode(std::str::from_utf8(&changed).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("copied completely"));
+
+        assert!(ConnectionCode::decode(&code[..code.len() - 8]).is_err());
+        assert!(ConnectionCode::decode("").is_err());
+    }
+
+    #[test]
+    fn computes_the_standard_crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
@@ -0,0 +1,360 @@
+pub mod gateway;
+pub mod natpmp;
+pub mod pcp;
+pub mod upnp;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+
+use crate::util;
+
+/// Port of NAT-PMP and PCP servers.
+pub const PMP_PORT: u16 = 5351;
+pub const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+/// Lifetime asked for. Mappings are renewed at half of what the gateway
+/// grants, so they disappear soon after a crash.
+pub const LIFETIME: Duration = Duration::from_secs(120);
+/// How long each protocol gets to answer before trying the next one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Protocol {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+/// Where to ask for port mappings. Usually derived from the default route,
+/// but can point anywhere, such as a stand-in gateway on loopback.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gateway {
+    /// NAT-PMP and PCP server.
+    pub pmp: SocketAddr,
+    /// Where UPnP discovery requests go, the SSDP multicast group by default.
+    pub ssdp: SocketAddr,
+}
+
+impl Gateway {
+    /// The gateway of the default IPv4 route.
+    pub fn default_route() -> Result<Self> {
+        let ip = default_gateway()?;
+
+        Ok(Self {
+            pmp: SocketAddr::new(IpAddr::V4(ip), PMP_PORT),
+            ssdp: SSDP_ADDRESS.parse()?
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr> {
+    // Fields are the interface, destination and gateway, the addresses in
+    // hex with the bytes in host order.
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+
+    routes.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 2 && fields[1] == "00000000")
+        .find_map(|fields| u32::from_str_radix(fields[2], 16).ok())
+        .filter(|gateway| *gateway != 0)
+        .map(|gateway| Ipv4Addr::from(u32::from_be(gateway)))
+        .ok_or(anyhow!("No default route"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Result<Ipv4Addr> {
+    let output = std::process::Command::new("route").args(["-n", "get", "default"]).output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    output.lines()
+        .filter_map(|line| line.trim().strip_prefix("gateway:"))
+        .find_map(|gateway| gateway.trim().parse().ok())
+        .ok_or(anyhow!("No default route"))
+}
+
+/// A granted mapping, with what each protocol needs to renew or delete it.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub external: SocketAddr,
+    pub lifetime: Duration,
+    method: Method,
+}
+
+#[derive(Clone, Debug)]
+enum Method {
+    Pcp { nonce: [u8; 12] },
+    NatPmp,
+    Upnp { control_url: String, service_type: String },
+}
+
+impl Lease {
+    pub fn protocol(&self) -> Protocol {
+        match self.method {
+            Method::Pcp { .. } => Protocol::Pcp,
+            Method::NatPmp => Protocol::NatPmp,
+            Method::Upnp { .. } => Protocol::Upnp,
+        }
+    }
+}
+
+/// Requests a mapping of `internal_port` through PCP, NAT-PMP and UPnP in
+/// turn, returning the first that succeeds.
+pub fn request(gateway: &Gateway, internal_port: u16, timeout: Duration) -> Result<Lease> {
+    let mut nonce = [0u8; 12];
+    util::random_bytes(&mut nonce)?;
+
+    let mut errors = Vec::new();
+
+    for protocol in [Protocol::Pcp, Protocol::NatPmp, Protocol::Upnp].iter() {
+        let method = match protocol {
+            Protocol::Pcp => Ok(Method::Pcp { nonce }),
+            Protocol::NatPmp => Ok(Method::NatPmp),
+            Protocol::Upnp => upnp::discover(gateway, timeout)
+                .map(|(control_url, service_type)| Method::Upnp { control_url, service_type })
+        };
+
+        match method.and_then(|method| renew(gateway, internal_port, &method, None, timeout)) {
+            Ok(lease) => return Ok(lease),
+            Err(err) => errors.push(format!("{:?}: {}", protocol, err))
+        };
+    }
+
+    Err(anyhow!("No port mapping ({})", errors.join(", ")))
+}
+
+/// Asks for the mapping again, keeping the external port if there is one.
+fn renew(gateway: &Gateway, internal_port: u16, method: &Method, external_port: Option<u16>, timeout: Duration) -> Result<Lease> {
+    let lifetime = LIFETIME.as_secs() as u32;
+    let suggested_port = external_port.unwrap_or(internal_port);
+
+    let (external, lifetime) = match method {
+        Method::Pcp { nonce } => pcp::map(gateway.pmp, nonce, internal_port, suggested_port, lifetime, timeout)?,
+        Method::NatPmp => natpmp::map(gateway.pmp, internal_port, suggested_port, lifetime, timeout)?,
+        Method::Upnp { control_url, service_type } => {
+            upnp::map(control_url, service_type, internal_port, suggested_port, lifetime, timeout)?
+        }
+    };
+
+    Ok(Lease {
+        external,
+        lifetime: Duration::from_secs(lifetime as u64),
+        method: method.clone()
+    })
+}
+
+fn release(gateway: &Gateway, internal_port: u16, lease: &Lease, timeout: Duration) -> Result<()> {
+    match &lease.method {
+        Method::Pcp { nonce } => pcp::map(gateway.pmp, nonce, internal_port, 0, 0, timeout).map(|_| ()),
+        Method::NatPmp => natpmp::map(gateway.pmp, internal_port, 0, 0, timeout).map(|_| ()),
+        Method::Upnp { control_url, service_type } => upnp::unmap(control_url, service_type, lease.external.port(), timeout)
+    }
+}
+
+/// A port mapping on the gateway, renewed in the background for as long as
+/// the handle lives and removed when it is dropped.
+pub struct PortMapping {
+    gateway: Gateway,
+    internal_port: u16,
+    lease: Arc<Mutex<Lease>>,
+    running: Arc<AtomicBool>,
+}
+
+impl PortMapping {
+    pub fn start(gateway: Gateway, internal_port: u16, timeout: Duration) -> Result<Self> {
+        let lease = request(&gateway, internal_port, timeout)?;
+        println!("Mapped port {} to {} through {:?}", internal_port, lease.external, lease.protocol());
+
+        let lease = Arc::new(Mutex::new(lease));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_lease = lease.clone();
+        let thread_running = running.clone();
+        thread::spawn(move || {
+            let mut renewed = Instant::now();
+
+            while thread_running.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(500));
+
+                let mut lease = thread_lease.lock().unwrap();
+                if !thread_running.load(Ordering::Relaxed) || renewed.elapsed() < lease.lifetime / 2 {
+                    continue;
+                }
+
+                match renew(&gateway, internal_port, &lease.method, Some(lease.external.port()), timeout) {
+                    Ok(renewed_lease) => {
+                        if renewed_lease.external != lease.external {
+                            println!("Port mapping moved to {}", renewed_lease.external);
+                        }
+                        *lease = renewed_lease;
+                    },
+                    Err(err) => eprintln!("Could not renew port mapping: {}", err)
+                };
+                renewed = Instant::now();
+            }
+        });
+
+        Ok(Self {
+            gateway,
+            internal_port,
+            lease,
+            running
+        })
+    }
+
+    pub fn external_address(&self) -> SocketAddr {
+        self.lease.lock().unwrap().external
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.lease.lock().unwrap().protocol()
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        let lease = self.lease.lock().unwrap();
+        if let Err(err) = release(&self.gateway, self.internal_port, &lease, DEFAULT_TIMEOUT) {
+            eprintln!("Could not remove port mapping: {}", err);
+        }
+    }
+}
+
+/// The local address used to reach `gateway`, which PCP and UPnP need in
+/// their requests.
+fn local_ip_towards(gateway: SocketAddr) -> Result<IpAddr> {
+    let conn = UdpSocket::bind(match gateway {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    })?;
+    conn.connect(gateway)?;
+
+    Ok(conn.local_addr()?.ip())
+}
+
+/// Sends `request` until a datagram that `parse` accepts comes back from
+/// `server`, retransmitting every `RETRANSMIT_INTERVAL`.
+fn transact<T, F>(server: SocketAddr, request: &[u8], timeout: Duration, parse: F) -> Result<T>
+where F: Fn(&[u8]) -> Option<Result<T>> {
+    let conn = UdpSocket::bind(match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    })?;
+    conn.connect(server)?;
+
+    let start = Instant::now();
+    let mut buffer = [0u8; 1100];
+
+    while start.elapsed() < timeout {
+        conn.send(request)?;
+
+        let wait = RETRANSMIT_INTERVAL.min(timeout.saturating_sub(start.elapsed())).max(Duration::from_millis(1));
+        conn.set_read_timeout(Some(wait))?;
+
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline {
+            let len = match conn.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(_) => break
+            };
+
+            if let Some(result) = parse(&buffer[..len]) {
+                return result;
+            }
+        }
+    }
+
+    Err(anyhow!("No answer from {}", server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portmap::gateway::StandInGateway;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+    const INTERNAL_PORT: u16 = 40000;
+    const TIMEOUT: Duration = Duration::from_millis(300);
+
+    fn spawn(protocols: &[Protocol]) -> (Arc<StandInGateway>, Gateway) {
+        let stand_in = Arc::new(StandInGateway::bind(Ipv4Addr::LOCALHOST.into(), 0, 0, EXTERNAL_IP, protocols).unwrap());
+        let gateway = stand_in.gateway().unwrap();
+
+        let running = stand_in.clone();
+        thread::spawn(move || running.run());
+
+        (stand_in, gateway)
+    }
+
+    /// Maps, renews and deletes through the one protocol the gateway answers.
+    fn map_renew_delete(protocol: Protocol) {
+        let (stand_in, gateway) = spawn(&[protocol]);
+
+        let lease = request(&gateway, INTERNAL_PORT, TIMEOUT).unwrap();
+        assert_eq!(lease.protocol(), protocol);
+        assert_eq!(lease.external, SocketAddr::new(EXTERNAL_IP.into(), INTERNAL_PORT));
+        assert_eq!(lease.lifetime, LIFETIME);
+
+        let granted = stand_in.mappings();
+        assert_eq!(granted.len(), 1);
+        assert_eq!(granted[0].protocol, protocol);
+        assert_eq!(granted[0].internal.port(), INTERNAL_PORT);
+
+        let renewed = renew(&gateway, INTERNAL_PORT, &lease.method, Some(lease.external.port()), TIMEOUT).unwrap();
+        assert_eq!(renewed.external, lease.external);
+        assert_eq!(stand_in.mappings().len(), 1);
+
+        release(&gateway, INTERNAL_PORT, &renewed, TIMEOUT).unwrap();
+        assert!(stand_in.mappings().is_empty());
+    }
+
+    #[test]
+    fn maps_renews_and_deletes_through_pcp() {
+        map_renew_delete(Protocol::Pcp);
+    }
+
+    #[test]
+    fn maps_renews_and_deletes_through_natpmp() {
+        map_renew_delete(Protocol::NatPmp);
+    }
+
+    #[test]
+    fn maps_renews_and_deletes_through_upnp() {
+        map_renew_delete(Protocol::Upnp);
+    }
+
+    #[test]
+    fn moves_to_a_free_port_on_conflict() {
+        let (stand_in, gateway) = spawn(&[Protocol::NatPmp]);
+
+        natpmp::map(gateway.pmp, INTERNAL_PORT + 1, INTERNAL_PORT, 120, TIMEOUT).unwrap();
+        let lease = request(&gateway, INTERNAL_PORT, TIMEOUT).unwrap();
+
+        assert_eq!(lease.external.port(), INTERNAL_PORT + 1);
+        assert_eq!(stand_in.mappings().len(), 2);
+    }
+
+    #[test]
+    fn removes_the_mapping_when_dropped() {
+        let (stand_in, gateway) = spawn(&[Protocol::Pcp, Protocol::NatPmp, Protocol::Upnp]);
+
+        let mapping = PortMapping::start(gateway, INTERNAL_PORT, TIMEOUT).unwrap();
+        assert_eq!(mapping.protocol(), Protocol::Pcp);
+        assert_eq!(stand_in.mappings().len(), 1);
+
+        drop(mapping);
+        assert!(stand_in.mappings().is_empty());
+    }
+}
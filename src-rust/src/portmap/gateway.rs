@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+use crate::portmap::{natpmp, pcp, upnp, Gateway, Protocol};
+
+const CONTROL_PATH: &str = "/ctl/IPConn";
+
+/// A mapping the stand-in gateway has granted.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GrantedMapping {
+    pub protocol: Protocol,
+    pub internal: SocketAddr,
+    pub external_port: u16,
+    pub lifetime: u32,
+    #[serde(skip)]
+    expires: Instant,
+}
+
+type Mappings = Arc<Mutex<HashMap<u16, GrantedMapping>>>;
+
+/// Stands in for a home router, answering PCP, NAT-PMP and UPnP IGD
+/// requests, so port mapping can be tried on a single machine, e.g. on
+/// loopback. Mappings are only recorded, no traffic is forwarded.
+pub struct StandInGateway {
+    pmp: UdpSocket,
+    ssdp: UdpSocket,
+    http: TcpListener,
+    external_ip: Ipv4Addr,
+    protocols: Vec<Protocol>,
+    mappings: Mappings,
+    start: Instant,
+}
+
+impl StandInGateway {
+    /// Binds the PCP and NAT-PMP port, the SSDP port and an HTTP port for
+    /// UPnP on `ip`. Only the given protocols are answered, so fallbacks can
+    /// be tried too.
+    pub fn bind(ip: IpAddr, pmp_port: u16, ssdp_port: u16, external_ip: Ipv4Addr, protocols: &[Protocol]) -> Result<Self> {
+        Ok(Self {
+            pmp: UdpSocket::bind((ip, pmp_port))?,
+            ssdp: UdpSocket::bind((ip, ssdp_port))?,
+            http: TcpListener::bind((ip, 0))?,
+            external_ip,
+            protocols: protocols.to_vec(),
+            mappings: Arc::new(Mutex::new(HashMap::new())),
+            start: Instant::now()
+        })
+    }
+
+    /// What clients should use to reach this gateway.
+    pub fn gateway(&self) -> Result<Gateway> {
+        Ok(Gateway {
+            pmp: self.pmp.local_addr()?,
+            ssdp: self.ssdp.local_addr()?
+        })
+    }
+
+    /// The mappings that have not expired or been deleted.
+    pub fn mappings(&self) -> Vec<GrantedMapping> {
+        let mut mappings = self.mappings.lock().unwrap();
+        mappings.retain(|_, mapping| mapping.expires > Instant::now());
+
+        let mut mappings: Vec<_> = mappings.values().cloned().collect();
+        mappings.sort_by_key(|mapping| mapping.external_port);
+
+        mappings
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let pmp = Responder {
+            external_ip: self.external_ip,
+            protocols: self.protocols.clone(),
+            mappings: self.mappings.clone(),
+            start: self.start
+        };
+        let ssdp = pmp.clone();
+        let http = pmp.clone();
+
+        let pmp_conn = self.pmp.try_clone()?;
+        let ssdp_conn = self.ssdp.try_clone()?;
+        let http_listener = self.http.try_clone()?;
+        let location = format!("http://{}/rootDesc.xml", self.http.local_addr()?);
+
+        let handles = vec![
+            thread::spawn(move || pmp.serve_pmp(&pmp_conn)),
+            thread::spawn(move || ssdp.serve_ssdp(&ssdp_conn, &location)),
+            thread::spawn(move || http.serve_http(&http_listener)),
+        ];
+
+        for handle in handles {
+            handle.join().map_err(|_| anyhow!("Gateway thread panicked"))??;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Responder {
+    external_ip: Ipv4Addr,
+    protocols: Vec<Protocol>,
+    mappings: Mappings,
+    start: Instant,
+}
+
+impl Responder {
+    fn epoch(&self) -> u32 {
+        self.start.elapsed().as_secs() as u32
+    }
+
+    /// Grants `external_port` if it is free or already belongs to
+    /// `internal`, otherwise the next free port. A lifetime of 0 deletes
+    /// the mapping of `internal`.
+    fn grant(&self, protocol: Protocol, internal: SocketAddr, external_port: u16, lifetime: u32) -> u16 {
+        let mut mappings = self.mappings.lock().unwrap();
+        mappings.retain(|_, mapping| mapping.expires > Instant::now());
+
+        if lifetime == 0 {
+            mappings.retain(|_, mapping| mapping.internal != internal);
+            return 0;
+        }
+
+        let mut port = match external_port {
+            0 => internal.port(),
+            port => port
+        };
+        while mappings.get(&port).is_some_and(|mapping| mapping.internal != internal) {
+            port = port.checked_add(1).unwrap_or(1024);
+        }
+
+        mappings.retain(|_, mapping| mapping.internal != internal);
+        mappings.insert(port, GrantedMapping {
+            protocol,
+            internal,
+            external_port: port,
+            lifetime,
+            expires: Instant::now() + Duration::from_secs(lifetime as u64)
+        });
+        println!("{:?} mapping {} -> {}:{} for {}s", protocol, internal, self.external_ip, port, lifetime);
+
+        port
+    }
+
+    fn serve_pmp(&self, conn: &UdpSocket) -> Result<()> {
+        let mut buffer = [0u8; 1100];
+
+        loop {
+            let (len, from) = conn.recv_from(&mut buffer)?;
+
+            let response = match buffer[0] {
+                pcp::VERSION if len >= pcp::HEADER_SIZE => self.answer_pcp(&buffer[..len]),
+                natpmp::VERSION if len >= 2 => self.answer_natpmp(&buffer[..len], from),
+                _ => None
+            };
+
+            if let Some(response) = response {
+                conn.send_to(&response, from)?;
+            }
+        }
+    }
+
+    fn answer_pcp(&self, request: &[u8]) -> Option<Vec<u8>> {
+        if !self.protocols.contains(&Protocol::Pcp) {
+            // What a NAT-PMP only gateway answers
+            return match self.protocols.contains(&Protocol::NatPmp) {
+                true => Some(self.natpmp_header(request[1] & !pcp::RESPONSE, natpmp::RESULT_UNSUPPORTED_VERSION)),
+                false => None
+            };
+        }
+
+        if request[1] != pcp::OP_MAP || request.len() < pcp::HEADER_SIZE + pcp::MAP_SIZE {
+            return None;
+        }
+
+        let lifetime = u32::from_be_bytes(request[4..8].try_into().ok()?);
+        let client_octets: [u8; 16] = request[8..24].try_into().ok()?;
+        let client_ip = Ipv6Addr::from(client_octets).to_canonical();
+
+        let map = &request[pcp::HEADER_SIZE..];
+        let internal_port = u16::from_be_bytes(map[16..18].try_into().ok()?);
+        let suggested_port = u16::from_be_bytes(map[18..20].try_into().ok()?);
+
+        let external_port = self.grant(Protocol::Pcp, SocketAddr::new(client_ip, internal_port), suggested_port, lifetime);
+
+        let mut response = vec![pcp::VERSION, pcp::OP_MAP | pcp::RESPONSE, 0, pcp::RESULT_SUCCESS];
+        response.extend_from_slice(&lifetime.to_be_bytes());
+        response.extend_from_slice(&self.epoch().to_be_bytes());
+        response.extend_from_slice(&[0u8; 12]);
+
+        response.extend_from_slice(&map[..12]);
+        response.extend_from_slice(&[pcp::PROTOCOL_UDP, 0, 0, 0]);
+        response.extend_from_slice(&internal_port.to_be_bytes());
+        response.extend_from_slice(&external_port.to_be_bytes());
+        response.extend_from_slice(&self.external_ip.to_ipv6_mapped().octets());
+
+        Some(response)
+    }
+
+    fn answer_natpmp(&self, request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let opcode = request[1];
+
+        if !self.protocols.contains(&Protocol::NatPmp) {
+            return match self.protocols.contains(&Protocol::Pcp) {
+                true => Some(self.natpmp_header(opcode, natpmp::RESULT_UNSUPPORTED_VERSION)),
+                false => None
+            };
+        }
+
+        match opcode {
+            natpmp::OP_EXTERNAL_ADDRESS => {
+                let mut response = self.natpmp_header(opcode, natpmp::RESULT_SUCCESS);
+                response.extend_from_slice(&self.external_ip.octets());
+                Some(response)
+            },
+            natpmp::OP_MAP_UDP if request.len() >= 12 => {
+                let internal_port = u16::from_be_bytes(request[4..6].try_into().ok()?);
+                let suggested_port = u16::from_be_bytes(request[6..8].try_into().ok()?);
+                let lifetime = u32::from_be_bytes(request[8..12].try_into().ok()?);
+
+                let external_port = self.grant(Protocol::NatPmp, SocketAddr::new(from.ip(), internal_port), suggested_port, lifetime);
+
+                let mut response = self.natpmp_header(opcode, natpmp::RESULT_SUCCESS);
+                response.extend_from_slice(&internal_port.to_be_bytes());
+                response.extend_from_slice(&external_port.to_be_bytes());
+                response.extend_from_slice(&lifetime.to_be_bytes());
+                Some(response)
+            },
+            _ => None
+        }
+    }
+
+    fn natpmp_header(&self, opcode: u8, result: u16) -> Vec<u8> {
+        let mut response = vec![natpmp::VERSION, opcode + natpmp::RESPONSE];
+        response.extend_from_slice(&result.to_be_bytes());
+        response.extend_from_slice(&self.epoch().to_be_bytes());
+        response
+    }
+
+    fn serve_ssdp(&self, conn: &UdpSocket, location: &str) -> Result<()> {
+        let mut buffer = [0u8; 2048];
+
+        loop {
+            let (len, from) = conn.recv_from(&mut buffer)?;
+            let request = String::from_utf8_lossy(&buffer[..len]);
+
+            if !self.protocols.contains(&Protocol::Upnp) || !request.starts_with("M-SEARCH") {
+                continue;
+            }
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: {0}\r\nUSN: uuid:claudio-gateway::{0}\r\nLOCATION: {1}\r\n\r\n",
+                upnp::DEVICE_TYPE, location
+            );
+            conn.send_to(response.as_bytes(), from)?;
+        }
+    }
+
+    fn serve_http(&self, listener: &TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            if let Err(err) = self.answer_http(stream?) {
+                eprintln!("{}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn answer_http(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 2048];
+
+        // Read the head, then as much body as announced
+        let (head, body) = loop {
+            let len = stream.read(&mut buffer)?;
+            if len == 0 {
+                return Err(anyhow!("Connection closed before the request was complete"));
+            }
+            request.extend_from_slice(&buffer[..len]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = upnp::header(head, "content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+                if body.len() >= length {
+                    break (head.to_string(), body.to_string());
+                }
+            }
+        };
+
+        let (status, body) = match head.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+            ["GET", "/rootDesc.xml"] => (200, self.description()),
+            ["POST", CONTROL_PATH] => {
+                let action = upnp::header(&head, "soapaction").unwrap_or("").trim_matches('"');
+                let action = action.rsplit('#').next().unwrap_or("");
+                self.answer_soap(action, &body, stream.peer_addr()?)
+            },
+            _ => (404, String::new())
+        };
+
+        let reason = match status {
+            200 => "OK",
+            404 => "Not Found",
+            _ => "Internal Server Error"
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, reason, body.len(), body
+        );
+        stream.write_all(response.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn description(&self) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\"?><root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>",
+                "<deviceType>{}</deviceType><friendlyName>Stand-in gateway</friendlyName><serviceList><service>",
+                "<serviceType>{}</serviceType><controlURL>{}</controlURL>",
+                "</service></serviceList></device></root>"
+            ),
+            upnp::DEVICE_TYPE, upnp::SERVICE_TYPES[1], CONTROL_PATH
+        )
+    }
+
+    fn answer_soap(&self, action: &str, body: &str, from: SocketAddr) -> (u16, String) {
+        let argument = |name: &str| upnp::tag(body, name).unwrap_or("").trim().to_string();
+
+        let result = match action {
+            "GetExternalIPAddress" => Ok(format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", self.external_ip)),
+            "AddPortMapping" => {
+                let internal_ip = argument("NewInternalClient").parse().unwrap_or_else(|_| from.ip());
+                let internal_port = argument("NewInternalPort").parse().unwrap_or(0);
+                let external_port = argument("NewExternalPort").parse().unwrap_or(0);
+                let lifetime = match argument("NewLeaseDuration").parse().unwrap_or(0) {
+                    0 => u32::MAX / 2,
+                    lifetime => lifetime
+                };
+
+                // UPnP cannot move a mapping to another port
+                let conflict = self.mappings.lock().unwrap().get(&external_port)
+                    .is_some_and(|mapping| mapping.internal != SocketAddr::new(internal_ip, internal_port));
+
+                match conflict {
+                    true => Err(718),
+                    false => {
+                        self.grant(Protocol::Upnp, SocketAddr::new(internal_ip, internal_port), external_port, lifetime);
+                        Ok(String::new())
+                    }
+                }
+            },
+            "DeletePortMapping" => {
+                let external_port = argument("NewExternalPort").parse().unwrap_or(0);
+
+                match self.mappings.lock().unwrap().remove(&external_port) {
+                    Some(_) => Ok(String::new()),
+                    None => Err(714)
+                }
+            },
+            _ => Err(401)
+        };
+
+        match result {
+            Ok(arguments) => (200, format!(
+                concat!(
+                    "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>",
+                    "<u:{0}Response xmlns:u=\"{1}\">{2}</u:{0}Response></s:Body></s:Envelope>"
+                ),
+                action, upnp::SERVICE_TYPES[1], arguments
+            )),
+            Err(code) => (500, format!(
+                concat!(
+                    "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body><s:Fault>",
+                    "<faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>",
+                    "<UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode></UPnPError>",
+                    "</detail></s:Fault></s:Body></s:Envelope>"
+                ),
+                code
+            ))
+        }
+    }
+}
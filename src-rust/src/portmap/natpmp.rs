@@ -0,0 +1,70 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::portmap;
+
+pub const VERSION: u8 = 0;
+pub const OP_EXTERNAL_ADDRESS: u8 = 0;
+pub const OP_MAP_UDP: u8 = 1;
+/// Added to the opcode in responses.
+pub const RESPONSE: u8 = 128;
+
+pub const RESULT_SUCCESS: u16 = 0;
+pub const RESULT_UNSUPPORTED_VERSION: u16 = 1;
+
+/// Maps `internal_port` through NAT-PMP (RFC 6886) and returns the external
+/// address and granted lifetime. A lifetime of 0 deletes the mapping.
+pub fn map(server: SocketAddr, internal_port: u16, external_port: u16, lifetime: u32, timeout: Duration) -> Result<(SocketAddr, u32)> {
+    let ip = match lifetime {
+        0 => Ipv4Addr::UNSPECIFIED,
+        _ => external_address(server, timeout)?
+    };
+
+    let mut request = vec![VERSION, OP_MAP_UDP, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+
+    portmap::transact(server, &request, timeout, |response| {
+        let result = parse_header(response, OP_MAP_UDP)?;
+
+        Some(result.and_then(|_| {
+            if response.len() < 16 {
+                return Err(anyhow!("NAT-PMP response too short"));
+            }
+
+            let external_port = u16::from_be_bytes(response[10..12].try_into()?);
+            let lifetime = u32::from_be_bytes(response[12..16].try_into()?);
+
+            Ok((SocketAddr::new(IpAddr::V4(ip), external_port), lifetime))
+        }))
+    })
+}
+
+pub fn external_address(server: SocketAddr, timeout: Duration) -> Result<Ipv4Addr> {
+    portmap::transact(server, &[VERSION, OP_EXTERNAL_ADDRESS], timeout, |response| {
+        let result = parse_header(response, OP_EXTERNAL_ADDRESS)?;
+
+        Some(result.and_then(|_| {
+            let octets: [u8; 4] = response.get(8..12).ok_or(anyhow!("NAT-PMP response too short"))?.try_into()?;
+            Ok(Ipv4Addr::from(octets))
+        }))
+    })
+}
+
+/// Checks the common part of a response. Returns `None` for datagrams that
+/// are not a response to `opcode`.
+fn parse_header(response: &[u8], opcode: u8) -> Option<Result<()>> {
+    if response.len() < 8 || response[0] != VERSION || response[1] != opcode + RESPONSE {
+        return None;
+    }
+
+    match u16::from_be_bytes([response[2], response[3]]) {
+        RESULT_SUCCESS => Some(Ok(())),
+        RESULT_UNSUPPORTED_VERSION => Some(Err(anyhow!("NAT-PMP not supported"))),
+        result => Some(Err(anyhow!("NAT-PMP error {}", result)))
+    }
+}
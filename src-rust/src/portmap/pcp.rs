@@ -0,0 +1,80 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::portmap;
+
+pub const VERSION: u8 = 2;
+pub const OP_MAP: u8 = 1;
+/// Set in the opcode byte of responses.
+pub const RESPONSE: u8 = 0x80;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const RESULT_SUCCESS: u8 = 0;
+pub const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+
+pub const HEADER_SIZE: usize = 24;
+pub const MAP_SIZE: usize = 36;
+
+/// Maps `internal_port` through PCP (RFC 6887) and returns the external
+/// address and granted lifetime. Renewals and the deletion, with a lifetime
+/// of 0, have to use the same nonce.
+pub fn map(server: SocketAddr, nonce: &[u8; 12], internal_port: u16, external_port: u16, lifetime: u32, timeout: Duration) -> Result<(SocketAddr, u32)> {
+    let client_ip = portmap::local_ip_towards(server)?;
+
+    let mut request = vec![VERSION, OP_MAP, 0, 0];
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&to_ipv6(client_ip).octets());
+
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+
+    portmap::transact(server, &request, timeout, |response| {
+        // NAT-PMP servers answer unknown versions with their own header
+        if response.len() >= 4 && response[0] == 0 && response[1] >= 128 {
+            return Some(Err(anyhow!("PCP not supported")));
+        }
+
+        if response.len() < HEADER_SIZE + MAP_SIZE || response[0] != VERSION || response[1] != OP_MAP | RESPONSE {
+            return None;
+        }
+        if response[HEADER_SIZE..HEADER_SIZE + 12] != nonce[..] {
+            return None;
+        }
+
+        Some(match response[3] {
+            RESULT_SUCCESS => parse_map(response),
+            RESULT_UNSUPPORTED_VERSION => Err(anyhow!("PCP not supported")),
+            result => Err(anyhow!("PCP error {}", result))
+        })
+    })
+}
+
+fn parse_map(response: &[u8]) -> Result<(SocketAddr, u32)> {
+    let lifetime = u32::from_be_bytes(response[4..8].try_into()?);
+
+    let map = &response[HEADER_SIZE..];
+    let external_port = u16::from_be_bytes(map[18..20].try_into()?);
+    let octets: [u8; 16] = map[20..36].try_into()?;
+    let ip = Ipv6Addr::from(octets);
+
+    let ip = match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip)
+    };
+
+    Ok((SocketAddr::new(ip, external_port), lifetime))
+}
+
+/// PCP carries all addresses as IPv6, IPv4 ones in their mapped form.
+pub fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
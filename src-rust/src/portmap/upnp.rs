@@ -0,0 +1,235 @@
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+use crate::portmap::{self, Gateway};
+
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services that can map ports, best first.
+pub const SERVICE_TYPES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const DESCRIPTION: &str = "claudio";
+/// Returned by gateways that only accept mappings without a lease time.
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+/// Finds the gateway through SSDP and returns the control URL and type of
+/// its port mapping service.
+pub fn discover(gateway: &Gateway, timeout: Duration) -> Result<(String, String)> {
+    let location = search(gateway.ssdp, timeout)?;
+    let (_, description) = http("GET", &location, &[], "", timeout)?;
+
+    for service_type in SERVICE_TYPES {
+        let start = match description.find(&format!("<serviceType>{}</serviceType>", service_type)) {
+            Some(start) => start,
+            None => continue
+        };
+
+        let control_url = tag(&description[start..], "controlURL").ok_or(anyhow!("No control URL for {}", service_type))?;
+        let base = tag(&description, "URLBase").map(String::from).unwrap_or_else(|| origin(&location));
+
+        return Ok((resolve(&base, control_url), service_type.to_string()));
+    }
+
+    Err(anyhow!("The gateway has no port mapping service"))
+}
+
+pub fn map(control_url: &str, service_type: &str, internal_port: u16, external_port: u16, lifetime: u32, timeout: Duration) -> Result<(SocketAddr, u32)> {
+    let internal_client = portmap::local_ip_towards(host_address(control_url)?)?;
+
+    let add = |lifetime: u32| soap(control_url, service_type, "AddPortMapping", &[
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", external_port.to_string()),
+        ("NewProtocol", "UDP".to_string()),
+        ("NewInternalPort", internal_port.to_string()),
+        ("NewInternalClient", internal_client.to_string()),
+        ("NewEnabled", "1".to_string()),
+        ("NewPortMappingDescription", DESCRIPTION.to_string()),
+        ("NewLeaseDuration", lifetime.to_string()),
+    ], timeout);
+
+    let lifetime = match add(lifetime) {
+        Ok(_) => lifetime,
+        Err(err) if err.to_string().contains(ONLY_PERMANENT_LEASES) => {
+            // Renewing it all the same does no harm
+            add(0)?;
+            portmap::LIFETIME.as_secs() as u32
+        },
+        Err(err) => return Err(err)
+    };
+
+    let response = soap(control_url, service_type, "GetExternalIPAddress", &[], timeout)?;
+    let ip = tag(&response, "NewExternalIPAddress")
+        .ok_or(anyhow!("No external address from the gateway"))?
+        .trim()
+        .parse()?;
+
+    Ok((SocketAddr::new(ip, external_port), lifetime))
+}
+
+pub fn unmap(control_url: &str, service_type: &str, external_port: u16, timeout: Duration) -> Result<()> {
+    soap(control_url, service_type, "DeletePortMapping", &[
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", external_port.to_string()),
+        ("NewProtocol", "UDP".to_string()),
+    ], timeout)?;
+
+    Ok(())
+}
+
+/// Sends an SSDP search and returns the description URL of the first
+/// gateway that answers.
+fn search(ssdp: SocketAddr, timeout: Duration) -> Result<String> {
+    let conn = UdpSocket::bind("0.0.0.0:0")?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\n\r\n",
+        ssdp, DEVICE_TYPE
+    );
+
+    let start = Instant::now();
+    let mut buffer = [0u8; 2048];
+
+    while start.elapsed() < timeout {
+        conn.send_to(request.as_bytes(), ssdp)?;
+        conn.set_read_timeout(Some(timeout.saturating_sub(start.elapsed()).max(Duration::from_millis(1)).min(portmap::RETRANSMIT_INTERVAL)))?;
+
+        let len = match conn.recv_from(&mut buffer) {
+            Ok((len, _)) => len,
+            Err(_) => continue
+        };
+
+        let response = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(location) = header(&response, "location") {
+            return Ok(location.to_string());
+        }
+    }
+
+    Err(anyhow!("No UPnP gateway found"))
+}
+
+fn soap(control_url: &str, service_type: &str, action: &str, arguments: &[(&str, String)], timeout: Duration) -> Result<String> {
+    let arguments: String = arguments.iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+
+    let body = format!(
+        concat!(
+            "<?xml version=\"1.0\"?>",
+            "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">",
+            "<s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>"
+        ),
+        action, service_type, arguments
+    );
+
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\"".to_string()),
+        ("SOAPAction", format!("\"{}#{}\"", service_type, action)),
+    ];
+
+    let (status, response) = http("POST", control_url, &headers, &body, timeout)?;
+    if status != 200 {
+        let code = tag(&response, "errorCode").unwrap_or("unknown");
+        return Err(anyhow!("{} failed with UPnP error {}", action, code));
+    }
+
+    Ok(response)
+}
+
+/// Minimal HTTP/1.1 client, enough for gateway descriptions and SOAP.
+fn http(method: &str, url: &str, headers: &[(&str, String)], body: &str, timeout: Duration) -> Result<(u16, String)> {
+    let rest = url.strip_prefix("http://").ok_or(anyhow!("Unsupported URL {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+
+    let mut stream = TcpStream::connect_timeout(&host_address(url)?, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, host, body.len());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(anyhow!("Invalid HTTP response"))?;
+    let status = head.split_whitespace().nth(1).ok_or(anyhow!("Invalid HTTP response"))?.parse()?;
+
+    let body = match header(head, "transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => dechunk(body)?,
+        _ => body.to_string()
+    };
+
+    Ok((status, body))
+}
+
+fn dechunk(mut body: &str) -> Result<String> {
+    let mut result = String::new();
+
+    loop {
+        let (size, rest) = body.split_once("\r\n").ok_or(anyhow!("Invalid chunked encoding"))?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)?;
+        if size == 0 {
+            return Ok(result);
+        }
+
+        result.push_str(rest.get(..size).ok_or(anyhow!("Invalid chunked encoding"))?);
+        body = rest.get(size + 2..).unwrap_or("");
+    }
+}
+
+pub(crate) fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Contents of the first `<name>` element, ignoring attributes and nesting.
+pub(crate) fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+
+    Some(&xml[start..end])
+}
+
+/// `http://host:port` of a URL.
+fn origin(url: &str) -> String {
+    let rest = url.strip_prefix("http://").unwrap_or(url);
+    let host = rest.split('/').next().unwrap_or(rest);
+
+    format!("http://{}", host)
+}
+
+fn resolve(base: &str, url: &str) -> String {
+    if url.starts_with("http://") {
+        return url.to_string();
+    }
+
+    format!("{}/{}", base.trim_end_matches('/'), url.trim_start_matches('/'))
+}
+
+fn host_address(url: &str) -> Result<SocketAddr> {
+    let rest = url.strip_prefix("http://").ok_or(anyhow!("Unsupported URL {}", url))?;
+    let host = rest.split('/').next().unwrap_or(rest);
+
+    let host = match host.contains(':') && !host.ends_with(']') {
+        true => host.to_string(),
+        false => format!("{}:80", host)
+    };
+
+    host.to_socket_addrs()?.next().ok_or(anyhow!("Could not resolve {}", host))
+}
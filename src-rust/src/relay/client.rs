@@ -0,0 +1,72 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+
+use crate::net;
+use crate::relay::message::{BindStatus, RelayMessage};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where to find the relay, and the token both peers of a session use to
+/// find each other there.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RelayConfig {
+    pub address: String,
+    pub token: String,
+}
+
+/// Binds `conn` to the relay under `token` and waits until the peer has
+/// joined too. From then on, everything sent to the relay address reaches
+/// the peer, and the peer's datagrams arrive from the relay address.
+pub fn bind(conn: &UdpSocket, relay: SocketAddr, token: &str, timeout: Duration) -> Result<()> {
+    let previous_timeout = conn.read_timeout()?;
+    let request = RelayMessage::Bind { token: token.to_string() }.to_buffer();
+    let start = Instant::now();
+    let mut buffer = [0u8; 2048];
+
+    let result = loop {
+        if start.elapsed() >= timeout {
+            break Err(anyhow!("Peer did not join relay {} in time", relay));
+        }
+
+        net::send_to(conn, &request, relay)?;
+        conn.set_read_timeout(Some(RETRANSMIT_INTERVAL))?;
+
+        let deadline = Instant::now() + RETRANSMIT_INTERVAL;
+        let mut status = None;
+
+        while Instant::now() < deadline {
+            match net::recv_from(conn, &mut buffer) {
+                Ok((len, from)) if from == relay => {
+                    if let Ok(RelayMessage::BindResponse { status: s }) = RelayMessage::from_buffer(&buffer[..len]) {
+                        status = Some(s);
+                        break;
+                    }
+                },
+                Ok(_) => continue,
+                Err(_) => break
+            }
+        }
+
+        match status {
+            Some(BindStatus::Paired) => break Ok(()),
+            Some(BindStatus::Rejected) => break Err(anyhow!("Relay {} rejected the session", relay)),
+            _ => sleep_until(deadline)
+        }
+    };
+
+    conn.set_read_timeout(previous_timeout)?;
+
+    result
+}
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now {
+        std::thread::sleep(deadline - now);
+    }
+}
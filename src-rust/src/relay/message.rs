@@ -0,0 +1,68 @@
+use anyhow::{Result, anyhow};
+
+const BIND: u8 = 0xf0;
+const BIND_RESPONSE: u8 = 0xf1;
+
+pub const MAX_TOKEN_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindStatus {
+    /// Bound, waiting for the other endpoint to join.
+    Waiting,
+    /// Both endpoints have joined, datagrams are forwarded.
+    Paired,
+    /// The session already has two other endpoints.
+    Rejected,
+}
+
+/// Control messages between relay clients and the relay. Everything else an
+/// endpoint sends is forwarded to its peer. The type bytes do not overlap
+/// with those of audio packets.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelayMessage {
+    Bind { token: String },
+    BindResponse { status: BindStatus },
+}
+
+impl RelayMessage {
+    pub fn is_relay_message(buffer: &[u8]) -> bool {
+        matches!(buffer.first(), Some(&BIND) | Some(&BIND_RESPONSE))
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        match self {
+            RelayMessage::Bind { token } => {
+                let token = &token.as_bytes()[..token.len().min(MAX_TOKEN_LENGTH)];
+                let mut buffer = vec![BIND, token.len() as u8];
+                buffer.extend_from_slice(token);
+                buffer
+            },
+            RelayMessage::BindResponse { status } => {
+                let status = match status {
+                    BindStatus::Waiting => 0,
+                    BindStatus::Paired => 1,
+                    BindStatus::Rejected => 2,
+                };
+                vec![BIND_RESPONSE, status]
+            }
+        }
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<RelayMessage> {
+        match buffer {
+            [BIND, len, token @ ..] if token.len() >= *len as usize => Ok(RelayMessage::Bind {
+                token: String::from_utf8(token[..*len as usize].to_vec())?
+            }),
+            [BIND_RESPONSE, status, ..] => {
+                let status = match status {
+                    0 => BindStatus::Waiting,
+                    1 => BindStatus::Paired,
+                    2 => BindStatus::Rejected,
+                    _ => return Err(anyhow!("Invalid bind status"))
+                };
+                Ok(RelayMessage::BindResponse { status })
+            },
+            _ => Err(anyhow!("Not a relay message"))
+        }
+    }
+}
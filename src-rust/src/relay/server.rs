@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::net;
+use crate::relay::message::{BindStatus, RelayMessage};
+
+/// An endpoint that has been idle this long can be replaced by a new
+/// address binding with the same token, e.g. after a network change.
+const REBIND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub struct RelayServerConfig {
+    /// Bytes per second forwarded per session, in both directions together.
+    pub bandwidth: u32,
+    /// Sessions without traffic for this long are removed.
+    pub idle_timeout: Duration,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            bandwidth: 1_250_000,
+            idle_timeout: Duration::from_secs(30)
+        }
+    }
+}
+
+/// Token bucket limiting the forwarded bytes per second.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        // Allow bursts of half a second
+        let capacity = rate as f64 / 2.0;
+
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now()
+        }
+    }
+
+    fn take(&mut self, bytes: usize) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    addr: SocketAddr,
+    last_active: Instant,
+}
+
+#[derive(Debug)]
+struct Session {
+    endpoints: Vec<Endpoint>,
+    bucket: TokenBucket,
+    last_active: Instant,
+}
+
+impl Session {
+    fn status(&self) -> BindStatus {
+        match self.endpoints.len() {
+            2 => BindStatus::Paired,
+            _ => BindStatus::Waiting
+        }
+    }
+}
+
+/// Pairs the two endpoints binding with the same token and forwards
+/// datagrams between them.
+pub struct RelayServer {
+    conn: UdpSocket,
+    config: RelayServerConfig,
+    sessions: HashMap<String, Session>,
+    tokens: HashMap<SocketAddr, String>,
+}
+
+impl RelayServer {
+    pub fn new(conn: UdpSocket, config: RelayServerConfig) -> Self {
+        Self {
+            conn,
+            config,
+            sessions: HashMap::new(),
+            tokens: HashMap::new()
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.conn.local_addr()?)
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        self.conn.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut buffer = [0u8; 65536];
+
+        loop {
+            if let Ok((len, from)) = net::recv_from(&self.conn, &mut buffer) {
+                self.handle(&buffer[..len], from);
+            }
+
+            self.expire();
+        }
+    }
+
+    fn handle(&mut self, buffer: &[u8], from: SocketAddr) {
+        if let Ok(RelayMessage::Bind { token }) = RelayMessage::from_buffer(buffer) {
+            let status = self.bind(token, from);
+            let response = RelayMessage::BindResponse { status };
+
+            if let Err(err) = net::send_to(&self.conn, &response.to_buffer(), from) {
+                eprintln!("Error answering {}: {}", from, err);
+            }
+            return;
+        }
+
+        let sessions = &mut self.sessions;
+        let session = match self.tokens.get(&from).and_then(|token| sessions.get_mut(token)) {
+            Some(session) => session,
+            None => return
+        };
+
+        let now = Instant::now();
+        session.last_active = now;
+
+        let mut target = None;
+        for endpoint in session.endpoints.iter_mut() {
+            if endpoint.addr == from {
+                endpoint.last_active = now;
+            } else {
+                target = Some(endpoint.addr);
+            }
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => return
+        };
+
+        if !session.bucket.take(buffer.len()) {
+            return;
+        }
+
+        if let Err(err) = net::send_to(&self.conn, buffer, target) {
+            eprintln!("Error forwarding to {}: {}", target, err);
+        }
+    }
+
+    fn bind(&mut self, token: String, from: SocketAddr) -> BindStatus {
+        if let Some(previous) = self.tokens.get(&from) {
+            if *previous != token {
+                let previous = previous.clone();
+                self.unbind(&previous, from);
+            }
+        }
+
+        let bandwidth = self.config.bandwidth;
+        let session = self.sessions.entry(token.clone()).or_insert_with(|| Session {
+            endpoints: Vec::with_capacity(2),
+            bucket: TokenBucket::new(bandwidth),
+            last_active: Instant::now()
+        });
+        session.last_active = Instant::now();
+
+        if let Some(endpoint) = session.endpoints.iter_mut().find(|endpoint| endpoint.addr == from) {
+            endpoint.last_active = Instant::now();
+            return session.status();
+        }
+
+        if session.endpoints.len() == 2 {
+            // A peer whose address changed binds again from the new one
+            match session.endpoints.iter().position(|endpoint| endpoint.last_active.elapsed() >= REBIND_TIMEOUT) {
+                Some(i) => {
+                    let stale = session.endpoints.remove(i);
+                    self.tokens.remove(&stale.addr);
+                    println!("Session {}: {} replaced by {}", token, stale.addr, from);
+                },
+                None => return BindStatus::Rejected
+            };
+        }
+
+        session.endpoints.push(Endpoint { addr: from, last_active: Instant::now() });
+        self.tokens.insert(from, token.clone());
+
+        let status = session.status();
+        if status == BindStatus::Paired {
+            println!("Session {} paired", token);
+        }
+
+        status
+    }
+
+    fn unbind(&mut self, token: &str, addr: SocketAddr) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.endpoints.retain(|endpoint| endpoint.addr != addr);
+        }
+        self.tokens.remove(&addr);
+    }
+
+    fn expire(&mut self) {
+        let idle_timeout = self.config.idle_timeout;
+        let tokens = &mut self.tokens;
+
+        self.sessions.retain(|token, session| {
+            let active = session.last_active.elapsed() < idle_timeout;
+
+            if !active {
+                println!("Session {} expired", token);
+                for endpoint in &session.endpoints {
+                    tokens.remove(&endpoint.addr);
+                }
+            }
+
+            active
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::relay::client as relay_client;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn spawn(config: RelayServerConfig) -> SocketAddr {
+        let mut server = RelayServer::new(UdpSocket::bind("127.0.0.1:0").unwrap(), config);
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        addr
+    }
+
+    fn client() -> UdpSocket {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        conn
+    }
+
+    fn pair(relay: SocketAddr, token: &str) -> (UdpSocket, UdpSocket) {
+        let (a, b) = (client(), client());
+        let waiting = {
+            let (a, token) = (a.try_clone().unwrap(), token.to_string());
+            thread::spawn(move || relay_client::bind(&a, relay, &token, TIMEOUT))
+        };
+
+        relay_client::bind(&b, relay, token, TIMEOUT).unwrap();
+        waiting.join().unwrap().unwrap();
+
+        (a, b)
+    }
+
+    /// Datagrams `conn` receives until nothing arrives for a while.
+    fn received(conn: &UdpSocket) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut buffer = [0u8; 2048];
+        let mut datagrams = Vec::new();
+
+        while let Ok((len, from)) = conn.recv_from(&mut buffer) {
+            datagrams.push((buffer[..len].to_vec(), from));
+        }
+
+        datagrams
+    }
+
+    #[test]
+    fn pairs_endpoints_with_the_same_token() {
+        let relay = spawn(RelayServerConfig::default());
+        let (a, other) = (client(), client());
+
+        let waiting = {
+            let a = a.try_clone().unwrap();
+            thread::spawn(move || relay_client::bind(&a, relay, "token", TIMEOUT))
+        };
+        assert!(relay_client::bind(&other, relay, "other", Duration::from_millis(600)).is_err());
+
+        relay_client::bind(&client(), relay, "token", TIMEOUT).unwrap();
+        waiting.join().unwrap().unwrap();
+
+        // A session takes two endpoints, until one has been idle for a while
+        let err = relay_client::bind(&client(), relay, "token", TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("rejected"));
+    }
+
+    #[test]
+    fn forwards_in_both_directions() {
+        let relay = spawn(RelayServerConfig::default());
+        let (a, b) = pair(relay, "token");
+
+        a.send_to(b"from a", relay).unwrap();
+        assert_eq!(received(&b), vec![(b"from a".to_vec(), relay)]);
+
+        b.send_to(b"from b", relay).unwrap();
+        assert_eq!(received(&a), vec![(b"from b".to_vec(), relay)]);
+    }
+
+    #[test]
+    fn drops_datagrams_beyond_the_bandwidth() {
+        // Bursts of half a second, i.e. 1000 bytes
+        let relay = spawn(RelayServerConfig { bandwidth: 2000, ..Default::default() });
+        let (a, b) = pair(relay, "token");
+
+        for _ in 0..20 {
+            a.send_to(&[0u8; 200], relay).unwrap();
+        }
+
+        let count = received(&b).len();
+        assert!((5..=6).contains(&count), "forwarded {} datagrams", count);
+    }
+
+    #[test]
+    fn expires_idle_sessions() {
+        let relay = spawn(RelayServerConfig { idle_timeout: Duration::from_millis(300), ..Default::default() });
+        let (a, b) = pair(relay, "token");
+
+        // Expiry runs at least once a second
+        thread::sleep(Duration::from_millis(1500));
+        a.send_to(b"late", relay).unwrap();
+        assert!(received(&b).is_empty());
+
+        // The token is free for a new session
+        let (c, d) = pair(relay, "token");
+        c.send_to(b"again", relay).unwrap();
+        assert_eq!(received(&d), vec![(b"again".to_vec(), relay)]);
+    }
+
+    #[test]
+    fn rebinds_an_idle_endpoint_from_a_new_address() {
+        let relay = spawn(RelayServerConfig::default());
+        let (a, b) = pair(relay, "token");
+
+        thread::sleep(REBIND_TIMEOUT / 2);
+        b.send_to(b"still here", relay).unwrap();
+        assert_eq!(received(&a).len(), 1);
+        thread::sleep(REBIND_TIMEOUT / 2);
+
+        // `a` has been idle for longer than REBIND_TIMEOUT, `b` has not
+        let moved = client();
+        relay_client::bind(&moved, relay, "token", TIMEOUT).unwrap();
+
+        b.send_to(b"to the new address", relay).unwrap();
+        assert_eq!(received(&moved), vec![(b"to the new address".to_vec(), relay)]);
+        assert!(received(&a).is_empty());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod client;
+pub mod message;
+pub mod server;
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use tungstenite::{Error, Message, WebSocket};
+use tungstenite::stream::MaybeTlsStream;
+
+use crate::rendezvous::message::{ClientMessage, PeerInfo, ServerMessage, SessionDescription};
+
+pub const DEFAULT_URL: &str = "ws://localhost:4000";
+
+/// How often a wait checks whether it should stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Typed client for the rendezvous server.
+pub struct RendezvousClient {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    id: Option<String>,
+    pending: VecDeque<ServerMessage>,
+    running: Option<Arc<AtomicBool>>,
+}
+
+impl RendezvousClient {
+    pub fn connect(url: &str) -> Result<Self> {
+        let (socket, _) = tungstenite::connect(url)?;
+
+        Ok(Self {
+            socket,
+            id: None,
+            pending: VecDeque::new(),
+            running: None
+        })
+    }
+
+    /// Makes waits fail once `running` is cleared, e.g. when the wait for
+    /// the peer is given up from another thread.
+    pub fn stop_when_cleared(&mut self, running: Arc<AtomicBool>) {
+        self.running = Some(running);
+    }
+
+    /// Our id in the room, once joined.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Joins a room and returns the peers already in it.
+    pub fn join(&mut self, room: &str, name: Option<&str>) -> Result<Vec<PeerInfo>> {
+        self.send(&ClientMessage::Join {
+            room: room.to_string(),
+            name: name.map(String::from)
+        })?;
+
+        let (id, peers) = self.wait_for(None, |message| match message {
+            ServerMessage::Joined { id, peers, .. } => Some((id.clone(), peers.clone())),
+            _ => None
+        })?;
+        self.id = Some(id);
+
+        Ok(peers)
+    }
+
+    pub fn offer(&mut self, to: &str, session_id: u32, description: SessionDescription) -> Result<()> {
+        self.send(&ClientMessage::Offer { to: to.to_string(), session_id, description })
+    }
+
+    pub fn answer(&mut self, to: &str, session_id: u32, description: SessionDescription) -> Result<()> {
+        self.send(&ClientMessage::Answer { to: to.to_string(), session_id, description })
+    }
+
+    pub fn leave(&mut self) -> Result<()> {
+        self.send(&ClientMessage::Leave)
+    }
+
+    /// Waits for the next message pushed by the server.
+    pub fn recv(&mut self) -> Result<ServerMessage> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+
+        self.read(None)?.ok_or(anyhow!("Connection closed"))
+    }
+
+    /// Waits for the first peer joining, or returns one already in the room.
+    pub fn wait_for_peer(&mut self, peers: &[PeerInfo], timeout: Option<Duration>) -> Result<PeerInfo> {
+        if let Some(peer) = peers.first() {
+            return Ok(peer.clone());
+        }
+
+        self.wait_for(timeout, |message| match message {
+            ServerMessage::PeerJoined { peer } => Some(peer.clone()),
+            _ => None
+        })
+    }
+
+    /// Waits for an offer from any peer, returning the peer id, session id
+    /// and description.
+    pub fn wait_for_offer(&mut self, timeout: Option<Duration>) -> Result<(String, u32, SessionDescription)> {
+        self.wait_for(timeout, |message| match message {
+            ServerMessage::Offer { from, session_id, description } => Some((from.clone(), *session_id, description.clone())),
+            _ => None
+        })
+    }
+
+    pub fn wait_for_answer(&mut self, session_id: u32, timeout: Option<Duration>) -> Result<SessionDescription> {
+        self.wait_for(timeout, |message| match message {
+            ServerMessage::Answer { session_id: id, description, .. } if *id == session_id => Some(description.clone()),
+            _ => None
+        })
+    }
+
+    fn send(&mut self, message: &ClientMessage) -> Result<()> {
+        self.socket.send(Message::Text(serde_json::to_string(message)?))?;
+
+        Ok(())
+    }
+
+    /// Reads messages until `select` picks one, keeping the others for
+    /// later calls. Errors from the server end the wait.
+    fn wait_for<T, F>(&mut self, timeout: Option<Duration>, select: F) -> Result<T>
+    where F: Fn(&ServerMessage) -> Option<T> {
+        if let Some(i) = self.pending.iter().position(|message| select(message).is_some()) {
+            let message = self.pending.remove(i).unwrap();
+            return select(&message).ok_or(anyhow!("Unexpected message"));
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            if self.running.as_ref().is_some_and(|running| !running.load(Ordering::Relaxed)) {
+                return Err(anyhow!("Stopped waiting for the rendezvous server"));
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(anyhow!("Timed out waiting for the rendezvous server"));
+                    }
+                    Some(deadline - now)
+                },
+                None => None
+            };
+            let remaining = match self.running {
+                Some(_) => Some(remaining.map_or(STOP_POLL_INTERVAL, |remaining| remaining.min(STOP_POLL_INTERVAL))),
+                None => remaining
+            };
+
+            let message = match self.read(remaining)? {
+                Some(message) => message,
+                None => continue
+            };
+
+            if let ServerMessage::Error { message } = &message {
+                return Err(anyhow!("Rendezvous server: {}", message));
+            }
+
+            match select(&message) {
+                Some(value) => return Ok(value),
+                None => self.pending.push_back(message)
+            };
+        }
+    }
+
+    /// Reads one message, returning `None` if `timeout` passes first.
+    fn read(&mut self, timeout: Option<Duration>) -> Result<Option<ServerMessage>> {
+        set_read_timeout(self.socket.get_ref(), timeout)?;
+
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => return Ok(Some(serde_json::from_str(&text)?)),
+                Ok(Message::Close(_)) => return Err(anyhow!("Connection closed")),
+                Ok(_) => continue,
+                Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock || err.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
+                Err(err) => return Err(err.into())
+            };
+        }
+    }
+}
+
+/// Applies to the TCP connection under the TLS layer, if there is one.
+fn set_read_timeout(stream: &MaybeTlsStream<TcpStream>, timeout: Option<Duration>) -> io::Result<()> {
+    match stream {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(timeout),
+        #[cfg(feature = "native-tls")]
+        MaybeTlsStream::NativeTls(stream) => stream.get_ref().set_read_timeout(timeout),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, "TLS backend not built in"))
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+
+use crate::audio::AudioConfig;
+use crate::ice::Candidate;
+use crate::util::Mode;
+
+/// What a peer tells the other about itself when setting up a session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDescription {
+    /// Address the peer is reachable at, usually its STUN mapped address.
+    pub address: String,
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    /// The mode the sender of this description streams in.
+    pub mode: Mode,
+    /// Audio configuration both ends use, set by the offering side.
+    pub config: Option<AudioConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerInfo {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    #[serde(rename_all = "camelCase")]
+    Join { room: String, name: Option<String> },
+    /// Starts a session with another peer in the room.
+    #[serde(rename_all = "camelCase")]
+    Offer { to: String, session_id: u32, description: SessionDescription },
+    #[serde(rename_all = "camelCase")]
+    Answer { to: String, session_id: u32, description: SessionDescription },
+    Leave,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    #[serde(rename_all = "camelCase")]
+    Joined { id: String, room: String, peers: Vec<PeerInfo> },
+    #[serde(rename_all = "camelCase")]
+    PeerJoined { peer: PeerInfo },
+    #[serde(rename_all = "camelCase")]
+    PeerLeft { id: String },
+    #[serde(rename_all = "camelCase")]
+    Offer { from: String, session_id: u32, description: SessionDescription },
+    #[serde(rename_all = "camelCase")]
+    Answer { from: String, session_id: u32, description: SessionDescription },
+    Error { message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_camel_case_on_the_wire() {
+        let message = ClientMessage::Offer {
+            to: "0000abcd".to_string(),
+            session_id: 42,
+            description: SessionDescription {
+                address: "192.0.2.1:5000".to_string(),
+                candidates: Vec::new(),
+                mode: Mode::Send,
+                config: None
+            }
+        };
+
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["type"], "offer");
+        assert_eq!(json["sessionId"], 42);
+        assert_eq!(json["description"]["mode"], "Send");
+
+        match serde_json::from_value(json).unwrap() {
+            ClientMessage::Offer { to, session_id, description } => {
+                assert_eq!(to, "0000abcd");
+                assert_eq!(session_id, 42);
+                assert_eq!(description.address, "192.0.2.1:5000");
+            },
+            message => panic!("Unexpected {:?}", message)
+        };
+    }
+
+    #[test]
+    fn candidates_default_to_empty() {
+        let message: ServerMessage = serde_json::from_str(
+            r#"{"type": "answer", "from": "1", "sessionId": 3, "description": {"address": "192.0.2.1:5000", "mode": "Return"}}"#
+        ).unwrap();
+
+        match message {
+            ServerMessage::Answer { description, .. } => assert!(description.candidates.is_empty()),
+            message => panic!("Unexpected {:?}", message)
+        };
+        assert!(serde_json::from_str::<ServerMessage>(r#"{"type": "peerJoined"}"#).is_err());
+    }
+}
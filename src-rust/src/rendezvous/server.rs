@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use tungstenite::{Error, Message};
+use tungstenite::error::ProtocolError;
+
+use crate::rendezvous::message::{ClientMessage, PeerInfo, ServerMessage};
+use crate::util;
+
+/// How often a connection checks for messages pushed by other peers.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Member {
+    info: PeerInfo,
+    sender: Sender<ServerMessage>,
+}
+
+type Rooms = HashMap<String, HashMap<String, Member>>;
+
+/// Lets peers find each other in named rooms and relays session offers
+/// and answers between them over WebSockets.
+pub struct RendezvousServer {
+    listener: TcpListener,
+    rooms: Arc<Mutex<Rooms>>,
+}
+
+impl RendezvousServer {
+    pub fn bind(addr: &str) -> Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            rooms: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let rooms = self.rooms.clone();
+
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(stream, rooms) {
+                    eprintln!("{}", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_connection(stream: TcpStream, rooms: Arc<Mutex<Rooms>>) -> Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut socket = tungstenite::accept(stream)?;
+
+    let id = format!("{:08x}", util::random_id()?);
+    let (sender, receiver) = mpsc::channel();
+    let mut room: Option<String> = None;
+
+    let result = loop {
+        let message = match socket.read() {
+            Ok(Message::Text(text)) => Some(text),
+            Ok(Message::Close(_)) | Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => break Ok(()),
+            // Peers that quit without closing are gone all the same
+            Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => break Ok(()),
+            Ok(_) => None,
+            Err(Error::Io(err)) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => None,
+            Err(err) => break Err(err.into())
+        };
+
+        if let Some(text) = message {
+            let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(message) => handle_message(message, &id, &mut room, &sender, &rooms),
+                Err(err) => Some(ServerMessage::Error { message: err.to_string() })
+            };
+
+            if let Some(reply) = reply {
+                sender.send(reply)?;
+            }
+        }
+
+        let mut send_result = Ok(());
+        while let Ok(message) = receiver.try_recv() {
+            send_result = socket.send(Message::Text(serde_json::to_string(&message)?));
+            if send_result.is_err() {
+                break;
+            }
+        }
+
+        if let Err(err) = send_result {
+            break Err(err.into());
+        }
+    };
+
+    if let Some(room) = room {
+        leave(&rooms, &room, &id);
+    }
+
+    result
+}
+
+fn handle_message(
+    message: ClientMessage,
+    id: &str,
+    room: &mut Option<String>,
+    sender: &Sender<ServerMessage>,
+    rooms: &Mutex<Rooms>
+) -> Option<ServerMessage> {
+    match message {
+        ClientMessage::Join { room: name, name: peer_name } => {
+            if let Some(previous) = room.take() {
+                leave(rooms, &previous, id);
+            }
+
+            let info = PeerInfo { id: id.to_string(), name: peer_name };
+            let mut rooms = rooms.lock().unwrap();
+            let members = rooms.entry(name.clone()).or_default();
+
+            let peers = members.values().map(|member| member.info.clone()).collect();
+            for member in members.values() {
+                let _ = member.sender.send(ServerMessage::PeerJoined { peer: info.clone() });
+            }
+
+            members.insert(id.to_string(), Member { info, sender: sender.clone() });
+            *room = Some(name.clone());
+
+            Some(ServerMessage::Joined { id: id.to_string(), room: name, peers })
+        },
+        ClientMessage::Offer { to, session_id, description } => {
+            forward(rooms, room, &to, ServerMessage::Offer { from: id.to_string(), session_id, description })
+        },
+        ClientMessage::Answer { to, session_id, description } => {
+            forward(rooms, room, &to, ServerMessage::Answer { from: id.to_string(), session_id, description })
+        },
+        ClientMessage::Leave => {
+            if let Some(previous) = room.take() {
+                leave(rooms, &previous, id);
+            }
+            None
+        }
+    }
+}
+
+/// Pushes a message to a peer in the same room, returning an error for the
+/// sender if there is no such peer.
+fn forward(rooms: &Mutex<Rooms>, room: &Option<String>, to: &str, message: ServerMessage) -> Option<ServerMessage> {
+    let rooms = rooms.lock().unwrap();
+
+    let member = room.as_ref()
+        .and_then(|room| rooms.get(room))
+        .and_then(|members| members.get(to));
+
+    match member {
+        Some(member) => {
+            let _ = member.sender.send(message);
+            None
+        },
+        None => Some(ServerMessage::Error { message: format!("No peer {} in this room", to) })
+    }
+}
+
+fn leave(rooms: &Mutex<Rooms>, room: &str, id: &str) {
+    let mut rooms = rooms.lock().unwrap();
+
+    if let Some(members) = rooms.get_mut(room) {
+        members.remove(id);
+
+        for member in members.values() {
+            let _ = member.sender.send(ServerMessage::PeerLeft { id: id.to_string() });
+        }
+
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ice::{Candidate, CandidateType};
+    use crate::rendezvous::client::RendezvousClient;
+    use crate::rendezvous::message::SessionDescription;
+    use crate::util::Mode;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(2));
+
+    fn spawn() -> String {
+        let server = RendezvousServer::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+
+        url
+    }
+
+    fn description(address: &str, mode: Mode) -> SessionDescription {
+        SessionDescription {
+            address: address.to_string(),
+            candidates: vec![Candidate::new(CandidateType::Host, address.parse().unwrap(), 1)],
+            mode,
+            config: None
+        }
+    }
+
+    #[test]
+    fn relays_offer_and_answer_between_two_peers() {
+        let url = spawn();
+        let mut alice = RendezvousClient::connect(&url).unwrap();
+        let mut bob = RendezvousClient::connect(&url).unwrap();
+
+        assert!(alice.join("studio", Some("alice")).unwrap().is_empty());
+        let peers = bob.join("studio", Some("bob")).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, alice.id().unwrap());
+        assert_eq!(peers[0].name.as_deref(), Some("alice"));
+
+        let joined = alice.wait_for_peer(&[], TIMEOUT).unwrap();
+        assert_eq!(joined.id, bob.id().unwrap());
+
+        let offer = description("127.0.0.1:5000", Mode::Send);
+        alice.offer(&joined.id, 7, offer.clone()).unwrap();
+
+        let (from, session_id, received) = bob.wait_for_offer(TIMEOUT).unwrap();
+        assert_eq!(from, alice.id().unwrap());
+        assert_eq!(session_id, 7);
+        assert_eq!(received.address, offer.address);
+        assert_eq!(received.candidates, offer.candidates);
+
+        bob.answer(&from, session_id, description("127.0.0.1:6000", Mode::Return)).unwrap();
+
+        let answer = alice.wait_for_answer(7, TIMEOUT).unwrap();
+        assert_eq!(answer.address, "127.0.0.1:6000");
+        assert_eq!(answer.mode, Mode::Return);
+    }
+
+    #[test]
+    fn keeps_rooms_apart() {
+        let url = spawn();
+        let mut alice = RendezvousClient::connect(&url).unwrap();
+        let mut bob = RendezvousClient::connect(&url).unwrap();
+
+        alice.join("one", None).unwrap();
+        assert!(bob.join("two", None).unwrap().is_empty());
+
+        let bob_id = bob.id().unwrap().to_string();
+        alice.offer(&bob_id, 1, description("127.0.0.1:5000", Mode::Send)).unwrap();
+
+        let err = alice.wait_for_answer(1, TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("No peer"));
+    }
+
+    #[test]
+    fn tells_the_room_when_a_peer_leaves() {
+        let url = spawn();
+        let mut alice = RendezvousClient::connect(&url).unwrap();
+        let mut bob = RendezvousClient::connect(&url).unwrap();
+
+        alice.join("studio", None).unwrap();
+        bob.join("studio", None).unwrap();
+        let bob_id = bob.id().unwrap().to_string();
+        drop(bob);
+
+        match alice.recv().unwrap() {
+            ServerMessage::PeerJoined { peer } => assert_eq!(peer.id, bob_id),
+            message => panic!("Unexpected {:?}", message)
+        };
+        match alice.recv().unwrap() {
+            ServerMessage::PeerLeft { id } => assert_eq!(id, bob_id),
+            message => panic!("Unexpected {:?}", message)
+        };
+    }
+}
@@ -1,11 +1,18 @@
 use ringbuf::{RingBuffer, Consumer, Producer};
 
-pub fn create(packet_buffer_size: usize)
+/// Frames both buffers start out with, which is also the default jitter
+/// buffer target.
+pub const BUFFERED_FRAMES: usize = 4;
+
+/// `output_capacity` is the most the output buffer may hold, beyond the
+/// frames it starts out with. The audio callback keeps it near the jitter
+/// target.
+pub fn create(packet_buffer_size: usize, output_capacity: usize)
 -> (Producer<f32>, Consumer<f32>, Producer<f32>, Consumer<f32>)
 {
-    let ringbuffer_size = packet_buffer_size * 4;
+    let ringbuffer_size = packet_buffer_size * BUFFERED_FRAMES;
     let input_buffer = RingBuffer::new(ringbuffer_size);
-    let output_buffer = RingBuffer::new(ringbuffer_size);
+    let output_buffer = RingBuffer::new(ringbuffer_size + output_capacity);
 
     let (mut input_producer, input_consumer) = input_buffer.split();
     let (mut output_producer, output_consumer) = output_buffer.split();
@@ -19,4 +26,4 @@ pub fn create(packet_buffer_size: usize)
     }
 
     (input_producer, input_consumer, output_producer, output_consumer)
-}
\ No newline at end of file
+}
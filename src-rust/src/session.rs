@@ -0,0 +1,532 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::{Serialize, Deserialize};
+
+use crate::audio::{AudioConfig, AudioInterface, Xruns};
+use crate::audio::params::{self, AudioParams};
+use crate::control::{ControlError, ErrorCode, WithCode};
+use crate::events::{self, Event, Events, Monitor, State};
+use crate::identity::Identity;
+use crate::net::{self, BindOptions};
+use crate::noise;
+use crate::ringbuffer;
+use crate::udp::client::UdpClient;
+use crate::udp::congestion::RateStats;
+use crate::udp::crypto::SessionKey;
+use crate::udp::keepalive;
+use crate::udp::multipath::{self, PathStats};
+use crate::util::{self, Mode};
+
+/// Why a session could not start or a setting could not change. The code is
+/// the one the daemon reports for the same failure.
+pub type Error = ControlError;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Settings that can change while streaming.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct StreamParams {
+    /// Linear gain of what this end captures, when sending.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_gain: Option<f32>,
+    /// Linear gain of what this end plays, when returning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_gain: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    /// First device channel to capture from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_channel: Option<u32>,
+    /// First device channel to play to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_channel: Option<u32>,
+    /// Copies of each packet sent, from 1 to 3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redundancy: Option<u8>,
+    /// Milliseconds of audio the receiver keeps buffered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter_target: Option<u32>,
+    /// Bits per second on the wire, 0 for no limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bitrate: Option<u32>,
+}
+
+/// A snapshot of a running session, the same figures the `stats` and
+/// `xrun` events carry.
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    pub paths: Vec<PathStats>,
+    pub rate: RateStats,
+    pub overruns: u64,
+    pub underruns: u64,
+}
+
+/// Sets up a session with a peer whose address is known. Finding that
+/// address, through connection codes, discovery or connectivity checks, is
+/// up to the caller.
+///
+/// ```no_run
+/// # use p2p_audio::session::SessionBuilder;
+/// # use p2p_audio::identity::{self, Identity};
+/// # use p2p_audio::util::Mode;
+/// # fn main() -> anyhow::Result<()> {
+/// let identity = Identity::load_or_create(&identity::default_dir()?)?;
+/// let session = SessionBuilder::new(Mode::Send, "192.0.2.1:5000".parse()?)
+///     .identity(identity)
+///     .on_event(|event| println!("{:?}", event))
+///     .start()?;
+///
+/// println!("Streaming to {}", session.fingerprint());
+/// session.wait()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SessionBuilder {
+    mode: Mode,
+    remote_addr: SocketAddr,
+    conn: Option<Arc<UdpSocket>>,
+    audio_config: Option<AudioConfig>,
+    identity: Option<Identity>,
+    key: Option<SessionKey>,
+    session_id: Option<u32>,
+    handshake: Option<noise::Session>,
+    paths: Vec<String>,
+    dtx: bool,
+    keepalive_interval: Duration,
+    params: StreamParams,
+    events: Events,
+}
+
+impl SessionBuilder {
+    /// A session that sends to or returns from the peer at `remote_addr`.
+    pub fn new(mode: Mode, remote_addr: SocketAddr) -> Self {
+        Self {
+            mode,
+            remote_addr,
+            conn: None,
+            audio_config: None,
+            identity: None,
+            key: None,
+            session_id: None,
+            handshake: None,
+            paths: Vec::new(),
+            dtx: false,
+            keepalive_interval: keepalive::DEFAULT_INTERVAL,
+            params: StreamParams::default(),
+            events: Events::default()
+        }
+    }
+
+    /// Socket to stream over, by default a new one on any port. Pass the
+    /// socket the peer learned the address of, e.g. through STUN.
+    pub fn socket(mut self, conn: Arc<UdpSocket>) -> Self {
+        self.conn = Some(conn);
+        self
+    }
+
+    /// Devices and format, by default the default devices.
+    pub fn audio_config(mut self, audio_config: AudioConfig) -> Self {
+        self.audio_config = Some(audio_config);
+        self
+    }
+
+    /// Who to run the handshake as. Needed unless a finished handshake is
+    /// passed instead.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Pre-shared key mixed into the handshake, the peer has to use the same
+    /// one.
+    pub fn key(mut self, key: SessionKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Session id agreed on during signalling. Without one, the sender picks
+    /// a random id and the receiver learns it from the handshake.
+    pub fn session_id(mut self, session_id: u32) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Streams with a handshake that already ran, e.g. to check the peer's
+    /// fingerprint before audio starts.
+    pub fn handshake(mut self, session: noise::Session) -> Self {
+        self.handshake = Some(session);
+        self
+    }
+
+    /// Sends copies over another local address or interface as well.
+    pub fn path(mut self, spec: &str) -> Self {
+        self.paths.push(spec.to_string());
+        self
+    }
+
+    /// Stops sending audio during silence.
+    pub fn dtx(mut self, dtx: bool) -> Self {
+        self.dtx = dtx;
+        self
+    }
+
+    /// Between `keepalive::MIN_INTERVAL` and `keepalive::MAX_INTERVAL`,
+    /// otherwise starting fails with `ErrorCode::InvalidParams`.
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Settings applied once audio runs.
+    pub fn params(mut self, params: StreamParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Calls `callback` with the session's events, see `Events::callback`.
+    pub fn on_event<F: Fn(Event) + Send + Sync + 'static>(self, callback: F) -> Self {
+        self.events(Events::callback(callback))
+    }
+
+    /// Runs the handshake unless one was given, opens the audio devices and
+    /// starts streaming. Returns once audio runs.
+    pub fn start(self) -> Result<Session> {
+        Ok(self.build()?)
+    }
+
+    fn build(self) -> anyhow::Result<Session> {
+        let mode = self.mode;
+        let keepalive_interval = keepalive::check_interval(self.keepalive_interval).code(ErrorCode::InvalidParams)?;
+        let conn = match self.conn {
+            Some(conn) => conn,
+            None => Arc::new(net::bind(&BindOptions::default()).code(ErrorCode::BindFailed)?)
+        };
+        let audio_config = match self.audio_config {
+            Some(audio_config) => audio_config,
+            None => AudioConfig::from_default_devices().code(ErrorCode::AudioDevice)?
+        };
+
+        let session = match self.handshake {
+            Some(session) => session,
+            None => {
+                let identity = self.identity.ok_or(anyhow!("A session needs an identity or a finished handshake"))?;
+                let session_id = match mode {
+                    Mode::Send => Some(self.session_id.map_or_else(util::random_id, Ok)?),
+                    Mode::Return => self.session_id
+                };
+
+                self.events.emit(Event::State { state: State::Handshaking });
+                noise::handshake(&conn, self.remote_addr, mode, session_id, &identity, self.key.as_ref(), noise::DEFAULT_TIMEOUT)
+                    .code(ErrorCode::HandshakeFailed)?
+            }
+        };
+        let fingerprint = session.fingerprint();
+
+        let mut audio_interface = AudioInterface::new(audio_config.clone()).code(ErrorCode::AudioDevice)?;
+        audio_interface.set_events(self.events.clone());
+        let params = audio_interface.params();
+        let xruns = audio_interface.xruns();
+
+        let packet_buffer_size = (audio_config.buffer_size * audio_config.get_channel_count()) as usize;
+        let (input_producer, input_consumer, output_producer, output_consumer) = ringbuffer::create(packet_buffer_size, params.max_jitter_samples());
+
+        let mut client = UdpClient::new(
+            conn,
+            self.remote_addr,
+            Some(session.session_id),
+            // Not meant to be secret, only not to start where a previous
+            // session on the same addresses left off
+            util::random_id()? as u16,
+            audio_config
+        )?;
+
+        for path in &self.paths {
+            client.add_path(multipath::bind_path(path).code(ErrorCode::BindFailed)?);
+        }
+        client.set_dtx(self.dtx);
+
+        client.set_key(&session.key, mode);
+        if let Some(final_message) = session.final_message {
+            client.set_handshake_reply(final_message);
+        }
+
+        let session_client = client.clone();
+        let events = self.events;
+        let (started, start_result) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let client = session_client;
+            let _keepalive = client.start_keepalive(keepalive_interval);
+
+            let _streams = match audio_interface.build_streams(&mode, input_producer, output_consumer).code(ErrorCode::AudioDevice) {
+                Ok(streams) => {
+                    let _ = started.send(Ok(()));
+                    streams
+                },
+                Err(err) => {
+                    // Reported to whoever started the session instead
+                    let _ = started.send(Err(err));
+                    return Ok(());
+                }
+            };
+            let _monitor = Monitor::start(client.clone(), audio_interface.xruns(), events.clone(), events::STATS_INTERVAL);
+            events.emit(Event::State { state: State::Streaming });
+
+            let result = match mode {
+                Mode::Send => {
+                    let mut client1 = client.clone();
+                    let send_handle = thread::spawn(move || {
+                        client1.send_loop(input_consumer);
+                    });
+
+                    let mut client2 = client.clone();
+                    let control_handle = thread::spawn(move || {
+                        client2.control_loop();
+                    });
+
+                    send_handle.join()
+                        .and_then(|_| control_handle.join())
+                        .map_err(|_| anyhow!("The send loop stopped unexpectedly"))
+                },
+                Mode::Return => {
+                    let mut client2 = client.clone();
+                    let recv_handle = thread::spawn(move || {
+                        client2.recv_loop(output_producer);
+                    });
+
+                    recv_handle.join().map_err(|_| anyhow!("The receive loop stopped unexpectedly"))
+                }
+            };
+            events.emit(Event::State { state: State::Idle });
+
+            result
+        });
+
+        match start_result.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(anyhow!("The session stopped unexpectedly"))
+        };
+
+        let session = Session { client, params, xruns, fingerprint, handle: Some(handle) };
+        session.update(&self.params)?;
+
+        Ok(session)
+    }
+}
+
+/// A session streaming on its own thread, which also owns the audio
+/// streams, as those cannot move between threads. Dropping it stops it.
+pub struct Session {
+    client: UdpClient,
+    params: Arc<AudioParams>,
+    xruns: Arc<Xruns>,
+    fingerprint: String,
+    handle: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl Session {
+    pub fn is_running(&self) -> bool {
+        self.handle.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// The peer's fingerprint, as the handshake established it.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Where the peer is heard from, which changes when it roams.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.client.remote_addr()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            paths: self.client.path_stats(),
+            rate: self.client.rate_stats(),
+            overruns: self.xruns.overruns(),
+            underruns: self.xruns.underruns()
+        }
+    }
+
+    /// Applies the given settings, or none of them if any is invalid.
+    pub fn update(&self, params: &StreamParams) -> Result<()> {
+        Ok(self.apply(params).code(ErrorCode::InvalidParams)?)
+    }
+
+    fn apply(&self, params: &StreamParams) -> anyhow::Result<()> {
+        // Checked up front, so that a rejected update changes nothing
+        self.check(params)?;
+
+        if let Some(gain) = params.input_gain {
+            self.params.set_input_gain(gain)?;
+        }
+        if let Some(gain) = params.output_gain {
+            self.params.set_output_gain(gain)?;
+        }
+        if let Some(mute) = params.mute {
+            self.params.set_muted(mute);
+        }
+        if let Some(channel) = params.input_channel {
+            self.params.set_input_channel(channel)?;
+        }
+        if let Some(channel) = params.output_channel {
+            self.params.set_output_channel(channel)?;
+        }
+        if let Some(jitter_target) = params.jitter_target {
+            self.params.set_jitter_target(jitter_target)?;
+        }
+        if let Some(redundancy) = params.redundancy {
+            self.client.set_redundancy(redundancy)?;
+        }
+        if let Some(bitrate) = params.max_bitrate {
+            self.client.set_max_bitrate(Some(bitrate).filter(|bitrate| *bitrate > 0));
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, update: &StreamParams) -> anyhow::Result<()> {
+        for gain in update.input_gain.iter().chain(&update.output_gain) {
+            params::check_gain(*gain)?;
+        }
+        if let Some(channel) = update.input_channel {
+            self.params.check_input_channel(channel)?;
+        }
+        if let Some(channel) = update.output_channel {
+            self.params.check_output_channel(channel)?;
+        }
+        if let Some(jitter_target) = update.jitter_target {
+            params::check_jitter_target(jitter_target)?;
+        }
+        if let Some(redundancy) = update.redundancy {
+            self.client.check_redundancy(redundancy)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the session and waits for its threads.
+    pub fn stop(self) -> Result<()> {
+        self.client.stop();
+        self.wait()
+    }
+
+    /// Waits for the session to end, which it does on its own only when it
+    /// fails.
+    pub fn wait(mut self) -> Result<()> {
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(result)) => Ok(result?),
+            Some(Err(_)) => Err(anyhow!("The session stopped unexpectedly").into()),
+            None => Ok(())
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.client.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn missing_devices() -> AudioConfig {
+        AudioConfig::new("".into(), "No such input".into(), "No such output".into(), 48000, 64, false, 0, 0)
+    }
+
+    fn builder() -> SessionBuilder {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+
+        SessionBuilder::new(Mode::Send, "127.0.0.1:9".parse().unwrap())
+            .socket(conn)
+            .audio_config(missing_devices())
+    }
+
+    #[test]
+    fn rejects_keepalive_intervals_out_of_range() {
+        for interval in [Duration::ZERO, keepalive::MAX_INTERVAL + Duration::from_secs(1)] {
+            let err = builder().keepalive_interval(interval).start().err().unwrap();
+
+            assert_eq!(err.code, ErrorCode::InvalidParams);
+        }
+    }
+
+    #[test]
+    fn needs_an_identity_or_a_handshake() {
+        let err = builder().start().err().unwrap();
+
+        assert_eq!(err.code, ErrorCode::Internal);
+        assert!(err.to_string().contains("identity"));
+    }
+
+    #[test]
+    fn reports_missing_devices_after_the_handshake() {
+        let (conn, peer) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        let (conn_addr, peer_addr) = (conn.local_addr().unwrap(), peer.local_addr().unwrap());
+        let timeout = Duration::from_secs(1);
+
+        let returning = thread::spawn(move || {
+            noise::handshake(&peer, conn_addr, Mode::Return, None, &Identity::generate().unwrap(), None, timeout)
+        });
+        let (events, states) = Events::channel();
+
+        let err = SessionBuilder::new(Mode::Send, peer_addr)
+            .socket(Arc::new(conn))
+            .audio_config(missing_devices())
+            .identity(Identity::generate().unwrap())
+            .events(events)
+            .start()
+            .err()
+            .unwrap();
+
+        assert_eq!(err.code, ErrorCode::AudioDevice);
+        assert!(returning.join().unwrap().is_ok());
+        assert!(matches!(states.try_recv().unwrap(), Event::State { state: State::Handshaking }));
+    }
+
+    #[test]
+    fn rejected_updates_change_nothing() {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let client = UdpClient::new(conn, "127.0.0.1:9".parse().unwrap(), Some(7), 0, missing_devices()).unwrap();
+        let session = Session {
+            client,
+            params: Arc::new(AudioParams::new(0, 0, 2, 2, 2, 48000, 0)),
+            xruns: Arc::new(Xruns::default()),
+            fingerprint: String::new(),
+            handle: None
+        };
+
+        let update = StreamParams { input_gain: Some(0.5), input_channel: Some(u32::MAX), ..Default::default() };
+        assert_eq!(session.update(&update).err().unwrap().code, ErrorCode::InvalidParams);
+        assert_eq!(session.params.input_gain(), 1.0);
+
+        let update = StreamParams { input_gain: Some(0.5), redundancy: Some(0), ..Default::default() };
+        assert!(session.update(&update).is_err());
+        assert_eq!(session.params.input_gain(), 1.0);
+
+        session.update(&StreamParams { input_gain: Some(0.5), ..Default::default() }).unwrap();
+        assert_eq!(session.params.input_gain(), 0.5);
+    }
+
+    #[test]
+    fn serializes_only_the_params_given() {
+        let params = StreamParams { mute: Some(true), jitter_target: Some(20), ..Default::default() };
+
+        let json = serde_json::to_value(&params).unwrap();
+        assert_eq!(json, serde_json::json!({ "mute": true, "jitter_target": 20 }));
+
+        let params: StreamParams = serde_json::from_value(json).unwrap();
+        assert_eq!((params.mute, params.jitter_target, params.input_gain), (Some(true), Some(20), None));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod message;
+pub mod nat;
+pub mod server;
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+use crate::net;
+use crate::stun::message::{Message, BINDING_RESPONSE};
+
+pub const DEFAULT_SERVERS: &[&str] = &[
+    "stun.l.google.com:19302",
+    "stun1.l.google.com:19302",
+    "stun.cloudflare.com:3478",
+];
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A response to one request of a transaction.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub message: Message,
+    pub from: SocketAddr,
+    pub rtt: Duration,
+}
+
+/// The address one server reported for us.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Binding {
+    pub server: SocketAddr,
+    pub mapped_address: SocketAddr,
+    pub rtt: u32,
+}
+
+/// The external address of a socket, as agreed on by the servers that
+/// answered. If they disagree the NAT maps each destination to a different
+/// port, and the address seen by the peer cannot be predicted.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mapping {
+    /// The external address, the IPv4 one if the socket has both.
+    pub address: SocketAddr,
+    pub server: SocketAddr,
+    /// The external IPv6 address of a dual-stack socket that also has an
+    /// IPv4 one. Without NAT66 this is simply the host's own address.
+    pub address_v6: Option<SocketAddr>,
+    pub bindings: Vec<Binding>,
+    pub consistent: bool,
+}
+
+/// Resolves `host:port` strings to the addresses `local_addr` can reach,
+/// one per family. A dual-stack socket, bound to an IPv6 address, reaches
+/// both. Servers that cannot be resolved are skipped.
+pub fn resolve_servers<S: AsRef<str>>(servers: &[S], local_addr: SocketAddr) -> Vec<SocketAddr> {
+    servers.iter()
+        .filter_map(|server| match server.as_ref().to_socket_addrs() {
+            Ok(addrs) => Some(addrs),
+            Err(err) => {
+                eprintln!("Could not resolve STUN server {}: {}", server.as_ref(), err);
+                None
+            }
+        })
+        .flat_map(|addrs| {
+            let addrs: Vec<SocketAddr> = addrs.map(net::canonical).collect();
+            let v4 = addrs.iter().find(|addr| addr.is_ipv4()).copied();
+            let v6 = addrs.iter().find(|addr| addr.is_ipv6()).copied();
+
+            match local_addr {
+                SocketAddr::V4(_) => vec![v4],
+                SocketAddr::V6(_) => vec![v4, v6]
+            }.into_iter().flatten()
+        })
+        .collect()
+}
+
+/// Sends all requests at once and collects the responses, retransmitting
+/// unanswered requests until `timeout`. Responses are returned in the
+/// order of the requests.
+pub fn transact(conn: &UdpSocket, requests: &[(SocketAddr, Message)], timeout: Duration) -> Result<Vec<Option<Response>>> {
+    let previous_timeout = conn.read_timeout()?;
+    let start = Instant::now();
+
+    let mut pending: HashMap<[u8; 12], usize> = requests.iter()
+        .enumerate()
+        .map(|(i, (_, message))| (message.transaction_id, i))
+        .collect();
+    let mut responses = vec![None; requests.len()];
+    let mut last_sent: Option<Instant> = None;
+    let mut buffer = [0u8; 1024];
+
+    while !pending.is_empty() && start.elapsed() < timeout {
+        if last_sent.is_none_or(|last_sent| last_sent.elapsed() >= RETRANSMIT_INTERVAL) {
+            for (addr, message) in requests {
+                if pending.contains_key(&message.transaction_id) {
+                    if let Err(err) = net::send_to(conn, &message.to_buffer(), *addr) {
+                        eprintln!("Error sending STUN request to {}: {}", addr, err);
+                    }
+                }
+            }
+            last_sent = Some(Instant::now());
+        }
+
+        let wait = RETRANSMIT_INTERVAL.min(timeout.saturating_sub(start.elapsed())).max(Duration::from_millis(1));
+        conn.set_read_timeout(Some(wait))?;
+
+        let (len, from) = match net::recv_from(conn, &mut buffer) {
+            Ok(received) => received,
+            Err(_) => continue
+        };
+
+        let message = match Message::from_buffer(&buffer[..len]) {
+            Ok(message) => message,
+            Err(_) => continue
+        };
+
+        if let Some(i) = pending.remove(&message.transaction_id) {
+            responses[i] = Some(Response {
+                message,
+                from,
+                rtt: start.elapsed()
+            });
+        }
+    }
+
+    conn.set_read_timeout(previous_timeout)?;
+
+    Ok(responses)
+}
+
+/// Queries all servers in parallel for the external address of `conn`.
+pub fn query_external_address<S: AsRef<str>>(conn: &UdpSocket, servers: &[S], timeout: Duration) -> Result<Mapping> {
+    let servers = resolve_servers(servers, conn.local_addr()?);
+    if servers.is_empty() {
+        return Err(anyhow!("No usable STUN server"));
+    }
+
+    let requests = servers.iter()
+        .map(|server| Ok((*server, Message::binding_request()?)))
+        .collect::<Result<Vec<(SocketAddr, Message)>>>()?;
+
+    let bindings: Vec<Binding> = transact(conn, &requests, timeout)?
+        .into_iter()
+        .zip(servers.iter())
+        .filter_map(|(response, server)| {
+            let response = response?;
+            if response.message.message_type != BINDING_RESPONSE {
+                return None;
+            }
+
+            Some(Binding {
+                server: *server,
+                mapped_address: response.message.mapped_address()?,
+                rtt: response.rtt.as_millis() as u32
+            })
+        })
+        .collect();
+
+    let (v4, v6): (Vec<Binding>, Vec<Binding>) = bindings.iter()
+        .cloned()
+        .partition(|binding| binding.mapped_address.is_ipv4());
+
+    let best_v4 = best_binding(&v4);
+    let best_v6 = best_binding(&v6);
+
+    let consistent = best_v4.is_none_or(|(_, consistent)| consistent) && best_v6.is_none_or(|(_, consistent)| consistent);
+    if !consistent {
+        eprintln!("STUN servers disagree on the external address: {:?}", bindings);
+    }
+
+    let (best, address_v6) = match (best_v4, best_v6) {
+        (Some((best, _)), v6) => (best, v6.map(|(best_v6, _)| best_v6.mapped_address)),
+        (None, Some((best, _))) => (best, None),
+        (None, None) => return Err(anyhow!("No STUN server responded"))
+    };
+
+    Ok(Mapping {
+        address: best.mapped_address,
+        server: best.server,
+        address_v6,
+        bindings,
+        consistent
+    })
+}
+
+/// Picks the address most servers agree on, then the fastest server, and
+/// whether all servers agreed.
+fn best_binding(bindings: &[Binding]) -> Option<(&Binding, bool)> {
+    let mut votes: HashMap<SocketAddr, usize> = HashMap::new();
+    for binding in bindings {
+        *votes.entry(binding.mapped_address).or_insert(0) += 1;
+    }
+
+    let best = bindings.iter()
+        .max_by_key(|binding| (votes[&binding.mapped_address], std::cmp::Reverse(binding.rtt)))?;
+
+    Some((best, votes.len() == 1))
+}
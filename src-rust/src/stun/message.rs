@@ -0,0 +1,318 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Result, anyhow};
+
+use crate::util;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_INDICATION: u16 = 0x0011;
+pub const BINDING_RESPONSE: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const SOFTWARE: u16 = 0x8022;
+const RESPONSE_ORIGIN: u16 = 0x802b;
+const OTHER_ADDRESS: u16 = 0x802c;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    XorMappedAddress(SocketAddr),
+    /// Asks the server to answer from its other IP address and/or port
+    /// (RFC 5780).
+    ChangeRequest { ip: bool, port: bool },
+    ResponseOrigin(SocketAddr),
+    OtherAddress(SocketAddr),
+    Software(String),
+    Unknown(u16, Vec<u8>),
+}
+
+/// A STUN message (RFC 5389), limited to what binding requests need.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub message_type: u16,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<Attribute>,
+}
+
+impl Message {
+    pub fn new(message_type: u16, transaction_id: [u8; 12]) -> Self {
+        Self {
+            message_type,
+            transaction_id,
+            attributes: Vec::new()
+        }
+    }
+
+    pub fn binding_request() -> Result<Self> {
+        Ok(Message::new(BINDING_REQUEST, random_transaction_id()?))
+    }
+
+    pub fn binding_indication() -> Result<Self> {
+        Ok(Message::new(BINDING_INDICATION, random_transaction_id()?))
+    }
+
+    pub fn with_attribute(mut self, attribute: Attribute) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    /// The address the server saw the request coming from.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let xor_mapped = self.attributes.iter().find_map(|attribute| match attribute {
+            Attribute::XorMappedAddress(addr) => Some(*addr),
+            _ => None
+        });
+
+        xor_mapped.or_else(|| self.attributes.iter().find_map(|attribute| match attribute {
+            Attribute::MappedAddress(addr) => Some(*addr),
+            _ => None
+        }))
+    }
+
+    pub fn other_address(&self) -> Option<SocketAddr> {
+        self.attributes.iter().find_map(|attribute| match attribute {
+            Attribute::OtherAddress(addr) => Some(*addr),
+            _ => None
+        })
+    }
+
+    pub fn change_request(&self) -> Option<(bool, bool)> {
+        self.attributes.iter().find_map(|attribute| match attribute {
+            Attribute::ChangeRequest { ip, port } => Some((*ip, *port)),
+            _ => None
+        })
+    }
+
+    /// Whether a datagram looks like a STUN message, as opposed to audio.
+    pub fn is_stun(buffer: &[u8]) -> bool {
+        buffer.len() >= 20 &&
+            buffer[0] & 0xc0 == 0 &&
+            u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) == MAGIC_COOKIE
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for attribute in &self.attributes {
+            let (attribute_type, value) = match attribute {
+                Attribute::MappedAddress(addr) => (MAPPED_ADDRESS, encode_address(*addr, None)),
+                Attribute::XorMappedAddress(addr) => (XOR_MAPPED_ADDRESS, encode_address(*addr, Some(&self.transaction_id))),
+                Attribute::ChangeRequest { ip, port } => {
+                    let flags = ((*ip as u32) << 2) | ((*port as u32) << 1);
+                    (CHANGE_REQUEST, flags.to_be_bytes().to_vec())
+                },
+                Attribute::ResponseOrigin(addr) => (RESPONSE_ORIGIN, encode_address(*addr, None)),
+                Attribute::OtherAddress(addr) => (OTHER_ADDRESS, encode_address(*addr, None)),
+                Attribute::Software(software) => (SOFTWARE, software.as_bytes().to_vec()),
+                Attribute::Unknown(attribute_type, value) => (*attribute_type, value.clone()),
+            };
+
+            body.extend_from_slice(&attribute_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(&value);
+
+            while body.len() % 4 != 0 {
+                body.push(0);
+            }
+        }
+
+        let mut buffer = Vec::with_capacity(20 + body.len());
+        buffer.extend_from_slice(&self.message_type.to_be_bytes());
+        buffer.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buffer.extend_from_slice(&self.transaction_id);
+        buffer.extend_from_slice(&body);
+
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Message> {
+        if !Message::is_stun(buffer) {
+            return Err(anyhow!("Not a STUN message"));
+        }
+
+        let message_type = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buffer[8..20]);
+
+        if buffer.len() < 20 + length {
+            return Err(anyhow!("STUN message truncated"));
+        }
+
+        let mut attributes = Vec::new();
+        let mut offset = 20;
+
+        while offset + 4 <= 20 + length {
+            let attribute_type = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]);
+            let attribute_length = u16::from_be_bytes([buffer[offset + 2], buffer[offset + 3]]) as usize;
+            let start = offset + 4;
+            let end = start + attribute_length;
+
+            if end > buffer.len() {
+                return Err(anyhow!("STUN attribute truncated"));
+            }
+            let value = &buffer[start..end];
+
+            let attribute = match attribute_type {
+                MAPPED_ADDRESS => Attribute::MappedAddress(decode_address(value, None)?),
+                XOR_MAPPED_ADDRESS => Attribute::XorMappedAddress(decode_address(value, Some(&transaction_id))?),
+                CHANGE_REQUEST if value.len() == 4 => Attribute::ChangeRequest {
+                    ip: value[3] & 0x04 != 0,
+                    port: value[3] & 0x02 != 0
+                },
+                RESPONSE_ORIGIN => Attribute::ResponseOrigin(decode_address(value, None)?),
+                OTHER_ADDRESS => Attribute::OtherAddress(decode_address(value, None)?),
+                SOFTWARE => Attribute::Software(String::from_utf8_lossy(value).to_string()),
+                _ => Attribute::Unknown(attribute_type, value.to_vec())
+            };
+            attributes.push(attribute);
+
+            offset = end + (4 - attribute_length % 4) % 4;
+        }
+
+        Ok(Message {
+            message_type,
+            transaction_id,
+            attributes
+        })
+    }
+}
+
+/// Transaction ids have to be unpredictable, or anyone on the path could
+/// answer for the server (RFC 5389, section 6).
+fn random_transaction_id() -> Result<[u8; 12]> {
+    let mut transaction_id = [0u8; 12];
+    util::random_bytes(&mut transaction_id)?;
+
+    Ok(transaction_id)
+}
+
+/// XOR mask for an address, the magic cookie followed by the transaction id.
+fn xor_mask(transaction_id: &[u8; 12]) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction_id);
+    mask
+}
+
+fn encode_address(addr: SocketAddr, transaction_id: Option<&[u8; 12]>) -> Vec<u8> {
+    let mask = transaction_id.map(xor_mask).unwrap_or([0u8; 16]);
+    let mut value = vec![0u8];
+
+    let (family, ip) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    value.push(family);
+
+    let port = addr.port() ^ u16::from_be_bytes([mask[0], mask[1]]);
+    value.extend_from_slice(&port.to_be_bytes());
+
+    for (i, b) in ip.iter().enumerate() {
+        value.push(b ^ mask[i]);
+    }
+
+    value
+}
+
+fn decode_address(value: &[u8], transaction_id: Option<&[u8; 12]>) -> Result<SocketAddr> {
+    if value.len() < 8 {
+        return Err(anyhow!("STUN address too short"));
+    }
+
+    let mask = transaction_id.map(xor_mask).unwrap_or([0u8; 16]);
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ u16::from_be_bytes([mask[0], mask[1]]);
+
+    let ip = match value[1] {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        },
+        0x02 if value.len() >= 20 => {
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+        _ => return Err(anyhow!("Invalid STUN address family"))
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample IPv4 response from RFC 5769, section 2.2.
+    const RFC_5769_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42,
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20,
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+        0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    #[test]
+    fn parses_the_rfc_5769_response() {
+        let message = Message::from_buffer(&RFC_5769_RESPONSE).unwrap();
+
+        assert_eq!(message.message_type, BINDING_RESPONSE);
+        assert_eq!(message.mapped_address(), Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(message.attributes[0], Attribute::Software("test vector".to_string()));
+        // MESSAGE-INTEGRITY and FINGERPRINT are not needed for bindings
+        assert!(matches!(message.attributes[2], Attribute::Unknown(0x0008, _)));
+        assert!(matches!(message.attributes[3], Attribute::Unknown(0x8028, _)));
+    }
+
+    #[test]
+    fn round_trips_every_attribute() {
+        let message = Message::binding_request().unwrap()
+            .with_attribute(Attribute::ChangeRequest { ip: true, port: false })
+            .with_attribute(Attribute::XorMappedAddress("[2001:db8::1]:4242".parse().unwrap()))
+            .with_attribute(Attribute::MappedAddress("198.51.100.7:9".parse().unwrap()))
+            .with_attribute(Attribute::ResponseOrigin("192.0.2.1:3478".parse().unwrap()))
+            .with_attribute(Attribute::OtherAddress("192.0.2.2:3479".parse().unwrap()))
+            .with_attribute(Attribute::Software("odd".to_string()));
+
+        let buffer = message.to_buffer();
+        assert_eq!(buffer.len() % 4, 0);
+
+        let parsed = Message::from_buffer(&buffer).unwrap();
+        assert_eq!(parsed.message_type, BINDING_REQUEST);
+        assert_eq!(parsed.transaction_id, message.transaction_id);
+        assert_eq!(parsed.attributes, message.attributes);
+        assert_eq!(parsed.change_request(), Some((true, false)));
+        assert_eq!(parsed.mapped_address(), Some("[2001:db8::1]:4242".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_what_is_not_stun() {
+        assert!(!Message::is_stun(&RFC_5769_RESPONSE[..19]));
+        assert!(Message::from_buffer(&[0x80; 40]).is_err());
+
+        // Shorter than the length in the header
+        assert!(Message::from_buffer(&RFC_5769_RESPONSE[..60]).is_err());
+    }
+
+    #[test]
+    fn transaction_ids_are_random() {
+        let first = Message::binding_request().unwrap();
+        let second = Message::binding_request().unwrap();
+
+        assert_ne!(first.transaction_id, second.transaction_id);
+        assert_ne!(first.transaction_id, [0u8; 12]);
+    }
+}
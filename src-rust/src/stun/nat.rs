@@ -0,0 +1,297 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use local_ip_address::list_afinet_netifas;
+use serde::Serialize;
+
+use crate::net;
+use crate::stun::client::{self, Binding};
+use crate::stun::message::{Attribute, Message, BINDING_RESPONSE};
+use crate::util;
+
+/// How long each probe waits for its answer. Probes that are meant to go
+/// unanswered take this long, so it is shorter than a plain query's.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a NAT maps or filters, in the terms of RFC 4787.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Behavior {
+    /// The local address is public, there is no NAT.
+    NoNat,
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    /// Depends on the destination, but the servers did not allow telling
+    /// whether only the address or also the port matters.
+    Dependent,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Connectivity {
+    /// Peers can reach this host without any hole punching.
+    Direct,
+    /// Hole punching works with almost any peer.
+    Likely,
+    /// Hole punching works if the peer's NAT is endpoint independent.
+    Possible,
+    /// Hole punching only works with peers that have no NAT, a relay is
+    /// needed otherwise.
+    Unlikely,
+    Unknown,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NatReport {
+    pub local_address: SocketAddr,
+    pub mapped_address: Option<SocketAddr>,
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+    pub hairpinning: Option<bool>,
+    pub connectivity: Connectivity,
+    pub summary: String,
+    pub bindings: Vec<Binding>,
+}
+
+/// Classifies the NAT in front of this host with a series of binding
+/// requests from fresh sockets. `timeout` applies to each probe.
+///
+/// Telling address and port dependent behavior apart, and testing
+/// filtering at all, needs a server with an alternate address (RFC 5780),
+/// such as `stun-server --alternate`. With ordinary servers, mapping is
+/// only compared across the servers given.
+pub fn diagnose<S: AsRef<str>>(servers: &[S], timeout: Duration) -> Result<NatReport> {
+    let conn = UdpSocket::bind("0.0.0.0:0")?;
+    let local_port = conn.local_addr()?.port();
+
+    let servers = client::resolve_servers(servers, conn.local_addr()?);
+    let primary = *servers.first().ok_or(anyhow!("No usable STUN server"))?;
+
+    // Test I: the mapped address and the server's alternate address
+    let response = binding(&conn, primary, None, timeout)?
+        .ok_or(anyhow!("No answer from {}, UDP may be blocked", primary))?;
+    let mapped_address = response.mapped_address().ok_or(anyhow!("No mapped address from {}", primary))?;
+    let other_address = response.other_address();
+    let mut bindings = vec![Binding { server: primary, mapped_address, rtt: 0 }];
+
+    let local_ips: Vec<_> = list_afinet_netifas()
+        .map_err(|err| anyhow!("{:?}", err))?
+        .into_iter()
+        .map(|(_, ip)| ip)
+        .filter(|ip| ip.is_ipv4())
+        .collect();
+    let no_nat = local_ips.contains(&mapped_address.ip()) && mapped_address.port() == local_port;
+    let local_address = match local_ips.iter().find(|ip| !ip.is_loopback()) {
+        _ if no_nat => mapped_address,
+        Some(ip) => SocketAddr::new(*ip, local_port),
+        None => conn.local_addr()?
+    };
+
+    // Test II: another IP address of the server, or another server
+    let second = match other_address {
+        Some(other_address) => Some(SocketAddr::new(other_address.ip(), primary.port())),
+        None => servers.iter().find(|server| server.ip() != primary.ip()).copied()
+    };
+
+    let mut mapping = Behavior::Unknown;
+
+    if no_nat {
+        mapping = Behavior::NoNat;
+    } else if let Some(second) = second {
+        if let Some(second_mapped) = binding(&conn, second, None, timeout)?.and_then(|response| response.mapped_address()) {
+            bindings.push(Binding { server: second, mapped_address: second_mapped, rtt: 0 });
+
+            mapping = if second_mapped == mapped_address {
+                Behavior::EndpointIndependent
+            } else {
+                // Test III: the alternate address, which differs from
+                // test II only in the port
+                match other_address {
+                    Some(other_address) => match binding(&conn, other_address, None, timeout)?.and_then(|response| response.mapped_address()) {
+                        Some(third_mapped) => {
+                            bindings.push(Binding { server: other_address, mapped_address: third_mapped, rtt: 0 });
+
+                            match third_mapped == second_mapped {
+                                true => Behavior::AddressDependent,
+                                false => Behavior::AddressAndPortDependent
+                            }
+                        },
+                        None => Behavior::Dependent
+                    },
+                    None => Behavior::Dependent
+                }
+            };
+        }
+    }
+
+    let filtering = match other_address {
+        Some(_) => {
+            // Answers from addresses the request was not sent to only get
+            // through if filtering allows them. The tests above sent to the
+            // alternate addresses, which lets their answers through on that
+            // socket, so these run on one that has only talked to `primary`.
+            let conn = UdpSocket::bind("0.0.0.0:0")?;
+            let changed_address = Attribute::ChangeRequest { ip: true, port: true };
+            let changed_port = Attribute::ChangeRequest { ip: false, port: true };
+
+            if binding(&conn, primary, Some(changed_address), timeout)?.is_some() {
+                match no_nat {
+                    true => Behavior::NoNat,
+                    false => Behavior::EndpointIndependent
+                }
+            } else if binding(&conn, primary, Some(changed_port), timeout)?.is_some() {
+                Behavior::AddressDependent
+            } else {
+                Behavior::AddressAndPortDependent
+            }
+        },
+        None => Behavior::Unknown
+    };
+
+    let hairpinning = match no_nat {
+        true => None,
+        false => Some(hairpin(&conn, mapped_address, timeout)?)
+    };
+
+    let connectivity = match (mapping, filtering) {
+        (Behavior::NoNat, Behavior::NoNat) | (Behavior::NoNat, Behavior::Unknown) => Connectivity::Direct,
+        (Behavior::NoNat, _) | (Behavior::EndpointIndependent, _) => Connectivity::Likely,
+        (Behavior::AddressDependent, _) => Connectivity::Possible,
+        (Behavior::AddressAndPortDependent, _) | (Behavior::Dependent, _) => Connectivity::Unlikely,
+        (Behavior::Unknown, _) => Connectivity::Unknown,
+    };
+
+    let summary = match connectivity {
+        Connectivity::Direct => "No NAT detected, peers can connect directly".to_string(),
+        Connectivity::Likely => "The NAT keeps the same external address for all peers, direct connections should work".to_string(),
+        Connectivity::Possible => "The NAT changes the external address per peer IP, direct connections only work with peers that have an open NAT".to_string(),
+        Connectivity::Unlikely => "The NAT is symmetric, a relay will be needed unless the peer has no NAT".to_string(),
+        Connectivity::Unknown => "Could not determine the NAT behavior, try adding another STUN server".to_string(),
+    };
+
+    Ok(NatReport {
+        local_address,
+        mapped_address: Some(mapped_address),
+        mapping,
+        filtering,
+        hairpinning,
+        connectivity,
+        summary,
+        bindings
+    })
+}
+
+fn binding(conn: &UdpSocket, server: SocketAddr, attribute: Option<Attribute>, timeout: Duration) -> Result<Option<Message>> {
+    let mut request = Message::binding_request()?;
+    if let Some(attribute) = attribute {
+        request = request.with_attribute(attribute);
+    }
+
+    let response = client::transact(conn, &[(server, request)], timeout)?.remove(0);
+
+    Ok(response
+        .map(|response| response.message)
+        .filter(|message| message.message_type == BINDING_RESPONSE))
+}
+
+/// Sends a datagram to our own external address. It only comes back if the
+/// NAT forwards packets between two of its internal hosts, which peers on
+/// the same network need when they only know each other's public address.
+fn hairpin(conn: &UdpSocket, mapped_address: SocketAddr, timeout: Duration) -> Result<bool> {
+    let previous_timeout = conn.read_timeout()?;
+    let probe = util::random_id()?.to_be_bytes();
+    let start = Instant::now();
+    let mut buffer = [0u8; 1024];
+    let mut result = false;
+
+    net::send_to(conn, &probe, mapped_address)?;
+    conn.set_read_timeout(Some(timeout))?;
+
+    while start.elapsed() < timeout {
+        match net::recv_from(conn, &mut buffer) {
+            Ok((len, _)) if buffer[..len] == probe => {
+                result = true;
+                break;
+            },
+            Ok(_) => continue,
+            Err(_) => break
+        }
+    }
+
+    conn.set_read_timeout(previous_timeout)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::stun::server;
+
+    /// A server on a free port of 127.0.0.1, with its alternate on
+    /// 127.0.0.2 and the next port.
+    fn spawn_server() -> SocketAddr {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let primary: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let alternate: SocketAddr = format!("127.0.0.2:{}", port + 1).parse().unwrap();
+
+        thread::spawn(move || server::serve_with_alternate(primary, alternate));
+        thread::sleep(Duration::from_millis(100));
+
+        primary
+    }
+
+    #[test]
+    fn finds_no_nat_on_loopback() {
+        let server = spawn_server();
+
+        let report = diagnose(&[server.to_string()], Duration::from_millis(300)).unwrap();
+
+        assert!(report.mapped_address.unwrap().ip().is_loopback());
+        assert_eq!(report.mapping, Behavior::NoNat);
+        assert_eq!(report.filtering, Behavior::NoNat);
+        assert_eq!(report.connectivity, Connectivity::Direct);
+        assert_eq!(report.hairpinning, None);
+    }
+
+    #[test]
+    fn leaves_filtering_unknown_without_an_alternate() {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = conn.local_addr().unwrap();
+        thread::spawn(move || server::serve(&conn));
+
+        let report = diagnose(&[server.to_string()], Duration::from_millis(300)).unwrap();
+
+        assert_eq!(report.mapping, Behavior::NoNat);
+        assert_eq!(report.filtering, Behavior::Unknown);
+        assert_eq!(report.connectivity, Connectivity::Direct);
+    }
+
+    #[test]
+    fn fails_when_the_server_does_not_answer() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+
+        let err = diagnose(&[silent.local_addr().unwrap().to_string()], timeout).unwrap_err();
+
+        assert!(err.to_string().contains("UDP may be blocked"));
+        assert!(start.elapsed() < timeout * 10);
+    }
+
+    #[test]
+    fn hairpins_to_an_address_that_answers() {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let own_address = conn.local_addr().unwrap();
+        let elsewhere = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        assert!(hairpin(&conn, own_address, Duration::from_millis(200)).unwrap());
+        assert!(!hairpin(&conn, elsewhere, Duration::from_millis(200)).unwrap());
+    }
+}
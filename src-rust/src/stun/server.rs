@@ -0,0 +1,103 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Result, anyhow};
+
+use crate::net;
+use crate::stun::message::{Attribute, Message, BINDING_REQUEST, BINDING_RESPONSE};
+
+const SOFTWARE: &str = concat!("claudio ", env!("CARGO_PKG_VERSION"));
+
+/// Answers binding requests on `conn` until an error occurs. This is enough
+/// for address discovery in tests and private deployments.
+pub fn serve(conn: &UdpSocket) -> Result<()> {
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let (len, from) = net::recv_from(conn, &mut buffer)?;
+
+        let request = match Message::from_buffer(&buffer[..len]) {
+            Ok(request) if request.message_type == BINDING_REQUEST => request,
+            _ => continue
+        };
+
+        answer(conn, &request, from, None);
+    }
+}
+
+/// Serves on two addresses that differ in both IP and port, which also
+/// makes the NAT behavior tests of RFC 5780 possible. The server listens on
+/// all four combinations and answers change requests from the matching one.
+/// On a single host the alternate can be another loopback address, such as
+/// 127.0.0.2.
+pub fn serve_with_alternate(primary: SocketAddr, alternate: SocketAddr) -> Result<()> {
+    if primary.ip() == alternate.ip() || primary.port() == alternate.port() {
+        return Err(anyhow!("The alternate address needs a different IP and port"));
+    }
+
+    let primary_conn = UdpSocket::bind(primary)?;
+    let alternate_conn = UdpSocket::bind(alternate)?;
+    let (primary, alternate) = (primary_conn.local_addr()?, alternate_conn.local_addr()?);
+
+    // Indexed by [ip][port], 0 is the primary and 1 the alternate
+    let sockets = Arc::new([
+        [Arc::new(primary_conn), Arc::new(UdpSocket::bind(SocketAddr::new(primary.ip(), alternate.port()))?)],
+        [Arc::new(UdpSocket::bind(SocketAddr::new(alternate.ip(), primary.port()))?), Arc::new(alternate_conn)],
+    ]);
+
+    let mut handles = Vec::new();
+
+    for ip in 0..2 {
+        for port in 0..2 {
+            let sockets = sockets.clone();
+
+            handles.push(thread::spawn(move || -> Result<()> {
+                let conn = &sockets[ip][port];
+                let other_address = sockets[1 - ip][1 - port].local_addr()?;
+                let mut buffer = [0u8; 1024];
+
+                loop {
+                    let (len, from) = net::recv_from(conn, &mut buffer)?;
+
+                    let request = match Message::from_buffer(&buffer[..len]) {
+                        Ok(request) if request.message_type == BINDING_REQUEST => request,
+                        _ => continue
+                    };
+
+                    let (change_ip, change_port) = request.change_request().unwrap_or((false, false));
+                    let reply_conn = &sockets[ip ^ change_ip as usize][port ^ change_port as usize];
+
+                    answer(reply_conn, &request, from, Some(other_address));
+                }
+            }));
+        }
+    }
+
+    for handle in handles {
+        handle.join().map_err(|_| anyhow!("STUN server thread panicked"))??;
+    }
+
+    Ok(())
+}
+
+fn answer(conn: &UdpSocket, request: &Message, from: SocketAddr, other_address: Option<SocketAddr>) {
+    let local_addr = match conn.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(err) => return eprintln!("{}", err)
+    };
+
+    let mut response = Message::new(BINDING_RESPONSE, request.transaction_id)
+        .with_attribute(Attribute::XorMappedAddress(from))
+        .with_attribute(Attribute::MappedAddress(from))
+        .with_attribute(Attribute::ResponseOrigin(local_addr))
+        .with_attribute(Attribute::Software(SOFTWARE.to_string()));
+
+    if let Some(other_address) = other_address {
+        response = response.with_attribute(Attribute::OtherAddress(other_address));
+    }
+
+    if let Err(err) = net::send_to(conn, &response.to_buffer(), from) {
+        eprintln!("Error answering {}: {}", from, err);
+    }
+}
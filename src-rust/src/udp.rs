@@ -1,2 +1,7 @@
 pub mod client;
-pub mod packet;
\ No newline at end of file
+pub mod congestion;
+pub mod crypto;
+pub mod keepalive;
+pub mod multipath;
+pub mod packet;
+pub mod peer;
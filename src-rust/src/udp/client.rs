@@ -1,63 +1,199 @@
-
-use std::net::UdpSocket;
+use std::net::{SocketAddr, UdpSocket};
 use std::collections::VecDeque;
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use ringbuf::{Producer, Consumer};
 
-use crate::udp::packet::{Packet, MessageType};
+use crate::udp::packet::{Packet, MessageType, Report, ComfortNoiseDescriptor, ConnectivityCheck};
+use crate::ice;
+use crate::udp::congestion::{RateController, RateStats, ReportBuilder};
+use crate::udp::crypto::{self, SessionCipher, SessionKey};
+use crate::udp::peer::Peer;
+use crate::udp::multipath::{PathMonitor, PathStats};
+use crate::udp::keepalive::Keepalive;
 use crate::audio::AudioConfig;
+use crate::util::{Backoff, Mode};
+use crate::dtx::{self, SilenceDetector, ComfortNoise};
+use crate::net;
 
-pub struct UdpClientConfig {
-    pub remote: String,
-    pub port: String
-}
-
-impl UdpClientConfig {
-    pub fn new(port: &str, remote: &str) -> Self {
-        Self {
-            port: port.to_string(),
-            remote: remote.to_string()
-        }
-    } 
-}
+/// How long the receiver waits for audio before asking the sender to resume.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a loop waiting for packets checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 pub struct UdpClient {
     conn: Arc<UdpSocket>,
+    paths: Vec<Arc<UdpSocket>>,
+    peer: Arc<Mutex<Peer>>,
+    path_monitor: Arc<Mutex<PathMonitor>>,
+    rate_controller: Arc<Mutex<RateController>>,
+    cipher: Option<Arc<SessionCipher>>,
+    handshake_reply: Option<Arc<Vec<u8>>>,
+    report_builder: ReportBuilder,
+    silence_detector: Option<SilenceDetector>,
+    comfort_noise: ComfortNoise,
+    last_descriptor: Option<Instant>,
+    dtx: bool,
+    start: Instant,
     send_sequence_number: u16,
     recv_sequence_number: Option<u16>,
     redundancy: u16,
     send_packet_queue: VecDeque<Packet>,
-    audio_config: AudioConfig
+    audio_config: AudioConfig,
+    backoff: Backoff,
+    running: Arc<AtomicBool>
 }
 
 impl UdpClient {
+    /// Creates a client talking to `remote`. The session id is generated by
+    /// the sending side; a receiver may pass `None` and adopt the id of the
+    /// first packet arriving from `remote`.
     pub fn new(
         conn: Arc<UdpSocket>,
+        remote: SocketAddr,
+        session_id: Option<u32>,
         sequence_number: u16,
         audio_config: AudioConfig,
     ) -> Result<Self> {
         let redundancy = 3u16;
+        let packet_size = Packet::get_header_size() + audio_config.get_frame_size() * 4;
+        let stream_bitrate = (packet_size as u64 * 8 * audio_config.sample_rate as u64 / audio_config.buffer_size.max(1) as u64) as u32;
+        let rate_controller = RateController::new(redundancy as u8, stream_bitrate);
         let send_packet_queue = VecDeque::with_capacity(redundancy as usize);
         let send_sequence_number = sequence_number;
         let recv_sequence_number = Option::None;
+        let peer = Arc::new(Mutex::new(Peer::new(remote, session_id)));
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_secs(5));
 
         let client = Self {
             conn,
+            paths: Vec::new(),
+            peer,
+            path_monitor: Arc::new(Mutex::new(PathMonitor::new())),
+            rate_controller: Arc::new(Mutex::new(rate_controller)),
+            cipher: None,
+            handshake_reply: None,
+            report_builder: ReportBuilder::new(),
+            silence_detector: None,
+            comfort_noise: ComfortNoise::new(),
+            last_descriptor: None,
+            dtx: false,
+            start: Instant::now(),
             send_sequence_number,
             recv_sequence_number,
             redundancy,
             audio_config,
-            send_packet_queue
+            send_packet_queue,
+            backoff,
+            running: Arc::new(AtomicBool::new(true))
         };
 
         Ok(client)
     }
 
+    /// Adds a socket over which every packet is sent in addition to the main
+    /// one. The receiver drops whichever copy arrives last.
+    pub fn add_path(&mut self, conn: Arc<UdpSocket>) {
+        self.paths.push(conn);
+    }
+
+    /// Encrypts every datagram of the session with `key`, and drops those
+    /// from the peer that fail authentication. Both ends need the same key
+    /// and opposite modes.
+    pub fn set_key(&mut self, key: &SessionKey, mode: Mode) {
+        self.cipher = Some(Arc::new(SessionCipher::new(key, mode)));
+    }
+
+    /// Sends `datagram` again whenever a handshake message arrives from the
+    /// peer, which means our last one was lost.
+    pub fn set_handshake_reply(&mut self, datagram: Vec<u8>) {
+        self.handshake_reply = Some(Arc::new(datagram));
+    }
+
+    /// Stops sending audio while the input is silent and sends comfort noise
+    /// descriptors instead.
+    pub fn set_dtx(&mut self, enabled: bool) {
+        self.silence_detector = match enabled {
+            true => Some(SilenceDetector::new(self.audio_config.sample_rate, self.audio_config.buffer_size)),
+            false => None
+        };
+    }
+
+    /// Starts sending keepalive packets to the peer, wherever it currently
+    /// is, until the returned handle is dropped.
+    pub fn start_keepalive(&self, interval: Duration) -> Keepalive {
+        let peer = self.peer.clone();
+        let cipher = self.cipher.clone();
+        let sequence_number = self.send_sequence_number;
+
+        Keepalive::start(self.conn.clone(), interval, move || {
+            // A receiver that has not heard from the sender yet does not know
+            // the session id, but its keepalives still open the mapping.
+            let peer = peer.lock().unwrap();
+            let session_id = peer.session_id().unwrap_or(0);
+            let buffer = Packet::keepalive(session_id, sequence_number).to_buffer();
+
+            let buffer = match &cipher {
+                Some(cipher) => cipher.seal(&buffer).ok()?,
+                None => buffer
+            };
+
+            Some((buffer, peer.addr()))
+        })
+    }
+
+    /// Changes the copies of each packet sent, see `RateController`.
+    pub fn set_redundancy(&self, redundancy: u8) -> Result<()> {
+        self.rate_controller.lock().unwrap().set_redundancy(redundancy)
+    }
+
+    pub fn check_redundancy(&self, redundancy: u8) -> Result<u8> {
+        self.rate_controller.lock().unwrap().check_redundancy(redundancy)
+    }
+
+    pub fn set_max_bitrate(&self, bitrate: Option<u32>) {
+        self.rate_controller.lock().unwrap().set_max_bitrate(bitrate);
+    }
+
+    /// Ends the send, receive and control loops of every clone of this
+    /// client.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Statistics for each path packets have arrived on.
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        self.path_monitor.lock().unwrap().stats()
+    }
+
+    /// The sending rate currently chosen in response to receiver reports.
+    pub fn rate_stats(&self) -> RateStats {
+        self.rate_controller.lock().unwrap().stats()
+    }
+
+    /// Time since the last packet from the peer, or `None` if nothing has
+    /// arrived yet.
+    pub fn idle_time(&self) -> Option<Duration> {
+        self.peer.lock().unwrap().idle_time()
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.peer.lock().unwrap().addr()
+    }
+
+    fn session_id(&self) -> u32 {
+        self.peer.lock().unwrap().session_id().unwrap_or(0)
+    }
+
     fn get_packet_size(&self) -> usize {
         Packet::get_header_size() + (self.audio_config.get_frame_size() * 4)
     }
@@ -66,51 +202,224 @@ impl UdpClient {
         self.get_packet_size() * self.redundancy as usize
     }
 
+    fn seal(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(buffer),
+            None => Ok(buffer.to_vec())
+        }
+    }
+
     pub fn send(&mut self, samples: &[f32]) -> Result<()> {
-        self.send_sequence_number += 1;
+        self.send_sequence_number = self.send_sequence_number.wrapping_add(1);
+        let redundancy = self.rate_controller.lock().unwrap().redundancy();
 
         let packet = Packet::new(
             MessageType::Audio,
+            self.session_id(),
             self.send_sequence_number,
-            0,
-            3,
+            self.start.elapsed().as_millis() as u32,
+            redundancy,
             self.audio_config.sample_rate,
             self.audio_config.get_channel_count(),
             self.audio_config.buffer_size,
             samples.to_vec(),
         );
 
-        if self.send_packet_queue.len() == 3 {
+        if self.send_packet_queue.len() == self.redundancy as usize {
             self.send_packet_queue.pop_back();
         }
         self.send_packet_queue.push_front(packet);
 
         let mut buffer = Vec::with_capacity(self.get_redundant_packet_size());
 
-        for packet in &self.send_packet_queue {
+        for packet in self.send_packet_queue.iter().take(redundancy as usize) {
             buffer.append(&mut packet.to_buffer())
         }
 
-        self.conn.send(&buffer)?;
+        self.send_buffer(&buffer)
+    }
+
+    /// Sends a frame of audio, or a comfort noise descriptor if the frame is
+    /// silent and discontinuous transmission is enabled.
+    pub fn send_frame(&mut self, samples: &[f32]) -> Result<()> {
+        let detector = match &mut self.silence_detector {
+            Some(detector) => detector,
+            None => return self.send(samples)
+        };
+
+        if detector.process(samples) {
+            self.dtx = false;
+            return self.send(samples);
+        }
+
+        let level = detector.noise_level();
+
+        if !self.dtx {
+            // Redundant copies of the audio before the silence would
+            // otherwise be replayed when transmission restarts.
+            self.send_packet_queue.clear();
+            self.last_descriptor = None;
+            self.dtx = true;
+        }
+
+        if self.last_descriptor.is_some_and(|last| last.elapsed() < dtx::DESCRIPTOR_INTERVAL) {
+            return Ok(());
+        }
+        self.last_descriptor = Some(Instant::now());
+
+        let descriptor = ComfortNoiseDescriptor {
+            session_id: self.session_id(),
+            sequence_number: self.send_sequence_number,
+            level
+        };
+
+        self.send_buffer(&descriptor.to_buffer())
+    }
 
-        Ok(())
+    /// Sends a buffer over every path. Succeeds if at least one path could
+    /// send it. Each copy is sealed on its own, with its own counter, so that
+    /// the peer's replay check lets every copy through to the path statistics.
+    fn send_buffer(&self, buffer: &[u8]) -> Result<()> {
+        let remote_addr = self.remote_addr();
+        let mut result = self.seal(buffer)
+            .and_then(|sealed| Ok(net::send_to(&self.conn, &sealed, remote_addr).map(|_| ())?));
+
+        for path in &self.paths {
+            let sent = self.seal(buffer)
+                .and_then(|sealed| Ok(net::send_to(path, &sealed, remote_addr)?));
+
+            match sent {
+                Ok(_) => result = Ok(()),
+                Err(err) => eprintln!("Error sending on path {:?}: {}", path.local_addr(), err)
+            };
+        }
+
+        result
+    }
+
+    /// Sends a header-only resume packet, telling the peer where this end
+    /// can currently be reached.
+    pub fn send_resume(&mut self) -> Result<()> {
+        let packet = Packet::resume(self.session_id(), self.send_sequence_number);
+
+        self.send_buffer(&packet.to_buffer())
     }
     
     pub fn recv(&mut self) -> VecDeque<Packet> {
-        let mut buffer = vec![0u8; self.get_redundant_packet_size()];
+        let mut buffer = vec![0u8; self.get_redundant_packet_size() + crypto::OVERHEAD];
+
+        let (len, addr) = match net::recv_from(&self.conn, &mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut {
+                    eprintln!("Error receiving packet: {}", e);
+                }
+                return VecDeque::<Packet>::new();
+            }
+        };
+        let buffer = &buffer[..len];
 
-        match self.conn.recv(&mut buffer) {
-            Err(e) => eprintln!("Error receiving packet: {}", e),
-            _ => ()
+        let (message_type, session_id) = match Packet::peek(buffer) {
+            Some(peeked) => peeked,
+            None => return VecDeque::<Packet>::new()
         };
 
-        if buffer.len() < self.get_packet_size() {
+        // The peer may still be checking candidates after this end has
+        // picked one and started streaming.
+        if message_type == MessageType::Check {
+            if let Ok(check) = ConnectivityCheck::from_buffer(buffer) {
+                ice::respond(&self.conn, &check, addr);
+            }
             return VecDeque::<Packet>::new();
         }
 
-        let first_packet_buffer = &buffer[0..self.get_packet_size()];
-        let first_packet = Packet::from_buffer(first_packet_buffer);
+        if message_type == MessageType::Handshake {
+            if let Some(reply) = &self.handshake_reply {
+                if addr == self.remote_addr() {
+                    if let Err(err) = net::send_to(&self.conn, reply, addr) {
+                        eprintln!("Error repeating handshake: {}", err);
+                    }
+                }
+            }
+            return VecDeque::<Packet>::new();
+        }
 
+        // Decrypting before anything else means forged packets can neither
+        // be played nor move the session to another address.
+        let opened;
+        let buffer = match &self.cipher {
+            Some(cipher) => match cipher.open(buffer) {
+                Ok(buffer) => {
+                    opened = buffer;
+                    &opened[..]
+                },
+                Err(_) => return VecDeque::<Packet>::new()
+            },
+            None => buffer
+        };
+        let len = buffer.len();
+
+        if !self.peer.lock().unwrap().accept(addr, session_id) {
+            return VecDeque::<Packet>::new();
+        }
+
+        if message_type == MessageType::Report {
+            match Report::from_buffer(buffer) {
+                Ok(report) => self.rate_controller.lock().unwrap().on_report(&report),
+                Err(e) => eprintln!("Error parsing report: {}", e)
+            };
+            return VecDeque::<Packet>::new();
+        }
+
+        if message_type == MessageType::ComfortNoise {
+            match ComfortNoiseDescriptor::from_buffer(buffer) {
+                Ok(descriptor) => {
+                    self.comfort_noise.set_level(descriptor.level);
+                    self.dtx = true;
+                },
+                Err(e) => eprintln!("Error parsing comfort noise descriptor: {}", e)
+            };
+            return VecDeque::<Packet>::new();
+        }
+
+        let first_packet_buffer = &buffer[0..std::cmp::min(len, self.get_packet_size())];
+        let first_packet = match Packet::from_buffer(first_packet_buffer) {
+            Ok(packet) => packet,
+            Err(e) => {
+                eprintln!("Error parsing packet: {}", e);
+                return VecDeque::<Packet>::new();
+            }
+        };
+
+        if first_packet.message_type != MessageType::Audio {
+            return VecDeque::<Packet>::new();
+        }
+
+        // With several paths the same packet arrives more than once, only the
+        // first copy is played.
+        let duplicate = match self.recv_sequence_number {
+            Some(s) => (first_packet.sequence_number.wrapping_sub(s) as i16) <= 0,
+            None => false
+        };
+
+        self.path_monitor.lock().unwrap().record(
+            addr,
+            first_packet.sequence_number,
+            first_packet.timestamp,
+            duplicate
+        );
+
+        if duplicate {
+            return VecDeque::<Packet>::new();
+        }
+
+        self.report_builder.record(first_packet.sequence_number, first_packet.timestamp);
+
+        // The sender announces how many copies each datagram carries, which
+        // changes with the network conditions.
+        let redundancy = (first_packet.redundancy as u16)
+            .min(self.redundancy)
+            .min((len / self.get_packet_size()) as u16);
 
         let new_sequence_number = first_packet.sequence_number;
         let mut current_sequence_number = new_sequence_number;
@@ -118,20 +427,23 @@ impl UdpClient {
         let mut packets = VecDeque::with_capacity(self.redundancy as usize);
         packets.push_front(first_packet);
 
-        for i in 1..(self.redundancy - 1) {
+        for i in 1..redundancy {
             let prev_sequence_number = match self.recv_sequence_number {
                 Some(s) => s,
-                None => current_sequence_number - 1
+                None => current_sequence_number.wrapping_sub(1)
             };
 
-            if prev_sequence_number + 1 == current_sequence_number {
+            if prev_sequence_number.wrapping_add(1) == current_sequence_number {
                 break;
             }
-            
+
             let packet_buffer = &buffer[self.get_packet_size() * i as usize..self.get_packet_size() * (i + 1) as usize];
             
-            let packet = Packet::from_buffer(packet_buffer);
-            current_sequence_number -= 1;
+            let packet = match Packet::from_buffer(packet_buffer) {
+                Ok(packet) => packet,
+                Err(_) => break
+            };
+            current_sequence_number = current_sequence_number.wrapping_sub(1);
             packets.push_front(packet);
         }
 
@@ -142,32 +454,193 @@ impl UdpClient {
 
     pub fn send_loop(&mut self, mut input_consumer: Consumer<f32>) {
         println!("Sending...");
-        loop {
+        while self.is_running() {
             if input_consumer.len() < self.audio_config.get_frame_size() {
                 continue;
             }
 
             let mut buffer = vec![0f32; self.audio_config.get_frame_size()];
             input_consumer.pop_slice(&mut buffer);
+
+            // While the path is down, audio is dropped and the peer is
+            // re-probed with resume packets at increasing intervals.
+            if self.backoff.is_active() {
+                if !self.backoff.ready() {
+                    continue;
+                }
+
+                match self.send_resume() {
+                    Ok(_) => {
+                        println!("Path to {} restored", self.remote_addr());
+                        self.backoff.reset();
+                    },
+                    Err(_) => self.backoff.failed()
+                };
+                continue;
+            }
             
-            match self.send(&buffer) {
-                Ok(_) => (),
-                Err(err) => eprintln!("{}", err)
-            };
+            if let Err(err) = self.send_frame(&buffer) {
+                eprintln!("{}, re-probing {}", err, self.remote_addr());
+                self.backoff.failed();
+            }
+        }
+    }
+
+    /// Handles packets arriving at the sending side, such as resume requests
+    /// from a receiver whose address has changed.
+    pub fn control_loop(&mut self) {
+        if let Err(err) = self.conn.set_read_timeout(Some(STOP_POLL_INTERVAL)) {
+            eprintln!("{}", err);
+        }
+
+        while self.is_running() {
+            self.recv();
         }
     }
 
     pub fn recv_loop(&mut self, mut output_producer: Producer<f32>) {
         println!("Receiving...");
-        loop {
+        // Wake up at least once per frame, so that comfort noise can be
+        // generated in time while the sender is silent.
+        let frame_duration = Duration::from_secs_f64(
+            self.audio_config.buffer_size as f64 / self.audio_config.sample_rate as f64
+        );
+        if let Err(err) = self.conn.set_read_timeout(Some(frame_duration.max(Duration::from_millis(1)))) {
+            eprintln!("{}", err);
+        }
+
+        let frame_size = self.audio_config.get_frame_size();
+        let mut noise = vec![0f32; frame_size];
+
+        while self.is_running() {
             let packets = self.recv();
-            let mut samples = Vec::with_capacity(self.audio_config.get_frame_size() * packets.len());
+
+            self.send_report_if_due();
+
+            if packets.is_empty() {
+                if self.dtx {
+                    while output_producer.remaining() >= frame_size {
+                        self.comfort_noise.fill(&mut noise);
+                        output_producer.push_slice(&noise);
+                    }
+                }
+
+                self.resume_if_idle();
+                continue;
+            }
+            self.backoff.reset();
+
+            let mut samples = Vec::with_capacity(frame_size * packets.len());
 
             for packet in packets {
                 samples.extend_from_slice(&packet.audio_samples);
             }
 
+            if self.dtx {
+                self.dtx = false;
+                let fade_len = frame_size.min(samples.len());
+                dtx::fade_in(&mut samples[..fade_len]);
+            }
+
             output_producer.push_slice(&samples);
         }
     }
-}
\ No newline at end of file
+
+    fn send_report_if_due(&mut self) {
+        let session_id = self.session_id();
+
+        if let Some(report) = self.report_builder.poll(session_id) {
+            let result = self.seal(&report.to_buffer())
+                .and_then(|buffer| Ok(net::send_to(&self.conn, &buffer, self.remote_addr())?));
+
+            if let Err(err) = result {
+                eprintln!("Error sending report: {}", err);
+            }
+        }
+    }
+
+    /// Asks the sender to resume the session when no audio has arrived for a
+    /// while, backing off exponentially between attempts.
+    fn resume_if_idle(&mut self) {
+        let idle_time = self.peer.lock().unwrap().idle_time();
+
+        match idle_time {
+            Some(idle_time) if idle_time >= RESUME_TIMEOUT => (),
+            _ => return
+        };
+
+        if !self.backoff.ready() {
+            return;
+        }
+
+        if let Err(err) = self.send_resume() {
+            eprintln!("{}", err);
+        }
+        self.backoff.failed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind() -> Arc<UdpSocket> {
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        Arc::new(conn)
+    }
+
+    #[test]
+    fn every_path_is_counted_with_encryption() {
+        let config = AudioConfig::new("".into(), "".into(), "".into(), 48000, 64, false, 0, 0);
+        let key = SessionKey::random().unwrap();
+        let (conn, path, receiver_conn) = (bind(), bind(), bind());
+
+        let mut sender = UdpClient::new(conn.clone(), receiver_conn.local_addr().unwrap(), Some(7), 0, config.clone()).unwrap();
+        sender.add_path(path.clone());
+        sender.set_key(&key, Mode::Send);
+
+        let mut receiver = UdpClient::new(receiver_conn, conn.local_addr().unwrap(), Some(7), 0, config.clone()).unwrap();
+        receiver.set_key(&key, Mode::Return);
+
+        let samples = vec![0.5f32; config.get_frame_size()];
+        for _ in 0..5 {
+            sender.send(&samples).unwrap();
+        }
+
+        let mut played = 0;
+        for _ in 0..10 {
+            played += receiver.recv().len();
+        }
+
+        let stats = receiver.path_stats();
+        assert_eq!(stats.len(), 2);
+        for path_stats in &stats {
+            assert_eq!(path_stats.received, 5, "{:?}", path_stats);
+        }
+        assert_eq!(stats.iter().map(|stats| stats.duplicates).sum::<u64>(), 5);
+        assert_eq!(played, 5);
+    }
+
+    #[test]
+    fn plays_across_the_sequence_number_wrap() {
+        let config = AudioConfig::new("".into(), "".into(), "".into(), 48000, 64, false, 0, 0);
+        let (conn, receiver_conn) = (bind(), bind());
+
+        let mut sender = UdpClient::new(conn.clone(), receiver_conn.local_addr().unwrap(), Some(7), u16::MAX - 2, config.clone()).unwrap();
+        let mut receiver = UdpClient::new(receiver_conn, conn.local_addr().unwrap(), Some(7), 0, config.clone()).unwrap();
+
+        let samples = vec![0.5f32; config.get_frame_size()];
+        for _ in 0..6 {
+            sender.send(&samples).unwrap();
+        }
+
+        let mut played = 0;
+        for _ in 0..6 {
+            played += receiver.recv().len();
+        }
+
+        assert_eq!(played, 6);
+        assert_eq!(receiver.path_stats()[0].duplicates, 0);
+    }
+}
@@ -0,0 +1,330 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+use crate::udp::packet::Report;
+
+pub const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Loss above which the sender backs off.
+const LOSS_HIGH: f32 = 0.1;
+/// Loss below which the network is considered to have recovered.
+const LOSS_LOW: f32 = 0.02;
+/// Queuing delay in milliseconds above which the sender backs off.
+const DELAY_HIGH: u16 = 40;
+const DELAY_LOW: u16 = 10;
+/// Minimum time between two decreases, so that a single burst of loss
+/// does not take the rate all the way down.
+const DECREASE_HOLD: Duration = Duration::from_secs(1);
+/// How long the network has to stay healthy before probing upwards.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Collects what the receiver needs for its reports.
+#[derive(Clone, Debug)]
+pub struct ReportBuilder {
+    start: Instant,
+    highest_sequence_number: Option<u16>,
+    base_sequence_number: u16,
+    received: u32,
+    min_delay: Option<i32>,
+    delay: Option<f32>,
+    last_report: Instant,
+}
+
+impl ReportBuilder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            highest_sequence_number: None,
+            base_sequence_number: 0,
+            received: 0,
+            min_delay: None,
+            delay: None,
+            last_report: Instant::now()
+        }
+    }
+
+    pub fn record(&mut self, sequence_number: u16, timestamp: u32) {
+        match self.highest_sequence_number {
+            None => {
+                self.base_sequence_number = sequence_number.wrapping_sub(1);
+                self.highest_sequence_number = Some(sequence_number);
+            },
+            Some(highest) if (sequence_number.wrapping_sub(highest) as i16) > 0 => {
+                self.highest_sequence_number = Some(sequence_number);
+            },
+            _ => ()
+        };
+        self.received += 1;
+
+        let delay = (self.start.elapsed().as_millis() as u32).wrapping_sub(timestamp) as i32;
+        self.min_delay = Some(self.min_delay.map_or(delay, |min_delay| min_delay.min(delay)));
+        self.delay = Some(match self.delay {
+            Some(smoothed) => smoothed + (delay as f32 - smoothed) / 16.0,
+            None => delay as f32
+        });
+    }
+
+    /// Returns a report once per `REPORT_INTERVAL` and starts a new interval.
+    pub fn poll(&mut self, session_id: u32) -> Option<Report> {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return None;
+        }
+
+        let highest_sequence_number = self.highest_sequence_number?;
+        let expected = highest_sequence_number.wrapping_sub(self.base_sequence_number) as u32;
+        let lost = expected.saturating_sub(self.received);
+        let fraction_lost = match expected {
+            0 => 0,
+            _ => ((lost * 256) / expected).min(255) as u8
+        };
+
+        let delay = match (self.delay, self.min_delay) {
+            (Some(delay), Some(min_delay)) => (delay - min_delay as f32).max(0.0).min(u16::MAX as f32) as u16,
+            _ => 0
+        };
+
+        self.base_sequence_number = highest_sequence_number;
+        self.received = 0;
+        self.last_report = Instant::now();
+
+        Some(Report {
+            session_id,
+            highest_sequence_number,
+            fraction_lost,
+            delay
+        })
+    }
+}
+
+impl Default for ReportBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Current sending rate, as decided by the `RateController`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateStats {
+    pub redundancy: u8,
+    /// Bits per second on the wire, including redundant copies.
+    pub target_bitrate: u32,
+    pub loss: f32,
+    pub delay: u16,
+}
+
+/// Loss and delay based controller on the sending side.
+///
+/// Audio is sent uncompressed, so the rate is controlled through the number
+/// of redundant copies carried in each datagram. The chosen redundancy is
+/// written to the header of every packet, so the receiver follows changes
+/// without renegotiating.
+#[derive(Clone, Debug)]
+pub struct RateController {
+    redundancy: u8,
+    max_redundancy: u8,
+    /// Most copies a datagram can carry, fixed for the session.
+    ceiling: u8,
+    redundancy_limit: Option<u8>,
+    bitrate_limit: Option<u32>,
+    stream_bitrate: u32,
+    loss: f32,
+    delay: u16,
+    last_decrease: Option<Instant>,
+    healthy_since: Option<Instant>,
+}
+
+impl RateController {
+    /// `stream_bitrate` is the bitrate of a single copy of the stream.
+    pub fn new(max_redundancy: u8, stream_bitrate: u32) -> Self {
+        Self {
+            redundancy: max_redundancy,
+            max_redundancy,
+            ceiling: max_redundancy,
+            redundancy_limit: None,
+            bitrate_limit: None,
+            stream_bitrate,
+            loss: 0.0,
+            delay: 0,
+            last_decrease: None,
+            healthy_since: None
+        }
+    }
+
+    pub fn redundancy(&self) -> u8 {
+        self.redundancy
+    }
+
+    /// Switches to `redundancy` copies per datagram right away. Congestion
+    /// can still lower it, recovery raises it no further.
+    pub fn set_redundancy(&mut self, redundancy: u8) -> Result<()> {
+        self.redundancy_limit = Some(self.check_redundancy(redundancy)?);
+        self.update_max_redundancy();
+        self.redundancy = self.max_redundancy;
+
+        Ok(())
+    }
+
+    pub fn check_redundancy(&self, redundancy: u8) -> Result<u8> {
+        match redundancy >= 1 && redundancy <= self.ceiling {
+            true => Ok(redundancy),
+            false => Err(anyhow!("Redundancy has to be between 1 and {}", self.ceiling))
+        }
+    }
+
+    /// Caps the bitrate on the wire. Audio is sent uncompressed, so this
+    /// limits the redundant copies, a single copy is always sent.
+    pub fn set_max_bitrate(&mut self, bitrate: Option<u32>) {
+        self.bitrate_limit = bitrate;
+        self.update_max_redundancy();
+    }
+
+    fn update_max_redundancy(&mut self) {
+        let by_bitrate = self.bitrate_limit.map_or(self.ceiling as u32, |bitrate| bitrate / self.stream_bitrate.max(1));
+
+        self.max_redundancy = self.redundancy_limit.unwrap_or(self.ceiling)
+            .min(by_bitrate.min(self.ceiling as u32) as u8)
+            .max(1);
+        self.redundancy = self.redundancy.min(self.max_redundancy);
+    }
+
+    pub fn target_bitrate(&self) -> u32 {
+        self.stream_bitrate * self.redundancy as u32
+    }
+
+    pub fn stats(&self) -> RateStats {
+        RateStats {
+            redundancy: self.redundancy,
+            target_bitrate: self.target_bitrate(),
+            loss: self.loss,
+            delay: self.delay
+        }
+    }
+
+    pub fn on_report(&mut self, report: &Report) {
+        self.loss = report.fraction_lost as f32 / 256.0;
+        self.delay = report.delay;
+
+        if self.loss > LOSS_HIGH || self.delay > DELAY_HIGH {
+            self.healthy_since = None;
+
+            let hold = self.last_decrease.is_some_and(|last| last.elapsed() < DECREASE_HOLD);
+            if !hold && self.redundancy > 1 {
+                self.redundancy -= 1;
+                self.last_decrease = Some(Instant::now());
+                println!("Congestion (loss {:.2}, delay {} ms), redundancy {}", self.loss, self.delay, self.redundancy);
+            }
+            return;
+        }
+
+        if self.loss > LOSS_LOW || self.delay > DELAY_LOW {
+            self.healthy_since = None;
+            return;
+        }
+
+        let healthy_since = *self.healthy_since.get_or_insert_with(Instant::now);
+        if healthy_since.elapsed() >= PROBE_INTERVAL && self.redundancy < self.max_redundancy {
+            self.redundancy += 1;
+            self.healthy_since = Some(Instant::now());
+            println!("Network recovered, redundancy {}", self.redundancy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(fraction_lost: u8, delay: u16) -> Report {
+        Report { session_id: 1, highest_sequence_number: 0, fraction_lost, delay }
+    }
+
+    #[test]
+    fn reports_loss_per_interval() {
+        let mut builder = ReportBuilder::new();
+        for sequence_number in [1, 2, 4] {
+            builder.record(sequence_number, 0);
+        }
+        assert!(builder.poll(1).is_none());
+
+        builder.last_report -= REPORT_INTERVAL;
+        let report = builder.poll(1).unwrap();
+        assert_eq!(report.highest_sequence_number, 4);
+        // One of four lost
+        assert_eq!(report.fraction_lost, 64);
+
+        builder.record(5, 0);
+        builder.last_report -= REPORT_INTERVAL;
+        assert_eq!(builder.poll(1).unwrap().fraction_lost, 0);
+    }
+
+    #[test]
+    fn backs_off_once_per_hold_on_congestion() {
+        let mut controller = RateController::new(3, 1000);
+        assert_eq!(controller.target_bitrate(), 3000);
+
+        controller.on_report(&report(64, 0));
+        assert_eq!(controller.redundancy(), 2);
+        controller.on_report(&report(0, 100));
+        assert_eq!(controller.redundancy(), 2);
+
+        controller.last_decrease = Some(Instant::now() - DECREASE_HOLD);
+        controller.on_report(&report(64, 0));
+        controller.last_decrease = Some(Instant::now() - DECREASE_HOLD);
+        controller.on_report(&report(64, 0));
+        // A single copy is always sent
+        assert_eq!(controller.redundancy(), 1);
+        assert_eq!(controller.stats().target_bitrate, 1000);
+    }
+
+    #[test]
+    fn probes_upwards_after_staying_healthy() {
+        let mut controller = RateController::new(3, 1000);
+        controller.on_report(&report(64, 0));
+        assert_eq!(controller.redundancy(), 2);
+
+        controller.on_report(&report(0, 0));
+        assert_eq!(controller.redundancy(), 2);
+
+        controller.healthy_since = Some(Instant::now() - PROBE_INTERVAL);
+        controller.on_report(&report(0, 0));
+        assert_eq!(controller.redundancy(), 3);
+
+        // Never beyond the maximum
+        controller.healthy_since = Some(Instant::now() - PROBE_INTERVAL);
+        controller.on_report(&report(0, 0));
+        assert_eq!(controller.redundancy(), 3);
+    }
+
+    #[test]
+    fn sets_redundancy_within_the_ceiling() {
+        let mut controller = RateController::new(3, 1000);
+
+        controller.set_redundancy(2).unwrap();
+        assert_eq!(controller.redundancy(), 2);
+        assert_eq!(controller.target_bitrate(), 2000);
+
+        assert!(controller.set_redundancy(0).is_err());
+        assert!(controller.set_redundancy(4).is_err());
+        assert_eq!(controller.redundancy(), 2);
+    }
+
+    #[test]
+    fn caps_copies_by_the_bitrate() {
+        let mut controller = RateController::new(3, 1000);
+
+        controller.set_max_bitrate(Some(2500));
+        assert_eq!(controller.redundancy(), 2);
+
+        // A single copy is sent however low the cap
+        controller.set_max_bitrate(Some(10));
+        assert_eq!(controller.redundancy(), 1);
+        assert_eq!(controller.target_bitrate(), 1000);
+
+        controller.set_max_bitrate(None);
+        controller.set_redundancy(3).unwrap();
+        assert_eq!(controller.redundancy(), 3);
+    }
+}
@@ -0,0 +1,310 @@
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use serde::Deserialize;
+
+use crate::util::{self, Mode};
+
+pub const KEY_SIZE: usize = 32;
+/// Message type and session id, left in the clear so datagrams can still be
+/// told apart before decrypting them. They are authenticated all the same.
+const HEADER_SIZE: usize = 5;
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+/// Bytes added to each datagram.
+pub const OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+/// How far behind the newest datagram an older one may still arrive.
+const REPLAY_WINDOW: u64 = 64;
+
+/// Shared secret of a session, written as 64 hex digits in the control
+/// protocol.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SessionKey(pub [u8; KEY_SIZE]);
+
+impl SessionKey {
+    pub fn random() -> Result<Self> {
+        let mut key = [0u8; KEY_SIZE];
+        util::random_bytes(&mut key)?;
+
+        Ok(Self(key))
+    }
+}
+
+impl TryFrom<String> for SessionKey {
+    type Error = anyhow::Error;
+
+    fn try_from(hex: String) -> Result<Self> {
+        let key = util::from_hex(&hex)?
+            .try_into()
+            .map_err(|_| anyhow!("The session key must be {} hex digits", KEY_SIZE * 2))?;
+
+        Ok(Self(key))
+    }
+}
+
+/// Keeps the key out of logs.
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionKey(..)")
+    }
+}
+
+/// Encrypts and authenticates datagrams with ChaCha20-Poly1305.
+///
+/// Each sealed datagram carries a 64-bit counter, which together with the
+/// direction makes up the nonce. Both ends share the key, so the direction,
+/// taken from the streaming mode, keeps their nonces apart. The receiver
+/// drops datagrams whose counter it has already seen.
+///
+/// The counter is separate from the packet's sequence number, which would
+/// repeat nonces: it wraps after 65536 packets, every path sends its own
+/// copy of a packet, and reports, keepalives and resumes do not advance it.
+pub struct SessionCipher {
+    cipher: ChaCha20Poly1305,
+    mode: Mode,
+    counter: AtomicU64,
+    replay_window: Mutex<ReplayWindow>,
+}
+
+impl SessionCipher {
+    pub fn new(key: &SessionKey, mode: Mode) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.0.into()),
+            mode,
+            counter: AtomicU64::new(0),
+            replay_window: Mutex::new(ReplayWindow::default())
+        }
+    }
+
+    pub fn seal(&self, datagram: &[u8]) -> Result<Vec<u8>> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(anyhow!("Datagram too short"));
+        }
+
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut sealed = Vec::with_capacity(datagram.len() + OVERHEAD);
+        sealed.extend_from_slice(&datagram[..HEADER_SIZE]);
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.extend_from_slice(&datagram[HEADER_SIZE..]);
+
+        let (associated_data, payload) = sealed.split_at_mut(HEADER_SIZE + COUNTER_SIZE);
+        let tag = self.cipher.encrypt_in_place_detached(&nonce(self.mode, counter), associated_data, payload)
+            .map_err(|_| anyhow!("Could not encrypt datagram"))?;
+        sealed.extend_from_slice(&tag);
+
+        Ok(sealed)
+    }
+
+    /// Authenticates and decrypts a datagram from the peer, returning it as
+    /// it was before sealing.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < HEADER_SIZE + OVERHEAD {
+            return Err(anyhow!("Datagram too short"));
+        }
+
+        let mut counter = [0u8; COUNTER_SIZE];
+        counter.copy_from_slice(&sealed[HEADER_SIZE..HEADER_SIZE + COUNTER_SIZE]);
+        let counter = u64::from_be_bytes(counter);
+
+        if !self.replay_window.lock().unwrap().check(counter) {
+            return Err(anyhow!("Replayed datagram"));
+        }
+
+        let peer_mode = match self.mode {
+            Mode::Send => Mode::Return,
+            Mode::Return => Mode::Send
+        };
+
+        let (associated_data, rest) = sealed.split_at(HEADER_SIZE + COUNTER_SIZE);
+        let (payload, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        let mut datagram = Vec::with_capacity(sealed.len() - OVERHEAD);
+        datagram.extend_from_slice(&sealed[..HEADER_SIZE]);
+        datagram.extend_from_slice(payload);
+
+        self.cipher.decrypt_in_place_detached(&nonce(peer_mode, counter), associated_data, &mut datagram[HEADER_SIZE..], Tag::from_slice(tag))
+            .map_err(|_| anyhow!("Datagram failed authentication"))?;
+
+        // Only authentic datagrams move the window, or forged counters could
+        // push it past the real ones.
+        self.replay_window.lock().unwrap().update(counter);
+
+        Ok(datagram)
+    }
+}
+
+fn nonce(mode: Mode, counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = match mode {
+        Mode::Send => 0,
+        Mode::Return => 1
+    };
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+    nonce.into()
+}
+
+/// Counters seen recently, as a bitmap below the highest one (RFC 4303).
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, counter: u64) -> bool {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                let age = highest - counter;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            },
+            _ => true
+        }
+    }
+
+    fn update(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                if highest - counter < REPLAY_WINDOW {
+                    self.seen |= 1 << (highest - counter);
+                }
+            },
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = match shift < REPLAY_WINDOW {
+                    true => self.seen << shift | 1,
+                    false => 1
+                };
+                self.highest = Some(counter);
+            },
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (SessionCipher, SessionCipher) {
+        let key = SessionKey([7; KEY_SIZE]);
+
+        (SessionCipher::new(&key, Mode::Send), SessionCipher::new(&key, Mode::Return))
+    }
+
+    #[test]
+    fn round_trips_a_datagram() {
+        let (sender, receiver) = pair();
+        let datagram = b"\x00\x00\x00\x00\x2asome audio".to_vec();
+
+        let sealed = sender.seal(&datagram).unwrap();
+        assert_eq!(sealed.len(), datagram.len() + OVERHEAD);
+        assert_eq!(&sealed[..HEADER_SIZE], &datagram[..HEADER_SIZE]);
+        assert_ne!(&sealed[HEADER_SIZE + COUNTER_SIZE..sealed.len() - TAG_SIZE], &datagram[HEADER_SIZE..]);
+
+        assert_eq!(receiver.open(&sealed).unwrap(), datagram);
+    }
+
+    #[test]
+    fn rejects_any_flipped_byte() {
+        let (sender, receiver) = pair();
+        let sealed = sender.seal(b"\x00\x00\x00\x00\x2asome audio").unwrap();
+
+        // Header, counter, ciphertext and tag
+        for index in [0, 4, HEADER_SIZE, HEADER_SIZE + COUNTER_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 0x01;
+
+            assert!(receiver.open(&tampered).is_err(), "byte {} was not authenticated", index);
+        }
+        assert!(receiver.open(&sealed).is_ok());
+    }
+
+    #[test]
+    fn rejects_the_wrong_direction_or_key() {
+        let (sender, _) = pair();
+        let sealed = sender.seal(b"\x00\x00\x00\x00\x2a").unwrap();
+
+        // The sender's own datagrams reflected back at it
+        assert!(sender.open(&sealed).is_err());
+        assert!(SessionCipher::new(&SessionKey([8; KEY_SIZE]), Mode::Return).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn refuses_a_replayed_datagram() {
+        let (sender, receiver) = pair();
+        let first = sender.seal(b"\x00\x00\x00\x00\x2afirst").unwrap();
+        let second = sender.seal(b"\x00\x00\x00\x00\x2asecond").unwrap();
+
+        assert!(receiver.open(&second).is_ok());
+        // Late but not seen yet
+        assert!(receiver.open(&first).is_ok());
+
+        assert!(receiver.open(&first).is_err());
+        assert!(receiver.open(&second).is_err());
+    }
+
+    #[test]
+    fn forged_datagrams_do_not_move_the_window() {
+        let (sender, receiver) = pair();
+        let sealed = sender.seal(b"\x00\x00\x00\x00\x2a").unwrap();
+
+        let mut forged = sealed.clone();
+        forged[HEADER_SIZE..HEADER_SIZE + COUNTER_SIZE].copy_from_slice(&1000u64.to_be_bytes());
+        assert!(receiver.open(&forged).is_err());
+
+        assert!(receiver.open(&sealed).is_ok());
+    }
+
+    #[test]
+    fn window_accepts_counters_just_inside_it() {
+        let mut window = ReplayWindow::default();
+        window.update(100);
+
+        let oldest = 100 - (REPLAY_WINDOW - 1);
+        assert!(window.check(oldest));
+        window.update(oldest);
+        assert!(!window.check(oldest));
+
+        // Too old to tell whether it was seen
+        assert!(!window.check(oldest - 1));
+        assert!(!window.check(0));
+    }
+
+    #[test]
+    fn window_moves_with_the_highest_counter() {
+        let mut window = ReplayWindow::default();
+        window.update(10);
+        window.update(12);
+
+        assert!(!window.check(10));
+        assert!(window.check(11));
+        assert!(!window.check(12));
+        assert!(window.check(13));
+    }
+
+    #[test]
+    fn window_forgets_everything_after_a_jump_past_it() {
+        let mut window = ReplayWindow::default();
+        window.update(5);
+        window.update(6);
+
+        let far = 6 + REPLAY_WINDOW;
+        window.update(far);
+
+        assert!(!window.check(far));
+        assert!(!window.check(6));
+        assert!(window.check(far - 1));
+        assert!(window.check(far - (REPLAY_WINDOW - 1)));
+        assert!(!window.check(far - REPLAY_WINDOW));
+    }
+}
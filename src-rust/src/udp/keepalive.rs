@@ -0,0 +1,122 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::stun::message::Message;
+use crate::net;
+
+/// Most NATs drop idle UDP mappings after 30 seconds or more.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Shorter intervals flood the peer, longer ones let mappings expire.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks an interval a client asked for.
+pub fn check_interval(interval: Duration) -> Result<Duration> {
+    if interval < MIN_INTERVAL || interval > MAX_INTERVAL {
+        return Err(anyhow!(
+            "Keepalive interval must be between {} and {} ms, not {} ms",
+            MIN_INTERVAL.as_millis(), MAX_INTERVAL.as_millis(), interval.as_millis()
+        ));
+    }
+
+    Ok(interval)
+}
+
+/// Periodically sends a datagram on a socket to keep its NAT mapping open.
+/// Sending stops when the handle is dropped, nothing is sent after that.
+pub struct Keepalive {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Keepalive {
+    /// Starts sending the datagrams returned by `message`, which gives the
+    /// content and destination of each keepalive or `None` to skip one.
+    pub fn start<F>(conn: Arc<UdpSocket>, interval: Duration, mut message: F) -> Self
+    where F: FnMut() -> Option<(Vec<u8>, SocketAddr)> + Send + 'static {
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            // Waits out the interval, or wakes up as soon as the handle is
+            // dropped, which disconnects the channel
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Some((buffer, addr)) = message() {
+                    if let Err(err) = net::send_to(&conn, &buffer, addr) {
+                        eprintln!("Error sending keepalive to {}: {}", addr, err);
+                    }
+                }
+            }
+        });
+
+        Self { stop: Some(stop), thread: Some(thread) }
+    }
+
+    /// Keeps the mapping towards a STUN server open before the peer is known,
+    /// using binding indications, which the server does not answer.
+    pub fn stun(conn: Arc<UdpSocket>, interval: Duration, stun_server: SocketAddr) -> Self {
+        Keepalive::start(conn, interval, move || Some((Message::binding_indication().ok()?.to_buffer(), stun_server)))
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_intervals_out_of_range() {
+        assert!(check_interval(Duration::ZERO).is_err());
+        assert!(check_interval(Duration::from_millis(999)).is_err());
+        assert!(check_interval(Duration::from_millis(60_001)).is_err());
+
+        assert_eq!(check_interval(MIN_INTERVAL).unwrap(), MIN_INTERVAL);
+        assert_eq!(check_interval(DEFAULT_INTERVAL).unwrap(), DEFAULT_INTERVAL);
+        assert_eq!(check_interval(MAX_INTERVAL).unwrap(), MAX_INTERVAL);
+    }
+
+    #[test]
+    fn sends_at_the_interval_until_dropped() {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let keepalive = Keepalive::start(conn, Duration::from_millis(50), move || Some((b"keepalive".to_vec(), addr)));
+        let mut buffer = [0u8; 16];
+        let (len, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"keepalive");
+
+        drop(keepalive);
+        while receiver.recv_from(&mut buffer).is_ok() {}
+        assert!(receiver.recv_from(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn stops_without_waiting_out_the_interval() {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let keepalive = Keepalive::start(conn, MAX_INTERVAL, move || Some((b"keepalive".to_vec(), addr)));
+        let start = std::time::Instant::now();
+        drop(keepalive);
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(receiver.recv_from(&mut [0u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::net;
+
+/// Binds an extra sending path. `spec` is either a local address, with or
+/// without a port, or the name of a network interface.
+pub fn bind_path(spec: &str) -> Result<Arc<UdpSocket>> {
+    let addr = match spec.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(resolve_local_ip(spec)?, 0)
+    };
+
+    let conn = UdpSocket::bind(addr)?;
+    println!("Bound path {} on {}", spec, conn.local_addr()?);
+
+    Ok(Arc::new(conn))
+}
+
+fn resolve_local_ip(spec: &str) -> Result<IpAddr> {
+    if let Ok(ip) = spec.parse::<IpAddr>() {
+        return Ok(ip);
+    }
+
+    net::interface_ip(spec)
+}
+
+/// Loss and latency statistics for one path, keyed by the address the
+/// packets arrive from.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PathStats {
+    pub addr: SocketAddr,
+    pub received: u64,
+    pub duplicates: u64,
+    pub loss: f32,
+    /// Smoothed one-way delay in milliseconds, relative to the fastest path.
+    pub latency: f32,
+    #[serde(skip)]
+    first_sequence_number: u16,
+    #[serde(skip)]
+    highest_sequence_number: u16,
+    #[serde(skip)]
+    delay: Option<f32>,
+}
+
+impl PathStats {
+    fn new(addr: SocketAddr, sequence_number: u16) -> Self {
+        Self {
+            addr,
+            received: 0,
+            duplicates: 0,
+            loss: 0.0,
+            latency: 0.0,
+            first_sequence_number: sequence_number,
+            highest_sequence_number: sequence_number,
+            delay: None
+        }
+    }
+}
+
+/// Collects per-path statistics on the receiving side. Delays are measured
+/// against the sender's packet timestamps, so they include the clock offset
+/// between the hosts and only make sense relative to each other.
+#[derive(Debug)]
+pub struct PathMonitor {
+    start: Instant,
+    paths: HashMap<SocketAddr, PathStats>,
+}
+
+impl PathMonitor {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            paths: HashMap::new()
+        }
+    }
+
+    /// Records a packet arriving on a path. `duplicate` marks packets that
+    /// another path delivered first.
+    pub fn record(&mut self, addr: SocketAddr, sequence_number: u16, timestamp: u32, duplicate: bool) {
+        let now = self.start.elapsed().as_millis() as u32;
+        let delay = now.wrapping_sub(timestamp) as i32 as f32;
+
+        let stats = self.paths.entry(addr).or_insert_with(|| PathStats::new(addr, sequence_number));
+
+        stats.received += 1;
+        if duplicate {
+            stats.duplicates += 1;
+        }
+
+        if (sequence_number.wrapping_sub(stats.highest_sequence_number) as i16) > 0 {
+            stats.highest_sequence_number = sequence_number;
+        }
+
+        let expected = stats.highest_sequence_number.wrapping_sub(stats.first_sequence_number) as u64 + 1;
+        stats.loss = 1.0 - (stats.received.min(expected) as f32 / expected as f32);
+
+        stats.delay = Some(match stats.delay {
+            Some(smoothed) => smoothed + (delay - smoothed) / 16.0,
+            None => delay
+        });
+
+        let min_delay = self.paths.values()
+            .filter_map(|stats| stats.delay)
+            .fold(f32::INFINITY, f32::min);
+
+        for stats in self.paths.values_mut() {
+            if let Some(delay) = stats.delay {
+                stats.latency = delay - min_delay;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<PathStats> {
+        self.paths.values().cloned().collect()
+    }
+}
+
+impl Default for PathMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    fn stats_of(monitor: &PathMonitor, addr: SocketAddr) -> PathStats {
+        monitor.stats().into_iter().find(|stats| stats.addr == addr).unwrap()
+    }
+
+    #[test]
+    fn counts_loss_from_sequence_gaps() {
+        let mut monitor = PathMonitor::new();
+
+        for sequence_number in [65534u16, 65535, 1, 3] {
+            monitor.record(addr(1), sequence_number, 0, false);
+        }
+
+        let stats = stats_of(&monitor, addr(1));
+        assert_eq!(stats.received, 4);
+        // 65534 to 3 are 6 packets, 0 and 2 are missing
+        assert!((stats.loss - 2.0 / 6.0).abs() < 1e-6, "{}", stats.loss);
+    }
+
+    #[test]
+    fn measures_latency_against_the_fastest_path() {
+        let mut monitor = PathMonitor::new();
+
+        monitor.record(addr(1), 1, 0, false);
+        // Sent 20 ms before the first path's packet but arriving with it
+        monitor.record(addr(2), 1, 0u32.wrapping_sub(20), true);
+
+        assert!(stats_of(&monitor, addr(1)).latency.abs() < 2.0);
+        assert!((stats_of(&monitor, addr(2)).latency - 20.0).abs() < 2.0);
+        assert_eq!(stats_of(&monitor, addr(2)).duplicates, 1);
+    }
+
+    #[test]
+    fn binds_paths_by_address() {
+        let with_port = bind_path("127.0.0.1:0").unwrap();
+        assert!(with_port.local_addr().unwrap().ip().is_loopback());
+
+        let ip_only = bind_path("127.0.0.1").unwrap();
+        assert_ne!(ip_only.local_addr().unwrap().port(), 0);
+
+        assert!(bind_path("no-such-interface").is_err());
+    }
+}
@@ -1,12 +1,28 @@
-#[derive(Copy, Clone, Debug)]
+use anyhow::{Result, anyhow};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MessageType {
-    Audio
+    Audio,
+    Resume,
+    Report,
+    ComfortNoise,
+    Keepalive,
+    Check,
+    CheckResponse,
+    Handshake
 }
 
 impl MessageType {
-    fn from_u8(v: u8) -> Option<MessageType> {
+    pub fn from_u8(v: u8) -> Option<MessageType> {
         match v {
             0 => Some(MessageType::Audio),
+            1 => Some(MessageType::Resume),
+            2 => Some(MessageType::Report),
+            3 => Some(MessageType::ComfortNoise),
+            4 => Some(MessageType::Keepalive),
+            5 => Some(MessageType::Check),
+            6 => Some(MessageType::CheckResponse),
+            7 => Some(MessageType::Handshake),
             _ => None
         }
     }
@@ -15,6 +31,7 @@ impl MessageType {
 #[derive(Clone)]
 pub struct Packet {
     pub message_type: MessageType,
+    pub session_id: u32,
     pub sequence_number: u16,
     pub timestamp: u32,
     pub redundancy: u8,
@@ -25,8 +42,10 @@ pub struct Packet {
 }
 
 impl Packet {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         message_type: MessageType,
+        session_id: u32,
         sequence_number: u16,
         timestamp: u32,
         redundancy: u8,
@@ -37,6 +56,7 @@ impl Packet {
     ) -> Packet {
         Packet {
             message_type,
+            session_id,
             sequence_number,
             timestamp,
             redundancy,
@@ -47,13 +67,30 @@ impl Packet {
         }
     }
 
+    /// Creates a header-only packet used to ask the sender to resume the
+    /// session towards the address the packet arrives from.
+    pub fn resume(session_id: u32, sequence_number: u16) -> Packet {
+        Packet::new(MessageType::Resume, session_id, sequence_number, 0, 0, 0, 0, 0, Vec::new())
+    }
+
+    /// Creates a header-only packet that keeps NAT mappings on the path open.
+    pub fn keepalive(session_id: u32, sequence_number: u16) -> Packet {
+        Packet::new(MessageType::Keepalive, session_id, sequence_number, 0, 0, 0, 0, 0, Vec::new())
+    }
+
     pub fn get_header_size() -> usize {
-        17
+        21
     }
 
     fn get_header_buffer(&self) -> Vec<u8> {
-        let mut buf_header = Vec::with_capacity(19);
+        let mut buf_header = Vec::with_capacity(Packet::get_header_size());
         buf_header.push(self.message_type as u8);
+
+        let session_id = self.session_id.to_be_bytes();
+        buf_header.push(session_id[0]);
+        buf_header.push(session_id[1]);
+        buf_header.push(session_id[2]);
+        buf_header.push(session_id[3]);
         
         let sequence_number = self.sequence_number.to_be_bytes();
         buf_header.push(sequence_number[0]);
@@ -85,7 +122,7 @@ impl Packet {
     }
 
     pub fn get_buffer_size(&self) -> usize {
-        Packet::get_header_size() + (self.buffer_size * self.channel_count as u32 * 4) as usize
+        Packet::get_header_size() + (self.buffer_size * self.channel_count * 4) as usize
     }
 
     pub fn to_buffer(&self) -> Vec<u8> {
@@ -108,32 +145,61 @@ impl Packet {
         buffer
     }
 
-    pub fn from_buffer(buffer: &[u8]) -> Packet {
-        let message_type = MessageType::from_u8(buffer[0]).expect("Invalid message type");
-        
-        let sequence_number = u16::from_be_bytes([buffer[1], buffer[2]]);
-        let timestamp = u32::from_be_bytes([
+    /// Reads the message type and session id of a datagram without parsing
+    /// the rest of it. All message types share these leading fields.
+    pub fn peek(buffer: &[u8]) -> Option<(MessageType, u32)> {
+        if buffer.len() < 5 {
+            return None;
+        }
+
+        let message_type = MessageType::from_u8(buffer[0])?;
+        let session_id = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+
+        Some((message_type, session_id))
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Packet> {
+        if buffer.len() < Packet::get_header_size() {
+            return Err(anyhow!("Packet too short"));
+        }
+
+        let message_type = MessageType::from_u8(buffer[0]).ok_or(anyhow!("Invalid message type"))?;
+
+        let session_id = u32::from_be_bytes([
+            buffer[1],
+            buffer[2],
             buffer[3],
             buffer[4],
-            buffer[5],
-            buffer[6],
         ]);
-        let redundancy = buffer[7];
-        let sample_rate = u32::from_be_bytes([
+        let sequence_number = u16::from_be_bytes([buffer[5], buffer[6]]);
+        let timestamp = u32::from_be_bytes([
+            buffer[7],
             buffer[8],
             buffer[9],
             buffer[10],
-            buffer[11],
         ]);
-        let channel_count = buffer[12] as u32;
-        let buffer_size = u32::from_be_bytes([
+        let redundancy = buffer[11];
+        let sample_rate = u32::from_be_bytes([
+            buffer[12],
             buffer[13],
             buffer[14],
             buffer[15],
-            buffer[16],
         ]);
+        let channel_count = buffer[16] as u32;
+        let buffer_size = u32::from_be_bytes([
+            buffer[17],
+            buffer[18],
+            buffer[19],
+            buffer[20],
+        ]);
+
+        // Both sizes come off the wire, so they are checked against what
+        // actually arrived before anything is allocated for them
+        let sample_count = buffer_size.checked_mul(channel_count)
+            .filter(|sample_count| *sample_count as usize <= (buffer.len() - Packet::get_header_size()) / 4)
+            .ok_or(anyhow!("Packet shorter than its buffer size and channel count"))?;
 
-        let mut audio_samples = Vec::with_capacity((buffer_size * channel_count as u32) as usize);
+        let mut audio_samples = Vec::with_capacity(sample_count as usize);
         
         let mut audio_buffer = [0u8; 4];
         let mut count = 0;
@@ -147,8 +213,9 @@ impl Packet {
             }
         }
 
-        Packet {
+        Ok(Packet {
             message_type,
+            session_id,
             sequence_number,
             timestamp,
             redundancy,
@@ -156,6 +223,233 @@ impl Packet {
             channel_count,
             buffer_size,
             audio_samples
+        })
+    }
+}
+
+/// Receiver report, sent periodically from the receiving to the sending side.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub session_id: u32,
+    pub highest_sequence_number: u16,
+    /// Fraction of packets lost since the previous report, in 1/256ths.
+    pub fraction_lost: u8,
+    /// Queuing delay in milliseconds, the smoothed one-way delay above the
+    /// lowest one seen.
+    pub delay: u16,
+}
+
+impl Report {
+    pub fn get_size() -> usize {
+        10
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(Report::get_size());
+        buffer.push(MessageType::Report as u8);
+        buffer.extend_from_slice(&self.session_id.to_be_bytes());
+        buffer.extend_from_slice(&self.highest_sequence_number.to_be_bytes());
+        buffer.push(self.fraction_lost);
+        buffer.extend_from_slice(&self.delay.to_be_bytes());
+
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Report> {
+        if buffer.len() < Report::get_size() {
+            return Err(anyhow!("Report too short"));
+        }
+
+        Ok(Report {
+            session_id: u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]),
+            highest_sequence_number: u16::from_be_bytes([buffer[5], buffer[6]]),
+            fraction_lost: buffer[7],
+            delay: u16::from_be_bytes([buffer[8], buffer[9]]),
+        })
+    }
+}
+
+/// Sent instead of audio while the sender's input is silent, describing the
+/// background noise the receiver should play in the meantime.
+#[derive(Clone, Debug)]
+pub struct ComfortNoiseDescriptor {
+    pub session_id: u32,
+    pub sequence_number: u16,
+    /// RMS level of the noise.
+    pub level: f32,
+}
+
+impl ComfortNoiseDescriptor {
+    pub fn get_size() -> usize {
+        11
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(ComfortNoiseDescriptor::get_size());
+        buffer.push(MessageType::ComfortNoise as u8);
+        buffer.extend_from_slice(&self.session_id.to_be_bytes());
+        buffer.extend_from_slice(&self.sequence_number.to_be_bytes());
+        buffer.extend_from_slice(&self.level.to_be_bytes());
+
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<ComfortNoiseDescriptor> {
+        if buffer.len() < ComfortNoiseDescriptor::get_size() {
+            return Err(anyhow!("Comfort noise descriptor too short"));
         }
+
+        Ok(ComfortNoiseDescriptor {
+            session_id: u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]),
+            sequence_number: u16::from_be_bytes([buffer[5], buffer[6]]),
+            level: f32::from_be_bytes([buffer[7], buffer[8], buffer[9], buffer[10]]),
+        })
+    }
+}
+
+/// Connectivity check sent to each of the peer's candidate addresses
+/// before streaming, and the response to it.
+#[derive(Clone, Debug)]
+pub struct ConnectivityCheck {
+    pub response: bool,
+    pub transaction_id: u32,
+    /// Set by the controlling peer on the address both are to use, like
+    /// USE-CANDIDATE in ICE. Responses repeat it.
+    pub nominate: bool,
+}
+
+impl ConnectivityCheck {
+    pub fn get_size() -> usize {
+        10
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let message_type = match self.response {
+            true => MessageType::CheckResponse,
+            false => MessageType::Check
+        };
+
+        let mut buffer = Vec::with_capacity(ConnectivityCheck::get_size());
+        buffer.push(message_type as u8);
+        // Checks precede the session, the session id is left empty
+        buffer.extend_from_slice(&0u32.to_be_bytes());
+        buffer.extend_from_slice(&self.transaction_id.to_be_bytes());
+        buffer.push(self.nominate as u8);
+
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<ConnectivityCheck> {
+        if buffer.len() < ConnectivityCheck::get_size() {
+            return Err(anyhow!("Connectivity check too short"));
+        }
+
+        let response = match MessageType::from_u8(buffer[0]) {
+            Some(MessageType::Check) => false,
+            Some(MessageType::CheckResponse) => true,
+            _ => return Err(anyhow!("Not a connectivity check"))
+        };
+
+        Ok(ConnectivityCheck {
+            response,
+            transaction_id: u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]),
+            nominate: buffer[9] != 0
+        })
     }
-}
\ No newline at end of file
+}
+
+/// One message of the key exchange that precedes a session.
+#[derive(Clone, Debug)]
+pub struct HandshakeMessage {
+    pub session_id: u32,
+    /// Position of the message in the handshake, from 1.
+    pub step: u8,
+    pub payload: Vec<u8>,
+}
+
+impl HandshakeMessage {
+    pub fn get_header_size() -> usize {
+        6
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HandshakeMessage::get_header_size() + self.payload.len());
+        buffer.push(MessageType::Handshake as u8);
+        buffer.extend_from_slice(&self.session_id.to_be_bytes());
+        buffer.push(self.step);
+        buffer.extend_from_slice(&self.payload);
+
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<HandshakeMessage> {
+        if buffer.len() < HandshakeMessage::get_header_size() {
+            return Err(anyhow!("Handshake message too short"));
+        }
+
+        if MessageType::from_u8(buffer[0]) != Some(MessageType::Handshake) {
+            return Err(anyhow!("Not a handshake message"));
+        }
+
+        Ok(HandshakeMessage {
+            session_id: u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]),
+            step: buffer[5],
+            payload: buffer[HandshakeMessage::get_header_size()..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_audio() {
+        let samples = vec![0.25, -0.5, 1.0, 0.0];
+        let packet = Packet::new(MessageType::Audio, 0xdead_beef, 65535, 123_456, 3, 48000, 2, 2, samples.clone());
+
+        let buffer = packet.to_buffer();
+        assert_eq!(buffer.len(), packet.get_buffer_size());
+
+        let parsed = Packet::from_buffer(&buffer).unwrap();
+        assert_eq!(parsed.message_type, MessageType::Audio);
+        assert_eq!(parsed.session_id, 0xdead_beef);
+        assert_eq!(parsed.sequence_number, 65535);
+        assert_eq!(parsed.timestamp, 123_456);
+        assert_eq!(parsed.redundancy, 3);
+        assert_eq!((parsed.sample_rate, parsed.channel_count, parsed.buffer_size), (48000, 2, 2));
+        assert_eq!(parsed.audio_samples, samples);
+    }
+
+    #[test]
+    fn peeks_at_any_message() {
+        let resume = Packet::resume(42, 1).to_buffer();
+        assert_eq!(resume.len(), Packet::get_header_size());
+        assert_eq!(Packet::peek(&resume), Some((MessageType::Resume, 42)));
+
+        let report = Report { session_id: 9, highest_sequence_number: 3, fraction_lost: 64, delay: 12 }.to_buffer();
+        assert_eq!(Packet::peek(&report), Some((MessageType::Report, 9)));
+
+        assert_eq!(Packet::peek(&[0, 0, 0]), None);
+        assert_eq!(Packet::peek(&[200, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn rejects_sizes_beyond_the_payload() {
+        let mut buffer = Packet::new(MessageType::Audio, 1, 1, 0, 0, 48000, 2, 2, vec![0.0; 4]).to_buffer();
+        buffer[17..21].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Packet::from_buffer(&buffer).is_err());
+
+        buffer[17..21].copy_from_slice(&3u32.to_be_bytes());
+        assert!(Packet::from_buffer(&buffer).is_err());
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_packets() {
+        assert!(Packet::from_buffer(&[0u8; 20]).is_err());
+
+        let mut buffer = Packet::keepalive(1, 1).to_buffer();
+        buffer[0] = 200;
+        assert!(Packet::from_buffer(&buffer).is_err());
+    }
+}
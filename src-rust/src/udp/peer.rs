@@ -0,0 +1,117 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long the current address has to be silent before packets for the
+/// same session are accepted from another address.
+const MIGRATION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The remote end of a session and the address its traffic currently
+/// arrives from.
+#[derive(Debug)]
+pub struct Peer {
+    addr: SocketAddr,
+    session_id: Option<u32>,
+    last_seen: Option<Instant>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, session_id: Option<u32>) -> Self {
+        Self {
+            addr,
+            session_id,
+            last_seen: None
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn session_id(&self) -> Option<u32> {
+        self.session_id
+    }
+
+    /// Time since the last accepted packet, or `None` if nothing has arrived yet.
+    pub fn idle_time(&self) -> Option<Duration> {
+        self.last_seen.map(|last_seen| last_seen.elapsed())
+    }
+
+    /// Checks whether a packet from `addr` belongs to this session.
+    ///
+    /// The first packet from the signalled address fixes the session id if it
+    /// was not known up front. Packets for the same session are also accepted
+    /// from other addresses, which are either additional paths of a multipath
+    /// sender or a new address the peer has moved to. The peer is migrated
+    /// there once its current address has gone quiet.
+    pub fn accept(&mut self, addr: SocketAddr, session_id: u32) -> bool {
+        if addr == self.addr {
+            match self.session_id {
+                Some(id) if id != session_id => return false,
+                Some(_) => (),
+                None => self.session_id = Some(session_id),
+            }
+
+            self.last_seen = Some(Instant::now());
+            return true;
+        }
+
+        if self.session_id != Some(session_id) {
+            return false;
+        }
+
+        if let Some(idle_time) = self.idle_time() {
+            if idle_time < MIGRATION_TIMEOUT {
+                return true;
+            }
+        }
+
+        println!("Session {:08x} moved from {} to {}", session_id, self.addr, addr);
+        self.addr = addr;
+        self.last_seen = Some(Instant::now());
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn learns_the_session_id_from_the_signalled_address() {
+        let mut peer = Peer::new(addr(1), None);
+
+        assert!(!peer.accept(addr(2), 7));
+        assert!(peer.accept(addr(1), 7));
+        assert_eq!(peer.session_id(), Some(7));
+        assert!(!peer.accept(addr(1), 8));
+    }
+
+    #[test]
+    fn accepts_other_paths_of_the_same_session() {
+        let mut peer = Peer::new(addr(1), Some(7));
+
+        assert!(peer.accept(addr(1), 7));
+        assert!(peer.accept(addr(2), 7));
+        assert!(!peer.accept(addr(2), 8));
+        // Still active where it was, so it does not move
+        assert_eq!(peer.addr(), addr(1));
+    }
+
+    #[test]
+    fn moves_once_the_current_address_is_quiet() {
+        let mut peer = Peer::new(addr(1), Some(7));
+        assert!(peer.accept(addr(1), 7));
+
+        thread::sleep(MIGRATION_TIMEOUT + Duration::from_millis(50));
+        assert!(peer.accept(addr(2), 7));
+        assert_eq!(peer.addr(), addr(2));
+        assert!(peer.idle_time().unwrap() < MIGRATION_TIMEOUT);
+    }
+}
@@ -1,8 +1,119 @@
-use serde::Deserialize;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Deserialize)]
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Mode {
     Send,
     Return
 }
 
+/// Returns a random identifier from the operating system's random number
+/// generator.
+pub fn random_id() -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    random_bytes(&mut bytes)?;
+
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Fills `buffer` from the operating system's random number generator, for
+/// values that have to stay secret.
+pub fn random_bytes(buffer: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buffer).map_err(|err| anyhow!("{}", err))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(anyhow!("Invalid hex string"));
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Exponential backoff for retrying a failing operation.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    delay: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            delay: initial,
+            next_attempt: None
+        }
+    }
+
+    /// Whether a failure has been recorded since the last reset.
+    pub fn is_active(&self) -> bool {
+        self.next_attempt.is_some()
+    }
+
+    /// Whether the next attempt is due.
+    pub fn ready(&self) -> bool {
+        match self.next_attempt {
+            Some(next_attempt) => Instant::now() >= next_attempt,
+            None => true
+        }
+    }
+
+    /// Records a failed attempt and doubles the delay until the next one.
+    pub fn failed(&mut self) {
+        self.next_attempt = Some(Instant::now() + self.delay);
+        self.delay = std::cmp::min(self.delay * 2, self.max);
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = self.initial;
+        self.next_attempt = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(25));
+        assert!(backoff.ready());
+        assert!(!backoff.is_active());
+
+        backoff.failed();
+        assert!(backoff.is_active());
+        assert!(!backoff.ready());
+        assert_eq!(backoff.delay, Duration::from_millis(20));
+
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.delay, Duration::from_millis(25));
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(backoff.ready());
+
+        backoff.reset();
+        assert!(!backoff.is_active());
+        assert_eq!(backoff.delay, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn random_ids_differ() {
+        assert_ne!(random_id().unwrap(), random_id().unwrap());
+    }
+}
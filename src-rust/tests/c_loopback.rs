@@ -0,0 +1,65 @@
+//! Builds and runs the C loopback example against the shared library, so
+//! the generated header and the C interface are exercised as C sees them.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Builds the library and the example once for all tests, returning the
+/// example. Everything goes to the target directory these tests were built
+/// in, wherever `CARGO_TARGET_DIR` or `--target-dir` put it.
+fn example() -> &'static Path {
+    static EXAMPLE: OnceLock<PathBuf> = OnceLock::new();
+
+    EXAMPLE.get_or_init(|| {
+        // Tests run from <target>/<profile>/deps, next to the library
+        let exe = env::current_exe().unwrap();
+        let lib_dir = exe.parent().and_then(Path::parent).unwrap();
+        let target_dir = lib_dir.parent().unwrap();
+        let profile = lib_dir.file_name().unwrap();
+        let example_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/c");
+        let example = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("c_loopback");
+
+        // `cargo test` does not build the shared library the example links
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo.current_dir(env!("CARGO_MANIFEST_DIR")).args(["build", "-p", "p2p_audio", "--lib", "--target-dir"]).arg(target_dir);
+        if profile == "release" {
+            cargo.arg("--release");
+        }
+        assert!(cargo.status().unwrap().success(), "Building the shared library failed");
+
+        let status = Command::new("make")
+            .current_dir(&example_dir)
+            .arg(format!("LIB_DIR={}", lib_dir.display()))
+            .arg(format!("OUT={}", example.display()))
+            .arg(&example)
+            .status()
+            .expect("Could not run make");
+        assert!(status.success(), "Building the C example failed");
+
+        example
+    })
+}
+
+fn run(name: &str, args: &[&str]) {
+    let identity_dir = env::temp_dir().join(format!("p2p_audio_{}_{}", name, std::process::id()));
+
+    let output = Command::new(example()).args(args).arg(&identity_dir).output().unwrap();
+    let _ = std::fs::remove_dir_all(&identity_dir);
+    println!("{}", String::from_utf8_lossy(&output.stdout));
+    eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(output.status.success(), "The C example failed with {:?}", output.status.code());
+}
+
+#[test]
+fn c_handshake() {
+    run("c_handshake", &["--handshake-only"]);
+}
+
+#[test]
+#[ignore = "streams between the default audio devices"]
+fn c_loopback() {
+    run("c_loopback", &[]);
+}
@@ -5,7 +5,9 @@ import Config from '../Config';
 import './Return.css';
 
 const Return = () => {
-    const [connection, setConnection] = React.useState();
+    const [room, setRoom] = React.useState();
+    const [state, setState] = React.useState();
+    const [error, setError] = React.useState();
     const [configs, setConfigs] = React.useState();
     const [config, setConfig] = React.useState({});
 
@@ -14,19 +16,17 @@ const Return = () => {
             .invoke('return', 'listen', {
                 ...config,
             })
-            .then(connection => setConnection(connection))
-            .catch(e => console.log(e));
+            .then(joined => setRoom(joined.room))
+            .catch(e => setError(e.message));
     };
 
-    const ack = () => {
-        window.electron.ipcRenderer
-            .invoke('return', 'ack', connection.id)
-            .then(connection => setConnection(connection));;
-    }
-
     React.useEffect(() => {
-        window.electron.ipcRenderer.on('return', (payload) => {
-            setConnection(payload);
+        window.electron.ipcRenderer.on('audio', event => {
+            if (event.type === 'state') {
+                setState(event.state);
+            } else if (event.type === 'error') {
+                setError(event.message);
+            }
         });
 
         window.electron.ipcRenderer.invoke('return', 'config').then(config => {
@@ -40,24 +40,21 @@ const Return = () => {
 
     return (
         <div className="return">
-            {connection ? (
+            {room ? (
                 <div>
-                    ID: {connection.id}<br/>
-                    State: {connection.state}<br/>
-                    Return: {connection.return_address}<br/>
-                    Send: {connection.send_address}
-                    {connection.state === 'SynSent' && (
-                        <button onClick={ack}>Ack</button>
-                    )}
+                    Room: {room}<br/>
+                    State: {state}<br/>
+                    {error}
                 </div>
             ) : (
                 <>
                     <Config configs={configs} config={config} setConfig={setConfig}/>
                     <button onClick={connect}>Connect</button>
+                    {error}
                 </>
             )}
         </div>
     )
 };
 
-export default Return;
\ No newline at end of file
+export default Return;
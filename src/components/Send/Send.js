@@ -1,48 +1,45 @@
 import React from 'react';
 
 const Send = () => {
-    const [connection, setConnection] = React.useState();
+    const [room, setRoom] = React.useState();
+    const [state, setState] = React.useState();
     const [error, setError] = React.useState();
     const [id, setId] = React.useState('');
 
     const connect = () => {
         window.electron.ipcRenderer
-            .invoke('send', 'syn', id)
-            .then(connection => setConnection(connection))
-            .catch(e => setError(e));
+            .invoke('send', 'join', id)
+            .then(joined => setRoom(joined.room))
+            .catch(e => setError(e.message));
     };
 
-    const stream = () => {
-        window.electron.ipcRenderer
-            .invoke('send', 'stream', connection);
-    }
-
     React.useEffect(() => {
-        window.electron.ipcRenderer.on('send', (payload) => {
-            setConnection(payload);
+        window.electron.ipcRenderer.on('audio', event => {
+            if (event.type === 'state') {
+                setState(event.state);
+            } else if (event.type === 'error') {
+                setError(event.message);
+            }
         });
-    }, []);
+    }, []);
 
     return (
         <div>
-            {connection ? (
+            {room ? (
                 <div>
-                    ID: {connection.id}<br/>
-                    State: {connection.state}<br/>
-                    Local: {connection.send_address}<br/>
-                    Remote: {connection.return_address}<br/>
-                    {connection.state === 'SynAcked' && (
-                        <button onClick={stream}>Stream</button>
-                    )}
+                    Room: {room}<br/>
+                    State: {state}<br/>
+                    {error}
                 </div>
             ): (
                 <>
                     <input onChange={e => setId(e.target.value)} value={id}/>
                     <button onClick={connect}>Connect</button>
+                    {error}
                 </>
             )}
         </div>
     )
 };
 
-export default Send;
\ No newline at end of file
+export default Send;